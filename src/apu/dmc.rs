@@ -0,0 +1,222 @@
+use crate::region::Region;
+use crate::savestate::{Snapshot, State};
+
+// Delta modulation channel: plays 1-bit delta samples read from PRG space.
+// Every byte it reads holds the CPU off the bus for a few cycles.
+// https://www.nesdev.org/wiki/APU_DMC
+
+pub struct	Dmc {
+    pub irq_enabled:	bool,
+    looping:	bool,
+    period:	u16,
+    timer:	u16,
+    level:	u8,	// 7-bit output level
+    sample_address:	u16,
+    sample_length:	u16,
+    address:	u16,	// of the next byte to fetch
+    bytes_remaining:	u16,
+    buffer:	Option<u8>,
+    shift:	u8,
+    bits_remaining:	u8,
+    silence:	bool,
+    pub irq:	bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+	Dmc {
+	    irq_enabled: false,
+	    looping: false,
+	    period: Region::Ntsc.dmc_rate(0) - 1,
+	    timer: 0,
+	    level: 0,
+	    sample_address: 0xC000,
+	    sample_length: 1,
+	    address: 0xC000,
+	    bytes_remaining: 0,
+	    buffer: None,
+	    shift: 0,
+	    bits_remaining: 8,
+	    silence: true,
+	    irq: false,
+	}
+    }
+}
+
+impl	Dmc {
+    /// `reg` is the offset from $4010.
+    pub fn write(&mut self, reg: u16, value: u8, region: Region) {
+	match reg {
+	    0 => {
+		self.irq_enabled = value & 0x80 != 0;
+		if !self.irq_enabled {
+		    self.irq = false;
+		}
+		self.looping = value & 0x40 != 0;
+		self.period = region.dmc_rate(value) - 1;
+	    }
+	    1 => {
+		self.level = value & 0x7F;
+	    }
+	    2 => {
+		self.sample_address = 0xC000 | ((value as u16) << 6);
+	    }
+	    _ => {
+		self.sample_length = ((value as u16) << 4) | 0x01;
+	    }
+	}
+    }
+
+    fn restart(&mut self) {
+	self.address = self.sample_address;
+	self.bytes_remaining = self.sample_length;
+    }
+
+    /// The $4015 enable bit: stops the sample, or starts it over when it
+    /// has already finished.
+    pub fn set_enabled(&mut self, enabled: bool) {
+	if !enabled {
+	    self.bytes_remaining = 0;
+	}
+	else if self.bytes_remaining == 0 {
+	    self.restart();
+	}
+    }
+
+    pub fn active(&self) -> bool {
+	self.bytes_remaining > 0
+    }
+
+    /// The address the reader wants to fetch, when its buffer has run dry.
+    pub fn fetch_request(&self) -> Option<u16> {
+	if self.buffer.is_none() && self.bytes_remaining > 0 { Some(self.address) } else { None }
+    }
+
+    /// Hands over the byte fetched for `fetch_request`.
+    pub fn fill(&mut self, value: u8) {
+	self.buffer = Some(value);
+	self.address = if self.address == 0xFFFF { 0x8000 } else { self.address + 1 };
+	self.bytes_remaining -= 1;
+	if self.bytes_remaining == 0 {
+	    if self.looping {
+		self.restart();
+	    }
+	    else if self.irq_enabled {
+		self.irq = true;
+	    }
+	}
+    }
+
+    /// Clocked every CPU cycle; the rate table is in CPU cycles.
+    pub fn clock_timer(&mut self) {
+	if self.timer > 0 {
+	    self.timer -= 1;
+	    return;
+	}
+	self.timer = self.period;
+
+	if !self.silence {
+	    if self.shift & 0x01 != 0 {
+		if self.level <= 125 {
+		    self.level += 2;
+		}
+	    }
+	    else if self.level >= 2 {
+		self.level -= 2;
+	    }
+	}
+	self.shift >>= 1;
+	self.bits_remaining -= 1;
+	if self.bits_remaining == 0 {
+	    self.bits_remaining = 8;
+	    match self.buffer.take() {
+		Some(value) => {
+		    self.silence = false;
+		    self.shift = value;
+		}
+		None => self.silence = true,
+	    }
+	}
+    }
+
+    pub fn output(&self) -> u8 {
+	self.level
+    }
+}
+
+impl Snapshot for Dmc {
+    fn snapshot(&mut self, state: &mut State) {
+	state.bool(&mut self.irq_enabled);
+	state.bool(&mut self.looping);
+	state.u16(&mut self.period);
+	state.u16(&mut self.timer);
+	state.u8(&mut self.level);
+	state.u16(&mut self.sample_address);
+	state.u16(&mut self.sample_length);
+	state.u16(&mut self.address);
+	state.u16(&mut self.bytes_remaining);
+	state.option_u8(&mut self.buffer);
+	state.u8(&mut self.shift);
+	state.u8(&mut self.bits_remaining);
+	state.bool(&mut self.silence);
+	state.bool(&mut self.irq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(dmc: &mut Dmc, cycles: usize, memory: u8) {
+	for _ in 0..cycles {
+	    if dmc.fetch_request().is_some() {
+		dmc.fill(memory);
+	    }
+	    dmc.clock_timer();
+	}
+    }
+
+    #[test]
+    fn test_sample_plays_and_raises_irq() {
+	let mut dmc = Dmc::default();
+	dmc.write(0, 0x8F, Region::Ntsc);	// IRQ, fastest rate
+	dmc.write(1, 0x40, Region::Ntsc);
+	dmc.write(2, 0x01, Region::Ntsc);
+	dmc.write(3, 0x00, Region::Ntsc);	// one byte
+	dmc.set_enabled(true);
+	assert_eq!(dmc.fetch_request(), Some(0xC040));
+
+	play(&mut dmc, 54 * 24, 0xFF);
+	assert!(dmc.irq);
+	assert!(!dmc.active());
+	assert_eq!(dmc.output(), 0x40 + 2 * 8);
+
+	dmc.write(0, 0x0F, Region::Ntsc);
+	assert!(!dmc.irq);
+    }
+
+    #[test]
+    fn test_looping_sample_restarts() {
+	let mut dmc = Dmc::default();
+	dmc.write(0, 0x4F, Region::Ntsc);
+	dmc.write(3, 0x01, Region::Ntsc);	// 17 bytes
+	dmc.set_enabled(true);
+	play(&mut dmc, 54 * 8 * 20, 0x00);
+	assert!(dmc.active());
+	assert!(!dmc.irq);
+	assert_eq!(dmc.output(), 0);
+    }
+
+    #[test]
+    fn test_address_wraps_to_8000() {
+	let mut dmc = Dmc::default();
+	dmc.write(2, 0xFF, Region::Ntsc);
+	dmc.write(3, 0x04, Region::Ntsc);	// 65 bytes
+	dmc.set_enabled(true);
+	for _ in 0..0x40 {
+	    dmc.fill(0);
+	    dmc.buffer = None;
+	}
+	assert_eq!(dmc.fetch_request(), Some(0x8000));
+    }
+}
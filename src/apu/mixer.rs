@@ -0,0 +1,261 @@
+use std::f64::consts::PI;
+
+// Turns the channel levels into audio at the host's sample rate: the
+// non-linear DAC curves, a band-limited resampler from the CPU clock, and
+// the filters that sit between the 2A03 and the RCA jack.
+// https://www.nesdev.org/wiki/APU_Mixer
+
+const BLIP_WIDTH: usize = 16;	// output samples each step is spread over
+const BLIP_PHASES: usize = 64;	// sub-sample offsets the kernel is tabulated at
+const BLIP_CUTOFF: f64 = 0.45;	// of the output sample rate
+
+// flush the resampler this often even when nobody ends the frame
+const MAX_CYCLES_PER_FLUSH: u32 = 0x8000;
+
+// Band-limited synthesis: the mixed output only ever changes in steps, so
+// instead of filtering the 1.79 MHz signal, each step is added as a
+// windowed-sinc impulse to a buffer at the output rate which is then
+// integrated.
+struct	Blip {
+    kernels:	Vec<[f32; BLIP_WIDTH]>,
+    factor:	f64,	// output samples per input clock
+    offset:	f64,	// output position of the frame's first clock
+    buffer:	Vec<f32>,
+    integrator:	f32,
+}
+
+impl	Blip {
+    fn	new(clock_rate: f64, sample_rate: u32) -> Self {
+	let half = (BLIP_WIDTH / 2) as f64;
+	let mut kernels = Vec::with_capacity(BLIP_PHASES + 1);
+	for phase in 0..=BLIP_PHASES {
+	    let frac = phase as f64 / BLIP_PHASES as f64;
+	    let mut kernel = [0.0; BLIP_WIDTH];
+	    let mut sum = 0.0;
+	    for (k, tap) in kernel.iter_mut().enumerate() {
+		let x = k as f64 - half + 1.0 - frac;
+		let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * BLIP_CUTOFF * x).sin() / (2.0 * PI * BLIP_CUTOFF * x) };
+		let u = (x + half) / BLIP_WIDTH as f64;
+		let window = 0.42 - 0.5 * (2.0 * PI * u).cos() + 0.08 * (4.0 * PI * u).cos();
+		*tap = (sinc * window) as f32;
+		sum += *tap;
+	    }
+	    // every step has to integrate to exactly its own height
+	    for tap in kernel.iter_mut() {
+		*tap /= sum;
+	    }
+	    kernels.push(kernel);
+	}
+	Blip {
+	    kernels,
+	    factor: sample_rate as f64 / clock_rate,
+	    offset: 0.0,
+	    buffer: Vec::new(),
+	    integrator: 0.0,
+	}
+    }
+
+    fn add_delta(&mut self, clock: u32, delta: f32) {
+	let position = self.offset + clock as f64 * self.factor;
+	let index = position as usize;
+	let phase = ((position - index as f64) * BLIP_PHASES as f64).round() as usize;
+	if self.buffer.len() < index + 1 + BLIP_WIDTH {
+	    self.buffer.resize(index + 1 + BLIP_WIDTH, 0.0);
+	}
+	for (k, tap) in self.kernels[phase].iter().enumerate() {
+	    self.buffer[index + 1 + k] += delta * tap;
+	}
+    }
+
+    // Ends the frame after `clocks` input clocks and appends the samples
+    // no later step can touch any more.
+    fn end_frame(&mut self, clocks: u32, out: &mut Vec<f32>) {
+	let end = self.offset + clocks as f64 * self.factor;
+	let count = end as usize;
+	if self.buffer.len() < count {
+	    self.buffer.resize(count, 0.0);
+	}
+	for sample in self.buffer.drain(..count) {
+	    self.integrator += sample;
+	    out.push(self.integrator);
+	}
+	self.offset = end - count as f64;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum	FilterKind {
+    HighPass,
+    LowPass,
+}
+
+// first-order RC filter
+struct	Filter {
+    kind:	FilterKind,
+    alpha:	f32,
+    input:	f32,
+    output:	f32,
+}
+
+impl	Filter {
+    fn	new(kind: FilterKind, cutoff: f32, sample_rate: u32) -> Self {
+	let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+	let dt = 1.0 / sample_rate as f32;
+	let alpha = match kind {
+	    FilterKind::HighPass => rc / (rc + dt),
+	    FilterKind::LowPass => dt / (rc + dt),
+	};
+	Filter { kind, alpha, input: 0.0, output: 0.0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+	self.output = match self.kind {
+	    FilterKind::HighPass => self.alpha * (self.output + input - self.input),
+	    FilterKind::LowPass => self.output + self.alpha * (input - self.output),
+	};
+	self.input = input;
+	self.output
+    }
+}
+
+fn	filter_chain(sample_rate: u32) -> [Filter; 3] {
+    [
+	Filter::new(FilterKind::HighPass, 90.0, sample_rate),
+	Filter::new(FilterKind::HighPass, 440.0, sample_rate),
+	Filter::new(FilterKind::LowPass, 14000.0, sample_rate),
+    ]
+}
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+pub struct	Mixer {
+    pulse_table:	[f32; 31],
+    tnd_table:	[f32; 203],
+    sample_rate:	u32,
+    blip:	Blip,
+    filters:	[Filter; 3],
+    level:	f32,	// mixed output as of the last change
+    clock:	u32,	// CPU cycles since the last flush
+    pending:	Vec<f32>,	// samples of the frame in progress
+    frame:	Vec<f32>,	// samples of the last complete frame
+}
+
+impl	Mixer {
+    pub fn	new(clock_rate: f64, sample_rate: u32) -> Self {
+	let mut pulse_table = [0.0; 31];
+	for (n, entry) in pulse_table.iter_mut().enumerate().skip(1) {
+	    *entry = 95.52 / (8128.0 / n as f32 + 100.0);
+	}
+	let mut tnd_table = [0.0; 203];
+	for (n, entry) in tnd_table.iter_mut().enumerate().skip(1) {
+	    *entry = 163.67 / (24329.0 / n as f32 + 100.0);
+	}
+	Mixer {
+	    pulse_table,
+	    tnd_table,
+	    sample_rate,
+	    blip: Blip::new(clock_rate, sample_rate),
+	    filters: filter_chain(sample_rate),
+	    level: 0.0,
+	    clock: 0,
+	    pending: Vec::new(),
+	    frame: Vec::new(),
+	}
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+	self.sample_rate
+    }
+
+    /// Takes the channel levels for one CPU cycle. Expansion audio comes
+    /// already scaled to the output, and is added on as is.
+    pub fn mix(&mut self, pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8, expansion: f32) {
+	let level = self.pulse_table[(pulse1 + pulse2) as usize]
+	    + self.tnd_table[3 * triangle as usize + 2 * noise as usize + dmc as usize]
+	    + expansion;
+	if level != self.level {
+	    self.blip.add_delta(self.clock, level - self.level);
+	    self.level = level;
+	}
+	self.clock += 1;
+	if self.clock == MAX_CYCLES_PER_FLUSH {
+	    self.flush();
+	}
+    }
+
+    fn flush(&mut self) {
+	let start = self.pending.len();
+	self.blip.end_frame(self.clock, &mut self.pending);
+	self.clock = 0;
+	for sample in &mut self.pending[start..] {
+	    for filter in &mut self.filters {
+		*sample = filter.process(*sample);
+	    }
+	}
+    }
+
+    /// Finishes the frame's audio; it stays readable through `frame()`
+    /// until the next frame ends.
+    pub fn end_frame(&mut self) {
+	self.flush();
+	self.frame = std::mem::take(&mut self.pending);
+    }
+
+    pub fn frame(&self) -> &[f32] {
+	&self.frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_RATE: f64 = 1789772.7;
+
+    #[test]
+    fn test_dac_tables() {
+	let mixer = Mixer::new(CLOCK_RATE, DEFAULT_SAMPLE_RATE);
+	assert_eq!(mixer.pulse_table[0], 0.0);
+	assert!((mixer.pulse_table[30] - 0.2575).abs() < 0.0001);
+	assert!((mixer.tnd_table[202] - 0.7424).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_blip_step_settles() {
+	let mut blip = Blip::new(CLOCK_RATE, DEFAULT_SAMPLE_RATE);
+	let mut out = Vec::new();
+	blip.add_delta(1000, 1.0);
+	blip.end_frame(10000, &mut out);
+	assert_eq!(out.len(), 246);
+	assert_eq!(out[0], 0.0);
+	assert!((out[out.len() - 1] - 1.0).abs() < 1e-5);
+	// some ringing around the edge and no more
+	assert!(out.iter().all(|s| *s > -0.15 && *s < 1.15));
+    }
+
+    #[test]
+    fn test_samples_per_frame() {
+	let mut mixer = Mixer::new(CLOCK_RATE, 48000);
+	let mut total = 0;
+	for _ in 0..60 {
+	    for _ in 0..29781 {
+		mixer.mix(0, 0, 0, 0, 0, 0.0);
+	    }
+	    mixer.end_frame();
+	    total += mixer.frame().len();
+	}
+	assert!((47915..=47925).contains(&total), "{}", total);
+    }
+
+    #[test]
+    fn test_high_pass_removes_dc() {
+	let mut mixer = Mixer::new(CLOCK_RATE, DEFAULT_SAMPLE_RATE);
+	for _ in 0..CLOCK_RATE as usize / 2 {
+	    mixer.mix(15, 15, 0, 0, 0, 0.0);
+	}
+	mixer.end_frame();
+	let samples = mixer.frame();
+	assert!(samples[10] > 0.1);
+	assert!(samples[samples.len() - 1].abs() < 0.001);
+    }
+}
@@ -0,0 +1,108 @@
+use super::pulse::Pulse;
+use crate::savestate::{Snapshot, State};
+
+// Nintendo's MMC5 adds two more pulse channels, without sweep units, and
+// an 8-bit PCM channel. Its envelopes and length counters run off a
+// 240 Hz timer of its own rather than the APU's frame sequencer.
+// https://www.nesdev.org/wiki/MMC5_audio
+
+// the pulses go through a DAC like the APU's and come out about as loud
+const PULSE_LEVEL: f32 = 0.00752;
+// a PCM step is about half a DMC one
+const PCM_LEVEL: f32 = 0.00335 / 2.0;
+
+const QUARTER_FRAME_CYCLES: u16 = 7457;
+
+// $5015
+const STATUS_PULSE_1: u8 = 0x01;
+const STATUS_PULSE_2: u8 = 0x01 << 1;
+
+pub struct	Mmc5 {
+    pulse1:	Pulse,
+    pulse2:	Pulse,
+    pcm:	u8,
+    cycle:	u64,
+    frame_timer:	u16,
+}
+
+impl Default for Mmc5 {
+    fn default() -> Self {
+	Mmc5 {
+	    pulse1: Pulse::mmc5(),
+	    pulse2: Pulse::mmc5(),
+	    pcm: 0,
+	    cycle: 0,
+	    frame_timer: QUARTER_FRAME_CYCLES,
+	}
+    }
+}
+
+impl	Mmc5 {
+    pub fn write(&mut self, addr: u16, value: u8) {
+	match addr {
+	    0x5000..=0x5003 => self.pulse1.write(addr - 0x5000, value),
+	    0x5004..=0x5007 => self.pulse2.write(addr - 0x5004, value),
+	    // in write mode a 0 is ignored; reading samples from PRG isn't
+	    // something an NSF does
+	    0x5011 if value != 0 => self.pcm = value,
+	    0x5015 => {
+		self.pulse1.length.set_enabled(value & STATUS_PULSE_1 != 0);
+		self.pulse2.length.set_enabled(value & STATUS_PULSE_2 != 0);
+	    }
+	    _ => {}
+	}
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock(&mut self) {
+	self.frame_timer -= 1;
+	if self.frame_timer == 0 {
+	    self.frame_timer = QUARTER_FRAME_CYCLES;
+	    for pulse in [&mut self.pulse1, &mut self.pulse2] {
+		pulse.envelope.clock();
+		pulse.length.clock();
+	    }
+	}
+	if self.cycle & 0x01 != 0 {
+	    self.pulse1.clock_timer();
+	    self.pulse2.clock_timer();
+	}
+	self.cycle += 1;
+    }
+
+    pub fn output(&self) -> f32 {
+	(self.pulse1.output() + self.pulse2.output()) as f32 * PULSE_LEVEL + self.pcm as f32 * PCM_LEVEL
+    }
+}
+
+impl Snapshot for Mmc5 {
+    fn snapshot(&mut self, state: &mut State) {
+	self.pulse1.snapshot(state);
+	self.pulse2.snapshot(state);
+	state.u8(&mut self.pcm);
+	state.u64(&mut self.cycle);
+	state.u16(&mut self.frame_timer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulses_play_short_periods() {
+	let mut mmc5 = Mmc5::default();
+	mmc5.write(0x5015, STATUS_PULSE_1);
+	mmc5.write(0x5000, 0xBF);
+	mmc5.write(0x5002, 0x02);
+	mmc5.write(0x5003, 0x08);	// period 2 would mute an APU pulse
+	let high = (0..48).filter(|_| { mmc5.clock(); mmc5.output() > 0.0 }).count();
+	assert_eq!(high, 24);
+
+	mmc5.write(0x5015, 0);
+	mmc5.write(0x5011, 0x80);
+	assert_eq!(mmc5.output(), 128.0 * PCM_LEVEL);
+	mmc5.write(0x5011, 0x00);
+	assert_eq!(mmc5.output(), 128.0 * PCM_LEVEL);
+    }
+}
@@ -0,0 +1,618 @@
+mod dmc;
+mod mixer;
+mod mmc5;
+mod noise;
+mod pulse;
+mod triangle;
+mod units;
+mod vrc6;
+
+use dmc::Dmc;
+use mixer::Mixer;
+use mmc5::Mmc5;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+use vrc6::Vrc6;
+
+use crate::region::Region;
+use crate::savestate::{Snapshot, State};
+
+// The 2A03's audio processing unit, mapped at $4000-$4017.
+// https://www.nesdev.org/wiki/APU
+
+// 7  bit  0
+// ---- ----
+// IF.D NT21
+// |||| ||||
+// |||| |||+- Pulse 1 length counter enable / active
+// |||| ||+-- Pulse 2
+// |||| |+--- Triangle
+// |||| +---- Noise
+// |||+------ DMC
+// |+-------- Frame interrupt (read)
+// +--------- DMC interrupt (read)
+
+pub const APU_STATUS_PULSE_1: u8 = 0x01;
+pub const APU_STATUS_PULSE_2: u8 = 0x01 << 1;
+pub const APU_STATUS_TRIANGLE: u8 = 0x01 << 2;
+pub const APU_STATUS_NOISE: u8 = 0x01 << 3;
+pub const APU_STATUS_DMC: u8 = 0x01 << 4;
+pub const APU_STATUS_FRAME_IRQ: u8 = 0x01 << 6;
+pub const APU_STATUS_DMC_IRQ: u8 = 0x01 << 7;
+
+// 7  bit  0
+// ---- ----
+// MI.. ....
+// ||
+// |+-------- Frame interrupt inhibit
+// +--------- Sequencer mode: 0 = 4-step, 1 = 5-step
+
+pub const APU_FRAME_IRQ_INHIBIT: u8 = 0x01 << 6;
+pub const APU_FRAME_5_STEP: u8 = 0x01 << 7;
+
+pub const APU_STATUS: u16 = 0x4015;
+pub const APU_FRAME_COUNTER: u16 = 0x4017;
+
+const APU_CHANNELS: usize = 5;
+const CHANNELS: usize = APU_CHANNELS + 2;
+
+/// The sound sources that can be muted or recorded on their own. An
+/// expansion chip counts as one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum	Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Vrc6,
+    Mmc5,
+}
+
+impl	Channel {
+    pub const ALL: [Channel; CHANNELS] = [
+	Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc,
+	Channel::Vrc6, Channel::Mmc5,
+    ];
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+	Channel::ALL.iter()
+	    .find(|channel| channel.name() == name.to_ascii_lowercase())
+	    .copied()
+	    .ok_or(format!("unknown channel {}, expected pulse1, pulse2, triangle, noise, dmc, vrc6 or mmc5", name))
+    }
+
+    pub fn name(&self) -> &'static str {
+	match self {
+	    Channel::Pulse1 => "pulse1",
+	    Channel::Pulse2 => "pulse2",
+	    Channel::Triangle => "triangle",
+	    Channel::Noise => "noise",
+	    Channel::Dmc => "dmc",
+	    Channel::Vrc6 => "vrc6",
+	    Channel::Mmc5 => "mmc5",
+	}
+    }
+}
+
+pub struct	Apu {
+    pulse1:	Pulse,
+    pulse2:	Pulse,
+    triangle:	Triangle,
+    noise:	Noise,
+    dmc:	Dmc,
+    vrc6:	Option<Vrc6>,
+    mmc5:	Option<Mmc5>,
+    mixer:	Mixer,
+    muted:	[bool; CHANNELS],
+    stems:	Vec<Mixer>,	// one per channel, only while recording stems
+    region:	Region,
+    cycle:	u64,	// CPU cycles; the pulse timers run on every other one
+    frame_cycle:	u32,	// CPU cycles since the frame sequencer started over
+    five_step:	bool,
+    frame_irq_inhibit:	bool,
+    frame_irq:	bool,
+    frame_counter:	u8,	// last value written to $4017, written again on reset
+    frame_reset_delay:	u8,	// CPU cycles until a $4017 write restarts the sequencer
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl	Apu {
+    pub fn	new() -> Self {
+	Apu {
+	    pulse1: Pulse::new(1),
+	    pulse2: Pulse::new(2),
+	    triangle: Triangle::default(),
+	    noise: Noise::default(),
+	    dmc: Dmc::default(),
+	    vrc6: None,
+	    mmc5: None,
+	    mixer: Mixer::new(Region::Ntsc.cpu_clock_rate(), mixer::DEFAULT_SAMPLE_RATE),
+	    muted: [false; CHANNELS],
+	    stems: Vec::new(),
+	    region: Region::Ntsc,
+	    cycle: 0,
+	    frame_cycle: 0,
+	    five_step: false,
+	    frame_irq_inhibit: false,
+	    frame_irq: false,
+	    frame_counter: 0,
+	    frame_reset_delay: 0,
+	}
+    }
+
+    fn rebuild_mixers(&mut self, sample_rate: u32) {
+	self.mixer = Mixer::new(self.region.cpu_clock_rate(), sample_rate);
+	for stem in &mut self.stems {
+	    *stem = Mixer::new(self.region.cpu_clock_rate(), sample_rate);
+	}
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+	self.region = region;
+	self.rebuild_mixers(self.mixer.sample_rate());
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+	self.rebuild_mixers(sample_rate);
+    }
+
+    /// Takes the channel out of the mix. Its stem, if recorded, still
+    /// has it.
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+	self.muted[channel as usize] = muted;
+    }
+
+    /// Adds the expansion chip's channels to the mix, for cartridges and
+    /// NSFs that have one.
+    pub fn enable_expansion(&mut self, channel: Channel) {
+	match channel {
+	    Channel::Vrc6 => self.vrc6 = Some(Vrc6::default()),
+	    Channel::Mmc5 => self.mmc5 = Some(Mmc5::default()),
+	    _ => {}
+	}
+    }
+
+    /// The 2A03's channels and those of the enabled expansion chips.
+    pub fn channels(&self) -> Vec<Channel> {
+	Channel::ALL.iter()
+	    .filter(|channel| match channel {
+		Channel::Vrc6 => self.vrc6.is_some(),
+		Channel::Mmc5 => self.mmc5.is_some(),
+		_ => true,
+	    })
+	    .copied()
+	    .collect()
+    }
+
+    /// Starts mixing every channel on its own as well, each through the
+    /// same DAC curve and filters as the full mix.
+    pub fn record_stems(&mut self) {
+	self.stems = Channel::ALL.iter()
+	    .map(|_| Mixer::new(self.region.cpu_clock_rate(), self.mixer.sample_rate()))
+	    .collect();
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+	let mut status = 0;
+	if self.pulse1.length.active() {
+	    status |= APU_STATUS_PULSE_1;
+	}
+	if self.pulse2.length.active() {
+	    status |= APU_STATUS_PULSE_2;
+	}
+	if self.triangle.length.active() {
+	    status |= APU_STATUS_TRIANGLE;
+	}
+	if self.noise.length.active() {
+	    status |= APU_STATUS_NOISE;
+	}
+	if self.dmc.active() {
+	    status |= APU_STATUS_DMC;
+	}
+	if self.frame_irq {
+	    status |= APU_STATUS_FRAME_IRQ;
+	}
+	if self.dmc.irq {
+	    status |= APU_STATUS_DMC_IRQ;
+	}
+	// reading acknowledges the frame interrupt, not the DMC's
+	self.frame_irq = false;
+	status
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+	match addr {
+	    0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, value),
+	    0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, value),
+	    0x4008..=0x400B => self.triangle.write(addr - 0x4008, value),
+	    0x400C..=0x400F => self.noise.write(addr - 0x400C, value, self.region),
+	    0x4010..=0x4013 => self.dmc.write(addr - 0x4010, value, self.region),
+	    APU_STATUS => {
+		self.pulse1.length.set_enabled(value & APU_STATUS_PULSE_1 != 0);
+		self.pulse2.length.set_enabled(value & APU_STATUS_PULSE_2 != 0);
+		self.triangle.length.set_enabled(value & APU_STATUS_TRIANGLE != 0);
+		self.noise.length.set_enabled(value & APU_STATUS_NOISE != 0);
+		self.dmc.set_enabled(value & APU_STATUS_DMC != 0);
+		self.dmc.irq = false;
+	    }
+	    APU_FRAME_COUNTER => {
+		self.frame_counter = value;
+		self.frame_irq_inhibit = value & APU_FRAME_IRQ_INHIBIT != 0;
+		if self.frame_irq_inhibit {
+		    self.frame_irq = false;
+		}
+		// the sequencer restarts 3 cycles after a write that lands
+		// on an APU cycle, 4 after one that lands between them
+		self.frame_reset_delay = if self.cycle & 0x01 == 0 { 3 } else { 4 };
+	    }
+	    _ => {}
+	}
+    }
+
+    /// Writes the expansion chips listen to, which also go on to the
+    /// cartridge.
+    pub fn write_expansion(&mut self, addr: u16, value: u8) {
+	match addr {
+	    0x5000..=0x5015 => if let Some(mmc5) = &mut self.mmc5 { mmc5.write(addr, value) },
+	    0x9000..=0xB002 => if let Some(vrc6) = &mut self.vrc6 { vrc6.write(addr, value) },
+	    _ => {}
+	}
+    }
+
+    // envelopes and the triangle's linear counter
+    fn quarter_frame(&mut self) {
+	self.pulse1.envelope.clock();
+	self.pulse2.envelope.clock();
+	self.noise.envelope.clock();
+	self.triangle.clock_linear_counter();
+    }
+
+    // length counters and sweeps
+    fn half_frame(&mut self) {
+	self.pulse1.length.clock();
+	self.pulse2.length.clock();
+	self.triangle.length.clock();
+	self.noise.length.clock();
+	self.pulse1.clock_sweep();
+	self.pulse2.clock_sweep();
+    }
+
+    // The 4-step sequence raises its interrupt over the last three cycles
+    // of the frame, the 5-step one never does.
+    // https://www.nesdev.org/wiki/APU_Frame_Counter
+    fn clock_frame_sequencer(&mut self) {
+	if self.frame_reset_delay > 0 {
+	    self.frame_reset_delay -= 1;
+	    if self.frame_reset_delay == 0 {
+		self.five_step = self.frame_counter & APU_FRAME_5_STEP != 0;
+		self.frame_cycle = 0;
+		if self.five_step {
+		    self.quarter_frame();
+		    self.half_frame();
+		}
+		return;
+	    }
+	}
+
+	self.frame_cycle += 1;
+	let steps = self.region.apu_frame_steps(self.five_step);
+	let step = steps.iter().position(|cycle| *cycle == self.frame_cycle);
+	match step {
+	    Some(0) | Some(2) => self.quarter_frame(),
+	    Some(1) | Some(4) => {
+		self.quarter_frame();
+		self.half_frame();
+	    }
+	    Some(5) => self.frame_cycle = 0,
+	    _ => {}
+	}
+	if matches!(step, Some(3..=5)) && !self.five_step && !self.frame_irq_inhibit {
+	    self.frame_irq = true;
+	}
+    }
+
+    /// Runs the APU for one CPU cycle.
+    pub fn clock(&mut self) {
+	self.clock_frame_sequencer();
+	self.triangle.clock_timer();
+	self.noise.clock_timer();
+	self.dmc.clock_timer();
+	if self.cycle & 0x01 != 0 {
+	    self.pulse1.clock_timer();
+	    self.pulse2.clock_timer();
+	}
+	if let Some(vrc6) = &mut self.vrc6 {
+	    vrc6.clock();
+	}
+	if let Some(mmc5) = &mut self.mmc5 {
+	    mmc5.clock();
+	}
+	self.cycle += 1;
+
+	let mut levels = [self.pulse1.output(), self.pulse2.output(), self.triangle.output(), self.noise.output(), self.dmc.output()];
+	let mut expansion = [
+	    self.vrc6.as_ref().map_or(0.0, |vrc6| vrc6.output()),
+	    self.mmc5.as_ref().map_or(0.0, |mmc5| mmc5.output()),
+	];
+	for (channel, stem) in self.stems.iter_mut().enumerate() {
+	    let mut solo = [0; APU_CHANNELS];
+	    let mut solo_expansion = 0.0;
+	    if channel < APU_CHANNELS {
+		solo[channel] = levels[channel];
+	    }
+	    else {
+		solo_expansion = expansion[channel - APU_CHANNELS];
+	    }
+	    stem.mix(solo[0], solo[1], solo[2], solo[3], solo[4], solo_expansion);
+	}
+	for (level, muted) in levels.iter_mut().zip(self.muted) {
+	    if muted {
+		*level = 0;
+	    }
+	}
+	for (level, muted) in expansion.iter_mut().zip(&self.muted[APU_CHANNELS..]) {
+	    if *muted {
+		*level = 0.0;
+	    }
+	}
+	self.mixer.mix(levels[0], levels[1], levels[2], levels[3], levels[4], expansion.iter().sum());
+    }
+
+    pub fn end_frame(&mut self) {
+	self.mixer.end_frame();
+	for stem in &mut self.stems {
+	    stem.end_frame();
+	}
+    }
+
+    /// The audio of the last frame, at the configured sample rate.
+    pub fn frame_samples(&self) -> &[f32] {
+	self.mixer.frame()
+    }
+
+    /// The last frame of one channel on its own; empty unless
+    /// `record_stems` was called.
+    pub fn stem_samples(&self, channel: Channel) -> &[f32] {
+	self.stems.get(channel as usize).map_or(&[], |stem| stem.frame())
+    }
+
+    /// Back to the power-on state, as if `new`, but keeping the output
+    /// settings: region, sample rate, mutes, stems and expansion chips.
+    pub fn power_on(&mut self) {
+	self.pulse1 = Pulse::new(1);
+	self.pulse2 = Pulse::new(2);
+	self.triangle = Triangle::default();
+	self.noise = Noise::default();
+	self.dmc = Dmc::default();
+	if self.vrc6.is_some() {
+	    self.vrc6 = Some(Vrc6::default());
+	}
+	if self.mmc5.is_some() {
+	    self.mmc5 = Some(Mmc5::default());
+	}
+	self.cycle = 0;
+	self.frame_cycle = 0;
+	self.five_step = false;
+	self.frame_irq_inhibit = false;
+	self.frame_irq = false;
+	self.frame_counter = 0;
+	self.frame_reset_delay = 0;
+    }
+
+    /// Reset silences every channel and writes the last $4017 value again,
+    /// which is $00 at power on.
+    pub fn reset(&mut self) {
+	self.write_register(APU_STATUS, 0);
+	self.frame_irq = false;
+	self.write_register(APU_FRAME_COUNTER, self.frame_counter);
+    }
+
+    /// The level of the IRQ line, held until the source is acknowledged.
+    pub fn irq(&self) -> bool {
+	self.frame_irq || self.dmc.irq
+    }
+
+    /// Where the DMC wants its next sample byte read from. The bus stalls
+    /// the CPU for the read and passes the byte to `dmc_fill`.
+    pub fn dmc_fetch_request(&self) -> Option<u16> {
+	self.dmc.fetch_request()
+    }
+
+    pub fn dmc_fill(&mut self, value: u8) {
+	self.dmc.fill(value);
+    }
+}
+
+// The region comes from the bus, and the mixers are output settings.
+impl Snapshot for Apu {
+    fn snapshot(&mut self, state: &mut State) {
+	self.pulse1.snapshot(state);
+	self.pulse2.snapshot(state);
+	self.triangle.snapshot(state);
+	self.noise.snapshot(state);
+	self.dmc.snapshot(state);
+	state.option(&mut self.vrc6);
+	state.option(&mut self.mmc5);
+	state.u64(&mut self.cycle);
+	state.u32(&mut self.frame_cycle);
+	state.bool(&mut self.five_step);
+	state.bool(&mut self.frame_irq_inhibit);
+	state.bool(&mut self.frame_irq);
+	state.u8(&mut self.frame_counter);
+	state.u8(&mut self.frame_reset_delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_reports_length_counters() {
+	let mut apu = Apu::new();
+	apu.write_register(0x4003, 0x08);
+	assert_eq!(apu.read_status(), 0);
+	apu.write_register(APU_STATUS, APU_STATUS_PULSE_1 | APU_STATUS_PULSE_2);
+	apu.write_register(0x4003, 0x08);
+	apu.write_register(0x4007, 0x08);
+	assert_eq!(apu.read_status(), APU_STATUS_PULSE_1 | APU_STATUS_PULSE_2);
+	apu.write_register(APU_STATUS, APU_STATUS_PULSE_2);
+	assert_eq!(apu.read_status(), APU_STATUS_PULSE_2);
+    }
+
+    #[test]
+    fn test_length_runs_out_on_half_frames() {
+	let mut apu = Apu::new();
+	apu.write_register(APU_STATUS, APU_STATUS_PULSE_1);
+	apu.write_register(0x4003, 0x18);	// length index 3: 2 half frames
+	for _ in 0..14913 {
+	    apu.clock();
+	}
+	assert_eq!(apu.read_status() & APU_STATUS_PULSE_1, APU_STATUS_PULSE_1);
+	for _ in 14913..29829 {
+	    apu.clock();
+	}
+	assert_eq!(apu.read_status() & APU_STATUS_PULSE_1, 0);
+    }
+
+    fn run(apu: &mut Apu, cycles: u32) {
+	for _ in 0..cycles {
+	    apu.clock();
+	}
+    }
+
+    #[test]
+    fn test_5_step_write_clocks_after_delay() {
+	let mut apu = Apu::new();
+	apu.write_register(APU_STATUS, APU_STATUS_PULSE_1);
+	apu.write_register(0x4003, 0x18);
+	apu.write_register(APU_FRAME_COUNTER, APU_FRAME_5_STEP);
+	run(&mut apu, 2);
+	assert_eq!(apu.read_status(), APU_STATUS_PULSE_1);
+	run(&mut apu, 1);
+	apu.write_register(APU_FRAME_COUNTER, APU_FRAME_5_STEP);
+	run(&mut apu, 3);
+	assert_eq!(apu.read_status(), APU_STATUS_PULSE_1);
+	run(&mut apu, 1);
+	assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+	let mut apu = Apu::new();
+	apu.reset();
+	// 3 cycles of write delay, then the flag goes up on 29828
+	run(&mut apu, 3 + 29827);
+	assert!(!apu.irq());
+	run(&mut apu, 1);
+	assert!(apu.irq());
+	assert_eq!(apu.read_status() & APU_STATUS_FRAME_IRQ, APU_STATUS_FRAME_IRQ);
+	assert!(!apu.irq());
+	// still held for the two cycles after
+	run(&mut apu, 1);
+	assert!(apu.irq());
+	apu.write_register(APU_FRAME_COUNTER, APU_FRAME_IRQ_INHIBIT);
+	assert!(!apu.irq());
+	run(&mut apu, 2 * 29830);
+	assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_5_step_mode_has_no_irq() {
+	let mut apu = Apu::new();
+	apu.write_register(APU_FRAME_COUNTER, APU_FRAME_5_STEP);
+	run(&mut apu, 2 * 37282);
+	assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_dmc_irq_is_acknowledged_by_status_write() {
+	let mut apu = Apu::new();
+	apu.write_register(0x4010, 0x8F);
+	apu.write_register(APU_STATUS, APU_STATUS_DMC);
+	assert_eq!(apu.dmc_fetch_request(), Some(0xC000));
+	apu.dmc_fill(0x00);
+	assert!(apu.irq());
+	assert_eq!(apu.read_status(), APU_STATUS_DMC_IRQ);
+	apu.write_register(APU_STATUS, 0);
+	assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_frame_audio() {
+	let mut apu = Apu::new();
+	apu.write_register(APU_STATUS, APU_STATUS_PULSE_1);
+	apu.write_register(0x4000, 0xBF);
+	apu.write_register(0x4002, 0xFD);
+	apu.write_register(0x4003, 0x08);	// 440 Hz
+	run(&mut apu, 29781);
+	apu.end_frame();
+	let samples = apu.frame_samples();
+	assert_eq!(samples.len(), 733);
+	let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+	assert!(peak > 0.1, "{}", peak);
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+	samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn test_mute_and_stems() {
+	let mut apu = Apu::new();
+	apu.record_stems();
+	apu.set_muted(Channel::Pulse1, true);
+	apu.set_muted(Channel::Triangle, true);	// it rests at 15, not 0
+	apu.write_register(APU_STATUS, APU_STATUS_PULSE_1);
+	apu.write_register(0x4000, 0xBF);
+	apu.write_register(0x4002, 0xFD);
+	apu.write_register(0x4003, 0x08);
+	run(&mut apu, 29781);
+	apu.end_frame();
+	assert!(peak(apu.frame_samples()) < 0.001);
+	assert!(peak(apu.stem_samples(Channel::Pulse1)) > 0.1);
+	assert!(peak(apu.stem_samples(Channel::Pulse2)) < 0.001);
+	assert_eq!(apu.stem_samples(Channel::Dmc).len(), 733);
+    }
+
+    #[test]
+    fn test_channel_names() {
+	for channel in Channel::ALL {
+	    assert_eq!(Channel::from_name(channel.name()), Ok(channel));
+	}
+	assert_eq!(Channel::from_name("DMC"), Ok(Channel::Dmc));
+	assert!(Channel::from_name("fm").is_err());
+    }
+
+    #[test]
+    fn test_expansion_audio() {
+	assert_eq!(Apu::new().channels().len(), 5);
+	for muted in [false, true] {
+	    let mut apu = Apu::new();
+	    apu.enable_expansion(Channel::Vrc6);
+	    assert_eq!(apu.channels().last(), Some(&Channel::Vrc6));
+	    apu.record_stems();
+	    apu.set_muted(Channel::Triangle, true);
+	    apu.set_muted(Channel::Vrc6, muted);
+	    apu.write_expansion(0x9000, 0x7F);	// 50%, volume 15
+	    apu.write_expansion(0x9001, 0xFD);
+	    apu.write_expansion(0x9002, 0x80);
+	    run(&mut apu, 29781);
+	    apu.end_frame();
+	    assert!(peak(apu.stem_samples(Channel::Vrc6)) > 0.05);
+	    if muted {
+		assert!(peak(apu.frame_samples()) < 0.001);
+	    }
+	    else {
+		assert_eq!(apu.frame_samples(), apu.stem_samples(Channel::Vrc6));
+	    }
+	}
+    }
+}
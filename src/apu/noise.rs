@@ -0,0 +1,115 @@
+use super::units::{Envelope, LengthCounter};
+use crate::region::Region;
+use crate::savestate::{Snapshot, State};
+
+// https://www.nesdev.org/wiki/APU_Noise
+
+pub struct	Noise {
+    short_mode:	bool,	// feed back from bit 6 rather than bit 1, looping after 93 steps
+    period:	u16,
+    timer:	u16,
+    shift:	u16,	// 15-bit linear feedback shift register
+    pub envelope:	Envelope,
+    pub length:	LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+	Noise {
+	    short_mode: false,
+	    period: 0,
+	    timer: 0,
+	    shift: 1,
+	    envelope: Envelope::default(),
+	    length: LengthCounter::default(),
+	}
+    }
+}
+
+impl	Noise {
+    /// `reg` is the offset from $400C.
+    pub fn write(&mut self, reg: u16, value: u8, region: Region) {
+	match reg {
+	    0 => {
+		self.length.halt = value & 0x20 != 0;
+		self.envelope.write(value);
+	    }
+	    2 => {
+		self.short_mode = value & 0x80 != 0;
+		self.period = region.noise_period(value) - 1;
+	    }
+	    3 => {
+		self.length.load(value >> 3);
+		self.envelope.start = true;
+	    }
+	    _ => {}
+	}
+    }
+
+    /// Clocked every CPU cycle; the period table is in CPU cycles.
+    pub fn clock_timer(&mut self) {
+	if self.timer == 0 {
+	    self.timer = self.period;
+	    let tap = if self.short_mode { 6 } else { 1 };
+	    let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+	    self.shift = (self.shift >> 1) | (feedback << 14);
+	}
+	else {
+	    self.timer -= 1;
+	}
+    }
+
+    pub fn output(&self) -> u8 {
+	if self.shift & 0x01 != 0 || !self.length.active() {
+	    return 0;
+	}
+	self.envelope.output()
+    }
+}
+
+impl Snapshot for Noise {
+    fn snapshot(&mut self, state: &mut State) {
+	state.bool(&mut self.short_mode);
+	state.u16(&mut self.period);
+	state.u16(&mut self.timer);
+	state.u16(&mut self.shift);
+	self.envelope.snapshot(state);
+	self.length.snapshot(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // steps until the shift register comes back to where it started
+    fn loop_length(short_mode: bool) -> usize {
+	let mut noise = Noise::default();
+	noise.write(2, if short_mode { 0x80 } else { 0x00 }, Region::Ntsc);
+	noise.period = 0;	// shift on every clock
+	let start = noise.shift;
+	let mut steps = 0;
+	loop {
+	    noise.clock_timer();
+	    steps += 1;
+	    if noise.shift == start {
+		return steps;
+	    }
+	}
+    }
+
+    #[test]
+    fn test_lfsr_modes() {
+	assert_eq!(loop_length(false), 32767);
+	assert_eq!(loop_length(true), 93);
+    }
+
+    #[test]
+    fn test_period_comes_from_the_region() {
+	let mut noise = Noise::default();
+	noise.write(2, 0x0F, Region::Ntsc);
+	assert_eq!(noise.period, 4067);
+	noise.write(2, 0x0F, Region::Pal);
+	assert_eq!(noise.period, 3777);
+    }
+}
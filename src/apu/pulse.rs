@@ -0,0 +1,197 @@
+use super::units::{Envelope, LengthCounter};
+use crate::savestate::{Snapshot, State};
+
+// https://www.nesdev.org/wiki/APU_Pulse
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],	// 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0],	// 25%
+    [0, 1, 1, 1, 1, 0, 0, 0],	// 50%
+    [1, 0, 0, 1, 1, 1, 1, 1],	// 25% negated
+];
+
+#[derive(Default)]
+struct	Sweep {
+    enabled:	bool,
+    period:	u8,
+    negate:	bool,
+    shift:	u8,
+    reload:	bool,
+    divider:	u8,
+}
+
+#[derive(Default)]
+pub struct	Pulse {
+    // pulse 1 negates with ones' complement, pulse 2 with two's
+    ones_complement:	bool,
+    no_sweep:	bool,	// the MMC5's pulses have no sweep unit to mute them
+    duty:	u8,
+    sequence:	u8,
+    period:	u16,
+    timer:	u16,
+    sweep:	Sweep,
+    pub envelope:	Envelope,
+    pub length:	LengthCounter,
+}
+
+impl	Pulse {
+    pub fn	new(channel: u8) -> Self {
+	Pulse { ones_complement: channel == 1, ..Pulse::default() }
+    }
+
+    pub fn	mmc5() -> Self {
+	Pulse { no_sweep: true, ..Pulse::default() }
+    }
+
+    /// `reg` is the offset from the channel's first register.
+    pub fn write(&mut self, reg: u16, value: u8) {
+	match reg {
+	    0 => {
+		self.duty = value >> 6;
+		self.length.halt = value & 0x20 != 0;
+		self.envelope.write(value);
+	    }
+	    1 => {
+		self.sweep.enabled = value & 0x80 != 0;
+		self.sweep.period = (value >> 4) & 0x07;
+		self.sweep.negate = value & 0x08 != 0;
+		self.sweep.shift = value & 0x07;
+		self.sweep.reload = true;
+	    }
+	    2 => {
+		self.period = (self.period & 0x0700) | value as u16;
+	    }
+	    _ => {
+		self.period = (self.period & 0x00FF) | (((value & 0x07) as u16) << 8);
+		self.length.load(value >> 3);
+		self.sequence = 0;
+		self.envelope.start = true;
+	    }
+	}
+    }
+
+    fn target_period(&self) -> u16 {
+	let change = self.period >> self.sweep.shift;
+	if !self.sweep.negate {
+	    self.period + change
+	}
+	else if self.ones_complement {
+	    self.period.saturating_sub(change + 1)
+	}
+	else {
+	    self.period.saturating_sub(change)
+	}
+    }
+
+    // the sweep unit mutes the channel even when it isn't enabled
+    fn muted(&self) -> bool {
+	!self.no_sweep && (self.period < 8 || self.target_period() > 0x7FF)
+    }
+
+    /// Clocked every APU cycle, every other CPU cycle.
+    pub fn clock_timer(&mut self) {
+	if self.timer == 0 {
+	    self.timer = self.period;
+	    self.sequence = (self.sequence + 1) & 0x07;
+	}
+	else {
+	    self.timer -= 1;
+	}
+    }
+
+    pub fn clock_sweep(&mut self) {
+	if self.sweep.divider == 0 && self.sweep.enabled && self.sweep.shift > 0 && !self.muted() {
+	    self.period = self.target_period();
+	}
+	if self.sweep.divider == 0 || self.sweep.reload {
+	    self.sweep.divider = self.sweep.period;
+	    self.sweep.reload = false;
+	}
+	else {
+	    self.sweep.divider -= 1;
+	}
+    }
+
+    pub fn output(&self) -> u8 {
+	if self.muted() || !self.length.active() || DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0 {
+	    return 0;
+	}
+	self.envelope.output()
+    }
+}
+
+impl Snapshot for Sweep {
+    fn snapshot(&mut self, state: &mut State) {
+	state.bool(&mut self.enabled);
+	state.u8(&mut self.period);
+	state.bool(&mut self.negate);
+	state.u8(&mut self.shift);
+	state.bool(&mut self.reload);
+	state.u8(&mut self.divider);
+    }
+}
+
+// which pulse it is and whether it has a sweep unit come with the APU
+impl Snapshot for Pulse {
+    fn snapshot(&mut self, state: &mut State) {
+	state.u8(&mut self.duty);
+	state.u8(&mut self.sequence);
+	state.u16(&mut self.period);
+	state.u16(&mut self.timer);
+	self.sweep.snapshot(state);
+	self.envelope.snapshot(state);
+	self.length.snapshot(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing(channel: u8) -> Pulse {
+	let mut pulse = Pulse::new(channel);
+	pulse.length.set_enabled(true);
+	pulse.write(0, 0xBF);	// 50%, constant volume 15
+	pulse.write(2, 0x00);
+	pulse.write(3, 0x01);	// period $100
+	pulse
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+	let mut pulse = playing(1);
+	let mut high = 0;
+	for _ in 0..8 * 0x101 {
+	    pulse.clock_timer();
+	    if pulse.output() != 0 {
+		high += 1;
+	    }
+	}
+	assert_eq!(high, 4 * 0x101);
+    }
+
+    #[test]
+    fn test_sweep_negate_differs_between_channels() {
+	let mut pulse1 = playing(1);
+	let mut pulse2 = playing(2);
+	for pulse in [&mut pulse1, &mut pulse2] {
+	    pulse.write(1, 0x89);	// enabled, period 0, negate, shift 1
+	    pulse.clock_sweep();
+	}
+	assert_eq!(pulse1.period, 0x100 - 0x80 - 1);
+	assert_eq!(pulse2.period, 0x100 - 0x80);
+    }
+
+    #[test]
+    fn test_sweep_overflow_mutes() {
+	let mut pulse = playing(2);
+	pulse.write(2, 0xFF);
+	pulse.write(3, 0x07);	// shift 0 targets twice the period, $FFE
+	assert!(pulse.muted());
+	assert_eq!(pulse.output(), 0);
+
+	pulse.write(2, 0x07);
+	pulse.write(3, 0x00);	// period 7 is too short to play
+	assert!(pulse.muted());
+    }
+}
@@ -0,0 +1,114 @@
+use super::units::LengthCounter;
+use crate::savestate::{Snapshot, State};
+
+// https://www.nesdev.org/wiki/APU_Triangle
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+#[derive(Default)]
+pub struct	Triangle {
+    control:	bool,	// also halts the length counter
+    linear_reload_value:	u8,
+    linear_counter:	u8,
+    linear_reload:	bool,
+    period:	u16,
+    timer:	u16,
+    sequence:	u8,
+    pub length:	LengthCounter,
+}
+
+impl	Triangle {
+    /// `reg` is the offset from $4008.
+    pub fn write(&mut self, reg: u16, value: u8) {
+	match reg {
+	    0 => {
+		self.control = value & 0x80 != 0;
+		self.length.halt = self.control;
+		self.linear_reload_value = value & 0x7F;
+	    }
+	    2 => {
+		self.period = (self.period & 0x0700) | value as u16;
+	    }
+	    3 => {
+		self.period = (self.period & 0x00FF) | (((value & 0x07) as u16) << 8);
+		self.length.load(value >> 3);
+		self.linear_reload = true;
+	    }
+	    _ => {}
+	}
+    }
+
+    /// Clocked every CPU cycle, twice as fast as the pulse timers.
+    pub fn clock_timer(&mut self) {
+	if self.timer == 0 {
+	    self.timer = self.period;
+	    if self.length.active() && self.linear_counter > 0 {
+		self.sequence = (self.sequence + 1) & 0x1F;
+	    }
+	}
+	else {
+	    self.timer -= 1;
+	}
+    }
+
+    /// Clocked on quarter frames.
+    pub fn clock_linear_counter(&mut self) {
+	if self.linear_reload {
+	    self.linear_counter = self.linear_reload_value;
+	}
+	else if self.linear_counter > 0 {
+	    self.linear_counter -= 1;
+	}
+	if !self.control {
+	    self.linear_reload = false;
+	}
+    }
+
+    // a silenced triangle holds its last step rather than dropping to 0
+    pub fn output(&self) -> u8 {
+	SEQUENCE[self.sequence as usize]
+    }
+}
+
+impl Snapshot for Triangle {
+    fn snapshot(&mut self, state: &mut State) {
+	state.bool(&mut self.control);
+	state.u8(&mut self.linear_reload_value);
+	state.u8(&mut self.linear_counter);
+	state.bool(&mut self.linear_reload);
+	state.u16(&mut self.period);
+	state.u16(&mut self.timer);
+	state.u8(&mut self.sequence);
+	self.length.snapshot(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linear_counter_gates_the_sequencer() {
+	let mut triangle = Triangle::default();
+	triangle.length.set_enabled(true);
+	triangle.write(0, 0x02);
+	triangle.write(2, 0x00);
+	triangle.write(3, 0x08);
+	triangle.clock_timer();
+	assert_eq!(triangle.output(), 15);
+
+	triangle.clock_linear_counter();
+	triangle.clock_timer();
+	assert_eq!(triangle.output(), 14);
+
+	triangle.clock_linear_counter();
+	triangle.clock_linear_counter();
+	for _ in 0..4 {
+	    triangle.clock_timer();
+	}
+	assert_eq!(triangle.output(), 14);
+    }
+}
@@ -0,0 +1,149 @@
+// Building blocks shared by several of the APU channels.
+
+use crate::savestate::{Snapshot, State};
+
+// https://www.nesdev.org/wiki/APU_Length_Counter
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Silences a channel once a note has run for its set length. Clocked on
+/// half frames.
+#[derive(Default)]
+pub struct	LengthCounter {
+    enabled:	bool,	// $4015 bit for the channel
+    pub halt:	bool,
+    counter:	u8,
+}
+
+impl	LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+	self.enabled = enabled;
+	if !enabled {
+	    self.counter = 0;
+	}
+    }
+
+    pub fn load(&mut self, index: u8) {
+	if self.enabled {
+	    self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+	}
+    }
+
+    pub fn clock(&mut self) {
+	if self.counter > 0 && !self.halt {
+	    self.counter -= 1;
+	}
+    }
+
+    pub fn active(&self) -> bool {
+	self.counter > 0
+    }
+}
+
+/// A decaying volume, or a constant one. Clocked on quarter frames.
+/// https://www.nesdev.org/wiki/APU_Envelope
+#[derive(Default)]
+pub struct	Envelope {
+    pub start:	bool,
+    pub looping:	bool,	// shares its bit with the length counter halt
+    pub constant:	bool,
+    pub volume:	u8,	// the constant volume, and the decay period
+    divider:	u8,
+    decay:	u8,
+}
+
+impl	Envelope {
+    pub fn write(&mut self, value: u8) {
+	self.looping = value & 0x20 != 0;
+	self.constant = value & 0x10 != 0;
+	self.volume = value & 0x0F;
+    }
+
+    pub fn clock(&mut self) {
+	if self.start {
+	    self.start = false;
+	    self.decay = 15;
+	    self.divider = self.volume;
+	}
+	else if self.divider == 0 {
+	    self.divider = self.volume;
+	    if self.decay > 0 {
+		self.decay -= 1;
+	    }
+	    else if self.looping {
+		self.decay = 15;
+	    }
+	}
+	else {
+	    self.divider -= 1;
+	}
+    }
+
+    pub fn output(&self) -> u8 {
+	if self.constant { self.volume } else { self.decay }
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn snapshot(&mut self, state: &mut State) {
+	state.bool(&mut self.enabled);
+	state.bool(&mut self.halt);
+	state.u8(&mut self.counter);
+    }
+}
+
+impl Snapshot for Envelope {
+    fn snapshot(&mut self, state: &mut State) {
+	state.bool(&mut self.start);
+	state.bool(&mut self.looping);
+	state.bool(&mut self.constant);
+	state.u8(&mut self.volume);
+	state.u8(&mut self.divider);
+	state.u8(&mut self.decay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_counter() {
+	let mut length = LengthCounter::default();
+	length.load(1);
+	assert!(!length.active());
+	length.set_enabled(true);
+	length.load(3);
+	length.clock();
+	assert!(length.active());
+	length.clock();
+	assert!(!length.active());
+
+	length.load(0);
+	length.halt = true;
+	length.clock();
+	assert!(length.active());
+	length.set_enabled(false);
+	assert!(!length.active());
+    }
+
+    #[test]
+    fn test_envelope_decays_and_loops() {
+	let mut envelope = Envelope::default();
+	envelope.write(0x20);
+	envelope.start = true;
+	envelope.clock();
+	assert_eq!(envelope.output(), 15);
+	for _ in 0..15 {
+	    envelope.clock();
+	}
+	assert_eq!(envelope.output(), 0);
+	envelope.clock();
+	assert_eq!(envelope.output(), 15);
+
+	envelope.write(0x17);
+	assert_eq!(envelope.output(), 7);
+    }
+}
@@ -0,0 +1,214 @@
+// Konami's VRC6: two pulse channels with eight duty cycles and a sawtooth,
+// on the cartridge and mixed in with the 2A03. The NSF layout is VRC6a's.
+// https://www.nesdev.org/wiki/VRC6_audio
+
+use crate::savestate::{Snapshot, State};
+
+// roughly the loudness of an APU pulse step, which is about what a VRC6
+// pulse at the same volume sounds like next to it
+const LEVEL: f32 = 0.00752;
+
+// $9003
+const HALT: u8 = 0x01;
+const SHIFT_4: u8 = 0x01 << 1;
+const SHIFT_8: u8 = 0x01 << 2;
+
+#[derive(Default)]
+struct	Vrc6Pulse {
+    mode:	bool,	// ignore the duty and play the volume as is
+    duty:	u8,
+    volume:	u8,
+    enabled:	bool,
+    period:	u16,
+    timer:	u16,
+    step:	u8,	// counts down from 15; high while <= duty
+}
+
+impl	Vrc6Pulse {
+    fn write(&mut self, reg: u16, value: u8) {
+	match reg {
+	    0 => {
+		self.mode = value & 0x80 != 0;
+		self.duty = (value >> 4) & 0x07;
+		self.volume = value & 0x0F;
+	    }
+	    1 => self.period = (self.period & 0x0F00) | value as u16,
+	    _ => {
+		self.period = (self.period & 0x00FF) | (((value & 0x0F) as u16) << 8);
+		self.enabled = value & 0x80 != 0;
+		if !self.enabled {
+		    self.step = 15;
+		}
+	    }
+	}
+    }
+
+    fn clock(&mut self, shift: u8) {
+	if !self.enabled {
+	    return;
+	}
+	if self.timer == 0 {
+	    self.timer = self.period >> shift;
+	    self.step = self.step.wrapping_sub(1) & 0x0F;
+	}
+	else {
+	    self.timer -= 1;
+	}
+    }
+
+    fn output(&self) -> u8 {
+	if self.enabled && (self.mode || self.step <= self.duty) { self.volume } else { 0 }
+    }
+}
+
+#[derive(Default)]
+struct	Vrc6Saw {
+    rate:	u8,
+    enabled:	bool,
+    period:	u16,
+    timer:	u16,
+    step:	u8,	// the accumulator takes the rate on every other of 14 steps
+    accumulator:	u8,
+}
+
+impl	Vrc6Saw {
+    fn write(&mut self, reg: u16, value: u8) {
+	match reg {
+	    0 => self.rate = value & 0x3F,
+	    1 => self.period = (self.period & 0x0F00) | value as u16,
+	    _ => {
+		self.period = (self.period & 0x00FF) | (((value & 0x0F) as u16) << 8);
+		self.enabled = value & 0x80 != 0;
+		if !self.enabled {
+		    self.step = 0;
+		    self.accumulator = 0;
+		}
+	    }
+	}
+    }
+
+    fn clock(&mut self, shift: u8) {
+	if !self.enabled {
+	    return;
+	}
+	if self.timer == 0 {
+	    self.timer = self.period >> shift;
+	    self.step += 1;
+	    if self.step == 14 {
+		self.step = 0;
+		self.accumulator = 0;
+	    }
+	    else if self.step & 0x01 == 0 {
+		self.accumulator = self.accumulator.wrapping_add(self.rate);
+	    }
+	}
+	else {
+	    self.timer -= 1;
+	}
+    }
+
+    fn output(&self) -> u8 {
+	self.accumulator >> 3
+    }
+}
+
+#[derive(Default)]
+pub struct	Vrc6 {
+    pulse1:	Vrc6Pulse,
+    pulse2:	Vrc6Pulse,
+    saw:	Vrc6Saw,
+    control:	u8,	// $9003
+}
+
+impl	Vrc6 {
+    pub fn write(&mut self, addr: u16, value: u8) {
+	match addr {
+	    0x9000..=0x9002 => self.pulse1.write(addr - 0x9000, value),
+	    0x9003 => self.control = value,
+	    0xA000..=0xA002 => self.pulse2.write(addr - 0xA000, value),
+	    0xB000..=0xB002 => self.saw.write(addr - 0xB000, value),
+	    _ => {}
+	}
+    }
+
+    /// Clocked every CPU cycle.
+    pub fn clock(&mut self) {
+	if self.control & HALT != 0 {
+	    return;
+	}
+	let shift = if self.control & SHIFT_8 != 0 { 8 } else if self.control & SHIFT_4 != 0 { 4 } else { 0 };
+	self.pulse1.clock(shift);
+	self.pulse2.clock(shift);
+	self.saw.clock(shift);
+    }
+
+    pub fn output(&self) -> f32 {
+	(self.pulse1.output() + self.pulse2.output() + self.saw.output()) as f32 * LEVEL
+    }
+}
+
+impl Snapshot for Vrc6Pulse {
+    fn snapshot(&mut self, state: &mut State) {
+	state.bool(&mut self.mode);
+	state.u8(&mut self.duty);
+	state.u8(&mut self.volume);
+	state.bool(&mut self.enabled);
+	state.u16(&mut self.period);
+	state.u16(&mut self.timer);
+	state.u8(&mut self.step);
+    }
+}
+
+impl Snapshot for Vrc6Saw {
+    fn snapshot(&mut self, state: &mut State) {
+	state.u8(&mut self.rate);
+	state.bool(&mut self.enabled);
+	state.u16(&mut self.period);
+	state.u16(&mut self.timer);
+	state.u8(&mut self.step);
+	state.u8(&mut self.accumulator);
+    }
+}
+
+impl Snapshot for Vrc6 {
+    fn snapshot(&mut self, state: &mut State) {
+	self.pulse1.snapshot(state);
+	self.pulse2.snapshot(state);
+	self.saw.snapshot(state);
+	state.u8(&mut self.control);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulse_duty() {
+	let mut vrc6 = Vrc6::default();
+	vrc6.write(0x9000, 0x3F);	// duty 3 of 16, volume 15
+	vrc6.write(0x9001, 0x00);
+	vrc6.write(0x9002, 0x80);	// period 0
+	let high = (0..16).filter(|_| { vrc6.clock(); vrc6.output() > 0.0 }).count();
+	assert_eq!(high, 4);
+
+	vrc6.write(0x9000, 0x8F);	// mode: always high
+	vrc6.clock();
+	assert_eq!(vrc6.output(), 15.0 * LEVEL);
+	vrc6.write(0x9002, 0x00);
+	assert_eq!(vrc6.output(), 0.0);
+    }
+
+    #[test]
+    fn test_saw_ramp() {
+	let mut vrc6 = Vrc6::default();
+	vrc6.write(0xB000, 0x2A);	// the largest rate that doesn't overflow
+	vrc6.write(0xB002, 0x80);
+	let mut levels = Vec::new();
+	for _ in 0..14 {
+	    vrc6.clock();
+	    levels.push(vrc6.saw.output());
+	}
+	assert_eq!(levels, vec![0, 5, 5, 10, 10, 15, 15, 21, 21, 26, 26, 31, 31, 0]);
+    }
+}
@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+
+use crate::AddressingMode::{self, *};
+use crate::opcodes::{self, OPCODES};
+
+// A small two-pass 6502 assembler in the syntax the disassembler writes,
+// for tests and the debugger's patch command:
+//
+//     ; comment
+//     start:  lda #<table     ; labels end in :, and can share a line
+//             sta $10,x
+//             bne start
+//     count = 3               ; constants
+//             .org $9000
+//     table:  .byte 1, "hi", count
+//             .word start, *+2
+//
+// Numbers are decimal, $hex, %binary or 'c'; an expression is numbers,
+// labels and * (the address of the line) added and subtracted, and a <
+// or > in front of it takes its low or high byte. An operand that fits
+// in a byte gets the zero page mode, unless it's a label defined further
+// down or written a:$0012.
+
+/// A run of bytes that starts at `addr`. Each .org starts a new one.
+#[derive(Debug, PartialEq)]
+pub struct	Segment {
+    pub addr:	u16,
+    pub bytes:	Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+enum	Term {
+    Number(i64),
+    Symbol(String),
+    Here,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum	Part {
+    All,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone)]
+struct	Expr {
+    part:	Part,
+    terms:	Vec<(bool, Term)>,	// (negated, term)
+}
+
+impl	Expr {
+    fn parse(text: &str) -> Result<Self, String> {
+	let text = text.trim();
+	let (part, mut rest) = match text.chars().next() {
+	    Some('<') => (Part::Low, &text[1..]),
+	    Some('>') => (Part::High, &text[1..]),
+	    _ => (Part::All, text),
+	};
+	let mut terms = Vec::new();
+	let mut negated = false;
+	loop {
+	    rest = rest.trim_start();
+	    if let Some(after) = rest.strip_prefix('-') {
+		negated = !negated;
+		rest = after;
+		continue;
+	    }
+	    let len = if rest.starts_with('\'') {
+		rest.char_indices().nth(2).filter(|(_, c)| *c == '\'').map(|(i, _)| i + 1).ok_or(format!("bad character in {}", text))?
+	    }
+	    else if rest.starts_with('*') {
+		1
+	    }
+	    else {
+		rest.find(|c: char| !c.is_ascii_alphanumeric() && !matches!(c, '_' | '$' | '%')).unwrap_or(rest.len())
+	    };
+	    if len == 0 {
+		return Err(format!("missing a number or label in {}", text));
+	    }
+	    terms.push((negated, term(&rest[..len])?));
+	    rest = rest[len..].trim_start();
+	    match rest.chars().next() {
+		None => return Ok(Expr { part, terms }),
+		Some('+') => negated = false,
+		Some('-') => negated = true,
+		Some(c) => return Err(format!("unexpected {} in {}", c, text)),
+	    }
+	    rest = &rest[1..];
+	}
+    }
+
+    // None while a label isn't known yet
+    fn eval(&self, symbols: &HashMap<String, i64>, here: u16) -> Result<Option<i64>, String> {
+	let mut value = 0;
+	for (negated, term) in &self.terms {
+	    let n = match term {
+		Term::Number(n) => *n,
+		Term::Here => here as i64,
+		Term::Symbol(name) => match symbols.get(name) {
+		    Some(n) => *n,
+		    None => return Ok(None),
+		},
+	    };
+	    value += if *negated { -n } else { n };
+	}
+	Ok(Some(match self.part {
+	    Part::All => value,
+	    Part::Low => value & 0xFF,
+	    Part::High => (value >> 8) & 0xFF,
+	}))
+    }
+
+    // the value on the second pass, when every label should be known
+    fn value(&self, symbols: &HashMap<String, i64>, here: u16) -> Result<i64, String> {
+	self.eval(symbols, here)?.ok_or_else(|| {
+	    let unknown = self.terms.iter().find_map(|(_, term)| match term {
+		Term::Symbol(name) if !symbols.contains_key(name) => Some(name.as_str()),
+		_ => None,
+	    });
+	    format!("undefined label {}", unknown.unwrap_or("?"))
+	})
+    }
+}
+
+fn term(word: &str) -> Result<Term, String> {
+    let number = if let Some(hex) = word.strip_prefix('$') {
+	i64::from_str_radix(hex, 16)
+    }
+    else if let Some(binary) = word.strip_prefix('%') {
+	i64::from_str_radix(binary, 2)
+    }
+    else if let Some(c) = word.strip_prefix('\'') {
+	return Ok(Term::Number(c.chars().next().unwrap_or('\0') as i64));
+    }
+    else if word == "*" {
+	return Ok(Term::Here);
+    }
+    else if word.starts_with(|c: char| c.is_ascii_digit()) {
+	word.parse()
+    }
+    else if word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && !word.contains(['$', '%']) {
+	return Ok(Term::Symbol(word.to_string()));
+    }
+    else {
+	return Err(format!("bad number or label {}", word));
+    };
+    number.map(Term::Number).map_err(|_| format!("bad number {}", word))
+}
+
+#[derive(Debug)]
+enum	Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+    // an address, maybe indexed and maybe forced absolute with a:
+    Direct(Expr, Option<char>, bool),
+}
+
+impl	Operand {
+    fn parse(text: &str) -> Result<Self, String> {
+	let text = text.trim();
+	if text.is_empty() {
+	    return Ok(Operand::None);
+	}
+	if text.eq_ignore_ascii_case("a") {
+	    return Ok(Operand::Accumulator);
+	}
+	if let Some(value) = text.strip_prefix('#') {
+	    return Ok(Operand::Immediate(Expr::parse(value)?));
+	}
+	if let Some(inner) = text.strip_prefix('(') {
+	    let inner = inner.replace(' ', "");
+	    let lower = inner.to_ascii_lowercase();
+	    if lower.ends_with(",x)") {
+		return Ok(Operand::IndirectX(Expr::parse(&inner[..inner.len() - 3])?));
+	    }
+	    if lower.ends_with("),y") {
+		return Ok(Operand::IndirectY(Expr::parse(&inner[..inner.len() - 3])?));
+	    }
+	    if let Some(addr) = inner.strip_suffix(')') {
+		return Ok(Operand::Indirect(Expr::parse(addr)?));
+	    }
+	    return Err(format!("bad indirect operand {}", text));
+	}
+	let (absolute, text) = match text.strip_prefix("a:") {
+	    Some(rest) => (true, rest),
+	    None => (false, text),
+	};
+	let (addr, index) = match text.rsplit_once(',') {
+	    Some((addr, index)) => match index.trim().to_ascii_lowercase().as_str() {
+		"x" => (addr, Some('x')),
+		"y" => (addr, Some('y')),
+		_ => return Err(format!("bad index {}", index.trim())),
+	    },
+	    None => (text, None),
+	};
+	Ok(Operand::Direct(Expr::parse(addr)?, index, absolute))
+    }
+}
+
+#[derive(Debug)]
+enum	Statement {
+    Constant(String, Expr),
+    Org(Expr),
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+    Instruction(String, Operand),
+}
+
+// the items of .byte and .word, split on commas outside quotes, with
+// strings spelled out as characters
+fn items(text: &str) -> Result<Vec<Expr>, String> {
+    let mut items = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+	if let Some(string) = rest.strip_prefix('"') {
+	    let end = string.find('"').ok_or(format!("unterminated string in {}", text))?;
+	    items.extend(string[..end].chars().map(|c| Expr { part: Part::All, terms: vec![(false, Term::Number(c as i64))] }));
+	    rest = string[end + 1..].trim_start();
+	}
+	else {
+	    let end = rest.find(',').unwrap_or(rest.len());
+	    items.push(Expr::parse(&rest[..end])?);
+	    rest = &rest[end..];
+	}
+	rest = match rest.strip_prefix(',') {
+	    Some(after) if !after.trim().is_empty() => after.trim_start(),
+	    Some(_) => return Err(format!("missing an item after , in {}", text)),
+	    None if rest.is_empty() => rest,
+	    None => return Err(format!("missing , in {}", text)),
+	};
+    }
+    if items.is_empty() {
+	return Err("missing the values".to_string());
+    }
+    Ok(items)
+}
+
+// the labels and the statement on a line
+fn parse_line(line: &str) -> Result<(Vec<String>, Option<Statement>), String> {
+    // a ; in a string or character isn't a comment
+    let mut quote = None;
+    let end = line.char_indices().find(|&(_, c)| {
+	match quote {
+	    Some(q) if c == q => quote = None,
+	    None if c == '"' || c == '\'' => quote = Some(c),
+	    None => return c == ';',
+	    _ => {}
+	}
+	false
+    });
+    let mut rest = line[..end.map_or(line.len(), |(i, _)| i)].trim();
+
+    let mut labels = Vec::new();
+    while let Some((label, after)) = rest.split_once(':') {
+	let label = label.trim();
+	if label.eq_ignore_ascii_case("a") || !is_name(label) {
+	    break;
+	}
+	labels.push(label.to_string());
+	rest = after.trim();
+    }
+    if rest.is_empty() {
+	return Ok((labels, None));
+    }
+
+    let (word, operand) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let constant = rest.split_once('=').filter(|(name, _)| is_name(name.trim()));
+    let statement = if let Some((name, value)) = constant {
+	Statement::Constant(name.trim().to_string(), Expr::parse(value)?)
+    }
+    else {
+	match word.to_ascii_lowercase().as_str() {
+	    ".org" => Statement::Org(Expr::parse(operand)?),
+	    ".byte" => Statement::Bytes(items(operand)?),
+	    ".word" => Statement::Words(items(operand)?),
+	    directive if directive.starts_with('.') => return Err(format!("unknown directive {}", word)),
+	    mnem => {
+		if !OPCODES.iter().any(|(_, name, _, _)| name.eq_ignore_ascii_case(mnem)) {
+		    return Err(format!("unknown instruction {}", word));
+		}
+		Statement::Instruction(mnem.to_ascii_uppercase(), Operand::parse(operand)?)
+	    }
+	}
+    };
+    Ok((labels, Some(statement)))
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn find(mnem: &str, mode: AddressingMode) -> Option<u8> {
+    OPCODES.iter().find(|(_, name, m, _)| *name == mnem && *m == mode).map(|(op, _, _, _)| *op)
+}
+
+// The opcode and mode for an instruction. On the first pass `value` is
+// None if it's a label not seen yet, which gets a word to be safe.
+fn choose(mnem: &str, operand: &Operand, value: Option<i64>) -> Result<(u8, AddressingMode), String> {
+    let modes: &[AddressingMode] = match operand {
+	Operand::None => &[AddressingImplied, AddressingAccumulator],
+	Operand::Accumulator => &[AddressingAccumulator],
+	Operand::Immediate(_) => &[AddressingImmediate],
+	Operand::Indirect(_) => &[AddressingIndirect],
+	Operand::IndirectX(_) => &[AddressingIndirectX],
+	Operand::IndirectY(_) => &[AddressingIndirectY],
+	Operand::Direct(_, index, absolute) => {
+	    let zero_page = !absolute && value.is_some_and(|n| (0..0x100).contains(&n));
+	    match (index, zero_page) {
+		(None, _) if find(mnem, AddressingRelative).is_some() => &[AddressingRelative],
+		(None, true) => &[AddressingZeroPage, AddressingAbsolute],
+		(None, false) => &[AddressingAbsolute, AddressingZeroPage],
+		(Some('x'), true) => &[AddressingZeroPageX, AddressingAbsoluteX],
+		(Some('x'), false) => &[AddressingAbsoluteX, AddressingZeroPageX],
+		(_, true) => &[AddressingZeroPageY, AddressingAbsoluteY],
+		(_, false) => &[AddressingAbsoluteY, AddressingZeroPageY],
+	    }
+	}
+    };
+    // a: asks for a word, so it can't make do with a byte
+    let modes = match operand {
+	Operand::Direct(_, _, true) => &modes[..1],
+	_ => modes,
+    };
+    modes.iter().find_map(|&mode| find(mnem, mode).map(|op| (op, mode)))
+	.ok_or(format!("{} can't take that operand", mnem.to_lowercase()))
+}
+
+fn operand_expr(operand: &Operand) -> Option<&Expr> {
+    match operand {
+	Operand::None | Operand::Accumulator => None,
+	Operand::Immediate(e) | Operand::Indirect(e) | Operand::IndirectX(e) | Operand::IndirectY(e) | Operand::Direct(e, _, _) => Some(e),
+    }
+}
+
+fn byte(value: i64) -> Result<u8, String> {
+    match value {
+	-128..=255 => Ok(value as u8),
+	_ => Err(format!("{} doesn't fit in a byte", value)),
+    }
+}
+
+fn word(value: i64) -> Result<u16, String> {
+    match value {
+	-32768..=65535 => Ok(value as u16),
+	_ => Err(format!("{} doesn't fit in a word", value)),
+    }
+}
+
+/// Assembles `source`, which starts at `origin` until an .org says
+/// otherwise. Errors give the line they're on.
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<Segment>, String> {
+    let mut lines = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+	let parsed = parse_line(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+	lines.push((number + 1, parsed));
+    }
+
+    // first pass: where the labels are, and which mode each instruction takes
+    let mut symbols = HashMap::new();
+    let mut modes = HashMap::new();
+    let mut here = origin;
+    for (number, (labels, statement)) in &lines {
+	let at = |e: String| format!("line {}: {}", number, e);
+	for label in labels {
+	    if symbols.insert(label.clone(), here as i64).is_some() {
+		return Err(at(format!("{} is already defined", label)));
+	    }
+	}
+	let size = match statement {
+	    None => 0,
+	    Some(Statement::Constant(name, value)) => {
+		let value = value.value(&symbols, here).map_err(at)?;
+		if symbols.insert(name.clone(), value).is_some() {
+		    return Err(at(format!("{} is already defined", name)));
+		}
+		0
+	    }
+	    Some(Statement::Org(addr)) => {
+		here = word(addr.value(&symbols, here).map_err(at)?).map_err(at)?;
+		0
+	    }
+	    Some(Statement::Bytes(items)) => items.len(),
+	    Some(Statement::Words(items)) => items.len() * 2,
+	    Some(Statement::Instruction(mnem, operand)) => {
+		let value = match operand_expr(operand) {
+		    Some(e) => e.eval(&symbols, here).map_err(at)?,
+		    None => None,
+		};
+		let (op, mode) = choose(mnem, operand, value).map_err(at)?;
+		modes.insert(*number, (op, mode));
+		opcodes::length(mode)
+	    }
+	};
+	here = here.wrapping_add(size as u16);
+    }
+
+    // second pass: the bytes
+    let mut segments = vec![Segment { addr: origin, bytes: Vec::new() }];
+    for (number, (_, statement)) in &lines {
+	let at = |e: String| format!("line {}: {}", number, e);
+	let segment = segments.last_mut().unwrap();
+	let here = segment.addr.wrapping_add(segment.bytes.len() as u16);
+	match statement {
+	    None | Some(Statement::Constant(..)) => {}
+	    Some(Statement::Org(addr)) => {
+		let addr = addr.value(&symbols, here).map_err(at)? as u16;
+		if segment.bytes.is_empty() {
+		    segment.addr = addr;
+		}
+		else {
+		    segments.push(Segment { addr, bytes: Vec::new() });
+		}
+	    }
+	    Some(Statement::Bytes(items)) => {
+		for item in items {
+		    segment.bytes.push(byte(item.value(&symbols, here).map_err(at)?).map_err(at)?);
+		}
+	    }
+	    Some(Statement::Words(items)) => {
+		for item in items {
+		    segment.bytes.extend_from_slice(&word(item.value(&symbols, here).map_err(at)?).map_err(at)?.to_le_bytes());
+		}
+	    }
+	    Some(Statement::Instruction(_, operand)) => {
+		let (op, mode) = modes[number];
+		segment.bytes.push(op);
+		let Some(expr) = operand_expr(operand) else { continue };
+		let value = expr.value(&symbols, here).map_err(at)?;
+		match opcodes::length(mode) {
+		    3 => segment.bytes.extend_from_slice(&word(value).map_err(at)?.to_le_bytes()),
+		    _ if mode == AddressingRelative => {
+			let offset = value - (here as i64 + 2);
+			if !(-128..=127).contains(&offset) {
+			    return Err(at(format!("the branch is {} bytes, too far", offset)));
+			}
+			segment.bytes.push(offset as u8);
+		    }
+		    _ => segment.bytes.push(byte(value).map_err(at)?),
+		}
+	    }
+	}
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+
+    fn bytes(source: &str) -> Vec<u8> {
+	let segments = assemble(source, 0x8000).unwrap();
+	assert_eq!(segments.len(), 1);
+	segments.into_iter().next().unwrap().bytes
+    }
+
+    #[test]
+    fn test_assemble() {
+	assert_eq!(bytes("
+	    count = 3
+	    start:  ldx #count      ; a comment
+		    lda table-1,x
+		    sta $0200,y
+		    sta a:$10
+	    loop:   dex
+		    bne loop
+		    jsr sub
+		    asl
+		    rol a
+		    jmp (vector)
+	    sub:    rts
+	    vector: .word start, *
+	    table:  .byte 1, \"a;b\", 'c', -1, <vector, >vector
+	"), [
+	    0xa2, 0x03, 0xbd, 0x1a, 0x80, 0x99, 0x00, 0x02, 0x8d, 0x10, 0x00,
+	    0xca, 0xd0, 0xfd, 0x20, 0x16, 0x80, 0x0a, 0x2a, 0x6c, 0x17, 0x80,
+	    0x60, 0x00, 0x80, 0x17, 0x80, 0x01, 0x61, 0x3b, 0x62, 0x63, 0xff, 0x17, 0x80,
+	]);
+	// zero page when the address fits in a byte and is known
+	assert_eq!(bytes("zp = $10\nlda zp\nldx zp,y\nlda (zp),y\nlda (zp,x)\nstx $20,y\nlda ahead\nahead:"),
+		   [0xa5, 0x10, 0xb6, 0x10, 0xb1, 0x10, 0xa1, 0x10, 0x96, 0x20, 0xad, 0x0d, 0x80]);
+    }
+
+    #[test]
+    fn test_org() {
+	let segments = assemble(".org $C000\nreset: jmp reset\n.org $FFFC\n.word reset", 0).unwrap();
+	assert_eq!(segments, [
+	    Segment { addr: 0xC000, bytes: vec![0x4c, 0x00, 0xc0] },
+	    Segment { addr: 0xFFFC, bytes: vec![0x00, 0xc0] },
+	]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+	// every opcode, through the disassembler and back
+	for (op, _, mode, _) in OPCODES {
+	    let program = [op, 0x34, 0x12];
+	    let instruction = &disassemble(&program[..opcodes::length(mode)], 0x8000)[0];
+	    assert_eq!(bytes(&instruction.text), instruction.bytes, "{}", instruction.text);
+	}
+	let program = [0xa9, 0x05, 0xb9, 0x12, 0x00, 0xd0, 0xfb, 0x10, 0x7f, 0x02];
+	let source: Vec<String> = disassemble(&program, 0x8000).into_iter().map(|instruction| instruction.text).collect();
+	assert_eq!(bytes(&source.join("\n")), program);
+    }
+
+    #[test]
+    fn test_errors() {
+	let error = |source: &str| assemble(source, 0x8000).unwrap_err();
+	assert_eq!(error("nop\nfoo #1"), "line 2: unknown instruction foo");
+	assert_eq!(error("lda nowhere"), "line 1: undefined label nowhere");
+	assert_eq!(error("x: nop\nx: nop"), "line 2: x is already defined");
+	assert_eq!(error("lda #256"), "line 1: 256 doesn't fit in a byte");
+	assert_eq!(error("jmp #1"), "line 1: jmp can't take that operand");
+	assert_eq!(error("ldx $10,x"), "line 1: ldx can't take that operand");
+	assert_eq!(error("bne far\n.org $8100\nfar:"), "line 1: the branch is 254 bytes, too far");
+	assert_eq!(error(".bytes 1"), "line 1: unknown directive .bytes");
+	assert_eq!(error(".byte 1,"), "line 1: missing an item after , in 1,");
+    }
+}
@@ -0,0 +1,178 @@
+use crate::controller;
+use crate::movie::{Movie, MovieFrame, Reset, RomHash};
+use crate::zip::Zip;
+
+// BizHawk's movie format: a zip archive with a Header.txt of "Key value"
+// lines and an "Input Log.txt" with one line per frame between [Input]
+// and [/Input]. Each line has a |-separated group per controller, one
+// character per button, '.' when it's up; the LogKey line names the
+// buttons in the same layout.
+// https://tasvideos.org/Bizhawk/BK2Format
+
+const HEADER: &str = "Header.txt";
+const INPUT_LOG: &str = "Input Log.txt";
+
+// what NesHawk logs when there's no LogKey
+const DEFAULT_LOG_KEY: &str = "#Reset|Power|#P1 Up|P1 Down|P1 Left|P1 Right|P1 Start|P1 Select|P1 B|P1 A|\
+			       #P2 Up|P2 Down|P2 Left|P2 Right|P2 Start|P2 Select|P2 B|P2 A|";
+
+const BUTTON_NAMES: [(&str, u8); 8] = [
+    ("Up", controller::BUTTON_UP),
+    ("Down", controller::BUTTON_DOWN),
+    ("Left", controller::BUTTON_LEFT),
+    ("Right", controller::BUTTON_RIGHT),
+    ("Start", controller::BUTTON_START),
+    ("Select", controller::BUTTON_SELECT),
+    ("B", controller::BUTTON_B),
+    ("A", controller::BUTTON_A),
+];
+
+// What a button in the log key does to a frame.
+#[derive(Clone, Copy)]
+enum	Button {
+    Reset(Reset),
+    Joypad(usize, u8),
+    Other,	// whatever isn't emulated, like the FDS buttons
+}
+
+fn button(name: &str) -> Button {
+    match name {
+	"Reset" => Button::Reset(Reset::Soft),
+	"Power" => Button::Reset(Reset::Hard),
+	_ => {
+	    let player = name.strip_prefix('P')
+		.and_then(|name| name.split_once(' '))
+		.and_then(|(player, button)| Some((player.parse::<usize>().ok()?, button)));
+	    match player {
+		Some((player @ 1..=4, button)) => BUTTON_NAMES.iter()
+		    .find(|(name, _)| *name == button)
+		    .map_or(Button::Other, |(_, bit)| Button::Joypad(player - 1, *bit)),
+		_ => Button::Other,
+	    }
+	}
+    }
+}
+
+// "#Reset|Power|#P1 Up|..." -> the buttons of each group
+fn parse_log_key(key: &str) -> Vec<Vec<Button>> {
+    key.split('#')
+	.filter(|group| !group.is_empty())
+	.map(|group| group.split('|').filter(|name| !name.is_empty()).map(button).collect())
+	.collect()
+}
+
+fn parse_frame(line: &str, key: &[Vec<Button>]) -> Result<MovieFrame, String> {
+    let groups: Vec<&str> = line.trim_matches('|').split('|').collect();
+    if groups.len() != key.len() {
+	return Err(format!("'{}' doesn't match the log key", line));
+    }
+    let mut frame = MovieFrame::default();
+    for (group, buttons) in groups.iter().zip(key) {
+	// analog controls log numbers, which a NES has none of
+	for (c, button) in group.chars().zip(buttons) {
+	    if c == '.' || c == ' ' {
+		continue;
+	    }
+	    match button {
+		Button::Reset(reset) => frame.reset = Some(*reset),
+		Button::Joypad(player, bit) => frame.buttons[*player] |= bit,
+		Button::Other => {}
+	    }
+	}
+    }
+    Ok(frame)
+}
+
+/// Reads a .bk2 movie's joypad input and resets, and the SHA-1 of the
+/// ROM it was made with.
+pub fn parse(data: &[u8]) -> Result<Movie, String> {
+    let zip = Zip::parse(data)?;
+    let header = String::from_utf8_lossy(&zip.read(HEADER)?).to_string();
+    let mut movie = Movie::new("", None);
+    for line in header.lines() {
+	let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+	let value = value.trim();
+	match key {
+	    "Platform" if value != "NES" => return Err(format!("this is a {} movie, not an NES one", value)),
+	    "GameName" => movie.rom_filename = value.to_string(),
+	    "PAL" => movie.pal = value.eq_ignore_ascii_case("true") || value == "1",
+	    "SHA1" => {
+		let hex = value.strip_prefix("SHA1:").unwrap_or(value);
+		let digest = (0..hex.len()).step_by(2)
+		    .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+		    .collect::<Option<Vec<u8>>>()
+		    .and_then(|digest| digest.try_into().ok())
+		    .ok_or(format!("{}: '{}' is not a SHA-1", HEADER, value))?;
+		movie.rom_hash = Some(RomHash::Sha1(digest));
+	    }
+	    _ => {}
+	}
+    }
+
+    let log = String::from_utf8_lossy(&zip.read(INPUT_LOG)?).to_string();
+    let mut key = parse_log_key(DEFAULT_LOG_KEY);
+    for (number, line) in log.lines().enumerate() {
+	let line = line.trim_end_matches('\r');
+	if let Some(log_key) = line.strip_prefix("LogKey:") {
+	    key = parse_log_key(log_key);
+	}
+	else if line.starts_with('|') {
+	    let frame = parse_frame(line, &key).map_err(|e| format!("{} line {}: {}", INPUT_LOG, number + 1, e))?;
+	    movie.four_score |= frame.buttons[2..].iter().any(|buttons| *buttons != 0);
+	    movie.frames.push(frame);
+	}
+    }
+    Ok(movie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+
+    // a zip made by Python's zipfile, with deflated entries
+    const BK2: [&str; 11] = [
+	"504b030414000000080000002150772e0be990000000aa0000000a0000004865616465722e747874358ecb0e82301000",
+	"effb15fb054d5bc0d02394221789098977a26b2452d62c0f13bf5e3d7098cc650e73e26da00bc93cf084e5f069faf713",
+	"37abb4d250accb8305678ec41301c5750f775be59481f3d82f7796886de8e0d8476a7fe0c8fc82ae290cfaaad099f14e",
+	"9b243fd43ad8d499b4d42194ce863a0d49aebdc95c059e85b0a5f93f01424257969be7755a30812f504b030414000000",
+	"080000002150d634cba2a2000000760e00000d000000496e707574204c6f672e747874ed973d0bc2301086f7fc0aa17b",
+	"c51bdd2a2e628792d2a938889c5590a6d44811f2e3cda782e8ea206f48eede7be0b93deda61f6e7a274ad56df9becc24",
+	"5f599b4a4d3c9aac5acc9ac1d8ba5653ef7ac947edba3c77271f6abd1f43e00b1f7c5ab9525899bc4c51a628539229c9",
+	"f494c9c9646561f2dcde705e4198314cf51b0e93b4af48d82fa93e2ef9b21b18181818181818f85f71030c0c0cfc73dc",
+	"cee36ff301504b0102140314000000080000002150772e0be990000000aa0000000a0000000000000000000000800100",
+	"0000004865616465722e747874504b0102140314000000080000002150d634cba2a2000000760e00000d000000000000",
+	"00000000008001b8000000496e707574204c6f672e747874504b0506000000000200020073000000850100000000",
+    ];
+
+    fn bk2() -> Vec<u8> {
+	let hex = BK2.concat();
+	(0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+	let movie = parse(&bk2()).unwrap();
+	assert_eq!(movie.rom_filename, "loop");
+	assert_eq!(movie.rom_hash, Some(RomHash::Sha1(hash::sha1(b"game"))));
+	assert!(!movie.four_score);
+	assert_eq!(movie.frames.len(), 154);
+	assert_eq!(movie.frames[1], MovieFrame { reset: Some(Reset::Soft), buttons: [controller::BUTTON_START, 0, 0, 0] });
+	assert_eq!(movie.frames[2].buttons[..2], [controller::BUTTON_RIGHT | controller::BUTTON_A, controller::BUTTON_A]);
+	assert_eq!(movie.frames[3].reset, Some(Reset::Hard));
+	assert_eq!(movie.frames[153].buttons[0], controller::BUTTON_UP);
+
+	let mut broken = bk2();
+	broken[100] ^= 0xFF;
+	assert!(parse(&broken).is_err());
+	assert!(parse(b"PK not really").is_err());
+    }
+
+    #[test]
+    fn test_log_key() {
+	let key = parse_log_key("#Power|#P3 A|P3 Turbo|P1 Select|");
+	let frame = parse_frame("|P|AT.|", &key).unwrap();
+	assert_eq!(frame, MovieFrame { reset: Some(Reset::Hard), buttons: [0, 0, controller::BUTTON_A, 0] });
+	assert!(parse_frame("|P|", &key).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::fmt;
+
+use crate::controller::Device;
+use crate::hash;
+use crate::region::Region;
+use crate::savestate::{Snapshot, State};
+
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
+
+const INES_MAGIC: &[u8; 4] = b"NES\x1A";
+const INES_HEADER_SIZE: usize = 16;
+const INES_TRAINER_SIZE: usize = 512;
+
+pub const PRG_ROM_BANK_SIZE: usize = 0x4000;
+const CHR_ROM_BANK_SIZE: usize = 0x2000;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// PRG ROM is mapped into the CPU's $8000-$FFFF in 4KB slots
+const PRG_SLOT_SIZE: usize = 0x1000;
+const PRG_SLOTS: usize = 8;
+
+// NSFs bank switch by writing a 4KB bank number for each slot to
+// $5FF8-$5FFF.
+// https://www.nesdev.org/wiki/NSF#Bankswitching
+const NSF_BANK_REGISTERS: u16 = 0x5FF8;
+
+// 7  bit  0
+// ---- ----
+// NNNN FTBM
+// |||| ||||
+// |||| |||+- Nametable arrangement: 0 = horizontal mirroring, 1 = vertical
+// |||| ||+-- Battery-backed PRG RAM at $6000-$7FFF
+// |||| |+--- 512-byte trainer before the PRG data
+// |||| +---- Four-screen VRAM
+// ++++------ Lower nibble of the mapper number
+
+const FLAGS6_VERTICAL: u8 = 0x01;
+const FLAGS6_BATTERY: u8 = 0x01 << 1;
+const FLAGS6_TRAINER: u8 = 0x01 << 2;
+const FLAGS6_FOUR_SCREEN: u8 = 0x01 << 3;
+
+const FLAGS7_NES2_MASK: u8 = 0x0C;
+const FLAGS7_NES2: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+pub struct	Cartridge {
+    pub mapper:	u16,
+    pub mirroring:	Mirroring,
+    pub battery:	bool,
+    pub nes2:	bool,
+    pub region:	Region,
+    pub device:	Option<Device>,	// what the NES 2.0 header says to plug in
+    prg_rom:	Vec<u8>,
+    prg_ram:	Vec<u8>,
+    prg_slots:	[usize; PRG_SLOTS],	// offset into prg_rom of each 4KB slot
+    pub chr_rom:	Vec<u8>,	// empty when the board has CHR RAM
+    nsf_banks:	bool,
+}
+
+impl	Cartridge {
+    pub fn	from_ines(data: &[u8]) -> Result<Self, String> {
+	if data.len() < INES_HEADER_SIZE || &data[0..4] != INES_MAGIC {
+	    return Err("not an iNES file".to_string());
+	}
+	let flags6 = data[6];
+	let flags7 = data[7];
+	let nes2 = flags7 & FLAGS7_NES2_MASK == FLAGS7_NES2;
+
+	let mut mapper: u16 = ((flags7 & 0xF0) | (flags6 >> 4)) as u16;
+	let mut prg_banks: usize = data[4] as usize;
+	let mut chr_banks: usize = data[5] as usize;
+	let mut region = Region::Ntsc;
+	let mut device = None;
+	if nes2 {
+	    region = Region::from_nes2(data[12]);
+	    device = Device::from_nes2(data[15]);
+	    mapper |= ((data[8] & 0x0F) as u16) << 8;
+	    prg_banks |= ((data[9] & 0x0F) as usize) << 8;
+	    chr_banks |= ((data[9] >> 4) as usize) << 8;
+	}
+
+	let mirroring = if flags6 & FLAGS6_FOUR_SCREEN != 0 {
+	    Mirroring::FourScreen
+	}
+	else if flags6 & FLAGS6_VERTICAL != 0 {
+	    Mirroring::Vertical
+	}
+	else {
+	    Mirroring::Horizontal
+	};
+
+	let mut offset = INES_HEADER_SIZE;
+	if flags6 & FLAGS6_TRAINER != 0 {
+	    offset += INES_TRAINER_SIZE;
+	}
+	let prg_size = prg_banks * PRG_ROM_BANK_SIZE;
+	let chr_size = chr_banks * CHR_ROM_BANK_SIZE;
+	if prg_size == 0 || data.len() < offset + prg_size + chr_size {
+	    return Err(format!("truncated ROM: expected {} bytes of PRG and {} bytes of CHR", prg_size, chr_size));
+	}
+	if mapper != 0 {
+	    return Err(format!("mapper {} is not supported", mapper));
+	}
+
+	let prg_rom = data[offset..offset + prg_size].to_vec();
+	let chr_rom = data[offset + prg_size..offset + prg_size + chr_size].to_vec();
+
+	// NROM: 16KB images show up twice
+	let mut prg_slots = [0; PRG_SLOTS];
+	for (i, slot) in prg_slots.iter_mut().enumerate() {
+	    *slot = (i * PRG_SLOT_SIZE) % prg_rom.len();
+	}
+
+	Ok(Cartridge {
+	    mapper,
+	    mirroring,
+	    battery: flags6 & FLAGS6_BATTERY != 0,
+	    nes2,
+	    region,
+	    device,
+	    prg_rom,
+	    prg_ram: vec![0; PRG_RAM_SIZE],
+	    prg_slots,
+	    chr_rom,
+	    nsf_banks: false,
+	})
+    }
+
+    /// An NSF's program as a cartridge with no CHR. With `banks` the data
+    /// is cut into 4KB banks, starting at the load address's offset into
+    /// its bank, and those are what the slots start out with. Without, it
+    /// is a flat 32KB image with the data at the load address.
+    pub fn	from_nsf(data: &[u8], load_addr: u16, banks: Option<[u8; PRG_SLOTS]>, region: Region) -> Result<Self, String> {
+	if load_addr < 0x8000 {
+	    return Err(format!("load address ${:04X} is below $8000", load_addr));
+	}
+	if data.is_empty() {
+	    return Err("no program data".to_string());
+	}
+
+	let mut prg_rom;
+	let mut prg_slots = [0; PRG_SLOTS];
+	match banks {
+	    Some(banks) => {
+		let padding = load_addr as usize & (PRG_SLOT_SIZE - 1);
+		prg_rom = vec![0; padding];
+		prg_rom.extend_from_slice(data);
+		prg_rom.resize(prg_rom.len().div_ceil(PRG_SLOT_SIZE) * PRG_SLOT_SIZE, 0);
+		for (slot, bank) in prg_slots.iter_mut().zip(banks) {
+		    *slot = (bank as usize * PRG_SLOT_SIZE) % prg_rom.len();
+		}
+	    }
+	    None => {
+		let offset = load_addr as usize - 0x8000;
+		prg_rom = vec![0; PRG_SLOTS * PRG_SLOT_SIZE];
+		let len = data.len().min(prg_rom.len() - offset);
+		prg_rom[offset..offset + len].copy_from_slice(&data[..len]);
+		for (i, slot) in prg_slots.iter_mut().enumerate() {
+		    *slot = i * PRG_SLOT_SIZE;
+		}
+	    }
+	}
+
+	Ok(Cartridge {
+	    mapper: 0,
+	    mirroring: Mirroring::Horizontal,
+	    battery: false,
+	    nes2: false,
+	    region,
+	    device: None,
+	    prg_rom,
+	    prg_ram: vec![0; PRG_RAM_SIZE],
+	    prg_slots,
+	    chr_rom: Vec::new(),
+	    nsf_banks: banks.is_some(),
+	})
+    }
+
+    /// 16KB bank `bank` of the PRG ROM, as iNES counts them.
+    pub fn prg_bank(&self, bank: usize) -> Option<&[u8]> {
+	self.prg_rom.chunks(PRG_ROM_BANK_SIZE).nth(bank)
+    }
+
+    pub fn prg_banks(&self) -> usize {
+	self.prg_rom.len().div_ceil(PRG_ROM_BANK_SIZE)
+    }
+
+    /// MD5 of the PRG and CHR ROM, the way FCEUX identifies a game.
+    pub fn md5(&self) -> [u8; 16] {
+	hash::md5(&[&self.prg_rom[..], &self.chr_rom[..]].concat())
+    }
+
+    /// SHA-1 of the same, the way BizHawk does.
+    pub fn sha1(&self) -> [u8; 20] {
+	hash::sha1(&[&self.prg_rom[..], &self.chr_rom[..]].concat())
+    }
+
+    pub fn prg_read(&self, addr: u16) -> Option<u8> {
+	match addr {
+	    0x6000..=0x7FFF => Some(self.prg_ram[(addr - 0x6000) as usize]),
+	    0x8000..=0xFFFF => {
+		let slot = (addr as usize - 0x8000) / PRG_SLOT_SIZE;
+		Some(self.prg_rom[self.prg_slots[slot] + (addr as usize & (PRG_SLOT_SIZE - 1))])
+	    }
+	    _ => None,
+	}
+    }
+
+    // Changes the byte a read of `addr` sees, PRG ROM included, for the
+    // debugger's patches.
+    pub fn prg_patch(&mut self, addr: u16, value: u8) {
+	match addr {
+	    0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = value,
+	    0x8000..=0xFFFF => {
+		let slot = (addr as usize - 0x8000) / PRG_SLOT_SIZE;
+		self.prg_rom[self.prg_slots[slot] + (addr as usize & (PRG_SLOT_SIZE - 1))] = value;
+	    }
+	    _ => {}
+	}
+    }
+
+    pub fn prg_write(&mut self, addr: u16, value: u8) {
+	match addr {
+	    0x5FF8..=0x5FFF if self.nsf_banks => {
+		let slot = (addr - NSF_BANK_REGISTERS) as usize;
+		self.prg_slots[slot] = (value as usize * PRG_SLOT_SIZE) % self.prg_rom.len();
+	    }
+	    0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = value,
+	    _ => {}
+	}
+    }
+}
+
+impl fmt::Display for Cartridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}, mapper {}, {}KB PRG, ", if self.nes2 { "NES 2.0" } else { "iNES" }, self.mapper, self.prg_rom.len() / 1024)?;
+	if self.chr_rom.is_empty() {
+	    write!(f, "CHR RAM")?;
+	}
+	else {
+	    write!(f, "{}KB CHR", self.chr_rom.len() / 1024)?;
+	}
+	write!(f, ", {:?} mirroring, {}", self.mirroring, self.region)?;
+	if self.battery {
+	    write!(f, ", battery")?;
+	}
+	if let Some(device) = self.device {
+	    write!(f, ", {:?}", device)?;
+	}
+	Ok(())
+    }
+}
+
+// The ROM is checked against the state's header rather than saved; a
+// slot has to land on a 4KB bank inside it.
+impl Snapshot for Cartridge {
+    fn snapshot(&mut self, state: &mut State) {
+	state.bytes(&mut self.prg_ram);
+	for slot in &mut self.prg_slots {
+	    let mut offset = *slot;
+	    state.usize(&mut offset);
+	    if offset < self.prg_rom.len() && offset.is_multiple_of(PRG_SLOT_SIZE) {
+		*slot = offset;
+	    }
+	    else {
+		state.refuse(format!("PRG bank offset {:#X} is outside the {}KB PRG ROM", offset, self.prg_rom.len() / 1024));
+	    }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::savestate;
+
+    fn ines(prg_banks: u8, chr_banks: u8, flags6: u8, flags7: u8) -> Vec<u8> {
+	let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, chr_banks, flags6, flags7];
+	rom.resize(INES_HEADER_SIZE, 0);
+	for bank in 0..prg_banks {
+	    rom.extend(vec![bank + 1; PRG_ROM_BANK_SIZE]);
+	}
+	rom.extend(vec![0xCC; chr_banks as usize * CHR_ROM_BANK_SIZE]);
+	rom
+    }
+
+    #[test]
+    fn test_nrom_128_is_mirrored() {
+	let cart = Cartridge::from_ines(&ines(1, 1, FLAGS6_VERTICAL, 0)).unwrap();
+	assert_eq!(cart.mapper, 0);
+	assert_eq!(cart.mirroring, Mirroring::Vertical);
+	assert_eq!(cart.chr_rom.len(), CHR_ROM_BANK_SIZE);
+	assert_eq!(cart.prg_read(0x8000), Some(1));
+	assert_eq!(cart.prg_read(0xC000), Some(1));
+	assert_eq!(cart.prg_read(0x4020), None);
+	assert_eq!(cart.to_string(), "iNES, mapper 0, 16KB PRG, 8KB CHR, Vertical mirroring, NTSC");
+    }
+
+    #[test]
+    fn test_nrom_256() {
+	let cart = Cartridge::from_ines(&ines(2, 0, FLAGS6_FOUR_SCREEN, 0)).unwrap();
+	assert_eq!(cart.mirroring, Mirroring::FourScreen);
+	assert!(cart.chr_rom.is_empty());
+	assert_eq!(cart.prg_read(0xBFFF), Some(1));
+	assert_eq!(cart.prg_read(0xC000), Some(2));
+	assert_eq!(cart.prg_banks(), 2);
+	assert_eq!(cart.prg_bank(1).map(|bank| (bank.len(), bank[0])), Some((PRG_ROM_BANK_SIZE, 2)));
+	assert_eq!(cart.prg_bank(2), None);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+	let mut cart = Cartridge::from_ines(&ines(1, 1, FLAGS6_BATTERY, 0)).unwrap();
+	assert!(cart.battery);
+	cart.prg_write(0x6123, 0x42);
+	cart.prg_write(0x8000, 0x42);
+	assert_eq!(cart.prg_read(0x6123), Some(0x42));
+	assert_eq!(cart.prg_read(0x8000), Some(1));
+	// ROM only changes through the debugger
+	cart.prg_patch(0x8000, 0x42);
+	cart.prg_patch(0xC001, 0x43);
+	assert_eq!(cart.prg_read(0x8000), Some(0x42));
+	assert_eq!(cart.prg_read(0x8001), Some(0x43));
+    }
+
+    #[test]
+    fn test_rejected_files() {
+	assert!(Cartridge::from_ines(&[0; 32]).is_err());
+	assert!(Cartridge::from_ines(&ines(1, 1, 0x10, 0)).is_err());
+	let mut truncated = ines(2, 1, 0, 0);
+	truncated.truncate(INES_HEADER_SIZE + PRG_ROM_BANK_SIZE);
+	assert!(Cartridge::from_ines(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_nes2_header() {
+	let mut rom = ines(1, 1, 0, FLAGS7_NES2);
+	rom[12] = 0x01;
+	rom[15] = 0x0F;
+	let cart = Cartridge::from_ines(&rom).unwrap();
+	assert!(cart.nes2);
+	assert_eq!(cart.region, Region::Pal);
+	assert_eq!(cart.device, Some(Device::Vaus));
+    }
+
+    #[test]
+    fn test_nsf_banks() {
+	let data: Vec<u8> = (0..3).flat_map(|bank| vec![bank + 1; PRG_SLOT_SIZE]).collect();
+	let flat = Cartridge::from_nsf(&data[..0x100], 0x8100, None, Region::Ntsc).unwrap();
+	assert_eq!(flat.prg_read(0x80FF), Some(0));
+	assert_eq!(flat.prg_read(0x8100), Some(1));
+	assert!(Cartridge::from_nsf(&data, 0x6000, None, Region::Ntsc).is_err());
+
+	// the data starts $80 into its bank
+	let mut cart = Cartridge::from_nsf(&data, 0x8080, Some([0, 1, 2, 3, 0, 0, 0, 0]), Region::Ntsc).unwrap();
+	assert_eq!(cart.prg_read(0x807F), Some(0));
+	assert_eq!(cart.prg_read(0x8080), Some(1));
+	assert_eq!(cart.prg_read(0x9080), Some(2));
+	assert_eq!(cart.prg_read(0xB07F), Some(3));
+	assert_eq!(cart.prg_read(0xF000), Some(0));
+	cart.prg_write(0x5FFF, 2);
+	assert_eq!(cart.prg_read(0xF080), Some(3));
+	cart.prg_write(0x5FF8, 7);	// past the end wraps around
+	assert_eq!(cart.prg_read(0x807F), Some(3));
+    }
+
+    #[test]
+    fn test_snapshot() {
+	let rom = ines(2, 1, 0, 0);
+	let mut cart = Cartridge::from_ines(&rom).unwrap();
+	let md5 = cart.md5();
+	cart.prg_write(0x6000, 0x42);
+	let state = savestate::save(&mut cart, md5);
+
+	let mut loaded = Cartridge::from_ines(&rom).unwrap();
+	savestate::load(&mut loaded, md5, &state).unwrap();
+	assert_eq!(loaded.prg_read(0x6000), Some(0x42));
+
+	// a bank past the end of PRG ROM is refused, not read from later
+	cart.prg_slots[7] = 0x8000;
+	let error = savestate::load(&mut loaded, md5, &savestate::save(&mut cart, md5)).unwrap_err();
+	assert_eq!(error, "the save state doesn't fit this machine: PRG bank offset 0x8000 is outside the 32KB PRG ROM");
+	assert_eq!(loaded.prg_read(0xF000), Some(2));
+    }
+}
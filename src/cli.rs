@@ -0,0 +1,513 @@
+use crate::apu::Channel;
+use crate::controller::{self, Device};
+use crate::movie::Reset;
+use crate::region::Region;
+
+pub const USAGE: &str = "\
+usage: rusty_old_nes [options] [path/to/your/rom or .nsf]
+       rusty_old_nes disasm [--bank N] [--start ADDR] [--length N] path/to/your/rom
+
+options:
+  --headless          run without a display and dump frames to image files
+  --debug             step through the ROM in an interactive debugger
+  --gdb PORT          wait for gdb to connect on localhost:PORT and debug the ROM
+  --frames N          number of frames to run in headless mode (default 60),
+		      or of calls to PLAY for an NSF
+  --dump-every        dump every frame instead of only the last one
+  --dump-at N,M,...   dump the given frame numbers (1-based)
+  --out DIR           directory the images are written to (default .)
+  --png               write PNG images next to the PPM ones
+  --palette FILE      convert colors through a 64- or 512-entry .pal file
+  --ntsc              run frames through an NTSC composite video filter
+  --region NAME       ntsc, pal or dendy timing instead of the ROM header's
+  --sample-rate HZ    audio output rate (default 44100)
+  --wav FILE          record the audio of a headless run to a WAV file
+  --stems             also record each channel to FILE.<channel>.wav
+  --mute CH,CH,...    silence pulse1, pulse2, triangle, noise, dmc, vrc6 or mmc5
+  --song N            the NSF song to play instead of its first one
+  --press [pN:]BUTTONS@N[-M]
+		      hold buttons (a, b, select, start, up, down, left, right,
+		      joined with +) on frames N to M; player 1 unless p2: to p4:
+  --multitap NAME     fourscore or famicom, for players 3 and 4
+  --device NAME       plug in a fourscore, famicom4p, zapper, powerpad, vaus,
+		      famicom-vaus or keyboard instead of what the ROM header says
+  --aim X,Y@N[-M]     plug a Zapper into port 2 and point it at X,Y on frames N to M
+  --fire N[-M]        pull the Zapper's trigger, or press the Vaus's button,
+		      on frames N to M
+  --pad BUTTONS@N[-M] step on Power Pad buttons 1 to 12, joined with +
+  --paddle POS@N[-M]  turn the Vaus's knob to POS, 0 to 255
+  --key KEYS@N[-M]    hold Family BASIC keyboard keys, joined with +
+  --reset soft@N      press reset before frame N, or with hard@N power cycle
+  --movie FILE        play the joypad input and resets of an FCEUX .fm2 movie,
+		      a BizHawk .bk2 one or a text input script
+  --record FILE       record the run's joypad input and resets to an .fm2 movie
+  --save-state FILE@N save the whole machine to FILE after frame N
+  --load-state FILE   start from a saved state instead of power on
+  --rewind N@M        after frame M, go back N frames and run on from there
+  --rewind-interval N keep a state for rewinding every N frames (default 1)
+  --rewind-memory MB  memory the rewind states may take up (default 64)
+
+disasm options:
+  --bank N            the 16KB PRG ROM bank to disassemble (default 0)
+  --start ADDR        the address the bank is mapped at, $8000 or $C000 say
+		      (default $8000)
+  --length N          bytes to disassemble (default the whole bank)";
+
+/// A range of frames, both ends included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct	Frames {
+    pub first:	u64,
+    pub last:	u64,
+}
+
+impl	Frames {
+    pub fn contains(&self, frame: u64) -> bool {
+	(self.first..=self.last).contains(&frame)
+    }
+}
+
+/// Buttons held on a joypad over a range of frames.
+#[derive(Debug, PartialEq)]
+pub struct	Press {
+    pub player:	usize,	// from 0
+    pub buttons:	u8,
+    pub frames:	Frames,
+}
+
+/// Where the Zapper points over a range of frames.
+#[derive(Debug, PartialEq)]
+pub struct	Aim {
+    pub x:	usize,
+    pub y:	usize,
+    pub frames:	Frames,
+}
+
+/// Something held on a device other than a joypad over a range of
+/// frames: Power Pad buttons, a knob position, keyboard keys.
+#[derive(Debug, PartialEq)]
+pub struct	Held<T> {
+    pub value:	T,
+    pub frames:	Frames,
+}
+
+pub struct	Options {
+    pub rom:	Option<String>,
+    pub headless:	bool,
+    pub debug:	bool,
+    pub disasm:	bool,
+    pub bank:	usize,
+    pub start:	u16,
+    pub length:	Option<usize>,
+    pub gdb:	Option<u16>,
+    pub frames:	u64,
+    pub dump_every:	bool,
+    pub dump_at:	Vec<u64>,
+    pub out_dir:	String,
+    pub png:	bool,
+    pub palette:	Option<String>,
+    pub ntsc:	bool,
+    pub region:	Option<Region>,
+    pub sample_rate:	u32,
+    pub wav:	Option<String>,
+    pub stems:	bool,
+    pub mute:	Vec<Channel>,
+    pub song:	Option<u8>,
+    pub presses:	Vec<Press>,
+    pub aims:	Vec<Aim>,
+    pub fires:	Vec<Frames>,
+    pub devices:	Vec<Device>,
+    pub pads:	Vec<Held<u16>>,
+    pub paddles:	Vec<Held<u8>>,
+    pub keys:	Vec<Held<u128>>,
+    pub resets:	Vec<(Reset, u64)>,
+    pub movie:	Option<String>,
+    pub record:	Option<String>,
+    pub save_states:	Vec<(String, u64)>,
+    pub load_state:	Option<String>,
+    pub rewinds:	Vec<(u64, u64)>,	// frames back, after frame
+    pub rewind_interval:	u64,
+    pub rewind_memory:	usize,	// in bytes, given in MB
+}
+
+impl Default for Options {
+    fn default() -> Self {
+	Options {
+	    rom: None,
+	    headless: false,
+	    debug: false,
+	    disasm: false,
+	    bank: 0,
+	    start: 0x8000,
+	    length: None,
+	    gdb: None,
+	    frames: 60,
+	    dump_every: false,
+	    dump_at: Vec::new(),
+	    out_dir: ".".to_string(),
+	    png: false,
+	    palette: None,
+	    ntsc: false,
+	    region: None,
+	    sample_rate: 44100,
+	    wav: None,
+	    stems: false,
+	    mute: Vec::new(),
+	    song: None,
+	    presses: Vec::new(),
+	    aims: Vec::new(),
+	    fires: Vec::new(),
+	    devices: Vec::new(),
+	    pads: Vec::new(),
+	    paddles: Vec::new(),
+	    keys: Vec::new(),
+	    resets: Vec::new(),
+	    movie: None,
+	    record: None,
+	    save_states: Vec::new(),
+	    load_state: None,
+	    rewinds: Vec::new(),
+	    rewind_interval: 1,
+	    rewind_memory: 64 << 20,
+	}
+    }
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<&'a String, String> {
+    args.next().ok_or(format!("{} needs a value", flag))
+}
+
+fn number(s: &str, flag: &str) -> Result<u64, String> {
+    s.trim().parse::<u64>().map_err(|_| format!("{}: '{}' is not a number", flag, s))
+}
+
+// $C000, 0xC000 or 49152
+fn address(s: &str, flag: &str) -> Result<u16, String> {
+    let parsed = match s.strip_prefix('$').or(s.strip_prefix("0x")) {
+	Some(hex) => u16::from_str_radix(hex, 16),
+	None => s.parse::<u16>(),
+    };
+    parsed.map_err(|_| format!("{}: '{}' is not an address", flag, s))
+}
+
+// 10 or 10-20
+fn frames(s: &str, flag: &str) -> Result<Frames, String> {
+    match s.split_once('-') {
+	Some((first, last)) => Ok(Frames { first: number(first, flag)?, last: number(last, flag)? }),
+	None => Ok(Frames { first: number(s, flag)?, last: number(s, flag)? }),
+    }
+}
+
+// what@10-20
+fn at<'a>(s: &'a str, flag: &str) -> Result<(&'a str, Frames), String> {
+    let (what, range) = s.split_once('@').ok_or(format!("{}: '{}' has no @frame", flag, s))?;
+    Ok((what, frames(range, flag)?))
+}
+
+// [p2:]a+b@10-20
+fn press(s: &str, flag: &str) -> Result<Press, String> {
+    let (buttons, frames) = at(s, flag)?;
+    let (player, buttons) = controller::parse_player_buttons(buttons).map_err(|e| format!("{}: {}", flag, e))?;
+    Ok(Press { player, buttons, frames })
+}
+
+// 128,120@10-20
+fn aim(s: &str, flag: &str) -> Result<Aim, String> {
+    let (position, frames) = at(s, flag)?;
+    let (x, y) = position.split_once(',').ok_or(format!("{}: '{}' is not x,y", flag, position))?;
+    Ok(Aim { x: number(x, flag)? as usize, y: number(y, flag)? as usize, frames })
+}
+
+// what@10-20, what parsed by `parse`
+fn held<T>(s: &str, flag: &str, parse: impl Fn(&str) -> Result<T, String>) -> Result<Held<T>, String> {
+    let (what, frames) = at(s, flag)?;
+    Ok(Held { value: parse(what).map_err(|e| format!("{}: {}", flag, e))?, frames })
+}
+
+/// Parses everything after the program name.
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter().peekable();
+    if args.next_if(|arg| *arg == "disasm").is_some() {
+	options.disasm = true;
+    }
+
+    while let Some(arg) = args.next() {
+	match arg.as_str() {
+	    "--headless" => { options.headless = true; }
+	    "--debug" => { options.debug = true; }
+	    "--bank" => { options.bank = number(value(&mut args, arg)?, arg)? as usize; }
+	    "--start" => { options.start = address(value(&mut args, arg)?, arg)?; }
+	    "--length" => { options.length = Some(number(value(&mut args, arg)?, arg)? as usize); }
+	    "--gdb" => {
+		let port = value(&mut args, arg)?;
+		options.gdb = Some(port.parse().map_err(|_| format!("{}: '{}' is not a port", arg, port))?);
+	    }
+	    "--frames" => { options.frames = number(value(&mut args, arg)?, arg)?; }
+	    "--dump-every" => { options.dump_every = true; }
+	    "--dump-at" => {
+		for frame in value(&mut args, arg)?.split(',') {
+		    options.dump_at.push(number(frame, arg)?);
+		}
+	    }
+	    "--out" => { options.out_dir = value(&mut args, arg)?.clone(); }
+	    "--png" => { options.png = true; }
+	    "--ntsc" => { options.ntsc = true; }
+	    "--sample-rate" => {
+		options.sample_rate = number(value(&mut args, arg)?, arg)? as u32;
+		if !(8000..=192000).contains(&options.sample_rate) {
+		    return Err(format!("{}: {} Hz is out of range", arg, options.sample_rate));
+		}
+	    }
+	    "--region" => { options.region = Some(Region::from_name(value(&mut args, arg)?)?); }
+	    "--song" => {
+		let song = number(value(&mut args, arg)?, arg)?;
+		if !(1..=255).contains(&song) {
+		    return Err(format!("{}: there's no song {}", arg, song));
+		}
+		options.song = Some(song as u8);
+	    }
+	    "--press" => { options.presses.push(press(value(&mut args, arg)?, arg)?); }
+	    "--aim" => { options.aims.push(aim(value(&mut args, arg)?, arg)?); }
+	    "--multitap" => { options.devices.push(Device::multitap_from_name(value(&mut args, arg)?)?); }
+	    "--device" => { options.devices.push(Device::from_name(value(&mut args, arg)?)?); }
+	    "--pad" => { options.pads.push(held(value(&mut args, arg)?, arg, controller::parse_pad_buttons)?); }
+	    "--paddle" => {
+		let position = |s: &str| s.trim().parse::<u8>().map_err(|_| format!("'{}' is not 0 to 255", s));
+		options.paddles.push(held(value(&mut args, arg)?, arg, position)?);
+	    }
+	    "--reset" => {
+		let reset = value(&mut args, arg)?;
+		let (kind, frame) = reset.split_once('@').ok_or(format!("{}: '{}' has no @frame", arg, reset))?;
+		options.resets.push((Reset::from_name(kind)?, number(frame, arg)?));
+	    }
+	    "--movie" => { options.movie = Some(value(&mut args, arg)?.clone()); }
+	    "--record" => { options.record = Some(value(&mut args, arg)?.clone()); }
+	    "--save-state" => {
+		let (path, frame) = value(&mut args, arg)?.rsplit_once('@').ok_or(format!("{} needs FILE@frame", arg))?;
+		options.save_states.push((path.to_string(), number(frame, arg)?));
+	    }
+	    "--load-state" => { options.load_state = Some(value(&mut args, arg)?.clone()); }
+	    "--rewind" => {
+		let (back, frame) = value(&mut args, arg)?.split_once('@').ok_or(format!("{} needs N@frame", arg))?;
+		options.rewinds.push((number(back, arg)?, number(frame, arg)?));
+	    }
+	    "--rewind-interval" => {
+		options.rewind_interval = number(value(&mut args, arg)?, arg)?;
+		if options.rewind_interval == 0 {
+		    return Err(format!("{} must be at least 1", arg));
+		}
+	    }
+	    "--rewind-memory" => {
+		let megabytes = value(&mut args, arg)?;
+		options.rewind_memory = usize::try_from(number(megabytes, arg)?).ok()
+		    .and_then(|mb| mb.checked_mul(1 << 20))
+		    .ok_or(format!("{}: '{}' is too large", arg, megabytes))?;
+	    }
+	    "--key" => { options.keys.push(held(value(&mut args, arg)?, arg, controller::parse_keys)?); }
+	    "--fire" => { options.fires.push(frames(value(&mut args, arg)?, arg)?); }
+	    "--palette" => { options.palette = Some(value(&mut args, arg)?.clone()); }
+	    "--wav" => { options.wav = Some(value(&mut args, arg)?.clone()); }
+	    "--stems" => { options.stems = true; }
+	    "--mute" => {
+		for channel in value(&mut args, arg)?.split(',') {
+		    options.mute.push(Channel::from_name(channel.trim())?);
+		}
+	    }
+	    _ if arg.starts_with("--") => { return Err(format!("unknown option {}", arg)); }
+	    _ => {
+		if options.rom.is_some() {
+		    return Err(format!("unexpected argument {}", arg));
+		}
+		options.rom = Some(arg.clone());
+	    }
+	}
+    }
+
+    if options.disasm && (options.rom.is_none() || options.headless || options.debug || options.gdb.is_some()) {
+	return Err("disasm needs a ROM, and no --headless, --debug or --gdb".to_string());
+    }
+    if !options.disasm && (options.bank != 0 || options.start != 0x8000 || options.length.is_some()) {
+	return Err("--bank, --start and --length are disasm options".to_string());
+    }
+    if options.headless && options.rom.is_none() {
+	return Err("--headless needs a ROM".to_string());
+    }
+    if options.wav.is_some() && !options.headless {
+	return Err("--wav needs --headless".to_string());
+    }
+    if (options.movie.is_some() || options.record.is_some()) && !options.headless {
+	return Err("--movie and --record need --headless".to_string());
+    }
+    if (!options.save_states.is_empty() || options.load_state.is_some()) && !options.headless {
+	return Err("--save-state and --load-state need --headless".to_string());
+    }
+    if (options.debug || options.gdb.is_some()) && options.headless {
+	return Err("--debug and --gdb can't be used with --headless".to_string());
+    }
+    if options.debug && options.gdb.is_some() {
+	return Err("--debug and --gdb can't be used together".to_string());
+    }
+    if !options.rewinds.is_empty() && !options.headless {
+	return Err("--rewind needs --headless".to_string());
+    }
+    if options.stems && options.wav.is_none() {
+	return Err("--stems needs --wav".to_string());
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+	s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rom_only() {
+	let options = parse(&args("game.nes")).unwrap();
+	assert_eq!(options.rom.as_deref(), Some("game.nes"));
+	assert!(!options.headless);
+	assert_eq!(options.region, None);
+	let options = parse(&args("--region dendy game.nes")).unwrap();
+	assert_eq!(options.region, Some(Region::Dendy));
+    }
+
+    #[test]
+    fn test_headless_options() {
+	let options = parse(&args("--headless --frames 120 --dump-at 10,20,120 --out shots --png --palette ntsc.pal game.nes")).unwrap();
+	assert!(options.headless);
+	assert!(!options.ntsc);
+	assert!(options.png);
+	assert_eq!(options.frames, 120);
+	assert_eq!(options.dump_at, vec![10, 20, 120]);
+	assert_eq!(options.out_dir, "shots");
+	assert_eq!(options.palette.as_deref(), Some("ntsc.pal"));
+	assert!(options.presses.is_empty());
+	assert_eq!(options.wav, None);
+	let options = parse(&args("--headless --wav music.wav --sample-rate 48000 game.nes")).unwrap();
+	assert_eq!(options.wav.as_deref(), Some("music.wav"));
+	assert_eq!(options.sample_rate, 48000);
+	assert!(!options.stems);
+	let options = parse(&args("--headless --wav music.wav --stems --mute noise,DMC game.nes")).unwrap();
+	assert!(options.stems);
+	assert_eq!(options.mute, vec![Channel::Noise, Channel::Dmc]);
+	assert_eq!(options.song, None);
+	let options = parse(&args("--headless --wav music.wav --song 3 --mute vrc6 music.nsf")).unwrap();
+	assert_eq!(options.song, Some(3));
+	assert_eq!(options.mute, vec![Channel::Vrc6]);
+    }
+
+    #[test]
+    fn test_bad_arguments() {
+	assert!(parse(&args("--frames")).is_err());
+	assert!(parse(&args("--frames ten game.nes")).is_err());
+	assert!(parse(&args("--bogus game.nes")).is_err());
+	assert!(parse(&args("--headless")).is_err());
+	assert!(parse(&args("a.nes b.nes")).is_err());
+	assert!(parse(&args("--region secam game.nes")).is_err());
+	assert!(parse(&args("--sample-rate 100 game.nes")).is_err());
+	assert!(parse(&args("--wav music.wav game.nes")).is_err());
+	assert!(parse(&args("--headless --stems game.nes")).is_err());
+	assert!(parse(&args("--mute fm game.nes")).is_err());
+	assert!(parse(&args("--song 0 music.nsf")).is_err());
+	assert!(parse(&args("--press start game.nes")).is_err());
+	assert!(parse(&args("--press turbo@3 game.nes")).is_err());
+    }
+
+    #[test]
+    fn test_presses() {
+	let options = parse(&args("--press start@30 --press p2:a+right@40-80 game.nes")).unwrap();
+	assert_eq!(options.presses, vec![
+	    Press { player: 0, buttons: controller::BUTTON_START, frames: Frames { first: 30, last: 30 } },
+	    Press { player: 1, buttons: controller::BUTTON_A | controller::BUTTON_RIGHT, frames: Frames { first: 40, last: 80 } },
+	]);
+	assert_eq!(options.devices, vec![]);
+
+	let options = parse(&args("--multitap fourscore --press p4:b@1 game.nes")).unwrap();
+	assert_eq!(options.devices, vec![Device::FourScore]);
+	assert_eq!(options.presses[0].player, 3);
+	assert!(parse(&args("--press p5:b@1 game.nes")).is_err());
+	assert!(parse(&args("--multitap hori game.nes")).is_err());
+    }
+
+    #[test]
+    fn test_zapper() {
+	let options = parse(&args("--aim 128,96@100-130 --fire 120 game.nes")).unwrap();
+	assert_eq!(options.aims, vec![Aim { x: 128, y: 96, frames: Frames { first: 100, last: 130 } }]);
+	assert_eq!(options.fires, vec![Frames { first: 120, last: 120 }]);
+	assert!(options.fires[0].contains(120));
+	assert!(!options.aims[0].frames.contains(131));
+	assert!(parse(&args("--aim 128@10 game.nes")).is_err());
+	assert!(parse(&args("--fire x game.nes")).is_err());
+    }
+
+    #[test]
+    fn test_devices() {
+	let options = parse(&args("--device powerpad --pad 1+12@5-9 --device keyboard --key lshift+a@20 \
+				   --paddle 98@1-3 game.nes")).unwrap();
+	assert_eq!(options.devices, vec![Device::PowerPad, Device::Keyboard]);
+	assert_eq!(options.pads, vec![Held { value: 0x801, frames: Frames { first: 5, last: 9 } }]);
+	assert_eq!(options.paddles, vec![Held { value: 98, frames: Frames { first: 1, last: 3 } }]);
+	assert_eq!(options.keys[0].frames, Frames { first: 20, last: 20 });
+	assert!(parse(&args("--device robot game.nes")).is_err());
+	assert!(parse(&args("--pad 0@1 game.nes")).is_err());
+	assert!(parse(&args("--paddle 256@1 game.nes")).is_err());
+	assert!(parse(&args("--key shift@1 game.nes")).is_err());
+    }
+
+    #[test]
+    fn test_movies() {
+	let options = parse(&args("--headless --movie in.fm2 --record out.fm2 --reset soft@10 --reset HARD@20 game.nes")).unwrap();
+	assert_eq!(options.movie.as_deref(), Some("in.fm2"));
+	assert_eq!(options.record.as_deref(), Some("out.fm2"));
+	assert_eq!(options.resets, vec![(Reset::Soft, 10), (Reset::Hard, 20)]);
+	assert!(parse(&args("--movie in.fm2 game.nes")).is_err());
+	assert!(parse(&args("--headless --reset warm@10 game.nes")).is_err());
+	assert!(parse(&args("--headless --reset soft game.nes")).is_err());
+    }
+
+    #[test]
+    fn test_save_states() {
+	let options = parse(&args("--headless --save-state a@b.state@100 --load-state start.state game.nes")).unwrap();
+	assert_eq!(options.save_states, vec![("a@b.state".to_string(), 100)]);
+	assert_eq!(options.load_state.as_deref(), Some("start.state"));
+	assert!(parse(&args("--headless --save-state a.state game.nes")).is_err());
+	assert!(parse(&args("--load-state a.state game.nes")).is_err());
+    }
+
+    #[test]
+    fn test_rewind() {
+	let options = parse(&args("--headless --rewind 30@100 --rewind-interval 4 --rewind-memory 8 game.nes")).unwrap();
+	assert_eq!(options.rewinds, vec![(30, 100)]);
+	assert_eq!((options.rewind_interval, options.rewind_memory), (4, 8 << 20));
+	assert!(parse(&args("--headless --rewind 30 game.nes")).is_err());
+	assert!(parse(&args("--headless --rewind-interval 0 game.nes")).is_err());
+	let error = parse(&args("--headless --rewind-memory 18446744073709551615 game.nes")).err();
+	assert_eq!(error.as_deref(), Some("--rewind-memory: '18446744073709551615' is too large"));
+	assert!(parse(&args("--rewind 30@100 game.nes")).is_err());
+    }
+
+    #[test]
+    fn test_debug() {
+	assert!(parse(&args("--debug game.nes")).unwrap().debug);
+	assert!(parse(&args("--debug --headless game.nes")).is_err());
+	assert_eq!(parse(&args("--gdb 6502 game.nes")).unwrap().gdb, Some(6502));
+	assert!(parse(&args("--gdb 65536 game.nes")).is_err());
+	assert!(parse(&args("--gdb 6502 --debug game.nes")).is_err());
+    }
+
+    #[test]
+    fn test_disasm() {
+	let options = parse(&args("disasm game.nes --bank 3 --start $C000 --length 256")).unwrap();
+	assert!(options.disasm);
+	assert_eq!((options.bank, options.start, options.length), (3, 0xC000, Some(256)));
+	assert_eq!(parse(&args("disasm --start 0xa000 game.nes")).unwrap().start, 0xA000);
+	assert!(parse(&args("disasm")).is_err());
+	assert!(parse(&args("disasm --start $10000 game.nes")).is_err());
+	assert!(parse(&args("--bank 1 game.nes")).is_err());
+	// only as the first argument
+	assert!(parse(&args("game.nes disasm")).is_err());
+    }
+}
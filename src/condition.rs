@@ -0,0 +1,285 @@
+// Conditions for the debugger's breakpoints and watchpoints, like
+//
+//     A == $40 && [$0300] > 3
+//
+// Registers are a, x, y, sp, p and pc, [ADDR] is the byte at ADDR, and
+// numbers are decimal or hex with $ or 0x. The operators, loosest first:
+//
+//     ||
+//     &&
+//     == != < <= > >=
+//     |
+//     &
+//     + -
+//     ! and unary -
+//
+// Everything is worked out on 32-bit values; true is 1 and false 0.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum	Register {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+}
+
+impl	Register {
+    fn from_name(name: &str) -> Option<Self> {
+	match name.to_ascii_lowercase().as_str() {
+	    "a" => Some(Register::A),
+	    "x" => Some(Register::X),
+	    "y" => Some(Register::Y),
+	    "sp" => Some(Register::Sp),
+	    "p" => Some(Register::P),
+	    "pc" => Some(Register::Pc),
+	    _ => None,
+	}
+    }
+}
+
+/// What a condition looks at.
+pub trait	Context {
+    fn register(&self, register: Register) -> u16;
+    /// Memory as a read would see it, without a read's side effects.
+    fn peek(&self, addr: u16) -> u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum	Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+// loosest first, the same as the table above
+const LEVELS: [&[(&str, Op)]; 6] = [
+    &[("||", Op::Or)],
+    &[("&&", Op::And)],
+    &[("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+    &[("|", Op::BitOr)],
+    &[("&", Op::BitAnd)],
+    &[("+", Op::Add), ("-", Op::Sub)],
+];
+
+#[derive(Debug, PartialEq)]
+enum	Expr {
+    Number(u32),
+    Register(Register),
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl	Expr {
+    fn eval(&self, context: &impl Context) -> u32 {
+	match self {
+	    Expr::Number(n) => *n,
+	    Expr::Register(register) => context.register(*register) as u32,
+	    Expr::Memory(addr) => context.peek(addr.eval(context) as u16) as u32,
+	    Expr::Not(e) => (e.eval(context) == 0) as u32,
+	    Expr::Negate(e) => e.eval(context).wrapping_neg(),
+	    Expr::Binary(op, left, right) => {
+		let left = left.eval(context);
+		// && and || don't look any further than they need to
+		match op {
+		    Op::Or if left != 0 => return 1,
+		    Op::And if left == 0 => return 0,
+		    _ => {}
+		}
+		let right = right.eval(context);
+		match op {
+		    Op::Or | Op::And => (right != 0) as u32,
+		    Op::Eq => (left == right) as u32,
+		    Op::Ne => (left != right) as u32,
+		    Op::Lt => (left < right) as u32,
+		    Op::Le => (left <= right) as u32,
+		    Op::Gt => (left > right) as u32,
+		    Op::Ge => (left >= right) as u32,
+		    Op::BitOr => left | right,
+		    Op::BitAnd => left & right,
+		    Op::Add => left.wrapping_add(right),
+		    Op::Sub => left.wrapping_sub(right),
+		}
+	    }
+	}
+    }
+}
+
+// A recursive descent over the text, one function per level of
+// LEVELS and one for what's inside them.
+struct	Parser<'a> {
+    text:	&'a str,
+    pos:	usize,
+}
+
+impl<'a>	Parser<'a> {
+    fn rest(&self) -> &'a str {
+	self.text[self.pos..].trim_start()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+	let rest = self.rest();
+	if !rest.starts_with(token) {
+	    return false;
+	}
+	// a lone | or & isn't the start of || or &&
+	if matches!(token, "|" | "&") && rest[1..].starts_with(token) {
+	    return false;
+	}
+	self.pos = self.text.len() - rest.len() + token.len();
+	true
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+	let Some(ops) = LEVELS.get(level) else { return self.unary() };
+	let mut left = self.binary(level + 1)?;
+	'more: loop {
+	    for (token, op) in ops.iter() {
+		if self.eat(token) {
+		    left = Expr::Binary(*op, Box::new(left), Box::new(self.binary(level + 1)?));
+		    continue 'more;
+		}
+	    }
+	    return Ok(left);
+	}
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+	if self.eat("!") {
+	    return Ok(Expr::Not(Box::new(self.unary()?)));
+	}
+	if self.eat("-") {
+	    return Ok(Expr::Negate(Box::new(self.unary()?)));
+	}
+	if self.eat("(") {
+	    let inner = self.binary(0)?;
+	    return if self.eat(")") { Ok(inner) } else { Err("missing )".to_string()) };
+	}
+	if self.eat("[") {
+	    let addr = self.binary(0)?;
+	    return if self.eat("]") { Ok(Expr::Memory(Box::new(addr))) } else { Err("missing ]".to_string()) };
+	}
+
+	let rest = self.rest();
+	let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '$').unwrap_or(rest.len());
+	let word = rest[..len].to_string();
+	if word.is_empty() {
+	    return Err(match rest.chars().next() {
+		Some(c) => format!("unexpected {}", c),
+		None => "the condition ends too soon".to_string(),
+	    });
+	}
+	self.pos = self.text.len() - rest.len() + len;
+	if let Some(register) = Register::from_name(&word) {
+	    return Ok(Expr::Register(register));
+	}
+	let number = match word.strip_prefix('$').or(word.strip_prefix("0x")) {
+	    Some(hex) => u32::from_str_radix(hex, 16),
+	    None => word.parse::<u32>(),
+	};
+	number.map(Expr::Number).map_err(|_| format!("'{}' is neither a number nor a register", word))
+    }
+}
+
+/// A parsed condition, which prints as it was written.
+#[derive(Debug)]
+pub struct	Condition {
+    text:	String,
+    expr:	Expr,
+}
+
+impl	Condition {
+    pub fn parse(text: &str) -> Result<Self, String> {
+	let mut parser = Parser { text, pos: 0 };
+	let expr = parser.binary(0)?;
+	if !parser.rest().is_empty() {
+	    return Err(format!("unexpected {} in the condition", parser.rest()));
+	}
+	Ok(Condition { text: text.trim().to_string(), expr })
+    }
+
+    pub fn holds(&self, context: &impl Context) -> bool {
+	self.expr.eval(context) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct	Machine {
+	a:	u8,
+	pc:	u16,
+	ram:	[u8; 0x800],
+    }
+
+    impl Context for Machine {
+	fn register(&self, register: Register) -> u16 {
+	    match register {
+		Register::A => self.a as u16,
+		Register::Pc => self.pc,
+		_ => 0,
+	    }
+	}
+
+	fn peek(&self, addr: u16) -> u8 {
+	    self.ram[addr as usize % 0x800]
+	}
+    }
+
+    fn holds(text: &str, machine: &Machine) -> bool {
+	Condition::parse(text).unwrap().holds(machine)
+    }
+
+    #[test]
+    fn test_conditions() {
+	let mut machine = Machine { a: 0x40, pc: 0xC000, ram: [0; 0x800] };
+	machine.ram[0x300] = 4;
+	assert!(holds("A == $40 && [$0300] > 3", &machine));
+	assert!(!holds("a == $40 && [$0300] > 4", &machine));
+	assert!(holds("a != 64 || [0x300] >= 4", &machine));
+	assert!(holds("pc - $C000 == 0 && [$2FF + 1] == 4", &machine));
+	assert!(holds("a & $C0 == $40 | 0", &machine));
+	assert!(holds("!(x < y) && x <= y && -1 > 0", &machine));
+	assert_eq!(Condition::parse(" A == $40 ").unwrap().to_string(), "A == $40");
+    }
+
+    #[test]
+    fn test_precedence() {
+	let machine = Machine { a: 1, pc: 0, ram: [0; 0x800] };
+	// + before ==, == before &&
+	assert!(holds("1 + 1 == 2 && 3 == 3", &machine));
+	// & before ==, so this is (a & 3) == 1
+	assert!(holds("a & 3 == 1", &machine));
+	assert!(!holds("(2 == 2) + 1 == 3", &machine));
+    }
+
+    #[test]
+    fn test_bad_conditions() {
+	assert_eq!(Condition::parse("a ==").unwrap_err(), "the condition ends too soon");
+	assert_eq!(Condition::parse("z == 1").unwrap_err(), "'z' is neither a number nor a register");
+	assert_eq!(Condition::parse("[$300").unwrap_err(), "missing ]");
+	assert!(Condition::parse("a == 1 2").is_err());
+	assert!(Condition::parse("a = 1").is_err());
+    }
+}
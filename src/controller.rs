@@ -0,0 +1,829 @@
+use crate::palette::Palette;
+use crate::ppu::{Ppu, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+// What's plugged into the controller ports and the Famicom's expansion
+// port. A write to $4016 goes to all of them; a read of $4016 is port 1's
+// bits plus the expansion port's, one of $4017 port 2's plus the
+// expansion port's.
+// https://www.nesdev.org/wiki/Input_devices
+
+pub const CONTROLLER_STROBE: u16 = 0x4016;
+pub const CONTROLLER_PORT_1: u16 = 0x4016;
+pub const CONTROLLER_PORT_2: u16 = 0x4017;
+
+// in the order they're read out
+pub const BUTTON_A: u8 = 0x01;
+pub const BUTTON_B: u8 = 0x01 << 1;
+pub const BUTTON_SELECT: u8 = 0x01 << 2;
+pub const BUTTON_START: u8 = 0x01 << 3;
+pub const BUTTON_UP: u8 = 0x01 << 4;
+pub const BUTTON_DOWN: u8 = 0x01 << 5;
+pub const BUTTON_LEFT: u8 = 0x01 << 6;
+pub const BUTTON_RIGHT: u8 = 0x01 << 7;
+
+const BUTTON_NAMES: [(&str, u8); 8] = [
+    ("a", BUTTON_A),
+    ("b", BUTTON_B),
+    ("select", BUTTON_SELECT),
+    ("start", BUTTON_START),
+    ("up", BUTTON_UP),
+    ("down", BUTTON_DOWN),
+    ("left", BUTTON_LEFT),
+    ("right", BUTTON_RIGHT),
+];
+
+/// Buttons by name, joined with '+': "start", "a+right".
+pub fn parse_buttons(names: &str) -> Result<u8, String> {
+    let mut buttons = 0;
+    for name in names.split('+') {
+	let (_, button) = BUTTON_NAMES.iter()
+	    .find(|(button, _)| *button == name.trim().to_ascii_lowercase())
+	    .ok_or(format!("unknown button {}, expected a, b, select, start, up, down, left or right", name))?;
+	buttons |= button;
+    }
+    Ok(buttons)
+}
+
+/// Buttons with an optional player in front, counting from 0: "start",
+/// "p2:a+b", up to "p4:".
+pub fn parse_player_buttons(s: &str) -> Result<(usize, u8), String> {
+    let (player, buttons) = match s.split_once(':') {
+	Some(("p1", buttons)) => (0, buttons),
+	Some(("p2", buttons)) => (1, buttons),
+	Some(("p3", buttons)) => (2, buttons),
+	Some(("p4", buttons)) => (3, buttons),
+	Some((player, _)) => return Err(format!("unknown player {}, expected p1 to p4", player)),
+	None => (0, s),
+    };
+    Ok((player, parse_buttons(buttons)?))
+}
+
+// only the low bits of a port read are driven, the rest is whatever was
+// last on the data bus, usually the $40 of the address
+pub const OPEN_BUS_MASK: u8 = 0xE0;
+const PORT_MASK: u8 = 0x1F;
+
+/// A device in one of the two controller ports.
+pub trait	ControllerPort {
+    /// A write to $4016; only bit 0, the strobe, reaches the ports.
+    fn write(&mut self, value: u8);
+
+    /// Bits 0-4 of a read from the port.
+    fn read(&mut self, ppu: &Ppu) -> u8;
+
+    /// The `index`th joypad behind the port, for devices that have any.
+    fn joypad(&mut self, _index: usize) -> Option<&mut Joypad> {
+	None
+    }
+
+    fn zapper(&mut self) -> Option<&mut Zapper> {
+	None
+    }
+
+    fn power_pad(&mut self) -> Option<&mut PowerPad> {
+	None
+    }
+
+    fn vaus(&mut self) -> Option<&mut Vaus> {
+	None
+    }
+}
+
+/// A device in the Famicom's expansion port, which sees all three output
+/// bits of $4016 and reads on both $4016 and $4017.
+pub trait	ExpansionDevice {
+    fn write(&mut self, value: u8);
+
+    /// Bits 1-4 of a read from `port`, 0 for $4016 and 1 for $4017.
+    fn read(&mut self, port: usize, ppu: &Ppu) -> u8;
+
+    fn joypad(&mut self, _index: usize) -> Option<&mut Joypad> {
+	None
+    }
+
+    fn vaus(&mut self) -> Option<&mut Vaus> {
+	None
+    }
+
+    fn keyboard(&mut self) -> Option<&mut Keyboard> {
+	None
+    }
+}
+
+/// The standard controller: a 4021 shift register that latches the eight
+/// buttons while the strobe is high and hands them out one per read after.
+/// https://www.nesdev.org/wiki/Standard_controller
+#[derive(Default)]
+pub struct	Joypad {
+    buttons:	u8,
+    strobe:	bool,
+    shift:	u8,
+}
+
+impl	Joypad {
+    pub fn set_buttons(&mut self, buttons: u8) {
+	self.buttons = buttons;
+	if self.strobe {
+	    self.shift = buttons;
+	}
+    }
+}
+
+impl ControllerPort for Joypad {
+    fn write(&mut self, value: u8) {
+	self.strobe = value & 0x01 != 0;
+	if self.strobe {
+	    self.shift = self.buttons;
+	}
+    }
+
+    // past the eighth read an official controller keeps returning 1
+    fn read(&mut self, _ppu: &Ppu) -> u8 {
+	if self.strobe {
+	    return self.buttons & 0x01;
+	}
+	let bit = self.shift & 0x01;
+	self.shift = (self.shift >> 1) | 0x80;
+	bit
+    }
+
+    fn joypad(&mut self, index: usize) -> Option<&mut Joypad> {
+	if index == 0 { Some(self) } else { None }
+    }
+}
+
+// After both joypads' buttons, the Four Score sends a signature that
+// tells it apart from two joypads chained together: 0001 0000 on
+// $4016, 0010 0000 on $4017, in the order they're read.
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0x08, 0x04];
+const FOUR_SCORE_READS: u8 = 24;
+
+/// One port's half of the NES Four Score: players 1 and 3 in port 1,
+/// 2 and 4 in port 2.
+/// https://www.nesdev.org/wiki/Four_Score
+pub struct	FourScore {
+    joypads:	[Joypad; 2],
+    signature:	u8,
+    strobe:	bool,
+    reads:	u8,
+}
+
+impl	FourScore {
+    /// The half that goes in `port`, 0 or 1.
+    pub fn	new(port: usize) -> Self {
+	FourScore {
+	    joypads: [Joypad::default(), Joypad::default()],
+	    signature: FOUR_SCORE_SIGNATURES[port],
+	    strobe: false,
+	    reads: 0,
+	}
+    }
+}
+
+impl ControllerPort for FourScore {
+    fn write(&mut self, value: u8) {
+	self.strobe = value & 0x01 != 0;
+	if self.strobe {
+	    self.reads = 0;
+	}
+	for joypad in &mut self.joypads {
+	    joypad.write(value);
+	}
+    }
+
+    fn read(&mut self, ppu: &Ppu) -> u8 {
+	if self.strobe {
+	    return self.joypads[0].read(ppu);
+	}
+	let bit = match self.reads {
+	    0..=7 => self.joypads[0].read(ppu),
+	    8..=15 => self.joypads[1].read(ppu),
+	    16..=23 => (self.signature >> (self.reads - 16)) & 0x01,
+	    _ => 1,
+	};
+	self.reads = (self.reads + 1).min(FOUR_SCORE_READS);
+	bit
+    }
+
+    fn joypad(&mut self, index: usize) -> Option<&mut Joypad> {
+	self.joypads.get_mut(index)
+    }
+}
+
+// bit 1 of both ports
+const FAMICOM_JOYPAD_BIT: u8 = 0x01 << 1;
+
+/// The Famicom way to four players: two more joypads in the expansion
+/// port, player 3 on bit 1 of $4016 and player 4 on bit 1 of $4017.
+#[derive(Default)]
+pub struct	FamicomFourPlayer {
+    joypads:	[Joypad; 2],
+}
+
+impl ExpansionDevice for FamicomFourPlayer {
+    fn write(&mut self, value: u8) {
+	for joypad in &mut self.joypads {
+	    joypad.write(value);
+	}
+    }
+
+    fn read(&mut self, port: usize, ppu: &Ppu) -> u8 {
+	self.joypads[port].read(ppu) << 1 & FAMICOM_JOYPAD_BIT
+    }
+
+    fn joypad(&mut self, index: usize) -> Option<&mut Joypad> {
+	self.joypads.get_mut(index)
+    }
+}
+
+/// What can be plugged in instead of, or next to, the two joypads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum	Device {
+    FourScore,
+    FamicomFourPlayer,
+    Zapper,
+    PowerPad,
+    Vaus,
+    FamicomVaus,
+    Keyboard,
+}
+
+const DEVICE_NAMES: [(&str, Device); 7] = [
+    ("fourscore", Device::FourScore),
+    ("famicom4p", Device::FamicomFourPlayer),
+    ("zapper", Device::Zapper),
+    ("powerpad", Device::PowerPad),
+    ("vaus", Device::Vaus),
+    ("famicom-vaus", Device::FamicomVaus),
+    ("keyboard", Device::Keyboard),
+];
+
+impl	Device {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+	DEVICE_NAMES.iter()
+	    .find(|(device, _)| *device == name.to_ascii_lowercase())
+	    .map(|(_, device)| *device)
+	    .ok_or(format!("unknown device {}, expected one of {}", name,
+			   DEVICE_NAMES.map(|(device, _)| device).join(", ")))
+    }
+
+    /// The two ways of plugging in four joypads, by their --multitap names.
+    pub fn multitap_from_name(name: &str) -> Result<Self, String> {
+	match name.to_ascii_lowercase().as_str() {
+	    "fourscore" => Ok(Device::FourScore),
+	    "famicom" => Ok(Device::FamicomFourPlayer),
+	    _ => Err(format!("unknown multitap {}, expected fourscore or famicom", name)),
+	}
+    }
+
+    /// The default expansion device of an NES 2.0 header, byte 15. None
+    /// for the standard joypads and for devices that aren't emulated.
+    /// https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+    pub fn from_nes2(byte: u8) -> Option<Self> {
+	match byte & 0x3F {
+	    0x02 => Some(Device::FourScore),
+	    0x03 => Some(Device::FamicomFourPlayer),
+	    0x08 => Some(Device::Zapper),
+	    0x0B | 0x0C => Some(Device::PowerPad),	// side A or B up
+	    0x0F => Some(Device::Vaus),
+	    0x10 => Some(Device::FamicomVaus),
+	    0x23 => Some(Device::Keyboard),	// with the data recorder
+	    _ => None,
+	}
+    }
+}
+
+/// Everything on the input side of the bus.
+pub struct	Input {
+    pub ports:	[Box<dyn ControllerPort>; 2],
+    pub expansion:	Option<Box<dyn ExpansionDevice>>,
+}
+
+impl Default for Input {
+    fn default() -> Self {
+	Input {
+	    ports: [Box::new(Joypad::default()), Box::new(Joypad::default())],
+	    expansion: None,
+	}
+    }
+}
+
+impl	Input {
+    /// Plugs `device` in where it goes: port 2 for the single-port ones,
+    /// the expansion port for the Famicom ones.
+    pub fn connect(&mut self, device: Device) {
+	match device {
+	    Device::FourScore => self.ports = [Box::new(FourScore::new(0)), Box::new(FourScore::new(1))],
+	    Device::FamicomFourPlayer => self.expansion = Some(Box::new(FamicomFourPlayer::default())),
+	    Device::Zapper => self.ports[1] = Box::new(Zapper::default()),
+	    Device::PowerPad => self.ports[1] = Box::new(PowerPad::default()),
+	    Device::Vaus => self.ports[1] = Box::new(Vaus::default()),
+	    Device::FamicomVaus => self.expansion = Some(Box::new(Vaus::default())),
+	    Device::Keyboard => self.expansion = Some(Box::new(Keyboard::default())),
+	}
+    }
+
+    pub fn write(&mut self, value: u8) {
+	for port in &mut self.ports {
+	    port.write(value);
+	}
+	if let Some(expansion) = &mut self.expansion {
+	    expansion.write(value);
+	}
+    }
+
+    /// The driven bits of a read from `port`, 0 for $4016 and 1 for $4017.
+    pub fn read(&mut self, port: usize, ppu: &Ppu) -> u8 {
+	let mut value = self.ports[port].read(ppu) & PORT_MASK;
+	if let Some(expansion) = &mut self.expansion {
+	    value |= expansion.read(port, ppu) & PORT_MASK & !0x01;
+	}
+	value
+    }
+
+    /// Players are numbered from 0 in the order games count them: the
+    /// first joypad behind each port, then the second (a Four Score's),
+    /// then the expansion port's.
+    pub fn joypad(&mut self, player: usize) -> Option<&mut Joypad> {
+	let mut slots = Vec::new();
+	for index in 0..2 {
+	    for port in 0..2 {
+		if self.ports[port].joypad(index).is_some() {
+		    slots.push((Some(port), index));
+		}
+	    }
+	}
+	for index in 0..2 {
+	    if self.expansion.as_mut().is_some_and(|expansion| expansion.joypad(index).is_some()) {
+		slots.push((None, index));
+	    }
+	}
+	match slots.get(player) {
+	    Some((Some(port), index)) => self.ports[*port].joypad(*index),
+	    Some((None, index)) => self.expansion.as_mut().and_then(|expansion| expansion.joypad(*index)),
+	    None => None,
+	}
+    }
+
+    /// The Vaus, in port 2 or the expansion port.
+    pub fn vaus(&mut self) -> Option<&mut Vaus> {
+	match self.ports[1].vaus() {
+	    Some(vaus) => Some(vaus),
+	    None => self.expansion.as_mut().and_then(|expansion| expansion.vaus()),
+	}
+    }
+}
+
+// Port 2 bits of the Zapper
+const ZAPPER_NO_LIGHT: u8 = 0x01 << 3;
+const ZAPPER_TRIGGER: u8 = 0x01 << 4;
+
+// The photodiode sees a few pixels around where it's aimed, and stays lit
+// for a while after the beam has passed.
+const ZAPPER_RADIUS: usize = 3;
+const ZAPPER_LIGHT_SCANLINES: usize = 20;
+// average of the RGB channels it takes to trip the sensor
+const ZAPPER_BRIGHTNESS: u32 = 0x55;
+
+/// The NES Zapper light gun, in port 2. Games flash the target white for
+/// a frame and look for light while the beam goes past it.
+/// https://www.nesdev.org/wiki/Zapper
+#[derive(Default)]
+pub struct	Zapper {
+    pub aim:	Option<(usize, usize)>,	// None points off the screen
+    pub trigger:	bool,
+    palette:	Palette,
+}
+
+impl	Zapper {
+    /// Whether a pixel near the aim is bright and has been drawn in the
+    /// last few scanlines, with the beam at `scanline`, `dot`.
+    pub fn light_sensed(&self, framebuffer: &[u16], scanline: u16, dot: u16) -> bool {
+	let Some((x, y)) = self.aim else {
+	    return false;
+	};
+	if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+	    return false;
+	}
+	// the PPU draws each line whole at dot 256
+	let scanline = scanline as usize;
+	let drawn = scanline > y || (scanline == y && dot > 256);
+	if !drawn || scanline - y > ZAPPER_LIGHT_SCANLINES {
+	    return false;
+	}
+	let last_drawn = if dot > 256 { scanline } else { scanline - 1 }.min(SCREEN_HEIGHT - 1);
+	let rows = y.saturating_sub(ZAPPER_RADIUS)..=(y + ZAPPER_RADIUS).min(last_drawn);
+	let columns = x.saturating_sub(ZAPPER_RADIUS)..=(x + ZAPPER_RADIUS).min(SCREEN_WIDTH - 1);
+	rows.flat_map(|row| columns.clone().map(move |column| row * SCREEN_WIDTH + column))
+	    .any(|i| {
+		let [r, g, b] = self.palette.rgb(framebuffer[i]);
+		(r as u32 + g as u32 + b as u32) / 3 >= ZAPPER_BRIGHTNESS
+	    })
+    }
+
+}
+
+// the strobe doesn't concern it
+impl ControllerPort for Zapper {
+    fn write(&mut self, _value: u8) {}
+
+    fn read(&mut self, ppu: &Ppu) -> u8 {
+	let (scanline, dot) = ppu.position();
+	let mut value = 0;
+	if !self.light_sensed(ppu.framebuffer(), scanline, dot) {
+	    value |= ZAPPER_NO_LIGHT;
+	}
+	if self.trigger {
+	    value |= ZAPPER_TRIGGER;
+	}
+	value
+    }
+
+    fn zapper(&mut self) -> Option<&mut Zapper> {
+	Some(self)
+    }
+}
+
+// Power Pad buttons, numbered as on side B of the mat:
+//
+//     1  2  3  4
+//     5  6  7  8
+//     9 10 11 12
+//
+// In the order they're read out, on bit 3 and on bit 4 of port 2. Bit 4
+// has 1s after its four.
+const POWER_PAD_BIT_3: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const POWER_PAD_BIT_4: [u8; 4] = [4, 3, 12, 8];
+
+/// Power Pad buttons by number, joined with '+': "1+5+9".
+pub fn parse_pad_buttons(numbers: &str) -> Result<u16, String> {
+    let mut buttons = 0;
+    for number in numbers.split('+') {
+	match number.trim().parse::<u16>() {
+	    Ok(n @ 1..=12) => buttons |= 1 << (n - 1),
+	    _ => return Err(format!("unknown Power Pad button {}, expected 1 to 12", number)),
+	}
+    }
+    Ok(buttons)
+}
+
+/// Bandai's Power Pad (the Family Trainer in Japan) in port 2: twelve
+/// buttons on a mat, read out through two shift registers at once.
+/// https://www.nesdev.org/wiki/Power_Pad
+#[derive(Default)]
+pub struct	PowerPad {
+    pub buttons:	u16,	// bit n is button n + 1
+    strobe:	bool,
+    shift:	[u8; 2],	// for bits 3 and 4
+}
+
+impl	PowerPad {
+    fn latch(&mut self) {
+	let pressed = |button: &u8| self.buttons & (1 << (button - 1)) != 0;
+	self.shift = [
+	    POWER_PAD_BIT_3.iter().rev().fold(0, |shift, button| shift << 1 | pressed(button) as u8),
+	    POWER_PAD_BIT_4.iter().rev().fold(0x0F, |shift, button| shift << 1 | pressed(button) as u8),
+	];
+    }
+}
+
+impl ControllerPort for PowerPad {
+    fn write(&mut self, value: u8) {
+	self.strobe = value & 0x01 != 0;
+	if self.strobe {
+	    self.latch();
+	}
+    }
+
+    fn read(&mut self, _ppu: &Ppu) -> u8 {
+	if self.strobe {
+	    self.latch();
+	}
+	let value = (self.shift[0] & 0x01) << 3 | (self.shift[1] & 0x01) << 4;
+	if !self.strobe {
+	    self.shift = self.shift.map(|shift| shift >> 1 | 0x80);
+	}
+	value
+    }
+
+    fn power_pad(&mut self) -> Option<&mut PowerPad> {
+	Some(self)
+    }
+}
+
+// The knob's potentiometer goes through an 8-bit converter, read out
+// most significant bit first and inverted. Arkanoid expects readings of
+// about 98 to 242 across the paddle's travel.
+const VAUS_BUTTON_NES: u8 = 0x01 << 3;
+const VAUS_DATA_NES: u8 = 0x01 << 4;
+const VAUS_FAMICOM: u8 = 0x01 << 1;
+
+/// Taito's Arkanoid controller, the Vaus: a knob and a button. The NES
+/// one goes in port 2, the Famicom one in the expansion port, where it
+/// reads the button on $4016 and the knob on $4017.
+/// https://www.nesdev.org/wiki/Arkanoid_controller
+#[derive(Default)]
+pub struct	Vaus {
+    pub position:	u8,
+    pub button:	bool,
+    shift:	u8,
+}
+
+impl	Vaus {
+    fn strobe(&mut self, value: u8) {
+	if value & 0x01 != 0 {
+	    self.shift = !self.position;
+	}
+    }
+
+    fn next_bit(&mut self) -> u8 {
+	let bit = self.shift >> 7;
+	self.shift <<= 1;
+	bit
+    }
+}
+
+impl ControllerPort for Vaus {
+    fn write(&mut self, value: u8) {
+	self.strobe(value);
+    }
+
+    fn read(&mut self, _ppu: &Ppu) -> u8 {
+	let mut value = if self.next_bit() != 0 { VAUS_DATA_NES } else { 0 };
+	if self.button {
+	    value |= VAUS_BUTTON_NES;
+	}
+	value
+    }
+
+    fn vaus(&mut self) -> Option<&mut Vaus> {
+	Some(self)
+    }
+}
+
+impl ExpansionDevice for Vaus {
+    fn write(&mut self, value: u8) {
+	self.strobe(value);
+    }
+
+    fn read(&mut self, port: usize, _ppu: &Ppu) -> u8 {
+	let bit = if port == 0 { self.button as u8 } else { self.next_bit() };
+	if bit != 0 { VAUS_FAMICOM } else { 0 }
+    }
+
+    fn vaus(&mut self) -> Option<&mut Vaus> {
+	Some(self)
+    }
+}
+
+// The Family BASIC keyboard's matrix: 9 rows of two 4-key columns,
+// read on bits 1-4 of $4017. Key n of row r, column c is bit
+// r * 8 + c * 4 + n of a key set.
+const KEYBOARD_ROWS: usize = 9;
+const KEY_NAMES: [[&str; 8]; KEYBOARD_ROWS] = [
+    ["f8", "return", "[", "]", "kana", "rshift", "yen", "stop"],
+    ["f7", "@", ":", ";", "_", "/", "-", "^"],
+    ["f6", "o", "l", "k", ".", ",", "p", "0"],
+    ["f5", "i", "u", "j", "m", "n", "9", "8"],
+    ["f4", "y", "g", "h", "b", "v", "7", "6"],
+    ["f3", "t", "r", "d", "f", "c", "5", "4"],
+    ["f2", "w", "s", "a", "x", "z", "e", "3"],
+    ["f1", "esc", "q", "ctr", "lshift", "grph", "1", "2"],
+    ["clr", "up", "right", "left", "down", "space", "del", "ins"],
+];
+
+/// Keyboard keys by name, joined with '+': "lshift+a", "return".
+pub fn parse_keys(names: &str) -> Result<u128, String> {
+    let mut keys = 0;
+    for name in names.split('+') {
+	let name = name.trim().to_ascii_lowercase();
+	let key = KEY_NAMES.iter().flatten().position(|key| *key == name)
+	    .ok_or(format!("unknown key {}, expected a Family BASIC key name like a, return or lshift", name))?;
+	keys |= 1 << key;
+    }
+    Ok(keys)
+}
+
+// $4016 write bits
+const KEYBOARD_RESET: u8 = 0x01;
+const KEYBOARD_COLUMN: u8 = 0x01 << 1;
+const KEYBOARD_ENABLE: u8 = 0x01 << 2;
+
+/// The Family BASIC keyboard in the expansion port. Games reset it to
+/// row 0, then select column 0 and 1 of each row in turn; going back
+/// to column 0 moves to the next row. Pressed keys read as 0.
+/// https://www.nesdev.org/wiki/Family_BASIC_Keyboard
+#[derive(Default)]
+pub struct	Keyboard {
+    pub keys:	u128,
+    row:	usize,
+    column:	usize,
+    enabled:	bool,
+}
+
+impl ExpansionDevice for Keyboard {
+    fn write(&mut self, value: u8) {
+	self.enabled = value & KEYBOARD_ENABLE != 0;
+	if !self.enabled {
+	    return;
+	}
+	let column = (value & KEYBOARD_COLUMN != 0) as usize;
+	if value & KEYBOARD_RESET != 0 {
+	    self.row = 0;
+	} else if self.column == 1 && column == 0 {
+	    self.row = (self.row + 1) % (KEYBOARD_ROWS + 1);
+	}
+	self.column = column;
+    }
+
+    // $4016 is the data recorder's, which isn't there
+    fn read(&mut self, port: usize, _ppu: &Ppu) -> u8 {
+	if port == 0 || !self.enabled {
+	    return 0;
+	}
+	if self.row == KEYBOARD_ROWS {
+	    return 0x1E;
+	}
+	let keys = (self.keys >> (self.row * 8 + self.column * 4)) as u8 & 0x0F;
+	!keys << 1 & 0x1E
+    }
+
+    fn keyboard(&mut self) -> Option<&mut Keyboard> {
+	Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_bits(port: &mut dyn ControllerPort, count: usize) -> Vec<u8> {
+	let ppu = Ppu::new();
+	(0..count).map(|_| port.read(&ppu)).collect()
+    }
+
+    #[test]
+    fn test_shift_out() {
+	let mut joypad = Joypad::default();
+	joypad.set_buttons(BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+	joypad.write(1);
+	assert_eq!(read_bits(&mut joypad, 2), vec![1, 1]);
+	joypad.write(0);
+	assert_eq!(read_bits(&mut joypad, 10), vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_buttons_change_after_latch() {
+	let mut joypad = Joypad::default();
+	joypad.write(1);
+	joypad.write(0);
+	joypad.set_buttons(BUTTON_A);
+	assert_eq!(read_bits(&mut joypad, 1), vec![0]);
+	joypad.write(1);
+	joypad.write(0);
+	assert_eq!(read_bits(&mut joypad, 1), vec![1]);
+    }
+
+    #[test]
+    fn test_four_score() {
+	let mut input = Input::default();
+	input.connect(Device::FourScore);
+	for (player, buttons) in [BUTTON_A, BUTTON_B, BUTTON_SELECT, BUTTON_START].into_iter().enumerate() {
+	    input.joypad(player).unwrap().set_buttons(buttons);
+	}
+	assert!(input.joypad(4).is_none());
+	input.write(1);
+	input.write(0);
+	let ppu = Ppu::new();
+	let port1: Vec<u8> = (0..26).map(|_| input.read(0, &ppu)).collect();
+	let port2: Vec<u8> = (0..26).map(|_| input.read(1, &ppu)).collect();
+	// player 1, player 3, signature, then 1s
+	assert_eq!(port1, vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 1]);
+	// player 2, player 4, signature
+	assert_eq!(port2, vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_famicom_four_player() {
+	let mut input = Input::default();
+	input.connect(Device::multitap_from_name("Famicom").unwrap());
+	input.joypad(0).unwrap().set_buttons(BUTTON_A);
+	input.joypad(2).unwrap().set_buttons(BUTTON_A);
+	input.joypad(3).unwrap().set_buttons(BUTTON_B);
+	input.write(1);
+	input.write(0);
+	let ppu = Ppu::new();
+	assert_eq!(input.read(0, &ppu), 0x03);
+	assert_eq!(input.read(1, &ppu), 0x00);
+	assert_eq!(input.read(1, &ppu), 0x02);
+    }
+
+    #[test]
+    fn test_parse_buttons() {
+	assert_eq!(parse_buttons("start"), Ok(BUTTON_START));
+	assert_eq!(parse_buttons("A+Right+select"), Ok(BUTTON_A | BUTTON_RIGHT | BUTTON_SELECT));
+	assert!(parse_buttons("a+turbo").is_err());
+	assert!(parse_buttons("").is_err());
+    }
+
+    #[test]
+    fn test_zapper_light() {
+	let mut framebuffer = vec![0x0F; SCREEN_WIDTH * SCREEN_HEIGHT];
+	// a white box at 100-115, 50-65
+	for y in 50..66 {
+	    framebuffer[y * SCREEN_WIDTH + 100..y * SCREEN_WIDTH + 116].fill(0x30);
+	}
+	let mut zapper = Zapper::default();
+	assert!(!zapper.light_sensed(&framebuffer, 70, 0));
+
+	zapper.aim = Some((110, 60));
+	assert!(zapper.light_sensed(&framebuffer, 70, 0));
+	// not drawn yet, or too long ago
+	assert!(!zapper.light_sensed(&framebuffer, 55, 0));
+	assert!(zapper.light_sensed(&framebuffer, 60, 257));
+	assert!(!zapper.light_sensed(&framebuffer, 100, 0));
+
+	// just off the box's edge still catches it
+	zapper.aim = Some((118, 60));
+	assert!(zapper.light_sensed(&framebuffer, 70, 0));
+	zapper.aim = Some((130, 60));
+	assert!(!zapper.light_sensed(&framebuffer, 70, 0));
+    }
+
+    #[test]
+    fn test_devices_by_name_and_header() {
+	assert_eq!(Device::from_name("PowerPad"), Ok(Device::PowerPad));
+	assert_eq!(Device::multitap_from_name("fourscore"), Ok(Device::FourScore));
+	assert!(Device::from_name("famicom").is_err());
+	assert_eq!(Device::from_nes2(0x01), None);
+	assert_eq!(Device::from_nes2(0x0C), Some(Device::PowerPad));
+	assert_eq!(Device::from_nes2(0x10), Some(Device::FamicomVaus));
+	assert_eq!(Device::from_nes2(0x23), Some(Device::Keyboard));
+    }
+
+    #[test]
+    fn test_power_pad() {
+	let mut input = Input::default();
+	input.connect(Device::PowerPad);
+	input.ports[1].power_pad().unwrap().buttons = parse_pad_buttons("1+4+11").unwrap();
+	assert!(parse_pad_buttons("13").is_err());
+	input.write(1);
+	input.write(0);
+	let ppu = Ppu::new();
+	let reads: Vec<u8> = (0..9).map(|_| input.read(1, &ppu)).collect();
+	// 1 and 11 on bit 3, 4 on bit 4
+	assert_eq!(reads, vec![0x10, 0x08, 0x00, 0x00, 0x10, 0x10, 0x18, 0x10, 0x18]);
+    }
+
+    #[test]
+    fn test_vaus() {
+	let ppu = Ppu::new();
+	let mut input = Input::default();
+	input.connect(Device::Vaus);
+	let vaus = input.vaus().unwrap();
+	vaus.position = 0xA5;
+	vaus.button = true;
+	input.write(1);
+	input.write(0);
+	let knob: Vec<u8> = (0..8).map(|_| input.read(1, &ppu) >> 4).collect();
+	assert_eq!(knob, vec![0, 1, 0, 1, 1, 0, 1, 0]);
+	assert_eq!(input.read(1, &ppu) & VAUS_BUTTON_NES, VAUS_BUTTON_NES);
+
+	let mut input = Input::default();
+	input.connect(Device::FamicomVaus);
+	input.vaus().unwrap().position = 0x80;
+	input.write(1);
+	input.write(0);
+	assert_eq!(input.read(0, &ppu), 0x00);
+	assert_eq!(input.read(1, &ppu), 0x00);
+	assert_eq!(input.read(1, &ppu), 0x02);
+    }
+
+    #[test]
+    fn test_keyboard() {
+	let ppu = Ppu::new();
+	let mut input = Input::default();
+	input.connect(Device::Keyboard);
+	let keyboard = input.expansion.as_mut().unwrap();
+	keyboard.keyboard().unwrap().keys = parse_keys("return+A+space").unwrap();
+	assert!(parse_keys("any").is_err());
+	// reset, then both columns of every row
+	keyboard.write(0x05);
+	let mut rows = Vec::new();
+	for _ in 0..KEYBOARD_ROWS {
+	    keyboard.write(0x04);
+	    let column0 = keyboard.read(1, &ppu);
+	    keyboard.write(0x06);
+	    rows.push([column0, keyboard.read(1, &ppu)]);
+	}
+	keyboard.write(0x04);
+	assert_eq!(keyboard.read(1, &ppu), 0x1E);
+	assert_eq!(rows[0], [0x1A, 0x1E]);
+	assert_eq!(rows[6], [0x0E, 0x1E]);
+	assert_eq!(rows[8], [0x1E, 0x1A]);
+	assert!(rows[1..6].iter().all(|row| *row == [0x1E, 0x1E]));
+	keyboard.write(0);
+	assert_eq!(keyboard.read(1, &ppu), 0);
+	assert_eq!(keyboard.read(0, &ppu), 0);
+    }
+}
@@ -0,0 +1,543 @@
+use std::io::{self, BufRead, Write};
+
+use crate::Cpu6502;
+use crate::asm;
+use crate::condition::{Condition, Context, Register};
+use crate::disasm;
+
+pub const HELP: &str = "\
+commands:
+  s, step [N]          run N instructions (default 1)
+  n, next              run an instruction, or a JSR through to its RTS
+  u, until ADDR        run until PC is ADDR
+  c, continue          run until a breakpoint or a BRK
+  b, break [ADDR] [if COND]
+		       set a breakpoint on PC, on any PC the condition holds
+		       at, or list them
+  watch MODE ADDR[-END] [if COND]
+		       set a watchpoint on reads (r), writes (w) or executes
+		       (x) in a range, for instance rw, or list them
+  d, delete N          remove breakpoint or watchpoint N
+  r, regs              print the registers
+  set REG VALUE        set a, x, y, sp, p or pc
+  m, mem ADDR [LEN]    hex dump LEN bytes (default 64)
+  w, write ADDR BYTE.. write bytes to memory
+  patch ADDR INS       assemble an instruction, such as lda #$05, into memory,
+		       ROM included
+  l, list [ADDR] [N]   disassemble N instructions (default 8) from ADDR or PC
+  h, help              this text
+  q, quit              leave the debugger
+numbers are decimal, or hex with $ or 0x; an empty line repeats the last command
+conditions compare registers and memory, as in A == $40 && [$0300] > 3";
+
+// what a watchpoint watches for, or'ed together
+pub const READ: u8 = 0x01;
+pub const WRITE: u8 = 0x02;
+pub const EXECUTE: u8 = 0x04;
+const ACCESS_NAMES: [(char, u8, &str); 3] = [('r', READ, "read"), ('w', WRITE, "write"), ('x', EXECUTE, "execute")];
+
+/// Stops on accesses to a range of addresses. Kept on the bus, which
+/// notes down every access that one of them sees.
+pub struct	Watchpoint {
+    id:	usize,
+    pub first:	u16,
+    pub last:	u16,
+    pub access:	u8,
+    condition:	Option<Condition>,
+    hits:	u64,
+}
+
+impl	Watchpoint {
+    /// One with no condition, for the gdb stub.
+    pub fn	new(first: u16, last: u16, access: u8) -> Self {
+	Watchpoint { id: 0, first, last, access, condition: None, hits: 0 }
+    }
+}
+
+/// An access a watchpoint saw, by its index on the bus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct	WatchHit {
+    pub index:	usize,
+    pub access:	u8,
+    pub addr:	u16,
+    pub value:	u8,
+}
+
+/// Stops at an address, or wherever its condition holds.
+struct	Breakpoint {
+    id:	usize,
+    addr:	Option<u16>,
+    condition:	Option<Condition>,
+    hits:	u64,
+}
+
+impl Context for Cpu6502 {
+    fn register(&self, register: Register) -> u16 {
+	match register {
+	    Register::A => self.regs.a as u16,
+	    Register::X => self.regs.x as u16,
+	    Register::Y => self.regs.y as u16,
+	    Register::Sp => self.regs.sp as u16,
+	    Register::P => self.regs.p as u16,
+	    Register::Pc => self.regs.pc,
+	}
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+	self.bus.peek(addr)
+    }
+}
+
+// "rw" -> READ | WRITE
+fn access(s: &str) -> Result<u8, String> {
+    s.chars().try_fold(0, |access, c| match ACCESS_NAMES.iter().find(|(name, _, _)| *name == c) {
+	Some((_, flag, _)) => Ok(access | flag),
+	None => Err(format!("'{}' is not a watchpoint mode, expected r, w and x", s)),
+    })
+}
+
+fn access_name(access: u8) -> String {
+    ACCESS_NAMES.iter().filter(|(_, flag, _)| access & flag != 0).map(|(name, _, _)| *name).collect()
+}
+
+// $0300 or $0300-$03FF
+fn range(s: &str) -> Result<(u16, u16), String> {
+    let (first, last) = match s.split_once('-') {
+	Some((first, last)) => (number(first)?, number(last)?),
+	None => (number(s)?, number(s)?),
+    };
+    if last < first {
+	return Err(format!("{} ends before it starts", s));
+    }
+    Ok((first, last))
+}
+
+fn condition_text(condition: &Option<Condition>) -> String {
+    condition.as_ref().map_or(String::new(), |condition| format!(" if {}", condition))
+}
+
+// "$C000", "0xc000" or "49152"
+fn number(s: &str) -> Result<u16, String> {
+    let parsed = match s.strip_prefix('$').or(s.strip_prefix("0x")) {
+	Some(hex) => u16::from_str_radix(hex, 16),
+	None => s.parse::<u16>(),
+    };
+    parsed.map_err(|_| format!("'{}' is not a number", s))
+}
+
+fn byte(s: &str) -> Result<u8, String> {
+    u8::try_from(number(s)?).map_err(|_| format!("'{}' doesn't fit in a byte", s))
+}
+
+// One line of disassembly and the address of the next instruction.
+fn disassemble(cpu: &Cpu6502, addr: u16) -> (String, u16) {
+    let bytes: Vec<u8> = (0..3).map(|i| cpu.bus.peek(addr.wrapping_add(i))).collect();
+    let instruction = disasm::decode(&bytes, addr);
+    (instruction.to_string(), instruction.next())
+}
+
+fn flags(p: u8) -> String {
+    "NV-BDIZC".chars().enumerate()
+	.map(|(i, flag)| if p & (0x80 >> i) != 0 { flag } else { flag.to_ascii_lowercase() })
+	.collect()
+}
+
+fn registers(cpu: &Cpu6502) -> String {
+    let regs = &cpu.regs;
+    format!("PC=${:04X} A=${:02X} X=${:02X} Y=${:02X} SP=${:02X} P=${:02X} {} CYC={}",
+	    regs.pc, regs.a, regs.x, regs.y, regs.sp, regs.p, flags(regs.p), cpu.cycles)
+}
+
+/// Why running stopped.
+#[derive(Debug, PartialEq)]
+enum	Stop {
+    Done,
+    Breakpoint(usize),	// index
+    Watchpoint(WatchHit),
+    Brk,
+}
+
+/// The debugger's state between commands; the watchpoints are on the bus.
+#[derive(Default)]
+pub struct	Debugger {
+    breakpoints:	Vec<Breakpoint>,
+    next_id:	usize,	// breakpoints and watchpoints count up together
+    last:	String,	// the command an empty line repeats
+}
+
+impl	Debugger {
+    // Runs one instruction. The accesses the watchpoints saw come from
+    // this instruction only.
+    fn step(&self, cpu: &mut Cpu6502) -> Result<Stop, String> {
+	cpu.bus.watch_hits.clear();
+	Ok(if cpu.step()? == 0 { Stop::Brk } else { Stop::Done })
+    }
+
+    // Counts the hits of the watchpoints that saw the last instruction's
+    // accesses and whose conditions hold after it, returning the first.
+    fn watch_hit(cpu: &mut Cpu6502) -> Option<WatchHit> {
+	let mut first = None;
+	for hit in std::mem::take(&mut cpu.bus.watch_hits) {
+	    if cpu.bus.watchpoints[hit.index].condition.as_ref().is_none_or(|condition| condition.holds(cpu)) {
+		cpu.bus.watchpoints[hit.index].hits += 1;
+		first.get_or_insert(hit);
+	    }
+	}
+	first
+    }
+
+    // The same for the breakpoints at PC.
+    fn breakpoint_hit(&mut self, cpu: &Cpu6502) -> Option<usize> {
+	let mut first = None;
+	for (i, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+	    if breakpoint.addr.is_none_or(|addr| addr == cpu.regs.pc)
+		&& breakpoint.condition.as_ref().is_none_or(|condition| condition.holds(cpu)) {
+		breakpoint.hits += 1;
+		first.get_or_insert(i);
+	    }
+	}
+	first
+    }
+
+    // Runs until `done` says so, a breakpoint, a watchpoint or a BRK. The
+    // instruction at PC runs even if it has a breakpoint, so running goes
+    // on from one.
+    fn run_until(&mut self, cpu: &mut Cpu6502, done: impl Fn(&Cpu6502) -> bool) -> Result<Stop, String> {
+	loop {
+	    if self.step(cpu)? == Stop::Brk {
+		return Ok(Stop::Brk);
+	    }
+	    if let Some(hit) = Self::watch_hit(cpu) {
+		return Ok(Stop::Watchpoint(hit));
+	    }
+	    if done(cpu) {
+		return Ok(Stop::Done);
+	    }
+	    if let Some(i) = self.breakpoint_hit(cpu) {
+		return Ok(Stop::Breakpoint(i));
+	    }
+	}
+    }
+
+    fn stopped(&self, cpu: &Cpu6502, stop: Stop, out: &mut impl Write) -> io::Result<()> {
+	match stop {
+	    Stop::Done => {}
+	    Stop::Breakpoint(i) => {
+		let breakpoint = &self.breakpoints[i];
+		writeln!(out, "breakpoint {} at ${:04X} (hits: {})", breakpoint.id, cpu.regs.pc, breakpoint.hits)?;
+	    }
+	    Stop::Watchpoint(hit) => {
+		let watchpoint = &cpu.bus.watchpoints[hit.index];
+		let (_, _, access) = ACCESS_NAMES.iter().find(|(_, flag, _)| *flag == hit.access).unwrap();
+		writeln!(out, "watchpoint {}: {} of ${:02X} at ${:04X} (hits: {})",
+			 watchpoint.id, access, hit.value, hit.addr, watchpoint.hits)?;
+	    }
+	    Stop::Brk => writeln!(out, "BRK at ${:04X}", cpu.regs.pc.wrapping_sub(1))?,
+	}
+	writeln!(out, "{}", disassemble(cpu, cpu.regs.pc).0)
+    }
+
+    /// Carries out one command line. Ok(false) when it's time to quit.
+    pub fn command(&mut self, cpu: &mut Cpu6502, line: &str, out: &mut impl Write) -> Result<bool, String> {
+	let line = if line.trim().is_empty() { self.last.clone() } else { line.trim().to_string() };
+	self.last = line.clone();
+	let mut words: Vec<&str> = line.split_whitespace().collect();
+	// everything after "if" is a condition
+	let condition = match words.iter().position(|word| *word == "if") {
+	    Some(i) => {
+		let condition = Condition::parse(&words[i + 1..].join(" "))?;
+		words.truncate(i);
+		Some(condition)
+	    }
+	    None => None,
+	};
+	let Some((&name, args)) = words.split_first() else { return Ok(true) };
+	if condition.is_some() && !matches!(name, "b" | "break" | "watch") {
+	    return Err(format!("{} doesn't take a condition", name));
+	}
+	let arg = |i: usize| args.get(i).copied().ok_or(format!("{} needs more arguments, see help", name));
+	let io = |e: io::Error| e.to_string();
+
+	match name {
+	    "s" | "step" => {
+		let count = match args.first() { Some(n) => number(n)?, None => 1 };
+		let mut stop = Stop::Done;
+		for _ in 0..count {
+		    stop = self.step(cpu)?;
+		    if stop == Stop::Brk {
+			break;
+		    }
+		}
+		self.stopped(cpu, stop, out).map_err(io)?;
+	    }
+	    "n" | "next" => {
+		let stop = if cpu.bus.peek(cpu.regs.pc) == 0x20 {
+		    // the RTS back from this JSR, not one from deeper down
+		    let (ret, sp) = (cpu.regs.pc.wrapping_add(3), cpu.regs.sp);
+		    self.run_until(cpu, |cpu| cpu.regs.pc == ret && cpu.regs.sp == sp)?
+		}
+		else {
+		    self.step(cpu)?
+		};
+		self.stopped(cpu, stop, out).map_err(io)?;
+	    }
+	    "u" | "until" => {
+		let addr = number(arg(0)?)?;
+		let stop = self.run_until(cpu, |cpu| cpu.regs.pc == addr)?;
+		self.stopped(cpu, stop, out).map_err(io)?;
+	    }
+	    "c" | "continue" => {
+		let stop = self.run_until(cpu, |_| false)?;
+		self.stopped(cpu, stop, out).map_err(io)?;
+	    }
+	    "b" | "break" if args.is_empty() && condition.is_none() => {
+		for breakpoint in &self.breakpoints {
+		    let addr = breakpoint.addr.map_or("anywhere".to_string(), |addr| format!("${:04X}", addr));
+		    writeln!(out, "{}: {}{} (hits: {})",
+			     breakpoint.id, addr, condition_text(&breakpoint.condition), breakpoint.hits).map_err(io)?;
+		}
+	    }
+	    "b" | "break" => {
+		let addr = args.first().map(|addr| number(addr)).transpose()?;
+		self.next_id += 1;
+		self.breakpoints.push(Breakpoint { id: self.next_id, addr, condition, hits: 0 });
+		writeln!(out, "breakpoint {}", self.next_id).map_err(io)?;
+	    }
+	    "watch" if args.is_empty() => {
+		for watchpoint in &cpu.bus.watchpoints {
+		    writeln!(out, "{}: {} ${:04X}-${:04X}{} (hits: {})",
+			     watchpoint.id, access_name(watchpoint.access), watchpoint.first, watchpoint.last,
+			     condition_text(&watchpoint.condition), watchpoint.hits).map_err(io)?;
+		}
+	    }
+	    "watch" => {
+		let access = access(arg(0)?)?;
+		let (first, last) = range(arg(1)?)?;
+		self.next_id += 1;
+		cpu.bus.watchpoints.push(Watchpoint { id: self.next_id, first, last, access, condition, hits: 0 });
+		writeln!(out, "watchpoint {}", self.next_id).map_err(io)?;
+	    }
+	    "d" | "delete" => {
+		let id = number(arg(0)?)? as usize;
+		let count = self.breakpoints.len() + cpu.bus.watchpoints.len();
+		self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+		cpu.bus.watchpoints.retain(|watchpoint| watchpoint.id != id);
+		if self.breakpoints.len() + cpu.bus.watchpoints.len() == count {
+		    return Err(format!("no breakpoint or watchpoint {}", id));
+		}
+	    }
+	    "r" | "regs" => writeln!(out, "{}", registers(cpu)).map_err(io)?,
+	    "set" => {
+		let value = number(arg(1)?)?;
+		let regs = &mut cpu.regs;
+		match arg(0)?.to_ascii_lowercase().as_str() {
+		    "pc" => regs.pc = value,
+		    reg => {
+			let value = byte(arg(1)?)?;
+			match reg {
+			    "a" => regs.a = value,
+			    "x" => regs.x = value,
+			    "y" => regs.y = value,
+			    "sp" => regs.sp = value,
+			    "p" => regs.p = value,
+			    _ => return Err(format!("unknown register {}", reg)),
+			}
+		    }
+		}
+		writeln!(out, "{}", registers(cpu)).map_err(io)?;
+	    }
+	    "m" | "mem" => {
+		let addr = number(arg(0)?)?;
+		let len = match args.get(1) { Some(n) => number(n)?, None => 64 };
+		for row in (0..len).step_by(16) {
+		    let start = addr.wrapping_add(row);
+		    let bytes: Vec<u8> = (0..16.min(len - row)).map(|i| cpu.bus.peek(start.wrapping_add(i))).collect();
+		    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+		    let text: String = bytes.iter().map(|&b| if b.is_ascii_graphic() { b as char } else { '.' }).collect();
+		    writeln!(out, "${:04X}  {:<47}  {}", start, hex.join(" "), text).map_err(io)?;
+		}
+	    }
+	    "w" | "write" => {
+		let addr = number(arg(0)?)?;
+		let bytes = args[1..].iter().map(|b| byte(b)).collect::<Result<Vec<u8>, String>>()?;
+		if bytes.is_empty() {
+		    return Err(format!("{} needs bytes to write", name));
+		}
+		for (i, value) in bytes.into_iter().enumerate() {
+		    cpu.bus.write(addr.wrapping_add(i as u16), value);
+		}
+	    }
+	    "patch" => {
+		let addr = number(arg(0)?)?;
+		let source = args[1..].join(" ");
+		if source.is_empty() {
+		    return Err("patch needs an instruction".to_string());
+		}
+		for segment in asm::assemble(&source, addr)? {
+		    for (i, value) in segment.bytes.iter().enumerate() {
+			cpu.bus.poke(segment.addr.wrapping_add(i as u16), *value);
+		    }
+		    writeln!(out, "{}", disassemble(cpu, segment.addr).0).map_err(io)?;
+		}
+	    }
+	    "l" | "list" => {
+		let mut addr = match args.first() { Some(addr) => number(addr)?, None => cpu.regs.pc };
+		let count = match args.get(1) { Some(n) => number(n)?, None => 8 };
+		for _ in 0..count {
+		    let (text, next) = disassemble(cpu, addr);
+		    let marker = if addr == cpu.regs.pc { '>' } else if self.breakpoints.iter().any(|breakpoint| breakpoint.addr == Some(addr)) { '*' } else { ' ' };
+		    writeln!(out, "{} {}", marker, text).map_err(io)?;
+		    addr = next;
+		}
+	    }
+	    "h" | "help" => writeln!(out, "{}", HELP).map_err(io)?,
+	    "q" | "quit" => return Ok(false),
+	    _ => return Err(format!("unknown command {}, see help", name)),
+	}
+	Ok(true)
+    }
+}
+
+/// Reads commands from `input` until it ends or one says to quit,
+/// writing what they print and any errors to `out`.
+pub fn run(cpu: &mut Cpu6502, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+    let mut debugger = Debugger::default();
+    writeln!(out, "{}", registers(cpu))?;
+    writeln!(out, "{}", disassemble(cpu, cpu.regs.pc).0)?;
+    write!(out, "(debug) ")?;
+    out.flush()?;
+    for line in input.lines() {
+	match debugger.command(cpu, &line?, &mut out) {
+	    Ok(true) => {}
+	    Ok(false) => return Ok(()),
+	    Err(e) => writeln!(out, "error: {}", e)?,
+	}
+	write!(out, "(debug) ")?;
+	out.flush()?;
+    }
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds `commands` to the debugger on `program` loaded at $8000 and
+    // returns what it printed.
+    fn session(program: &[u8], commands: &str) -> (Cpu6502, String) {
+	let mut cpu = Cpu6502::new();
+	cpu.trace = false;
+	cpu.load(program);
+	cpu.regs.sp = 0xFD;
+	let mut out = Vec::new();
+	run(&mut cpu, commands.as_bytes(), &mut out).unwrap();
+	(cpu, String::from_utf8(out).unwrap())
+    }
+
+    const PROGRAM: [u8; 12] = [
+	0x20, 0x08, 0x80,       // jsr $8008
+	0xa2, 0x05,             // ldx #$05
+	0x00,                   // brk
+	0xea, 0xea,
+	0xa9, 0x2a,             // lda #$2a
+	0x60,                   // rts
+	0xff,
+    ];
+
+    #[test]
+    fn test_disassemble() {
+	let mut cpu = Cpu6502::new();
+	cpu.load(&PROGRAM);
+	assert_eq!(disassemble(&cpu, 0x8000), ("$8000  20 08 80  jsr $8008".to_string(), 0x8003));
+	assert_eq!(disassemble(&cpu, 0x8008).0, "$8008  A9 2A     lda #$2A");
+	assert_eq!(disassemble(&cpu, 0x800B), ("$800B  FF        .byte $FF".to_string(), 0x800C));
+	assert_eq!(flags(0x81), "Nv-bdizC");
+    }
+
+    #[test]
+    fn test_step_and_next() {
+	let (cpu, out) = session(&PROGRAM, "step\nregs\n\nstep 3\n");
+	assert!(out.contains("$8008  A9 2A     lda #$2A"), "{}", out);
+	// the empty line repeated regs
+	assert_eq!(out.matches("PC=$8008 A=$00").count(), 2, "{}", out);
+	assert_eq!((cpu.regs.pc, cpu.regs.a, cpu.regs.x), (0x8005, 0x2a, 0x05));
+
+	let (cpu, _) = session(&PROGRAM, "next\n");
+	assert_eq!((cpu.regs.pc, cpu.regs.a), (0x8003, 0x2a));
+    }
+
+    #[test]
+    fn test_breakpoints() {
+	let (cpu, out) = session(&PROGRAM, "b $800A\nbreak\ncontinue\nc\n");
+	assert!(out.contains("(debug) 1: $800A (hits: 0)\n"), "{}", out);
+	assert!(out.contains("breakpoint 1 at $800A (hits: 1)"), "{}", out);
+	assert!(out.contains("BRK at $8005"), "{}", out);
+	assert_eq!(cpu.regs.x, 0x05);
+
+	let (cpu, out) = session(&PROGRAM, "until 0x8003\nd 3\nq\nregs\n");
+	assert_eq!(cpu.regs.pc, 0x8003);
+	assert!(out.contains("error: no breakpoint or watchpoint 3"), "{}", out);
+	assert!(!out.contains("PC=$8003"), "{}", out);
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+	// the breakpoint at $800A only counts once A is $2A
+	let (cpu, out) = session(&PROGRAM, "b $8008 if a == $2A\nb if x == 5 && [$1FC] == $02\nc\nb\nregs\n");
+	assert!(out.contains("breakpoint 2 at $8005 (hits: 1)"), "{}", out);
+	assert!(out.contains("1: $8008 if a == $2A (hits: 0)\n2: anywhere if x == 5 && [$1FC] == $02 (hits: 1)"), "{}", out);
+	assert_eq!(cpu.regs.pc, 0x8005);
+
+	let (_, out) = session(&PROGRAM, "b $8008 if a ==\nstep if a\nb 1 2\n");
+	assert!(out.contains("error: the condition ends too soon"), "{}", out);
+	assert!(out.contains("error: step doesn't take a condition"), "{}", out);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+	// sta $0300 ; inc $0300 ; lda $0300,x
+	let program = [0x8d, 0x00, 0x03, 0xee, 0x00, 0x03, 0xbd, 0x00, 0x03, 0x00];
+	let (cpu, out) = session(&program, "set a 7\nwatch w $0300-$03FF if [$0300] > 7\nwatch r $0300\nwatch x $8006\nc\nc\nc\nwatch\n");
+	// the write of sta doesn't pass the condition, inc's read is the
+	// first access that counts
+	assert!(out.contains("watchpoint 2: read of $07 at $0300 (hits: 1)\n$8006"), "{}", out);
+	assert!(out.contains("watchpoint 3: execute of $BD at $8006 (hits: 1)"), "{}", out);
+	assert!(out.contains("BRK at $8009"), "{}", out);
+	assert!(out.contains("1: w $0300-$03FF if [$0300] > 7 (hits: 1)\n2: r $0300-$0300 (hits: 2)\n3: x $8006-$8006 (hits: 1)"), "{}", out);
+	assert_eq!(cpu.regs.pc, 0x800A);
+
+	let (_, out) = session(&program, "watch q $0300\nwatch r $0300-$02FF\n");
+	assert!(out.contains("error: 'q' is not a watchpoint mode"), "{}", out);
+	assert!(out.contains("error: $0300-$02FF ends before it starts"), "{}", out);
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+	let (cpu, out) = session(&PROGRAM, "set a $40\nset PC $8008\nset x 300\nset q 1\nw $10 1 2 $41\nm $10 4\nl $800A 2\n");
+	assert_eq!((cpu.regs.a, cpu.regs.pc), (0x40, 0x8008));
+	assert!(out.contains("error: '300' doesn't fit in a byte"), "{}", out);
+	assert!(out.contains("error: unknown register q"), "{}", out);
+	assert_eq!(cpu.bus.vram[0x10..0x13], [1, 2, 0x41]);
+	assert!(out.contains("$0010  01 02 41 00"), "{}", out);
+	assert!(out.contains("  $800A  60        rts\n  $800B  FF        .byte $FF\n"), "{}", out);
+
+	let (cpu, out) = session(&PROGRAM, "set pc $800B\nstep\n");
+	assert!(out.contains("error: unimplemented opcode $FF at $800B"), "{}", out);
+	assert_eq!(cpu.regs.pc, 0x800B);
+
+	// in the CPU's table but not run by it yet, and an X that wraps
+	let (cpu, out) = session(&[0xa2, 0xff, 0xe8, 0x96, 0x10], "step 3\n");
+	assert!(out.contains("error: unimplemented opcode $96 at $8003"), "{}", out);
+	assert_eq!((cpu.regs.pc, cpu.regs.x), (0x8003, 0x00));
+    }
+
+    #[test]
+    fn test_patch() {
+	let (cpu, out) = session(&PROGRAM, "patch $8008 lda #7\npatch $8003 ldy #%1\npatch $8000 foo\npatch $8000\nc\n");
+	assert!(out.contains("$8008  A9 07     lda #$07\n"), "{}", out);
+	assert!(out.contains("$8003  A0 01     ldy #$01\n"), "{}", out);
+	assert!(out.contains("error: line 1: unknown instruction foo"), "{}", out);
+	assert!(out.contains("error: patch needs an instruction"), "{}", out);
+	assert_eq!((cpu.regs.a, cpu.regs.x, cpu.regs.y), (0x07, 0x00, 0x01));
+    }
+}
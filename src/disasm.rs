@@ -0,0 +1,127 @@
+use std::fmt;
+use std::fs;
+
+use crate::AddressingMode::{self, *};
+use crate::cartridge::{Cartridge, PRG_ROM_BANK_SIZE};
+use crate::cli::Options;
+use crate::opcodes::{length, opcode};
+
+// A 6502 disassembler writing ca65 syntax. It knows every official
+// opcode, not only those the CPU runs so far, since ROMs use them all;
+// the rest come out as .byte.
+
+/// One disassembled instruction, or a byte that isn't one.
+#[derive(Debug, PartialEq)]
+pub struct	Instruction {
+    pub addr:	u16,
+    pub bytes:	Vec<u8>,
+    pub text:	String,
+}
+
+impl	Instruction {
+    pub fn next(&self) -> u16 {
+	self.addr.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+// ca65 syntax. A word operand under $100 gets a: in front, or ca65 would
+// pick the zero page mode instead.
+fn operand(mode: AddressingMode, addr: u16, bytes: &[u8]) -> String {
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+    let absolute = if word < 0x100 { format!("a:${:04X}", word) } else { format!("${:04X}", word) };
+    match mode {
+	AddressingImplied => String::new(),
+	AddressingAccumulator => " a".to_string(),
+	AddressingImmediate => format!(" #${:02X}", byte),
+	AddressingZeroPage => format!(" ${:02X}", byte),
+	AddressingZeroPageX => format!(" ${:02X},x", byte),
+	AddressingZeroPageY => format!(" ${:02X},y", byte),
+	AddressingIndirectX => format!(" (${:02X},x)", byte),
+	AddressingIndirectY => format!(" (${:02X}),y", byte),
+	AddressingAbsolute => format!(" {}", absolute),
+	AddressingAbsoluteX => format!(" {},x", absolute),
+	AddressingAbsoluteY => format!(" {},y", absolute),
+	AddressingIndirect => format!(" (${:04X})", word),
+	// branches show where they go
+	AddressingRelative => format!(" ${:04X}", addr.wrapping_add(2).wrapping_add(byte as i8 as u16)),
+    }
+}
+
+/// Disassembles the instruction at the start of `bytes`, which sits at
+/// `addr`. One cut short by the end of `bytes` comes out as a .byte.
+pub fn decode(bytes: &[u8], addr: u16) -> Instruction {
+    let first = bytes.first().copied().unwrap_or(0);
+    match opcode(first) {
+	Some((mnem, mode)) if length(mode) <= bytes.len() => {
+	    let bytes = &bytes[..length(mode)];
+	    Instruction { addr, bytes: bytes.to_vec(), text: format!("{}{}", mnem.to_lowercase(), operand(mode, addr, bytes)) }
+	}
+	_ => Instruction { addr, bytes: vec![first], text: format!(".byte ${:02X}", first) },
+    }
+}
+
+/// Disassembles all of `data`, which starts at `addr`.
+pub fn disassemble(data: &[u8], addr: u16) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+	let instruction = decode(&data[offset..], addr.wrapping_add(offset as u16));
+	offset += instruction.bytes.len();
+	instructions.push(instruction);
+    }
+    instructions
+}
+
+// $8000  20 08 80  jsr $8008
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+	write!(f, "${:04X}  {:<9} {}", self.addr, bytes.join(" "), self.text)
+    }
+}
+
+/// The disasm command: a listing of `--length` bytes of 16KB PRG bank
+/// `--bank`, as mapped at `--start`.
+pub fn run(options: &Options) -> Result<(), String> {
+    let rom_filename = options.rom.as_ref().ok_or("no ROM given")?;
+    let rom = fs::read(rom_filename).map_err(|e| format!("{}: {}", rom_filename, e))?;
+    let cartridge = Cartridge::from_ines(&rom)?;
+    let bank = cartridge.prg_bank(options.bank)
+	.ok_or(format!("{}: there is no PRG bank {}, the ROM has {}", rom_filename, options.bank, cartridge.prg_banks()))?;
+    let length = options.length.unwrap_or(PRG_ROM_BANK_SIZE).min(bank.len());
+    for instruction in disassemble(&bank[..length], options.start) {
+	println!("{}", instruction);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operand_syntax() {
+	let program = [
+	    0xa9, 0x05, 0x8d, 0x01, 0x20, 0xb5, 0x10, 0xbd, 0x34, 0x12,
+	    0xb9, 0x12, 0x00, 0xa1, 0x20, 0xb1, 0x20, 0x6c, 0xfc, 0xff,
+	    0x0a, 0xd0, 0xfe, 0x10, 0x02, 0xb6, 0x80, 0x02,
+	];
+	let text: Vec<String> = disassemble(&program, 0x8000).iter().map(|instruction| instruction.text.clone()).collect();
+	assert_eq!(text, [
+	    "lda #$05", "sta $2001", "lda $10,x", "lda $1234,x",
+	    "lda a:$0012,y", "lda ($20,x)", "lda ($20),y", "jmp ($FFFC)",
+	    "asl a", "bne $8015", "bpl $801B", "ldx $80,y", ".byte $02",
+	]);
+    }
+
+    #[test]
+    fn test_listing() {
+	let instructions = disassemble(&[0x20, 0x08, 0x80, 0xff, 0xad, 0x02], 0xC000);
+	assert_eq!(instructions[0].to_string(), "$C000  20 08 80  jsr $8008");
+	assert_eq!(instructions[1].to_string(), "$C003  FF        .byte $FF");
+	// cut short
+	assert_eq!(instructions[2].to_string(), "$C004  AD        .byte $AD");
+	assert_eq!(instructions[3].next(), 0xC006);
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::Cpu6502;
+use crate::debugger::{self, Watchpoint, WatchHit};
+
+// A stub for GDB's remote serial protocol, so gdb and the IDEs that drive
+// it can debug the emulated CPU over TCP:
+//
+//     (gdb) target remote localhost:6502
+//
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// gdb has no 6502 of its own, so the registers are described to it in a
+// target description: a, x, y, p and sp of 8 bits, then the 16-bit pc,
+// numbered from 0 in that order.
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rusty_old_nes.6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="p" bitsize="8"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// how many instructions a continue runs between looks for gdb's ^C
+const INTERRUPT_CHECK: u32 = 1000;
+
+// the longest packet gdb is told it may send, which also bounds an m
+// reply at two hex digits a byte
+const PACKET_SIZE: usize = 0x1000;
+
+// stop replies, by Unix signal number
+const SIGINT: &str = "S02";
+const SIGILL: &str = "S04";
+const SIGTRAP: &str = "S05";
+
+/// The link to gdb.
+pub trait	Connection: Read + Write {
+    /// Whether gdb has sent a ^C to stop the target, without waiting for
+    /// it to.
+    fn interrupted(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> io::Result<bool> {
+	self.set_nonblocking(true)?;
+	let mut byte = [0];
+	let read = self.read(&mut byte);
+	self.set_nonblocking(false)?;
+	match read {
+	    Ok(1) => Ok(byte[0] == 0x03),
+	    Ok(_) => Err(ErrorKind::UnexpectedEof.into()),
+	    Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+	    Err(e) => Err(e),
+	}
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+	return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn number(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+// "addr,length"
+fn addr_length(s: &str) -> Option<(u16, usize)> {
+    let (addr, length) = s.split_once(',')?;
+    Some((number(addr)?, usize::from_str_radix(length, 16).ok()?))
+}
+
+// Reads the next packet, acknowledging it, and returns what's between
+// the $ and the #. None once gdb hangs up.
+fn receive(conn: &mut impl Connection) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+	// acks, and ^C with the target already stopped, are skipped
+	loop {
+	    if conn.read(&mut byte)? == 0 {
+		return Ok(None);
+	    }
+	    if byte[0] == b'$' {
+		break;
+	    }
+	}
+	let mut data = Vec::new();
+	loop {
+	    if conn.read(&mut byte)? == 0 {
+		return Ok(None);
+	    }
+	    if byte[0] == b'#' {
+		break;
+	    }
+	    data.push(byte[0]);
+	}
+	let mut sum = [0; 2];
+	conn.read_exact(&mut sum)?;
+	let sum = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+	if sum == Some(checksum(&data)) {
+	    conn.write_all(b"+")?;
+	    return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+	}
+	conn.write_all(b"-")?;
+    }
+}
+
+fn send(conn: &mut impl Connection, data: &str) -> io::Result<()> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for b in data.bytes() {
+	if matches!(b, b'$' | b'#' | b'}' | b'*') {
+	    escaped.extend_from_slice(&[b'}', b ^ 0x20]);
+	}
+	else {
+	    escaped.push(b);
+	}
+    }
+    write!(conn, "$")?;
+    conn.write_all(&escaped)?;
+    write!(conn, "#{:02x}", checksum(&escaped))?;
+    conn.flush()
+}
+
+/// What gdb has set on the target.
+#[derive(Default)]
+pub struct	Stub {
+    breakpoints:	BTreeSet<u16>,
+}
+
+impl	Stub {
+    // Runs one instruction. Err with the stop reply if it can't be run
+    // or it stopped the CPU.
+    fn step(cpu: &mut Cpu6502) -> Result<(), &'static str> {
+	cpu.bus.watch_hits.clear();
+	match cpu.step() {
+	    Ok(0) => Err(SIGTRAP),
+	    Ok(_) => Ok(()),
+	    Err(_) => Err(SIGILL),
+	}
+    }
+
+    fn watch_reply(cpu: &mut Cpu6502) -> Option<String> {
+	let WatchHit { index, .. } = *cpu.bus.watch_hits.first()?;
+	cpu.bus.watch_hits.clear();
+	let watchpoint = &cpu.bus.watchpoints[index];
+	let kind = match watchpoint.access {
+	    debugger::WRITE => "watch",
+	    debugger::READ => "rwatch",
+	    _ => "awatch",
+	};
+	Some(format!("T05{}:{:04x};", kind, watchpoint.first))
+    }
+
+    // Runs until a breakpoint, a watchpoint or a ^C.
+    fn run(&self, cpu: &mut Cpu6502, conn: &mut impl Connection) -> io::Result<String> {
+	for count in 1.. {
+	    if let Err(reply) = Self::step(cpu) {
+		return Ok(reply.to_string());
+	    }
+	    if let Some(reply) = Self::watch_reply(cpu) {
+		return Ok(reply);
+	    }
+	    if self.breakpoints.contains(&cpu.regs.pc) {
+		break;
+	    }
+	    if count % INTERRUPT_CHECK == 0 && conn.interrupted()? {
+		return Ok(SIGINT.to_string());
+	    }
+	}
+	Ok(SIGTRAP.to_string())
+    }
+
+    fn registers(cpu: &Cpu6502) -> Vec<u8> {
+	let regs = &cpu.regs;
+	let [pc_lo, pc_hi] = regs.pc.to_le_bytes();
+	vec![regs.a, regs.x, regs.y, regs.p, regs.sp, pc_lo, pc_hi]
+    }
+
+    fn set_registers(cpu: &mut Cpu6502, bytes: &[u8]) -> Option<()> {
+	let [a, x, y, p, sp, pc_lo, pc_hi] = bytes.try_into().ok()?;
+	let regs = &mut cpu.regs;
+	(regs.a, regs.x, regs.y, regs.p, regs.sp) = (a, x, y, p, sp);
+	regs.pc = u16::from_le_bytes([pc_lo, pc_hi]);
+	Some(())
+    }
+
+    // Z and z packets: "kind,addr,length"
+    fn breakpoint(&mut self, cpu: &mut Cpu6502, packet: &str, insert: bool) -> Option<String> {
+	let mut fields = packet.split(',');
+	let kind = fields.next()?;
+	let addr = number(fields.next()?)?;
+	// no range is longer than the address space
+	let length = usize::from_str_radix(fields.next()?, 16).ok()?.clamp(1, 0x10000);
+	let last = addr.checked_add((length - 1) as u16)?;
+	let access = match kind {
+	    "0" | "1" => {
+		if insert {
+		    self.breakpoints.insert(addr);
+		}
+		else {
+		    self.breakpoints.remove(&addr);
+		}
+		return Some("OK".to_string());
+	    }
+	    "2" => debugger::WRITE,
+	    "3" => debugger::READ,
+	    "4" => debugger::READ | debugger::WRITE,
+	    _ => return Some(String::new()),
+	};
+	let watchpoints = &mut cpu.bus.watchpoints;
+	if insert {
+	    watchpoints.push(Watchpoint::new(addr, last, access));
+	}
+	else {
+	    watchpoints.retain(|w| (w.first, w.last, w.access) != (addr, last, access));
+	}
+	Some("OK".to_string())
+    }
+
+    // the reply to qXfer:features:read:target.xml:offset,length
+    fn target_xml(args: &str) -> Option<String> {
+	let (offset, length) = args.strip_prefix("target.xml:")?.split_once(',')?;
+	let offset = usize::from_str_radix(offset, 16).ok()?.min(TARGET_XML.len());
+	let length = usize::from_str_radix(length, 16).ok()?;
+	let chunk = &TARGET_XML[offset..offset.saturating_add(length).min(TARGET_XML.len())];
+	let more = offset + chunk.len() < TARGET_XML.len();
+	Some(format!("{}{}", if more { 'm' } else { 'l' }, chunk))
+    }
+
+    /// Answers one packet. None once gdb is done with the target.
+    pub fn handle(&mut self, cpu: &mut Cpu6502, packet: &str, conn: &mut impl Connection) -> io::Result<Option<String>> {
+	let error = || "E01".to_string();
+	let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+	let reply = match command {
+	    "?" => SIGTRAP.to_string(),
+	    "g" => hex(&Self::registers(cpu)),
+	    "G" => from_hex(args).and_then(|bytes| Self::set_registers(cpu, &bytes)).map_or_else(error, |_| "OK".to_string()),
+	    "p" => match usize::from_str_radix(args, 16) {
+		Ok(n @ 0..=4) => hex(&Self::registers(cpu)[n..n + 1]),
+		Ok(5) => hex(&cpu.regs.pc.to_le_bytes()),
+		_ => error(),
+	    },
+	    "P" => {
+		let set = args.split_once('=').and_then(|(n, value)| {
+		    let n = usize::from_str_radix(n, 16).ok()?;
+		    let value = from_hex(value)?;
+		    let mut regs = Self::registers(cpu);
+		    let width = if n == 5 { 2 } else { 1 };
+		    regs.get_mut(n..n + width)?.copy_from_slice(value.get(..width)?);
+		    Self::set_registers(cpu, &regs)
+		});
+		set.map_or_else(error, |_| "OK".to_string())
+	    }
+	    "m" => match addr_length(args) {
+		Some((addr, length)) if length <= PACKET_SIZE / 2 => hex(&(0..length).map(|i| cpu.bus.peek(addr.wrapping_add(i as u16))).collect::<Vec<u8>>()),
+		_ => error(),
+	    },
+	    "M" => {
+		let written = args.split_once(':').and_then(|(at, data)| {
+		    let (addr, length) = addr_length(at)?;
+		    let bytes = from_hex(data).filter(|bytes| bytes.len() == length)?;
+		    for (i, value) in bytes.into_iter().enumerate() {
+			cpu.bus.write(addr.wrapping_add(i as u16), value);
+		    }
+		    Some(())
+		});
+		written.map_or_else(error, |_| "OK".to_string())
+	    }
+	    "s" | "c" => {
+		if let Some(addr) = number(args) {
+		    cpu.regs.pc = addr;
+		}
+		if command == "s" {
+		    match Self::step(cpu) {
+			Err(reply) => reply.to_string(),
+			Ok(()) => Self::watch_reply(cpu).unwrap_or(SIGTRAP.to_string()),
+		    }
+		}
+		else {
+		    self.run(cpu, conn)?
+		}
+	    }
+	    "Z" | "z" => self.breakpoint(cpu, args, command == "Z").unwrap_or_else(error),
+	    "H" => "OK".to_string(),
+	    "k" => return Ok(None),
+	    "D" => {
+		send(conn, "OK")?;
+		return Ok(None);
+	    }
+	    _ => match packet {
+		"qSupported" => format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE),
+		"qAttached" => "1".to_string(),
+		"qC" => "QC1".to_string(),
+		"qfThreadInfo" => "m1".to_string(),
+		"qsThreadInfo" => "l".to_string(),
+		_ if packet.starts_with("qSupported:") => format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE),
+		_ => match packet.strip_prefix("qXfer:features:read:") {
+		    Some(args) => Self::target_xml(args).unwrap_or_else(error),
+		    // anything else isn't supported
+		    None => String::new(),
+		},
+	    },
+	};
+	Ok(Some(reply))
+    }
+
+    /// Answers gdb's packets until it detaches, kills the target or
+    /// hangs up.
+    pub fn serve(&mut self, cpu: &mut Cpu6502, conn: &mut impl Connection) -> io::Result<()> {
+	while let Some(packet) = receive(conn)? {
+	    match self.handle(cpu, &packet, conn)? {
+		Some(reply) => send(conn, &reply)?,
+		None => return Ok(()),
+	    }
+	}
+	Ok(())
+    }
+}
+
+/// Waits for gdb to connect on `listener` and serves it.
+pub fn run(cpu: &mut Cpu6502, listener: TcpListener) -> io::Result<()> {
+    println!("Waiting for gdb on {}...", listener.local_addr()?);
+    let (mut stream, peer) = listener.accept()?;
+    println!("gdb connected from {}", peer);
+    Stub::default().serve(cpu, &mut stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // gdb's side of a conversation
+    struct	Script {
+	input:	Cursor<Vec<u8>>,
+	output:	Vec<u8>,
+    }
+
+    impl Read for Script {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	    self.input.read(buf)
+	}
+    }
+
+    impl Write for Script {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	    self.output.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+	    Ok(())
+	}
+    }
+
+    impl Connection for Script {
+	fn interrupted(&mut self) -> io::Result<bool> {
+	    Ok(false)
+	}
+    }
+
+    fn packet(data: &str) -> String {
+	format!("${}#{:02x}", data, checksum(data.as_bytes()))
+    }
+
+    // Sends `packets` to a stub debugging `program` and returns the
+    // replies, without the acks.
+    fn session(program: &[u8], packets: &[&str]) -> (Cpu6502, Vec<String>) {
+	let mut cpu = Cpu6502::new();
+	cpu.trace = false;
+	cpu.load(program);
+	cpu.regs.sp = 0xFD;
+	let input: String = packets.iter().map(|data| packet(data)).collect();
+	let mut script = Script { input: Cursor::new(input.into_bytes()), output: Vec::new() };
+	Stub::default().serve(&mut cpu, &mut script).unwrap();
+	let output = String::from_utf8(script.output).unwrap();
+	let replies = output.split('$').skip(1).map(|reply| reply.split('#').next().unwrap().to_string()).collect();
+	(cpu, replies)
+    }
+
+    const PROGRAM: [u8; 10] = [
+	0x20, 0x07, 0x80,       // jsr $8007
+	0xa2, 0x05,             // ldx #$05
+	0x00, 0x00,             // brk
+	0xa9, 0x2a,             // lda #$2a
+	0x60,                   // rts
+    ];
+
+    #[test]
+    fn test_framing() {
+	assert_eq!(packet("g"), "$g#67");
+	let mut script = Script { input: Cursor::new(b"+$g#00$g#67".to_vec()), output: Vec::new() };
+	assert_eq!(receive(&mut script).unwrap(), Some("g".to_string()));
+	assert_eq!(script.output, b"-+");
+	assert_eq!(receive(&mut script).unwrap(), None);
+
+	send(&mut script, "a#b").unwrap();
+	assert!(script.output.ends_with(b"$a}\x03b#43"));
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+	let (cpu, replies) = session(&PROGRAM, &["qSupported:xmlRegisters=i386", "g", "G0102030405c0d0", "p5", "P0=2a",
+						  "m8000,3", "M0010,2:beef", "m0010,2", "D"]);
+	assert_eq!(replies[0], "PacketSize=1000;qXfer:features:read+");
+	assert_eq!(replies[1], "00000000fd0080");
+	assert_eq!(replies[2..], ["OK", "c0d0", "OK", "200780", "OK", "beef", "OK"]);
+	assert_eq!((cpu.regs.a, cpu.regs.x, cpu.regs.sp, cpu.regs.pc), (0x2a, 0x02, 0x05, 0xd0c0));
+
+	// lengths from the wire are bounded
+	let (cpu, replies) = session(&PROGRAM, &["m0,800", "m0,801", "mffff,ffffffffffff", "Z2,0,10000", "Z3,1,10000",
+						  "Z4,0,ffffffffffff", "qXfer:features:read:target.xml:0,ffffffffffffffff", "k"]);
+	assert_eq!(replies[0].len(), 0x1000);
+	assert_eq!(replies[1..6], ["E01", "E01", "OK", "E01", "OK"]);
+	assert_eq!(replies[6], format!("l{}", TARGET_XML));
+	assert_eq!(cpu.bus.watchpoints.iter().map(|w| (w.first, w.last)).collect::<Vec<_>>(), [(0, 0xFFFF), (0, 0xFFFF)]);
+    }
+
+    #[test]
+    fn test_step_and_breakpoints() {
+	let (cpu, replies) = session(&PROGRAM, &["s", "p5", "Z0,8003,1", "c", "z0,8003,1", "c", "k"]);
+	assert_eq!(replies, ["S05", "0780", "OK", "S05", "OK", "S05"]);
+	// stopped on the BRK
+	assert_eq!((cpu.regs.a, cpu.regs.x, cpu.regs.pc), (0x2a, 0x05, 0x8006));
+
+	// sta $0300 ; brk
+	let (_, replies) = session(&[0x8d, 0x00, 0x03, 0x00], &["Z2,0300,1", "c", "z2,0300,1", "Z0,8000,1", "c", "k"]);
+	assert_eq!(replies, ["OK", "T05watch:0300;", "OK", "OK", "S05"]);
+	let (_, replies) = session(&[0xff], &["s", "vMustReplyEmpty", "Z9,0,1", "k"]);
+	assert_eq!(replies, ["S04", "", ""]);
+	// ldx #$ff ; inx ; stx $10,y, which the CPU has no zero page,y for yet
+	let (cpu, replies) = session(&[0xa2, 0xff, 0xe8, 0x96, 0x10], &["c", "k"]);
+	assert_eq!(replies, ["S04"]);
+	assert_eq!((cpu.regs.pc, cpu.regs.x), (0x8003, 0x00));
+    }
+
+    #[test]
+    fn test_target_description() {
+	let (_, replies) = session(&PROGRAM, &["qXfer:features:read:target.xml:0,20", "qXfer:features:read:target.xml:20,1000", "k"]);
+	assert_eq!(replies[0], format!("m{}", &TARGET_XML[..0x20]));
+	assert_eq!(replies[1], format!("l{}", &TARGET_XML[0x20..]));
+    }
+
+    #[test]
+    fn test_over_tcp() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let gdb = std::thread::spawn(move || {
+	    let mut stream = TcpStream::connect(addr).unwrap();
+	    stream.write_all(packet("?").as_bytes()).unwrap();
+	    stream.write_all(packet("D").as_bytes()).unwrap();
+	    let mut replies = String::new();
+	    stream.read_to_string(&mut replies).unwrap();
+	    replies
+	});
+	let mut cpu = Cpu6502::new();
+	run(&mut cpu, listener).unwrap();
+	assert_eq!(gdb.join().unwrap(), "+$S05#b8+$OK#9a");
+    }
+}
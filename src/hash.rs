@@ -0,0 +1,123 @@
+// Checksums for file formats, and digests for matching ROMs against movie
+// files, which name the game they were recorded on by a hash of its ROM.
+
+/// The CRC-32 of PNG chunks and zip entries.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for b in bytes {
+	crc ^= *b as u32;
+	for _ in 0..8 {
+	    crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+	}
+    }
+    !crc
+}
+
+// Both hashes pad the message to a whole number of 64-byte blocks, with
+// its length in bits at the end.
+fn pad(bytes: &[u8], big_endian: bool) -> Vec<u8> {
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+	message.push(0);
+    }
+    let bits = (bytes.len() as u64).wrapping_mul(8);
+    message.extend_from_slice(&if big_endian { bits.to_be_bytes() } else { bits.to_le_bytes() });
+    message
+}
+
+// MD5, RFC 1321
+const MD5_SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+    5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+pub fn md5(bytes: &[u8]) -> [u8; 16] {
+    // the integer parts of abs(sin(i + 1)) * 2^32
+    let k: Vec<u32> = (0..64).map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32).collect();
+    let mut state: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+
+    for block in pad(bytes, false).chunks(64) {
+	let words: Vec<u32> = block.chunks(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]])).collect();
+	let [mut a, mut b, mut c, mut d] = state;
+	for i in 0..64 {
+	    let (f, g) = match i / 16 {
+		0 => ((b & c) | (!b & d), i),
+		1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+		2 => (b ^ c ^ d, (3 * i + 5) % 16),
+		_ => (c ^ (b | !d), (7 * i) % 16),
+	    };
+	    let rotated = a.wrapping_add(f).wrapping_add(k[i]).wrapping_add(words[g]).rotate_left(MD5_SHIFTS[i]);
+	    (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+	}
+	for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+	    *word = word.wrapping_add(add);
+	}
+    }
+
+    let mut digest = [0; 16];
+    for (out, word) in digest.chunks_mut(4).zip(state) {
+	out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+// SHA-1, RFC 3174
+pub fn sha1(bytes: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    for block in pad(bytes, true).chunks(64) {
+	let mut words = [0u32; 80];
+	for (i, w) in block.chunks(4).enumerate() {
+	    words[i] = u32::from_be_bytes([w[0], w[1], w[2], w[3]]);
+	}
+	for i in 16..80 {
+	    words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+	}
+	let [mut a, mut b, mut c, mut d, mut e] = state;
+	for (i, word) in words.iter().enumerate() {
+	    let (f, k) = match i / 20 {
+		0 => ((b & c) | (!b & d), 0x5A827999),
+		1 => (b ^ c ^ d, 0x6ED9EBA1),
+		2 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+		_ => (b ^ c ^ d, 0xCA62C1D6),
+	    };
+	    let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+	    (a, b, c, d, e) = (temp, a, b.rotate_left(30), c, d);
+	}
+	for (word, add) in state.iter_mut().zip([a, b, c, d, e]) {
+	    *word = word.wrapping_add(add);
+	}
+    }
+
+    let mut digest = [0; 20];
+    for (out, word) in digest.chunks_mut(4).zip(state) {
+	out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_md5() {
+	assert_eq!(to_hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+	assert_eq!(to_hex(&md5(b"The quick brown fox jumps over the lazy dog")), "9e107d9d372bb6826bd81d3542a419d6");
+	// more than one block
+	assert_eq!(to_hex(&md5(&[b'a'; 100])), "36a92cc94a9e0fa21f625f8bfb007adf");
+    }
+
+    #[test]
+    fn test_sha1() {
+	assert_eq!(to_hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+	assert_eq!(to_hex(&sha1(b"The quick brown fox jumps over the lazy dog")), "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12");
+	assert_eq!(to_hex(&sha1(&[b'a'; 100])), "7f9000257a4918d7072655ea468540cdcbd42e0c");
+    }
+}
@@ -0,0 +1,560 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::Cpu6502;
+use crate::apu::Channel;
+use crate::cartridge::Cartridge;
+use crate::cli::{Held, Options};
+use crate::controller::Device;
+use crate::image;
+use crate::movie::{Movie, MovieFrame, Reset, RomHash};
+use crate::nsf::{Nsf, NsfPlayer};
+use crate::ntsc::NtscFilter;
+use crate::palette::Palette;
+use crate::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::region::Region;
+use crate::rewind::Rewind;
+use crate::wav::{self, WavWriter};
+
+fn should_dump(options: &Options, frame: u64) -> bool {
+    if options.dump_every {
+	return true;
+    }
+    if options.dump_at.is_empty() {
+	return frame == options.frames;
+    }
+    options.dump_at.contains(&frame)
+}
+
+fn dump_frame(options: &Options, frame: u64, width: usize, rgb: &[u8]) -> Result<(), String> {
+    let base = Path::new(&options.out_dir).join(format!("frame_{:05}", frame));
+    let ppm = base.with_extension("ppm");
+    image::write_ppm(&ppm.to_string_lossy(), width, SCREEN_HEIGHT, rgb)
+	.map_err(|e| format!("{}: {}", ppm.display(), e))?;
+    if options.png {
+	let png = base.with_extension("png");
+	image::write_png(&png.to_string_lossy(), width, SCREEN_HEIGHT, rgb)
+	    .map_err(|e| format!("{}: {}", png.display(), e))?;
+    }
+    Ok(())
+}
+
+// music.wav -> music.noise.wav
+fn stem_path(wav: &str, channel: Channel) -> String {
+    Path::new(wav).with_extension(format!("{}.wav", channel.name())).to_string_lossy().to_string()
+}
+
+struct	Recording {
+    channel:	Option<Channel>,	// None is the full mix
+    path:	String,
+    wav:	WavWriter<BufWriter<File>>,
+}
+
+// Sets the APU up the way the audio options ask and opens the WAV files,
+// if any.
+fn start_recording(cpu: &mut Cpu6502, options: &Options) -> Result<Vec<Recording>, String> {
+    cpu.bus.apu.set_sample_rate(options.sample_rate);
+    for channel in &options.mute {
+	cpu.bus.apu.set_muted(*channel, true);
+    }
+
+    let mut recordings = Vec::new();
+    if let Some(path) = &options.wav {
+	recordings.push((None, path.clone()));
+	if options.stems {
+	    cpu.bus.apu.record_stems();
+	    recordings.extend(cpu.bus.apu.channels().into_iter().map(|channel| (Some(channel), stem_path(path, channel))));
+	}
+    }
+    recordings.into_iter()
+	.map(|(channel, path)| match wav::create(&path, options.sample_rate) {
+	    Ok(wav) => Ok(Recording { channel, path, wav }),
+	    Err(e) => Err(format!("{}: {}", path, e)),
+	})
+	.collect()
+}
+
+fn record(cpu: &Cpu6502, recordings: &mut [Recording]) -> Result<(), String> {
+    for recording in recordings {
+	let samples = match recording.channel {
+	    Some(channel) => cpu.bus.apu.stem_samples(channel),
+	    None => cpu.bus.apu.frame_samples(),
+	};
+	recording.wav.write_samples(samples).map_err(|e| format!("{}: {}", recording.path, e))?;
+    }
+    Ok(())
+}
+
+fn finish_recording(recordings: Vec<Recording>) -> Result<(), String> {
+    for recording in recordings {
+	recording.wav.finish().map_err(|e| format!("{}: {}", recording.path, e))?;
+    }
+    Ok(())
+}
+
+// An NSF has no picture, so all there is to do is record it: `frames`
+// counts calls to PLAY.
+fn run_nsf(options: &Options, filename: &str, data: &[u8]) -> Result<(), String> {
+    let nsf = Nsf::parse(data)?;
+    println!("{}: {}", filename, nsf);
+    if options.wav.is_none() {
+	return Err("an NSF needs --wav to play to".to_string());
+    }
+    for (chip, channel) in nsf.expansion_chips() {
+	if channel.is_none() {
+	    println!("warning: {} audio is not supported, it will be silent", chip);
+	}
+    }
+
+    let song = options.song.unwrap_or(nsf.start_song);
+    let mut player = NsfPlayer::new(nsf)?;
+    if let Some(region) = options.region {
+	player.set_region(region);
+    }
+    let mut recordings = start_recording(&mut player.cpu, options)?;
+    player.init(song)?;
+    for frame in 1..=options.frames {
+	player.play().map_err(|e| format!("PLAY call {}: {}", frame, e))?;
+	record(&player.cpu, &mut recordings)?;
+    }
+    finish_recording(recordings)
+}
+
+// the values of --pad, --paddle or --key that are held on `frame`
+fn held<T: Copy>(held: &[Held<T>], frame: u64) -> impl Iterator<Item = T> + '_ {
+    held.iter().filter(move |held| held.frames.contains(frame)).map(|held| held.value)
+}
+
+// the joypad input and reset that --press and --reset ask for on `frame`
+fn scripted_frame(options: &Options, frame: u64) -> MovieFrame {
+    let mut input = MovieFrame {
+	reset: options.resets.iter().find(|(_, at)| *at == frame).map(|(reset, _)| *reset),
+	..MovieFrame::default()
+    };
+    for press in options.presses.iter().filter(|press| press.frames.contains(frame)) {
+	if let Some(buttons) = input.buttons.get_mut(press.player) {
+	    *buttons |= press.buttons;
+	}
+    }
+    input
+}
+
+// Sets up the input `frame` gets from the movie or the command line and
+// runs it, returning the joypad input and reset for recording.
+fn play_frame(cpu: &mut Cpu6502, options: &Options, movie: Option<&Movie>, frame: u64) -> Result<MovieFrame, String> {
+    // past the end of a movie nothing is pressed
+    let input = match movie {
+	Some(movie) => movie.frames.get(frame as usize - 1).cloned().unwrap_or_default(),
+	None => scripted_frame(options, frame),
+    };
+    match input.reset {
+	Some(Reset::Soft) => cpu.reset(),
+	Some(Reset::Hard) => cpu.power_cycle(),
+	None => {}
+    }
+    for (player, buttons) in input.buttons.iter().enumerate() {
+	cpu.set_buttons(player, *buttons);
+    }
+    if let Some(zapper) = cpu.bus.input.ports[1].zapper() {
+	zapper.aim = options.aims.iter().rfind(|aim| aim.frames.contains(frame)).map(|aim| (aim.x, aim.y));
+	zapper.trigger = options.fires.iter().any(|fire| fire.contains(frame));
+    }
+    if let Some(pad) = cpu.bus.input.ports[1].power_pad() {
+	pad.buttons = held(&options.pads, frame).fold(0, |buttons, pad| buttons | pad);
+    }
+    if let Some(vaus) = cpu.bus.input.vaus() {
+	if let Some(position) = held(&options.paddles, frame).last() {
+	    vaus.position = position;
+	}
+	vaus.button = options.fires.iter().any(|fire| fire.contains(frame));
+    }
+    if let Some(keyboard) = cpu.bus.input.expansion.as_mut().and_then(|expansion| expansion.keyboard()) {
+	keyboard.keys = held(&options.keys, frame).fold(0, |keys, key| keys | key);
+    }
+    match cpu.run_frame() {
+	Ok(true) => {}
+	Ok(false) => return Err(format!("CPU hit BRK during frame {}", frame)),
+	Err(e) => return Err(format!("{} during frame {}", e, frame)),
+    }
+    Ok(input)
+}
+
+/// Runs the ROM for `options.frames` frames with no display attached,
+/// writing the requested frames out as images and, with `--wav`, the
+/// audio out as a WAV file plus one per channel with `--stems`. NSFs are
+/// played to the WAV files only. Joypad input comes from `--movie` if
+/// given, and is recorded to `--record`. `--rewind` goes back to an
+/// earlier frame and runs the frames from there on again.
+pub fn run(options: &Options) -> Result<(), String> {
+    let rom_filename = options.rom.as_ref().ok_or("no ROM given")?;
+    let rom = fs::read(rom_filename).map_err(|e| format!("{}: {}", rom_filename, e))?;
+    if Nsf::is_nsf(&rom) {
+	return run_nsf(options, rom_filename, &rom);
+    }
+    let cartridge = Cartridge::from_ines(&rom)?;
+    let cartridge_device = cartridge.device;
+    let rom_hash = RomHash::Md5(cartridge.md5());
+    let movie = match &options.movie {
+	Some(path) => {
+	    let movie = Movie::load(path)?;
+	    movie.check_rom(&cartridge).map_err(|e| format!("{}: {}", path, e))?;
+	    Some(movie)
+	}
+	None => None,
+    };
+    println!("{}: {}", rom_filename, cartridge);
+    let palette = match &options.palette {
+	Some(path) => Palette::load(path)?,
+	None => Palette::default(),
+    };
+    let ntsc = if options.ntsc { Some(NtscFilter::default()) } else { None };
+    fs::create_dir_all(&options.out_dir).map_err(|e| format!("{}: {}", options.out_dir, e))?;
+
+    let mut cpu = Cpu6502::new();
+    cpu.trace = false;
+    cpu.insert_cartridge(cartridge);
+    if let Some(region) = options.region {
+	cpu.set_region(region);
+    }
+    else if movie.as_ref().is_some_and(|movie| movie.pal) {
+	cpu.set_region(Region::Pal);
+    }
+    let mut recordings = start_recording(&mut cpu, options)?;
+    let mut devices = match cartridge_device {
+	Some(device) if options.devices.is_empty() => vec![device],
+	_ => options.devices.clone(),
+    };
+    if movie.as_ref().is_some_and(|movie| movie.four_score) {
+	devices.push(Device::FourScore);
+    }
+    for device in &devices {
+	cpu.bus.input.connect(*device);
+    }
+    // --fire on its own means a Zapper, unless there's a Vaus to press
+    if !options.aims.is_empty() || (!options.fires.is_empty() && cpu.bus.input.vaus().is_none()) {
+	cpu.bus.input.connect(Device::Zapper);
+    }
+    cpu.reset();
+    if let Some(path) = &options.load_state {
+	let state = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+	cpu.load_state(&state).map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    let mut recorded = options.record.as_ref().map(|_| {
+	let rom_name = Path::new(rom_filename).file_name().unwrap_or_default().to_string_lossy();
+	let mut recorded = Movie::new(&rom_name, Some(rom_hash));
+	recorded.pal = cpu.bus.region == Region::Pal;
+	recorded.four_score = devices.contains(&Device::FourScore);
+	recorded
+    });
+    let mut rewind = options.rewinds.first().map(|_| Rewind::new(options.rewind_interval, options.rewind_memory));
+    if let Some(rewind) = &mut rewind {
+	rewind.capture(0, cpu.save_state());
+    }
+    let mut rewinds = options.rewinds.clone();
+    let mut frame = 0;
+    // the last frame whose audio is in the WAV files; the frames run again
+    // after a rewind aren't recorded twice
+    let mut recorded_audio = 0;
+    while frame < options.frames {
+	frame += 1;
+	let input = play_frame(&mut cpu, options, movie.as_ref(), frame)?;
+	if let Some(recorded) = &mut recorded {
+	    recorded.frames.push(input);
+	}
+	if frame > recorded_audio {
+	    record(&cpu, &mut recordings)?;
+	    recorded_audio = frame;
+	}
+	for (path, _) in options.save_states.iter().filter(|(_, at)| *at == frame) {
+	    fs::write(path, cpu.save_state()).map_err(|e| format!("{}: {}", path, e))?;
+	}
+	if should_dump(options, frame) {
+	    let ppu = &cpu.bus.ppu;
+	    match &ntsc {
+		Some(filter) => dump_frame(options, frame, filter.width(), &filter.filter(ppu.framebuffer(), ppu.frame()))?,
+		None => dump_frame(options, frame, SCREEN_WIDTH, &palette.to_rgb(ppu.framebuffer()))?,
+	    }
+	}
+	let Some(rewind) = &mut rewind else { continue };
+	rewind.capture(frame, cpu.save_state());
+	// each --rewind goes back once, to the state before the frame it
+	// asks for, and runs forward from there with the input of the
+	// frames in between
+	if let Some(i) = rewinds.iter().position(|(_, at)| *at == frame) {
+	    let (back, _) = rewinds.remove(i);
+	    let target = frame.saturating_sub(back);
+	    let (at, state) = rewind.rewind_to(target)
+		.ok_or(format!("frame {} is no longer in the rewind buffer", target))?;
+	    cpu.load_state(state)?;
+	    for replayed in at + 1..=target {
+		play_frame(&mut cpu, options, movie.as_ref(), replayed)?;
+		rewind.capture(replayed, cpu.save_state());
+	    }
+	    if let Some(recorded) = &mut recorded {
+		recorded.frames.truncate(target as usize);
+	    }
+	    frame = target;
+	}
+    }
+    if let (Some(path), Some(recorded)) = (&options.record, recorded) {
+	recorded.save(path)?;
+    }
+    finish_recording(recordings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntsc::NTSC_WIDTH;
+
+    #[test]
+    fn test_should_dump() {
+	let mut options = Options { frames: 10, ..Options::default() };
+	assert!(should_dump(&options, 10));
+	assert!(!should_dump(&options, 9));
+
+	options.dump_at = vec![3, 5];
+	assert!(should_dump(&options, 3));
+	assert!(!should_dump(&options, 10));
+
+	options.dump_every = true;
+	assert!(should_dump(&options, 1));
+    }
+
+    // NROM-128 that turns on the background and spins: jmp $8005
+    fn write_rom(dir: &Path) -> std::path::PathBuf {
+	write_program(dir, "loop.nes", &[0xa9, 0x08, 0x8d, 0x01, 0x20, 0x4c, 0x05, 0x80], 0x8000)
+    }
+
+    // NROM-128 whose NMI handler counts frames at $10 and shows the count
+    // as the backdrop color, so every frame differs from the last
+    fn write_counter_rom(dir: &Path) -> std::path::PathBuf {
+	write_program(dir, "counter.nes", &[
+	    0xa9, 0x08, 0x8d, 0x01, 0x20,   // lda #$08 ; sta $2001
+	    0xa9, 0x80, 0x8d, 0x00, 0x20,   // lda #$80 ; sta $2000
+	    0x4c, 0x0a, 0x80,               // jmp $800A
+	    0xe6, 0x10,                     // nmi: inc $10
+	    0xa9, 0x3f, 0x8d, 0x06, 0x20,   // lda #$3f ; sta $2006
+	    0xa9, 0x00, 0x8d, 0x06, 0x20,   // lda #$00 ; sta $2006
+	    0xa5, 0x10, 0x8d, 0x07, 0x20,   // lda $10 ; sta $2007
+	    0x40,                           // rti
+	], 0x800D)
+    }
+
+    // the machine in the state file at `state`
+    fn machine(rom_path: &Path, state: &str) -> Cpu6502 {
+	let mut cpu = Cpu6502::new();
+	cpu.insert_cartridge(Cartridge::from_ines(&fs::read(rom_path).unwrap()).unwrap());
+	cpu.load_state(&fs::read(state).unwrap()).unwrap();
+	cpu
+    }
+
+    // NROM-128 with `program` at $8000 and the NMI vector at `nmi`
+    fn write_program(dir: &Path, name: &str, program: &[u8], nmi: u16) -> std::path::PathBuf {
+	let rom_path = dir.join(name);
+	fs::create_dir_all(dir).unwrap();
+	let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00];
+	rom.resize(16, 0);
+	let mut prg = vec![0xEA; 0x4000];
+	prg[..program.len()].copy_from_slice(program);
+	prg[0x3FFA..0x3FFC].copy_from_slice(&nmi.to_le_bytes());
+	prg[0x3FFC] = 0x00;
+	prg[0x3FFD] = 0x80;
+	rom.extend(prg);
+	rom.extend(vec![0; 0x2000]);
+	fs::write(&rom_path, rom).unwrap();
+	rom_path
+    }
+
+    #[test]
+    fn test_run_dumps_frames() {
+	let dir = std::env::temp_dir().join(format!("rusty_old_nes_headless_{}", std::process::id()));
+	let rom_path = write_rom(&dir);
+
+	let options = Options {
+	    rom: Some(rom_path.to_string_lossy().to_string()),
+	    headless: true,
+	    frames: 3,
+	    dump_at: vec![2, 3],
+	    out_dir: dir.to_string_lossy().to_string(),
+	    png: true,
+	    ..Options::default()
+	};
+	run(&options).unwrap();
+	assert!(!dir.join("frame_00001.ppm").exists());
+	assert!(dir.join("frame_00002.png").exists());
+	let ppm = fs::read(dir.join("frame_00003.ppm")).unwrap();
+	assert_eq!(ppm.len(), 15 + SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+
+	let options = Options { ntsc: true, dump_at: vec![1], png: false, ..options };
+	run(&options).unwrap();
+	let ppm = fs::read(dir.join("frame_00001.ppm")).unwrap();
+	assert_eq!(ppm.len(), 15 + NTSC_WIDTH * SCREEN_HEIGHT * 3);
+
+	// about 735 samples a frame at 44100 Hz, less for the first one which
+	// starts at power-on rather than at vblank
+	let wav_path = dir.join("audio.wav");
+	let options = Options { ntsc: false, dump_at: vec![], wav: Some(wav_path.to_string_lossy().to_string()), ..options };
+	run(&options).unwrap();
+	let wav = fs::read(&wav_path).unwrap();
+	let samples = u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize / 2;
+	assert_eq!(wav.len(), 44 + samples * 2);
+	assert!((2 * 730 + 600..=3 * 740).contains(&samples), "{} samples", samples);
+
+	let options = Options { stems: true, mute: vec![Channel::Triangle], ..options };
+	run(&options).unwrap();
+	for channel in &Channel::ALL[..5] {
+	    let stem = fs::read(stem_path(&options.wav.clone().unwrap(), *channel)).unwrap();
+	    assert_eq!(stem.len(), wav.len());
+	}
+	assert!(dir.join("audio.triangle.wav").exists());
+	// no expansion chip, no stem for it
+	assert!(!dir.join("audio.vrc6.wav").exists());
+	fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unimplemented_opcode() {
+	let dir = std::env::temp_dir().join(format!("rusty_old_nes_unimplemented_{}", std::process::id()));
+	// ldx #$ff ; inx ; stx $10,y, which the CPU has no zero page,y for yet
+	let rom_path = write_program(&dir, "stx.nes", &[0xa2, 0xff, 0xe8, 0x96, 0x10], 0x8000);
+	let options = Options {
+	    rom: Some(rom_path.to_string_lossy().to_string()),
+	    headless: true,
+	    frames: 3,
+	    out_dir: dir.to_string_lossy().to_string(),
+	    ..Options::default()
+	};
+	assert_eq!(run(&options).unwrap_err(), "unimplemented opcode $96 at $8003 during frame 1");
+	fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_record_and_play_movie() {
+	let dir = std::env::temp_dir().join(format!("rusty_old_nes_movie_{}", std::process::id()));
+	let rom_path = write_rom(&dir);
+	let fm2 = dir.join("run.fm2").to_string_lossy().to_string();
+	let options = Options {
+	    rom: Some(rom_path.to_string_lossy().to_string()),
+	    headless: true,
+	    frames: 4,
+	    dump_at: vec![4],
+	    out_dir: dir.to_string_lossy().to_string(),
+	    presses: crate::cli::parse(&["--press".to_string(), "a+start@2-3".to_string()]).unwrap().presses,
+	    resets: vec![(Reset::Soft, 2), (Reset::Hard, 4)],
+	    record: Some(fm2.clone()),
+	    ..Options::default()
+	};
+	run(&options).unwrap();
+	let movie = Movie::load(&fm2).unwrap();
+	assert_eq!(movie.rom_filename, "loop.nes");
+	assert_eq!(movie.frames.len(), 4);
+	assert_eq!(movie.frames[1], MovieFrame { reset: Some(Reset::Soft), buttons: [0x09, 0, 0, 0] });
+	assert_eq!(movie.frames[3].reset, Some(Reset::Hard));
+	let recorded = fs::read(dir.join("frame_00004.ppm")).unwrap();
+
+	// playing it back, without the scripted input, gives the same run
+	let played = dir.join("played.fm2").to_string_lossy().to_string();
+	let options = Options { presses: vec![], resets: vec![], movie: Some(fm2.clone()), record: Some(played.clone()), ..options };
+	run(&options).unwrap();
+	assert_eq!(fs::read_to_string(&played).unwrap(), fs::read_to_string(&fm2).unwrap());
+	assert_eq!(fs::read(dir.join("frame_00004.ppm")).unwrap(), recorded);
+
+	let mut other = movie;
+	other.rom_hash = Some(RomHash::Md5([0; 16]));
+	other.save(&fm2).unwrap();
+	assert!(run(&options).is_err());
+
+	// the same input as a script, which has no ROM hash to check
+	let script = dir.join("run.txt");
+	fs::write(&script, ".\nreset a+start x2\npower\n").unwrap();
+	let options = Options { movie: Some(script.to_string_lossy().to_string()), ..options };
+	run(&options).unwrap();
+	assert_eq!(Movie::load(&played).unwrap().frames, Movie::load(&fm2).unwrap().frames);
+	assert_eq!(fs::read(dir.join("frame_00004.ppm")).unwrap(), recorded);
+	fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+	let dir = std::env::temp_dir().join(format!("rusty_old_nes_state_{}", std::process::id()));
+	let rom_path = write_counter_rom(&dir);
+	let state = |name: &str| dir.join(name).to_string_lossy().to_string();
+	let options = Options {
+	    rom: Some(rom_path.to_string_lossy().to_string()),
+	    headless: true,
+	    frames: 4,
+	    out_dir: dir.to_string_lossy().to_string(),
+	    save_states: vec![(state("frame2.state"), 2), (state("frame4.state"), 4)],
+	    ..Options::default()
+	};
+	run(&options).unwrap();
+	let straight = fs::read(dir.join("frame_00004.ppm")).unwrap();
+
+	// two frames on from the state is frame 4 again
+	let options = Options {
+	    frames: 2,
+	    save_states: vec![(state("loaded.state"), 2)],
+	    load_state: Some(state("frame2.state")),
+	    ..options
+	};
+	run(&options).unwrap();
+	assert_eq!(fs::read(dir.join("frame_00002.ppm")).unwrap(), straight);
+	let (frame2, frame4) = (machine(&rom_path, &state("frame2.state")), machine(&rom_path, &state("frame4.state")));
+	let loaded = machine(&rom_path, &state("loaded.state"));
+	assert_eq!(frame4.bus.vram[0x10], frame2.bus.vram[0x10] + 2);
+	assert_eq!(loaded.bus.vram[..0x800], frame4.bus.vram[..0x800]);
+	assert_eq!((loaded.regs.pc, loaded.regs.a, loaded.cycles), (frame4.regs.pc, frame4.regs.a, frame4.cycles));
+	assert_eq!(fs::read(state("loaded.state")).unwrap(), fs::read(state("frame4.state")).unwrap());
+
+	let mut data = fs::read(state("frame2.state")).unwrap();
+	data[8] = 99;
+	fs::write(state("frame2.state"), data).unwrap();
+	let error = run(&options).unwrap_err();
+	assert!(error.contains("version 99 is not supported"), "{}", error);
+	fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rewind() {
+	let dir = std::env::temp_dir().join(format!("rusty_old_nes_rewind_{}", std::process::id()));
+	let rom_path = write_counter_rom(&dir);
+	let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+	let options = Options {
+	    rom: Some(rom_path.to_string_lossy().to_string()),
+	    headless: true,
+	    frames: 6,
+	    out_dir: dir.to_string_lossy().to_string(),
+	    save_states: vec![(path("straight.state"), 6)],
+	    wav: Some(path("straight.wav")),
+	    ..Options::default()
+	};
+	run(&options).unwrap();
+	let straight = fs::read(dir.join("frame_00006.ppm")).unwrap();
+
+	// back from frame 4 to frame 1 goes to the state after frame 0 and
+	// runs frame 1 again
+	let options = Options {
+	    rewinds: vec![(3, 4)],
+	    rewind_interval: 2,
+	    save_states: vec![(path("rewound.state"), 6)],
+	    wav: Some(path("rewound.wav")),
+	    ..options
+	};
+	run(&options).unwrap();
+	assert_eq!(fs::read(dir.join("frame_00006.ppm")).unwrap(), straight);
+	let (straight, rewound) = (machine(&rom_path, &path("straight.state")), machine(&rom_path, &path("rewound.state")));
+	// the NMI for frame 6's vblank runs at the start of frame 7
+	assert_eq!((straight.bus.vram[0x10], rewound.bus.vram[0x10]), (5, 5));
+	assert_eq!(rewound.bus.vram[..0x800], straight.bus.vram[..0x800]);
+	assert_eq!((rewound.regs.pc, rewound.cycles), (straight.regs.pc, straight.cycles));
+	// the three frames run again aren't in the WAV twice
+	assert_eq!(fs::read(path("rewound.wav")).unwrap(), fs::read(path("straight.wav")).unwrap());
+
+	let error = run(&Options { rewind_interval: 1, rewind_memory: 0, ..options }).unwrap_err();
+	assert_eq!(error, "frame 1 is no longer in the rewind buffer");
+	fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::io;
+
+use crate::hash::crc32;
+
+// Writers for packed 8-bit RGB images. Nothing here compresses: PPM is raw
+// by design, and the PNGs use stored deflate blocks, which every decoder
+// accepts and which keeps golden image diffs byte-for-byte stable.
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const DEFLATE_MAX_STORED: usize = 0xFFFF;
+
+pub fn encode_ppm(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    out.extend_from_slice(rgb);
+    out
+}
+
+pub fn write_ppm(path: &str, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    fs::write(path, encode_ppm(width, height, rgb))
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b): (u32, u32) = (1, 0);
+    for chunk in bytes.chunks(5552) {
+	for byte in chunk {
+	    a += *byte as u32;
+	    b += a;
+	}
+	a %= 65521;
+	b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(DEFLATE_MAX_STORED).peekable();
+    if blocks.peek().is_none() {
+	out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+	out.push(if blocks.peek().is_none() { 0x01 } else { 0x00 });
+	let len = block.len() as u16;
+	out.extend_from_slice(&len.to_le_bytes());
+	out.extend_from_slice(&(!len).to_le_bytes());
+	out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);	// 8-bit truecolor, no interlace
+
+    // every scanline starts with its filter type, 0 for none
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for row in rgb.chunks(width * 3) {
+	raw.push(0);
+	raw.extend_from_slice(row);
+    }
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+pub fn write_png(path: &str, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    fs::write(path, encode_png(width, height, rgb))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ppm() {
+	let ppm = encode_ppm(2, 1, &[1, 2, 3, 4, 5, 6]);
+	assert_eq!(ppm, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06".to_vec());
+    }
+
+    #[test]
+    fn test_checksums() {
+	assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+	assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_png_layout() {
+	let png = encode_png(1, 1, &[0xFF, 0x00, 0x00]);
+	assert_eq!(png[..8], PNG_SIGNATURE);
+	assert_eq!(&png[12..16], b"IHDR");
+	assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+	// one stored block holding the filter byte and the pixel
+	let idat = &png[33..];
+	assert_eq!(&idat[4..8], b"IDAT");
+	assert_eq!(&idat[10..17], &[0x01, 0x04, 0x00, 0xFB, 0xFF, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn test_large_png_spans_blocks() {
+	let rgb = vec![0x80; 256 * 240 * 3];
+	let png = encode_png(256, 240, &rgb);
+	let raw_size: usize = (256 * 3 + 1) * 240;
+	let blocks = raw_size.div_ceil(DEFLATE_MAX_STORED);
+	assert_eq!(png.len(), 8 + 25 + (12 + 2 + raw_size + blocks * 5 + 4) + 12);
+    }
+}
@@ -1,12 +1,54 @@
+mod apu;
+mod asm;
+mod bk2;
+mod cartridge;
+mod cli;
+mod condition;
+mod controller;
+mod debugger;
+mod disasm;
+mod gdb;
+mod hash;
+mod headless;
+mod image;
+mod movie;
+mod nsf;
+mod ntsc;
+mod opcodes;
+mod palette;
+mod ppu;
+mod region;
+mod rewind;
+mod savestate;
+mod wav;
+mod zip;
+
 use std::fs;
 use std::env::args;
-use std::io::Read;
+use std::io::{self, Read};
+use std::net::TcpListener;
 use std::fmt;
+use std::process;
+
+use apu::Apu;
+use cartridge::Cartridge;
+use controller::Input;
+use debugger::{Watchpoint, WatchHit};
+use ppu::Ppu;
+use region::Region;
+use savestate::{Snapshot, State};
 
-const MEMSIZE_6502: u16 = 0xFFFF;
+const MEMSIZE_6502: usize = 0x10000;
 const MEMSTART_CARTRIDGE_ROM_6502: u16 = 0x8000;
 const MEMSTART_STACK_6502: u16 = 0x1FF;
 const MEMSTART_STACK_SIZE: u16 = 0xFF;
+const MEMSIZE_RAM_6502: u16 = 0x800;
+
+const VECTOR_NMI_6502: u16 = 0xFFFA;
+const VECTOR_RESET_6502: u16 = 0xFFFC;
+const VECTOR_IRQ_6502: u16 = 0xFFFE;
+
+const OAM_DMA_6502: u16 = 0x4014;
 
 // 7  bit  0
 // ---- ----
@@ -32,6 +74,7 @@ pub const STATUS_FLAG_C: u8 = 0x01;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum  AddressingMode {
+    AddressingAccumulator,
     AddressingAbsolute,
     AddressingAbsoluteX,
     AddressingAbsoluteY,
@@ -40,6 +83,7 @@ enum  AddressingMode {
     AddressingIndirect,
     AddressingIndirectX,
     AddressingIndirectY,
+    AddressingRelative,
     AddressingZeroPage,
     AddressingZeroPageX,
     AddressingZeroPageY,
@@ -48,6 +92,7 @@ enum  AddressingMode {
 impl fmt::Display for AddressingMode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+	    AddressingMode::AddressingAccumulator => write!(f, "AddressingAccumulator"),
 	    AddressingMode::AddressingAbsolute => write!(f, "AddressingAbsolute"),
 	    AddressingMode::AddressingAbsoluteX => write!(f, "AddressingAbsoluteX"),
 	    AddressingMode::AddressingAbsoluteY => write!(f, "AddressingAbsoluteY"),
@@ -56,6 +101,7 @@ impl fmt::Display for AddressingMode {
 	    AddressingMode::AddressingIndirect => write!(f, "AddressingIndirect"),
 	    AddressingMode::AddressingIndirectX => write!(f, "AddressingIndirectX"),
 	    AddressingMode::AddressingIndirectY => write!(f, "AddressingIndirectY"),
+	    AddressingMode::AddressingRelative => write!(f, "AddressingRelative"),
 	    AddressingMode::AddressingZeroPage => write!(f, "AddressingZeroPage"),
 	    AddressingMode::AddressingZeroPageX => write!(f, "AddressingZeroPageX"),
 	    AddressingMode::AddressingZeroPageY => write!(f, "AddressingZeroPageY")
@@ -63,10 +109,31 @@ impl fmt::Display for AddressingMode {
     }
 }
 
+// The opcodes the CPU runs so far; their mnemonics, addressing modes and
+// cycles come from the table in opcodes.rs. Everything else is reported
+// as unimplemented.
+const CPU_OPCODES: [u8; 67] = [
+    0x00, 0x40, 0xEA,					// BRK RTI NOP
+    0x18, 0xD8, 0x58, 0xB8, 0x78,			// CLC CLD CLI CLV SEI
+    0x09, 0x05, 0x15, 0x0D, 0x1D, 0x19, 0x01, 0x11,	// ORA
+    0xA9, 0xA5, 0xB5, 0xAD, 0xBD, 0xB9, 0xA1, 0xB1,	// LDA
+    0xA2, 0xA0,						// LDX #, LDY #
+    0x6C, 0x4C, 0x20, 0x60,				// JMP JSR RTS
+    0x84, 0x94, 0x8C,					// STY
+    0x85, 0x95, 0x8D, 0x9D, 0x99, 0x81, 0x91,		// STA
+    0x86, 0x96, 0x8E,					// STX
+    0xAA, 0xA8, 0xBA, 0x8A, 0x9A, 0x98,			// TAX TAY TSX TXA TXS TYA
+    0xE6, 0xF6, 0xEE, 0xFE, 0xE8, 0xC8,			// INC INX INY
+    0x48, 0x08, 0x68, 0x28,				// PHA PHP PLA PLP
+    0x26, 0x36, 0x2E, 0x3E,				// ROL
+    0x66, 0x76, 0x6E, 0x7E,				// ROR
+];
+
 struct	Ins6502 {
     opcode: u8,
     mnem: String,
     addressing_mode: AddressingMode,
+    cycles: u8,
 }
 
 struct Regs6502 {
@@ -79,13 +146,146 @@ struct Regs6502 {
 }
 
 struct	Bus6502 {
-    vram: [u8; MEMSIZE_6502 as usize],
+    vram: [u8; MEMSIZE_6502],
+    ppu: Ppu,
+    apu: Apu,
+    cartridge: Option<Cartridge>,
+    input: Input,
+    open_bus: u8,	// last value on the data bus
+    oam_dma: Option<u8>,	// page written to $4014, copied once the instruction is done
+    region: Region,
+    ppu_clock: u32,	// master clock ticks the PPU has yet to catch up on
+    watchpoints: Vec<Watchpoint>,	// the debugger's
+    watch_hits: Vec<WatchHit>,	// accesses the watchpoints saw, until the debugger takes them
+}
+
+impl	Bus6502 {
+    fn read(&mut self, addr: u16) -> u8 {
+	self.read_as(addr, debugger::READ)
+    }
+
+    // `access` is what the watchpoints see the read as: opcode fetches
+    // are executes.
+    fn read_as(&mut self, addr: u16, access: u8) -> u8 {
+	let value = match addr {
+	    0x0000..=0x1FFF => self.vram[(addr % MEMSIZE_RAM_6502) as usize],
+	    0x2000..=0x3FFF => self.ppu.read_register(addr),
+	    apu::APU_STATUS => self.apu.read_status(),
+	    controller::CONTROLLER_PORT_1 => (self.open_bus & controller::OPEN_BUS_MASK) | self.input.read(0, &self.ppu),
+	    controller::CONTROLLER_PORT_2 => (self.open_bus & controller::OPEN_BUS_MASK) | self.input.read(1, &self.ppu),
+	    _ => {
+		match self.cartridge.as_ref().and_then(|c| c.prg_read(addr)) {
+		    Some(value) => value,
+		    None => self.vram[addr as usize],
+		}
+	    }
+	};
+	self.open_bus = value;
+	if !self.watchpoints.is_empty() {
+	    self.watched(access, addr, value);
+	}
+	value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+	self.open_bus = value;
+	if !self.watchpoints.is_empty() {
+	    self.watched(debugger::WRITE, addr, value);
+	}
+	match addr {
+	    0x0000..=0x1FFF => self.vram[(addr % MEMSIZE_RAM_6502) as usize] = value,
+	    0x2000..=0x3FFF => self.ppu.write_register(addr, value),
+	    OAM_DMA_6502 => self.oam_dma = Some(value),
+	    controller::CONTROLLER_STROBE => self.input.write(value),
+	    0x4000..=0x4013 | apu::APU_STATUS | apu::APU_FRAME_COUNTER => self.apu.write_register(addr, value),
+	    _ => {
+		self.apu.write_expansion(addr, value);
+		match self.cartridge.as_mut() {
+		    Some(cartridge) if addr >= 0x4020 => cartridge.prg_write(addr, value),
+		    _ => self.vram[addr as usize] = value,
+		}
+	    }
+	}
+    }
+
+    fn watched(&mut self, access: u8, addr: u16, value: u8) {
+	for (index, watchpoint) in self.watchpoints.iter().enumerate() {
+	    if watchpoint.access & access != 0 && (watchpoint.first..=watchpoint.last).contains(&addr) {
+		self.watch_hits.push(WatchHit { index, access, addr, value });
+	    }
+	}
+    }
+
+    // What a read of `addr` would see, for the debugger: the registers
+    // aren't read, since that has side effects, and show the open bus.
+    fn peek(&self, addr: u16) -> u8 {
+	match addr {
+	    0x0000..=0x1FFF => self.vram[(addr % MEMSIZE_RAM_6502) as usize],
+	    0x2000..=0x401F => self.open_bus,
+	    _ => self.cartridge.as_ref().and_then(|c| c.prg_read(addr)).unwrap_or(self.vram[addr as usize]),
+	}
+    }
+
+    // The other way round, for the debugger's patches: writes ROM as well
+    // as RAM, and leaves the registers alone.
+    fn poke(&mut self, addr: u16, value: u8) {
+	match addr {
+	    0x0000..=0x1FFF => self.vram[(addr % MEMSIZE_RAM_6502) as usize] = value,
+	    0x2000..=0x401F => {}
+	    _ => match self.cartridge.as_mut() {
+		Some(cartridge) if addr >= 0x6000 => cartridge.prg_patch(addr, value),
+		_ => self.vram[addr as usize] = value,
+	    },
+	}
+    }
+
+    // IRQ is level triggered: it stays asserted until the source is
+    // acknowledged
+    fn irq(&self) -> bool {
+	self.apu.irq()
+    }
+
+    fn read_word(&mut self, addr: u16) -> u16 {
+	let lo: u16 = self.read(addr) as u16;
+	let hi: u16 = self.read(addr.wrapping_add(1)) as u16;
+	(hi << 8) | lo
+    }
+
+    // A pointer in zero page; its high byte at $FF wraps around to $00.
+    fn read_zp_word(&mut self, zp: u8) -> u16 {
+	let lo: u16 = self.read(zp as u16) as u16;
+	let hi: u16 = self.read(zp.wrapping_add(1) as u16) as u16;
+	(hi << 8) | lo
+    }
+
+    // The CPU and PPU divide down the same master clock: three dots per
+    // cycle on NTSC and Dendy, 3.2 on PAL.
+    fn tick(&mut self, cycles: u32) {
+	self.ppu_clock += cycles * self.region.cpu_divider();
+	while self.ppu_clock >= self.region.ppu_divider() {
+	    self.ppu_clock -= self.region.ppu_divider();
+	    self.ppu.tick();
+	}
+	for _ in 0..cycles {
+	    self.apu.clock();
+	}
+    }
 }
 
 pub struct	Cpu6502 {
     regs:	Regs6502,
     bus:	Bus6502,
     ins:	Vec<Ins6502>,
+    cycles:	u64,
+    nmi_pending:	bool,
+    irq_pending:	bool,
+    trace:	bool,	// print every instruction as it executes
+}
+
+impl Default for Cpu6502 {
+    fn default() -> Self {
+	Self::new()
+    }
 }
 
 // not implementing implied addressing because it's implied
@@ -101,254 +301,234 @@ impl	Cpu6502 {
 		y:  0
 	    }},
 	    bus: { Bus6502 {
-		vram: [0; MEMSIZE_6502 as usize]
+		vram: [0; MEMSIZE_6502],
+		ppu: Ppu::new(),
+		apu: Apu::new(),
+		cartridge: None,
+		input: Input::default(),
+		open_bus: 0,
+		oam_dma: None,
+		region: Region::Ntsc,
+		ppu_clock: 0,
+		watchpoints: Vec::new(),
+		watch_hits: Vec::new(),
 	    }},
-	    ins: vec![
-		Ins6502 {opcode: 0x00, mnem: "BRK".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0xEA, mnem: "NOP".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-
-		Ins6502 {opcode: 0x18, mnem: "CLC".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0xD8, mnem: "CLD".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0x58, mnem: "CLI".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0xB8, mnem: "CLI".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-
-		Ins6502 {opcode: 0x09, mnem: "ORA".to_string(), addressing_mode: AddressingMode::AddressingImmediate},
-		Ins6502 {opcode: 0x05, mnem: "ORA".to_string(), addressing_mode: AddressingMode::AddressingZeroPage},
-		Ins6502 {opcode: 0x15, mnem: "ORA".to_string(), addressing_mode: AddressingMode::AddressingZeroPageX},
-		Ins6502 {opcode: 0x0D, mnem: "ORA".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-		Ins6502 {opcode: 0x1D, mnem: "ORA".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteX},
-		Ins6502 {opcode: 0x19, mnem: "ORA".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteY},
-		Ins6502 {opcode: 0x01, mnem: "ORA".to_string(), addressing_mode: AddressingMode::AddressingIndirectX},
-		Ins6502 {opcode: 0x11, mnem: "ORA".to_string(), addressing_mode: AddressingMode::AddressingIndirectY},
-
-		Ins6502 {opcode: 0xA9, mnem: "LDA".to_string(), addressing_mode: AddressingMode::AddressingImmediate},
-		Ins6502 {opcode: 0xA5, mnem: "LDA".to_string(), addressing_mode: AddressingMode::AddressingZeroPage},
-		Ins6502 {opcode: 0xB5, mnem: "LDA".to_string(), addressing_mode: AddressingMode::AddressingZeroPageX},
-		Ins6502 {opcode: 0xAD, mnem: "LDA".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-		Ins6502 {opcode: 0xBD, mnem: "LDA".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteX},
-		Ins6502 {opcode: 0xB9, mnem: "LDA".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteY},
-		Ins6502 {opcode: 0xA1, mnem: "LDA".to_string(), addressing_mode: AddressingMode::AddressingIndirectX},
-		Ins6502 {opcode: 0xA1, mnem: "LDA".to_string(), addressing_mode: AddressingMode::AddressingIndirectY},
-
-		Ins6502 {opcode: 0xA2, mnem: "LDX".to_string(), addressing_mode: AddressingMode::AddressingImmediate},
-		Ins6502 {opcode: 0xA0, mnem: "LDY".to_string(), addressing_mode: AddressingMode::AddressingImmediate},
-
-		Ins6502 {opcode: 0x6C, mnem: "JMP".to_string(), addressing_mode: AddressingMode::AddressingIndirect},
-		Ins6502 {opcode: 0x4C, mnem: "JMP".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-
-		Ins6502 {opcode: 0x84, mnem: "STY".to_string(), addressing_mode: AddressingMode::AddressingZeroPage},
-		Ins6502 {opcode: 0x94, mnem: "STY".to_string(), addressing_mode: AddressingMode::AddressingZeroPageX},
-		Ins6502 {opcode: 0x8C, mnem: "STY".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-
-		Ins6502 {opcode: 0x85, mnem: "STA".to_string(), addressing_mode: AddressingMode::AddressingZeroPage},
-		Ins6502 {opcode: 0x95, mnem: "STA".to_string(), addressing_mode: AddressingMode::AddressingZeroPageX},
-		Ins6502 {opcode: 0x8D, mnem: "STA".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-		Ins6502 {opcode: 0x9D, mnem: "STA".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteX},
-		Ins6502 {opcode: 0x99, mnem: "STA".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteY},
-		Ins6502 {opcode: 0x81, mnem: "STA".to_string(), addressing_mode: AddressingMode::AddressingIndirectX},
-		Ins6502 {opcode: 0x91, mnem: "STA".to_string(), addressing_mode: AddressingMode::AddressingIndirectY},
-
-		Ins6502 {opcode: 0x86, mnem: "STX".to_string(), addressing_mode: AddressingMode::AddressingZeroPage},
-		Ins6502 {opcode: 0x96, mnem: "STX".to_string(), addressing_mode: AddressingMode::AddressingZeroPageY},
-		Ins6502 {opcode: 0x8E, mnem: "STX".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-
-
-		Ins6502 {opcode: 0xAA, mnem: "TAX".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0xA8, mnem: "TAY".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0xA8, mnem: "TSX".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0x8A, mnem: "TXA".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0x9A, mnem: "TXS".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0x98, mnem: "TYA".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-
-
-		Ins6502 {opcode: 0xE6, mnem: "INC".to_string(), addressing_mode: AddressingMode::AddressingZeroPage},
-		Ins6502 {opcode: 0xF6, mnem: "INC".to_string(), addressing_mode: AddressingMode::AddressingZeroPageX},
-		Ins6502 {opcode: 0xEE, mnem: "INC".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-		Ins6502 {opcode: 0xFE, mnem: "INC".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteX},
-		Ins6502 {opcode: 0xE8, mnem: "INX".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0xC8, mnem: "INY".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-
-		Ins6502 {opcode: 0x48, mnem: "PHA".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0x08, mnem: "PHP".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0x68, mnem: "PLA".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-		Ins6502 {opcode: 0x28, mnem: "PLP".to_string(), addressing_mode: AddressingMode::AddressingImplied},
-
-		Ins6502 {opcode: 0x26, mnem: "ROL".to_string(), addressing_mode: AddressingMode::AddressingZeroPage},
-		Ins6502 {opcode: 0x36, mnem: "ROL".to_string(), addressing_mode: AddressingMode::AddressingZeroPageX},
-		Ins6502 {opcode: 0x2E, mnem: "ROL".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-		Ins6502 {opcode: 0x3E, mnem: "ROL".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteX},
-
-		Ins6502 {opcode: 0x66, mnem: "ROR".to_string(), addressing_mode: AddressingMode::AddressingZeroPage},
-		Ins6502 {opcode: 0x76, mnem: "ROR".to_string(), addressing_mode: AddressingMode::AddressingZeroPageX},
-		Ins6502 {opcode: 0x6E, mnem: "ROR".to_string(), addressing_mode: AddressingMode::AddressingAbsolute},
-		Ins6502 {opcode: 0x7E, mnem: "ROR".to_string(), addressing_mode: AddressingMode::AddressingAbsoluteX},
-	    ]
-	}
-    }
-
-    fn set_status_bit (self: &mut Self, reg_id: char, bit: char) {
-	let mut target_register: &u8 = &0;
-
-	match reg_id {
-	    'a' => {target_register = &self.regs.a;}
-	    'x' => {target_register = &self.regs.x;}
-	    'y' => {target_register = &self.regs.y;}
-	    _ => unimplemented!()
+	    cycles: 0,
+	    nmi_pending: false,
+	    irq_pending: false,
+	    trace: true,
+	    ins: opcodes::OPCODES.iter()
+		.filter(|(opcode, _, _, _)| CPU_OPCODES.contains(opcode))
+		.map(|&(opcode, mnem, addressing_mode, cycles)| Ins6502 {opcode, mnem: mnem.to_string(), addressing_mode, cycles})
+		.collect(),
 	}
+    }
+
+    fn set_status_bit (&mut self, reg_id: char, bit: char) {
+	let target_register: u8 = match reg_id {
+	    'a' => self.regs.a,
+	    'x' => self.regs.x,
+	    'y' => self.regs.y,
+	    _ => unimplemented!()
+	};
 	match bit {
 	    'Z' => {
-		if *target_register == 0 {
-		    self.regs.p = self.regs.p | STATUS_FLAG_Z;
+		if target_register == 0 {
+		    self.regs.p |= STATUS_FLAG_Z;
 		}
 		else {
-		    self.regs.p = self.regs.p & !STATUS_FLAG_Z;
+		    self.regs.p &= !STATUS_FLAG_Z;
 		}
 	    }
 	    'N' => {
-		if (*target_register & STATUS_FLAG_N) == STATUS_FLAG_N {
-		    self.regs.p = self.regs.p | STATUS_FLAG_N;
+		if (target_register & STATUS_FLAG_N) == STATUS_FLAG_N {
+		    self.regs.p |= STATUS_FLAG_N;
 		}
 		else {
-		    self.regs.p = self.regs.p & !STATUS_FLAG_N;
+		    self.regs.p &= !STATUS_FLAG_N;
 		}
 	    }
 	    _ => todo!()
 	}
     }
 
+    fn stack_push(&mut self, value: u8) {
+	let addr: u16 = MEMSTART_STACK_6502 - MEMSTART_STACK_SIZE + self.regs.sp as u16;
+	self.bus.write(addr, value);
+	self.regs.sp = self.regs.sp.wrapping_sub(1);
+    }
 
-    fn get_operand(self: &mut Self, instruction_index: u8) -> u16 {
+    fn stack_pull(&mut self) -> u8 {
+	self.regs.sp = self.regs.sp.wrapping_add(1);
+	let addr: u16 = MEMSTART_STACK_6502 - MEMSTART_STACK_SIZE + self.regs.sp as u16;
+	self.bus.read(addr)
+    }
+
+    // None for the addressing modes not done yet, before anything moves.
+    fn get_operand(&mut self, instruction_index: u8) -> Option<u16> {
 	let mode = &self.ins[instruction_index as usize].addressing_mode;
 
 	match mode {
-	    AddressingMode::AddressingImplied	=> { return 0; } 
+	    AddressingMode::AddressingImplied	=> { Some(0) }
 
 	    AddressingMode::AddressingImmediate	=> {
 		let immediate: u16 = self.regs.pc;
-		self.regs.pc += 1;
-		immediate
+		self.regs.pc = self.regs.pc.wrapping_add(1);
+		Some(immediate)
 	    }
 
 	    AddressingMode::AddressingIndirect	=> {
-		let lo: u16 = self.regs.pc;
-		let hi: u16 = self.regs.pc + 1;
-		let addr: u16 = (hi << 4) as u16 | lo as u16;
-
-		self.regs.pc += 2;
-		addr
+		// the pointer's high byte comes from the start of its page
+		// when the low byte is at the end of it, as on the real 6502
+		let pointer: u16 = self.bus.read_word(self.regs.pc);
+		let lo: u16 = self.bus.read(pointer) as u16;
+		let hi: u16 = self.bus.read((pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF)) as u16;
+		let addr: u16 = (hi << 8) | lo;
+
+		self.regs.pc = self.regs.pc.wrapping_add(2);
+		Some(addr)
 	    }
 
 	    AddressingMode::AddressingIndirectX	=> {
-		let lo: u16 = (self.bus.vram[self.regs.pc as usize] + self.regs.x).into();
-		let addr: u16 = self.bus.vram[lo as usize].into();
-		self.regs.pc += 1;
-		addr
+		let zp: u8 = self.bus.read(self.regs.pc).wrapping_add(self.regs.x);
+		let addr: u16 = self.bus.read_zp_word(zp);
+		self.regs.pc = self.regs.pc.wrapping_add(1);
+		Some(addr)
 	    }
 
 	    AddressingMode::AddressingIndirectY	=> {
-		let lo: u16 = (self.bus.vram[self.regs.pc as usize] + self.regs.y).into();
-		let addr: u16 = self.bus.vram[lo as usize].into();
-		self.regs.pc += 1;
-		addr
+		let zp: u8 = self.bus.read(self.regs.pc);
+		let addr: u16 = self.bus.read_zp_word(zp).wrapping_add(self.regs.y as u16);
+		self.regs.pc = self.regs.pc.wrapping_add(1);
+		Some(addr)
 	    }
 
 	    AddressingMode::AddressingAbsolute	=> {
-		let absolute: u16 = (self.bus.vram[(self.regs.pc + 1) as usize] << 4) as u16
-		    | (self.bus.vram[(self.regs.pc + 2) as usize] as u16);
-		self.regs.pc += 2;
-		absolute
+		let absolute: u16 = self.bus.read_word(self.regs.pc);
+		self.regs.pc = self.regs.pc.wrapping_add(2);
+		Some(absolute)
 	    }
 
 	    AddressingMode::AddressingAbsoluteX	=> {
-		let mut absolute: u16 = (self.bus.vram[(self.regs.pc) as usize] << 4) as u16
-		    | (self.bus.vram[(self.regs.pc + 1) as usize] as u16);
-		absolute = (((absolute & 0xFF00) >> 4) as u8 + self.regs.x) as u16 | (absolute & 0xFF) as u16;
-		self.regs.pc += 2;
-		absolute
+		let absolute: u16 = self.bus.read_word(self.regs.pc)
+		    .wrapping_add(self.regs.x as u16);
+		self.regs.pc = self.regs.pc.wrapping_add(2);
+		Some(absolute)
 	    }
 
 	    AddressingMode::AddressingAbsoluteY	=> {
-		let mut absolute: u16 = (self.bus.vram[(self.regs.pc) as usize] << 4) as u16
-		    | (self.bus.vram[(self.regs.pc + 1) as usize] as u16);
-		absolute = (((absolute & 0xFF00) >> 4) as u8 + self.regs.y) as u16 | (absolute & 0xFF) as u16;
-		self.regs.pc += 2;
-		absolute
+		let absolute: u16 = self.bus.read_word(self.regs.pc)
+		    .wrapping_add(self.regs.y as u16);
+		self.regs.pc = self.regs.pc.wrapping_add(2);
+		Some(absolute)
 	    }
 
 	    AddressingMode::AddressingZeroPage	=> {
-		let zp: u16 = self.bus.vram[self.regs.pc as usize] as u16;
-		self.regs.pc += 1;
-		zp
+		let zp: u16 = self.bus.read(self.regs.pc) as u16;
+		self.regs.pc = self.regs.pc.wrapping_add(1);
+		Some(zp)
 	    }
 
 	    AddressingMode::AddressingZeroPageX	=> {
-		let zp: u16 = (self.bus.vram[self.regs.pc as usize] + self.regs.x)
-		    .try_into()
-		    .unwrap();
-		self.regs.pc += 1;
-		zp
+		let zp: u16 = self.bus.read(self.regs.pc).wrapping_add(self.regs.x)
+		    .into();
+		self.regs.pc = self.regs.pc.wrapping_add(1);
+		Some(zp)
 	    }
 
-	    _ => todo!()
+	    _ => None
 	}
     }
 
-    fn instruction_fetch(self: &mut Self) -> u8 {
-	let instruction = self.bus.vram[self.regs.pc as usize];
-	self.regs.pc = self.regs.pc + 1;
+    fn instruction_fetch(&mut self) -> u8 {
+	let instruction = self.bus.read_as(self.regs.pc, debugger::EXECUTE);
+	self.regs.pc = self.regs.pc.wrapping_add(1);
 	instruction
     }
 
-    fn instruction_execute(self: &mut Self, opcode: u8) -> i32{
+    // None if the CPU can't run `opcode` yet.
+    fn instruction_execute(&mut self, opcode: u8) -> Option<i32> {
 	// https://www.masswerk.at/6502/6502_instruction_set.html
-	let index_of_ins_in_vec = self.ins.iter().position(|ins| ins.opcode == opcode).unwrap();
-	let operand = self.get_operand(index_of_ins_in_vec as u8);
+	let index_of_ins_in_vec = self.ins.iter().position(|ins| ins.opcode == opcode)?;
+	let operand = self.get_operand(index_of_ins_in_vec as u8)?;
 	let ins: &Ins6502 = &self.ins[index_of_ins_in_vec];
 
-	println!("{}, {:#x} {:#x} ({})", ins.mnem, operand, self.bus.vram[operand as usize], ins.addressing_mode.to_string());
+	if self.trace {
+	    println!("{}, {:#x} {:#x} ({})", ins.mnem, operand, self.bus.vram[operand as usize], ins.addressing_mode);
+	}
+
+	// An instruction's own read or write lands on its last cycle, so the
+	// cycles leading up to it are clocked out first.
+	self.cycles += (ins.cycles - 1) as u64;
+	self.bus.tick((ins.cycles - 1).into());
+
+	// CLI, SEI and PLP change the I flag after the IRQ poll, so the old
+	// flag decides whether the IRQ is taken; RTI's restored flag counts
+	// right away.
+	let irq_inhibited = self.regs.p & STATUS_FLAG_I != 0;
+	let restores_flags = ins.mnem == "RTI";
 
 	match &ins.mnem as &str {
-	    "AND" => { self.regs.a = self.regs.a & self.bus.vram[operand as usize]; }
-	    "ASL" => { self.bus.vram[operand as usize] = self.bus.vram[operand as usize] << 1; }
+	    "AND" => { self.regs.a &= self.bus.read(operand); }
+	    "ASL" => { let v = self.bus.read(operand); self.bus.write(operand, v << 1); }
 
 	    "ORA" => {
-		self.regs.a = self.regs.a | operand as u8;
+		self.regs.a |= self.bus.read(operand);
 		self.set_status_bit('a', 'N');
 		self.set_status_bit('a', 'Z');
 	    }
 
 	    "LDA" => { // there's gotta be a better way to do this!
-		self.regs.a = self.bus.vram[operand as usize] as u8;
+		self.regs.a = self.bus.read(operand);
 		self.set_status_bit('a', 'N');
 		self.set_status_bit('a', 'Z');
 	    }
 
 	    "CMP" => {
-		let v: u8 = self.bus.vram[operand as usize];
-		if self.regs.a > v { self.regs.p = self.regs.p | STATUS_FLAG_C; }
+		let v: u8 = self.bus.read(operand);
+		if self.regs.a > v { self.regs.p |= STATUS_FLAG_C; }
 	    }
 
-	    "PHA" => { self.bus.vram[(MEMSTART_STACK_6502 - self.regs.sp as u16) as usize] = self.regs.a; self.regs.sp -= 1; }
-	    "PHP" => { self.bus.vram[(MEMSTART_STACK_6502 - self.regs.sp as u16) as usize] = self.regs.p; self.regs.sp -= 1; }
-	    "PLA" => { self.regs.a = self.bus.vram[(MEMSTART_STACK_6502 - self.regs.sp as u16) as usize]; self.regs.sp += 1; }
-	    "PLP" => { self.regs.p = self.bus.vram[(MEMSTART_STACK_6502 - self.regs.sp as u16) as usize]; self.regs.sp += 1; }
+	    "PHA" => { self.stack_push(self.regs.a); }
+	    "PHP" => { self.stack_push(self.regs.p); }
+	    "PLA" => { self.regs.a = self.stack_pull(); }
+	    "PLP" => { self.regs.p = self.stack_pull(); }
 
-	    "ROL" => { self.bus.vram[operand as usize] = self.bus.vram[operand as usize].rotate_left(1); }
-	    "ROR" => { self.bus.vram[operand as usize] = self.bus.vram[operand as usize].rotate_right(1); }
+	    "ROL" => { let v = self.bus.read(operand); self.bus.write(operand, v.rotate_left(1)); }
+	    "ROR" => { let v = self.bus.read(operand); self.bus.write(operand, v.rotate_right(1)); }
 
-	    "NOP" => { println!("!NOP!"); }
+	    "NOP" => { if self.trace { println!("!NOP!"); } }
 
 	    "JMP" => { self.regs.pc = operand; }
-	    "STX" => { self.bus.vram[operand as usize] = self.regs.x; }
-	    "STY" => { self.bus.vram[operand as usize] = self.regs.y; }
-	    "STA" => { self.bus.vram[operand as usize] = self.regs.a; }
-
-	    "BRK" => { return 0; }
+	    "JSR" => {
+		// the return address pushed is that of JSR's last byte
+		let ret = self.regs.pc.wrapping_sub(1);
+		self.stack_push((ret >> 8) as u8);
+		self.stack_push(ret as u8);
+		self.regs.pc = operand;
+	    }
+	    "RTS" => {
+		let lo: u16 = self.stack_pull() as u16;
+		let hi: u16 = self.stack_pull() as u16;
+		self.regs.pc = ((hi << 8) | lo).wrapping_add(1);
+	    }
+	    "STX" => { self.bus.write(operand, self.regs.x); }
+	    "STY" => { self.bus.write(operand, self.regs.y); }
+	    "STA" => { self.bus.write(operand, self.regs.a); }
+
+	    "BRK" => { return Some(0); }
+	    "RTI" => {
+		self.regs.p = self.stack_pull();
+		let lo: u16 = self.stack_pull() as u16;
+		let hi: u16 = self.stack_pull() as u16;
+		self.regs.pc = (hi << 8) | lo;
+	    }
 
-	    "CLC" => { self.regs.p = self.regs.p & !STATUS_FLAG_C; }
-	    "CLD" => { self.regs.p = self.regs.p & !STATUS_FLAG_D; }
-	    "CLI" => { self.regs.p = self.regs.p & !STATUS_FLAG_I; }
-	    "CLV" => { self.regs.p = self.regs.p & !STATUS_FLAG_V; }
+	    "CLC" => { self.regs.p &= !STATUS_FLAG_C; }
+	    "CLD" => { self.regs.p &= !STATUS_FLAG_D; }
+	    "CLI" => { self.regs.p &= !STATUS_FLAG_I; }
+	    "CLV" => { self.regs.p &= !STATUS_FLAG_V; }
+	    "SEI" => { self.regs.p |= STATUS_FLAG_I; }
 
 	    "TAX" => { self.regs.x = self.regs.a; }
 	    "TAY" => { self.regs.y = self.regs.a; }
@@ -357,40 +537,225 @@ impl	Cpu6502 {
 	    "TXS" => { self.regs.sp = self.regs.x;}
 	    "TYA" => { self.regs.a = self.regs.y;}
 
-	    
-	    "LDX" => { self.regs.x = self.bus.vram[operand as usize] as u8; }
-	    "LDY" => { self.regs.y = self.bus.vram[operand as usize] as u8; }
 
-	    "INC" => { self.bus.vram[operand as usize] = self.bus.vram[operand as usize] + 1 }
-	    "INX" => { self.regs.x = self.regs.x + 1 }
-	    "INY" => { self.regs.y = self.regs.y + 1 }
+	    "LDX" => { self.regs.x = self.bus.read(operand); }
+	    "LDY" => { self.regs.y = self.bus.read(operand); }
 
-	    _ => todo!()
+	    "INC" => { let v = self.bus.read(operand); self.bus.write(operand, v.wrapping_add(1)); }
+	    "INX" => { self.regs.x = self.regs.x.wrapping_add(1) }
+	    "INY" => { self.regs.y = self.regs.y.wrapping_add(1) }
+
+	    // every mnemonic in the table is handled above
+	    _ => return None
+	}
+
+	// NMI is sampled before the last cycle: an edge that shows up during
+	// it is only serviced after the next instruction.
+	if self.bus.ppu.take_nmi() {
+	    self.nmi_pending = true;
 	}
-	return 1;
+	let irq_inhibited = if restores_flags { self.regs.p & STATUS_FLAG_I != 0 } else { irq_inhibited };
+	self.irq_pending = self.bus.irq() && !irq_inhibited;
+	self.cycles += 1;
+	self.bus.tick(1);
+	Some(1)
     }
 
-    fn run(self: &mut Self){
+    fn interrupt(&mut self, vector: u16) {
+	self.stack_push((self.regs.pc >> 8) as u8);
+	self.stack_push(self.regs.pc as u8);
+	self.stack_push((self.regs.p | STATUS_FLAG_1) & !STATUS_FLAG_B);
+	self.regs.p |= STATUS_FLAG_I;
+	self.regs.pc = self.bus.read_word(vector);
+	self.clock(7);
+    }
+
+    fn clock(&mut self, cycles: u32) {
+	self.cycles += cycles as u64;
+	self.bus.tick(cycles);
+    }
+
+    fn oam_dma(&mut self, page: u8) {
+	// one cycle to halt the CPU, another to line up on an even cycle
+	let stall: u32 = 1 + (self.cycles % 2) as u32;
+	self.clock(stall);
+	for i in 0..=0xFF {
+	    let value = self.bus.read(((page as u16) << 8) | i);
+	    self.bus.ppu.write_oam(value);
+	    self.clock(2);
+	}
+    }
+
+    // The DMC takes the bus for its sample fetch, halting the CPU for up
+    // to four cycles.
+    fn dmc_fetch(&mut self, addr: u16) {
+	self.clock(3);
+	let value = self.bus.read(addr);
+	self.bus.apu.dmc_fill(value);
+	self.clock(1);
+    }
+
+    fn reset(&mut self) {
+	self.bus.apu.reset();
+	self.regs.sp = self.regs.sp.wrapping_sub(3);
+	self.regs.p |= STATUS_FLAG_I;
+	self.regs.pc = self.bus.read_word(VECTOR_RESET_6502);
+	self.clock(7);
+    }
+
+    // Runs one instruction and any interrupt after it. 0 on BRK; an
+    // opcode the CPU doesn't run yet is an error, with PC left on it.
+    fn step(&mut self) -> Result<i32, String> {
+	let pc = self.regs.pc;
+	let op = self.instruction_fetch();
+	let Some(status) = self.instruction_execute(op) else {
+	    self.regs.pc = pc;
+	    return Err(format!("unimplemented opcode ${:02X} at ${:04X}", op, pc));
+	};
+
+	if let Some(page) = self.bus.oam_dma.take() {
+	    self.oam_dma(page);
+	}
+	if let Some(addr) = self.bus.apu.dmc_fetch_request() {
+	    self.dmc_fetch(addr);
+	}
+	if status != 0 && self.nmi_pending {
+	    self.nmi_pending = false;
+	    self.interrupt(VECTOR_NMI_6502);
+	}
+	else if status != 0 && self.irq_pending {
+	    self.irq_pending = false;
+	    self.interrupt(VECTOR_IRQ_6502);
+	}
+	Ok(status)
+    }
+
+    fn run(&mut self){
 	println!("Executing...\n");
 	loop {
-	    let op = self.instruction_fetch();
-	    let status = self.instruction_execute(op);
-	    if status == 0 {
-		return;
+	    match self.step() {
+		Ok(0) => return,
+		Ok(_) => {}
+		Err(e) => {
+		    eprintln!("{}", e);
+		    return;
+		}
+	    }
+	}
+    }
+
+    // Runs until the PPU has a complete picture. False if execution stopped
+    // on a BRK first.
+    fn run_frame(&mut self) -> Result<bool, String> {
+	loop {
+	    if self.step()? == 0 {
+		return Ok(false);
+	    }
+	    if self.bus.ppu.take_frame() {
+		self.bus.apu.end_frame();
+		return Ok(true);
 	    }
 	}
     }
 
-    fn load(self: &mut Self, rom_buff: &Vec<u8>){
-	self.bus.vram[MEMSTART_CARTRIDGE_ROM_6502 as usize .. (MEMSTART_CARTRIDGE_ROM_6502 as usize + rom_buff.len())].copy_from_slice(&rom_buff[..]);
+    fn load(&mut self, rom_buff: &[u8]){
+	self.bus.vram[MEMSTART_CARTRIDGE_ROM_6502 as usize .. (MEMSTART_CARTRIDGE_ROM_6502 as usize + rom_buff.len())].copy_from_slice(rom_buff);
+    }
+
+    /// Turns the console off and on again. RAM and the chips start over;
+    /// the cartridge, what's plugged into the ports and the audio output
+    /// settings stay.
+    fn power_cycle(&mut self) {
+	self.bus.vram[..MEMSIZE_RAM_6502 as usize].fill(0);
+	self.bus.ppu = Ppu::new();
+	self.bus.ppu.set_region(self.bus.region);
+	if let Some(cartridge) = &self.bus.cartridge {
+	    self.bus.ppu.load_chr(&cartridge.chr_rom, cartridge.mirroring);
+	}
+	self.bus.apu.power_on();
+	self.bus.open_bus = 0;
+	self.bus.oam_dma = None;
+	self.bus.ppu_clock = 0;
+	self.regs = Regs6502 { pc: 0, sp: 0, p: 0, a: 0, x: 0, y: 0 };
+	self.nmi_pending = false;
+	self.irq_pending = false;
+	self.reset();
+    }
+
+    fn set_region(&mut self, region: Region) {
+	self.bus.region = region;
+	self.bus.ppu.set_region(region);
+	self.bus.apu.set_region(region);
+    }
+
+    /// The buttons held on `player`'s joypad, counting from 0, until
+    /// they're set again; `controller::BUTTON_*` or'ed together. Players
+    /// without a joypad plugged in are ignored.
+    fn set_buttons(&mut self, player: usize, buttons: u8) {
+	if let Some(joypad) = self.bus.input.joypad(player) {
+	    joypad.set_buttons(buttons);
+	}
+    }
+
+    fn rom_md5(&self) -> [u8; 16] {
+	self.bus.cartridge.as_ref().map_or([0; 16], |cartridge| cartridge.md5())
+    }
+
+    /// The whole machine as a save state, see savestate.rs.
+    fn save_state(&mut self) -> Vec<u8> {
+	let rom = self.rom_md5();
+	savestate::save(self, rom)
+    }
+
+    /// Loads a state made by `save_state` with the same cartridge in.
+    fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+	let rom = self.rom_md5();
+	savestate::load(self, rom, data)
+    }
+
+    fn insert_cartridge(&mut self, cartridge: Cartridge) {
+	self.set_region(cartridge.region);
+	self.bus.ppu.load_chr(&cartridge.chr_rom, cartridge.mirroring);
+	self.bus.cartridge = Some(cartridge);
+    }
+}
+
+// The instruction table and tracing belong to the emulator rather than
+// the machine, and the controller ports to the setup.
+impl Snapshot for Cpu6502 {
+    fn snapshot(&mut self, state: &mut State) {
+	state.u16(&mut self.regs.pc);
+	state.u8(&mut self.regs.sp);
+	state.u8(&mut self.regs.p);
+	state.u8(&mut self.regs.a);
+	state.u8(&mut self.regs.x);
+	state.u8(&mut self.regs.y);
+	state.u64(&mut self.cycles);
+	state.bool(&mut self.nmi_pending);
+	state.bool(&mut self.irq_pending);
+
+	let mut region = self.bus.region;
+	region.snapshot(state);
+	if region != self.bus.region {
+	    self.set_region(region);
+	}
+	state.bytes(&mut self.bus.vram);
+	state.u8(&mut self.bus.open_bus);
+	state.option_u8(&mut self.bus.oam_dma);
+	state.u32(&mut self.bus.ppu_clock);
+	self.bus.ppu.snapshot(state);
+	self.bus.apu.snapshot(state);
+	if let Some(cartridge) = &mut self.bus.cartridge {
+	    cartridge.snapshot(state);
+	}
     }
 }
 
 fn	file_to_u8_vector(filename: &String) -> Vec<u8> {
-    let mut f = fs::File::open(&filename).expect("File not found.");
-    let metadata = fs::metadata(&filename).expect("Unable to read the file's metadata.");
+    let mut f = fs::File::open(filename).expect("File not found.");
+    let metadata = fs::metadata(filename).expect("Unable to read the file's metadata.");
     let mut buffer = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).expect("Buffer overflow.");
+    f.read_exact(&mut buffer).expect("Buffer overflow.");
 
     buffer
 }
@@ -398,12 +763,34 @@ fn	file_to_u8_vector(filename: &String) -> Vec<u8> {
 fn	main() {
     println!("Welcome to RustyOldNes - A NES emulator, written in Rust.");
     let args: Vec<String> = args().collect();
+    let options = match cli::parse(&args[1..]) {
+	Ok(options) => options,
+	Err(e) => {
+	    eprintln!("{}\n\n{}", e, cli::USAGE);
+	    process::exit(1);
+	}
+    };
+
+    if options.disasm {
+	if let Err(e) = disasm::run(&options) {
+	    eprintln!("{}", e);
+	    process::exit(1);
+	}
+	return;
+    }
+
+    if options.headless {
+	if let Err(e) = headless::run(&options) {
+	    eprintln!("{}", e);
+	    process::exit(1);
+	}
+	println!("Done! See ya.");
+	return;
+    }
 
-    let rom_filename;
     let rom_buff;
 
-    if args.len() > 1 {
-	rom_filename = &args[1];
+    if let Some(rom_filename) = &options.rom {
 	println!("Reading contents of {}...", rom_filename);
 	rom_buff = file_to_u8_vector(rom_filename);
     }
@@ -412,6 +799,27 @@ fn	main() {
     }
 
     let mut cpu = Cpu6502::new();
+    if options.debug || options.gdb.is_some() {
+	// the debuggers print instructions themselves, and want iNES files
+	// mapped the way the headless mode does
+	cpu.trace = false;
+	match Cartridge::from_ines(&rom_buff) {
+	    Ok(cartridge) => {
+		cpu.insert_cartridge(cartridge);
+		cpu.reset();
+	    }
+	    Err(_) => cpu.load(&rom_buff),
+	}
+	let result = match options.gdb {
+	    Some(port) => TcpListener::bind(("127.0.0.1", port)).and_then(|listener| gdb::run(&mut cpu, listener)),
+	    None => debugger::run(&mut cpu, io::stdin().lock(), io::stdout()),
+	};
+	if let Err(e) = result {
+	    eprintln!("{}", e);
+	    process::exit(1);
+	}
+	return;
+    }
     cpu.load(&rom_buff);
 
     println!("Entering CPU loop!");
@@ -423,11 +831,18 @@ fn	main() {
 #[cfg(test)]
 mod tests{
     use super::*;
+    use crate::controller::Device;
+
+    // `source` assembled at $8000, where load() puts programs
+    fn assemble(source: &str) -> Vec<u8> {
+	let segments = asm::assemble(source, 0x8000).unwrap();
+	segments.into_iter().flat_map(|segment| segment.bytes).collect()
+    }
 
     #[test]
     fn test_0xa9_lda_immediate() {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec!(0xa9, 0x05, 0x00);
+	let rom_buff = vec!(0xa9, 0x05, 0x00);
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -440,7 +855,7 @@ mod tests{
     #[test]
     fn test_0xa9_lda_immediate_zero_flag() {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa9, 0x00, 0x00];
+	let rom_buff = vec![0xa9, 0x00, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -450,7 +865,7 @@ mod tests{
     #[test]
     fn test_0xa5_lda_zp() {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec!(0xa2, 0x2a, // load 42 into x
+	let rom_buff = vec!(0xa2, 0x2a, // load 42 into x
 				0x86, 0x01, // load x at mem[1]
 				0xa5, 0x01, // load mem[0] into a
 				0x00);
@@ -464,7 +879,7 @@ mod tests{
     #[test]
     fn test_0xb5_lda_zpx() {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec!(0xa2, 0x2a, // load 42 into x
+	let rom_buff = vec!(0xa2, 0x2a, // load 42 into x
 				0x86, 0x01, // load x at mem[1]
 				0xa2, 0x00, // load 42 into x
 				0xB5, 0x01, // load mem[1] into a
@@ -479,7 +894,7 @@ mod tests{
     #[test]
     fn test_0xa2_ldx_immediate () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa2, 0xc0, 0x00];
+	let rom_buff = vec![0xa2, 0xc0, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -489,7 +904,7 @@ mod tests{
     #[test]
     fn test_0xa0_ldy_immediate () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa0, 0xc0, 0x00];
+	let rom_buff = vec![0xa0, 0xc0, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -499,7 +914,7 @@ mod tests{
     #[test]
     fn test_0x8a_txa_implied () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa2, 0xc0, 0x8a, 0x00];
+	let rom_buff = vec![0xa2, 0xc0, 0x8a, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -509,7 +924,7 @@ mod tests{
     #[test]
     fn test_0xaa_tax_implied () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa9, 0xc0, 0xaa, 0x00];
+	let rom_buff = vec![0xa9, 0xc0, 0xaa, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -519,7 +934,7 @@ mod tests{
     #[test]
     fn test_0x86_stx_zp () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa2, 0x03, 0x86, 0x01, 0x00];
+	let rom_buff = vec![0xa2, 0x03, 0x86, 0x01, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -529,7 +944,7 @@ mod tests{
     #[test]
     fn test_0x84_sty_zp () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa0, 0x03, 0x84, 0x01, 0x00];
+	let rom_buff = vec![0xa0, 0x03, 0x84, 0x01, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -539,7 +954,7 @@ mod tests{
     #[test]
     fn test_0x85_sta_zp () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa9, 0x03, 0x85, 0x01, 0x00];
+	let rom_buff = vec![0xa9, 0x03, 0x85, 0x01, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
@@ -549,7 +964,7 @@ mod tests{
     #[test]
     fn test_0x95_sta_zpx () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa9, 0x03, // lda 3
+	let rom_buff = vec![0xa9, 0x03, // lda 3
 				0xa2, 0x03, // ldx 3
 				0x95, 0x01, // sta mem[x + 1]
 				0x00];
@@ -575,13 +990,13 @@ mod tests{
     #[test]
     fn test_0x01_ora_ind_x () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xa2, 0x01, // load 1 into x     
-				0x86, 0x01, // write x to mem[1]
+	let rom_buff = vec![0xa2, 0x02, // load 2 into x
+				0x86, 0x02, // write x to mem[2]
 				0xa2, 0x02, // load 2 into x
 				0x86, 0x24, // write x to mem[24]
 				0xa2, 0x04, // load 4 into X
 				0xa9, 0x01, // load 1 in a
-				0x01, 0x20, // a -> a | mem[mem[x + 20]]
+				0x01, 0x20, // a -> a | mem[word at mem[x + 20]]
 				0x00];	    // brk
 
 	cpu.load(&rom_buff);
@@ -592,11 +1007,334 @@ mod tests{
     #[test]
     fn test_e8_c8_inxy_implied () {
 	let mut cpu = Cpu6502::new();
-	let mut rom_buff = vec![0xe8, 0xc8, 0x00];
+	let rom_buff = vec![0xe8, 0xc8, 0x00];
 
 	cpu.load(&rom_buff);
 	cpu.run();
 	assert!(cpu.regs.x == 0x1);
 	assert!(cpu.regs.y == 0x1);
     }
+
+    #[test]
+    fn test_inxy_wrap () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa2, 0xff,         // ldx #$ff
+				0xa0, 0xff,         // ldy #$ff
+				0xe8, 0xc8, 0x00];  // inx, iny
+
+	cpu.load(&rom_buff);
+	cpu.run();
+	assert_eq!((cpu.regs.x, cpu.regs.y), (0x00, 0x00));
+    }
+
+    #[test]
+    fn test_ora_zero_page () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa9, 0x0f, 0x85, 0x10, // lda #$0f ; sta $10
+				0xa9, 0x30,         // lda #$30
+				0x05, 0x10,         // ora $10
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.run();
+	assert_eq!(cpu.regs.a, 0x3f);
+	assert_eq!(cpu.regs.p & (STATUS_FLAG_N | STATUS_FLAG_Z), 0);
+    }
+
+    #[test]
+    fn test_jmp_indirect () {
+	let mut cpu = Cpu6502::new();
+	cpu.load(&[0x6c, 0x00, 0x03]); // jmp ($0300)
+	cpu.bus.vram[0x300..0x302].copy_from_slice(&[0x34, 0x12]);
+	cpu.step().unwrap();
+	assert_eq!(cpu.regs.pc, 0x1234);
+
+	// a pointer at the end of a page takes its high byte from the start
+	let mut cpu = Cpu6502::new();
+	cpu.load(&[0x6c, 0xff, 0x03]); // jmp ($03ff)
+	cpu.bus.vram[0x3ff] = 0x78;
+	cpu.bus.vram[0x300] = 0x56;
+	cpu.bus.vram[0x400] = 0x12;
+	cpu.step().unwrap();
+	assert_eq!(cpu.regs.pc, 0x5678);
+    }
+
+    #[test]
+    fn test_indirect_x () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa9, 0x05, 0x85, 0x14, // lda #$05 ; sta $14
+				0xa9, 0x03, 0x85, 0x15, // lda #$03 ; sta $15
+				0xa9, 0x5a, 0x8d, 0x05, 0x03, // lda #$5a ; sta $0305
+				0xa2, 0x04,         // ldx #$04
+				0xa9, 0x00,         // lda #$00
+				0xa1, 0x10,         // lda ($10,x)
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.run();
+	assert_eq!(cpu.regs.a, 0x5a);
+    }
+
+    #[test]
+    fn test_indirect_y () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa9, 0x00, 0x85, 0x10, // lda #$00 ; sta $10
+				0xa9, 0x03, 0x85, 0x11, // lda #$03 ; sta $11
+				0xa9, 0x5a, 0x8d, 0x05, 0x03, // lda #$5a ; sta $0305
+				0xa9, 0xff, 0x85, 0xff, // lda #$ff ; sta $ff
+				0xa9, 0x02, 0x85, 0x00, // lda #$02 ; sta $00
+				0xa9, 0xa5, 0x8d, 0x04, 0x03, // lda #$a5 ; sta $0304
+				0xa0, 0x05,         // ldy #$05
+				0xb1, 0x10,         // lda ($10),y
+				0x8d, 0x00, 0x02,   // sta $0200
+				0xb1, 0xff,         // lda ($ff),y
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.run();
+	// $0300 + 5, and $02FF + 5 with the pointer's high byte from $00
+	assert_eq!(cpu.bus.vram[0x200], 0x5a);
+	assert_eq!(cpu.regs.a, 0xa5);
+    }
+
+    #[test]
+    fn test_unimplemented_opcode () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa2, 0x01,         // ldx #$01
+				0x96, 0x10,         // stx $10,y has no zero page,y yet
+				0x02];
+
+	cpu.load(&rom_buff);
+	cpu.step().unwrap();
+	assert_eq!(cpu.step(), Err("unimplemented opcode $96 at $8002".to_string()));
+	assert_eq!(cpu.regs.pc, 0x8002);
+	cpu.regs.pc = 0x8004;
+	assert_eq!(cpu.step(), Err("unimplemented opcode $02 at $8004".to_string()));
+	assert_eq!(cpu.regs.pc, 0x8004);
+    }
+
+    #[test]
+    fn test_opcode_table () {
+	let cpu = Cpu6502::new();
+	assert_eq!(cpu.ins.len(), CPU_OPCODES.len());
+	// every other official opcode stops the CPU where it is
+	for &(opcode, _, _, _) in opcodes::OPCODES.iter().filter(|(opcode, _, _, _)| !CPU_OPCODES.contains(opcode)) {
+	    let mut cpu = Cpu6502::new();
+	    cpu.trace = false;
+	    cpu.load(&[opcode, 0x10, 0x00]);
+	    assert_eq!(cpu.step(), Err(format!("unimplemented opcode ${:02X} at $8000", opcode)));
+	    assert_eq!(cpu.regs.pc, 0x8000);
+	}
+    }
+
+    #[test]
+    fn test_nmi_on_vblank () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa9, 0x80,         // lda #$80
+				0x8d, 0x00, 0x20,   // sta $2000, NMI on vblank
+				0x4c, 0x05, 0x80,   // jmp $8005
+				0xa2, 0x2a,         // nmi: ldx #$2a
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.bus.vram[VECTOR_NMI_6502 as usize] = 0x08;
+	cpu.bus.vram[VECTOR_NMI_6502 as usize + 1] = 0x80;
+	cpu.run();
+	assert!(cpu.regs.x == 0x2a);
+	assert!(cpu.regs.p & STATUS_FLAG_I != 0);
+	// return address of the jmp loop, then the status register
+	assert!(cpu.bus.vram[0x100] == 0x80);
+	assert!(cpu.bus.vram[0x1FF] == 0x05);
+	assert!(cpu.bus.vram[0x1FE] & STATUS_FLAG_1 != 0);
+	assert!(cpu.bus.vram[0x1FE] & STATUS_FLAG_B == 0);
+    }
+
+    #[test]
+    fn test_dmc_irq () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa9, 0x8f,         // lda #$8f
+				0x8d, 0x10, 0x40,   // sta $4010, IRQ at the end of the sample
+				0xa9, 0x10,         // lda #$10
+				0x8d, 0x15, 0x40,   // sta $4015, start it
+				0x4c, 0x0a, 0x80,   // jmp $800a
+				0xa2, 0x2a,         // irq: ldx #$2a
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.bus.vram[VECTOR_IRQ_6502 as usize] = 0x0d;
+	cpu.bus.vram[VECTOR_IRQ_6502 as usize + 1] = 0x80;
+	cpu.run();
+	assert!(cpu.regs.x == 0x2a);
+	assert!(cpu.regs.p & STATUS_FLAG_I != 0);
+    }
+
+    #[test]
+    fn test_sei_masks_irq () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0x78,               // sei
+				0xa9, 0x8f,         // lda #$8f
+				0x8d, 0x10, 0x40,   // sta $4010
+				0xa9, 0x10,         // lda #$10
+				0x8d, 0x15, 0x40,   // sta $4015
+				0x4c, 0x0b, 0x80,   // jmp $800b
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.trace = false;
+	for _ in 0..100 {
+	    cpu.step().unwrap();
+	}
+	assert!(cpu.bus.irq());
+	assert!(cpu.regs.pc == 0x800b);
+    }
+
+    #[test]
+    fn test_jsr_rts () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = assemble("
+		    jsr sub
+		    ldx #$05
+		    .byte 0, 0
+	    sub:    lda #$2a
+		    rts
+	");
+
+	cpu.load(&rom_buff);
+	cpu.trace = false;
+	cpu.regs.sp = 0xFD;
+	cpu.step().unwrap();
+	assert_eq!(cpu.regs.pc, 0x8007);
+	assert_eq!(cpu.bus.vram[0x1FD], 0x80);
+	assert_eq!(cpu.bus.vram[0x1FC], 0x02);
+	cpu.run();
+	assert_eq!(cpu.regs.a, 0x2a);
+	assert_eq!(cpu.regs.x, 0x05);
+	assert_eq!(cpu.regs.sp, 0xFD);
+    }
+
+    #[test]
+    fn test_save_state () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0x20, 0x07, 0x80,   // jsr $8007
+			    0xa2, 0x05,         // ldx #$05
+			    0x00, 0x00,
+			    0xa9, 0x2a,         // lda #$2a
+			    0x60];              // rts
+
+	cpu.load(&rom_buff);
+	cpu.trace = false;
+	cpu.regs.sp = 0xFD;
+	cpu.step().unwrap();
+	let state = cpu.save_state();
+	cpu.run();
+	assert_eq!(cpu.regs.x, 0x05);
+	let cycles = cpu.cycles;
+
+	cpu.load_state(&state).unwrap();
+	assert_eq!(cpu.regs.pc, 0x8007);
+	assert_eq!(cpu.regs.x, 0x00);
+	assert_eq!(cpu.bus.vram[0x1FC], 0x02);
+	cpu.run();
+	assert_eq!((cpu.regs.a, cpu.regs.x, cpu.cycles), (0x2a, 0x05, cycles));
+
+	// a state made with no cartridge in doesn't go into one with
+	let mut other = Cpu6502::new();
+	other.insert_cartridge(Cartridge::from_nsf(&[0; 0x100], 0x8000, None, Region::Ntsc).unwrap());
+	assert!(other.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_joypad_reads () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa9, 0x01,         // lda #$01
+				0x8d, 0x16, 0x40,   // sta $4016
+				0xa9, 0x00,         // lda #$00
+				0x8d, 0x16, 0x40,   // sta $4016
+				0xad, 0x16, 0x40,   // lda $4016
+				0x85, 0x00,         // sta $00
+				0xad, 0x16, 0x40,   // lda $4016
+				0x85, 0x01,         // sta $01
+				0xad, 0x17, 0x40,   // lda $4017
+				0x85, 0x02,         // sta $02
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.trace = false;
+	cpu.set_buttons(0, controller::BUTTON_B);
+	cpu.set_buttons(1, controller::BUTTON_A);
+	cpu.run();
+	// the upper bits are the $40 left on the bus by the address
+	assert_eq!(cpu.bus.vram[0x00], 0x40);
+	assert_eq!(cpu.bus.vram[0x01], 0x41);
+	assert_eq!(cpu.bus.vram[0x02], 0x41);
+    }
+
+    #[test]
+    fn test_zapper_in_port_2 () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xad, 0x17, 0x40,   // lda $4017
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.trace = false;
+	cpu.bus.input.connect(Device::Zapper);
+	cpu.bus.input.ports[1].zapper().unwrap().trigger = true;
+	cpu.run();
+	// trigger pulled, no light
+	assert_eq!(cpu.regs.a, 0x40 | 0x10 | 0x08);
+    }
+
+    #[test]
+    fn test_pal_frame_length () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0x78,               // sei, no frame IRQs
+				0x4c, 0x01, 0x80];  // jmp $8001
+
+	cpu.load(&rom_buff);
+	cpu.trace = false;
+	cpu.set_region(Region::Pal);
+	assert_eq!(cpu.run_frame(), Ok(true));
+	let start = cpu.cycles;
+	assert_eq!(cpu.run_frame(), Ok(true));
+	// 341 * 312 dots at 3.2 dots per cycle, give or take a jmp
+	let cycles = cpu.cycles - start;
+	assert!((33245..=33251).contains(&cycles), "{}", cycles);
+    }
+
+    #[test]
+    fn test_nmi_enabled_during_vblank_is_delayed () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xa9, 0x80,         // lda #$80
+				0x8d, 0x00, 0x20,   // sta $2000
+				0xa2, 0x01,         // ldx #$01, still runs
+				0xa0, 0x02,         // ldy #$02, interrupted
+				0x00];
+
+	cpu.load(&rom_buff);
+	cpu.bus.vram[VECTOR_NMI_6502 as usize] = 0x09;
+	cpu.bus.vram[VECTOR_NMI_6502 as usize + 1] = 0x80;
+	// into vblank with NMI still disabled
+	for _ in 0..(242 * 341) {
+	    cpu.bus.ppu.tick();
+	}
+	cpu.run();
+	assert!(cpu.regs.x == 0x01);
+	assert!(cpu.regs.y == 0x00);
+	assert!(cpu.bus.vram[0x1FF] == 0x07);
+    }
+
+    #[test]
+    fn test_0xad_lda_abs_ppustatus () {
+	let mut cpu = Cpu6502::new();
+	let rom_buff = vec![0xad, 0x02, 0x20,   // lda $2002
+				0x00];
+
+	cpu.load(&rom_buff);
+	for _ in 0..(242 * 341) {
+	    cpu.bus.ppu.tick();
+	}
+	cpu.run();
+	assert!(cpu.regs.a & 0x80 == 0x80);
+	assert!(cpu.regs.p & STATUS_FLAG_N != 0);
+    }
 }
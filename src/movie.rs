@@ -0,0 +1,415 @@
+use std::fmt;
+use std::fs;
+
+use crate::bk2;
+use crate::cartridge::Cartridge;
+use crate::controller;
+use crate::hash;
+
+// Joypad input per frame, from a movie file or recorded to one. The FCEUX
+// format is read and written here, BizHawk's is read by bk2.rs, and plain
+// text input scripts are read by `Movie::parse_script`.
+//
+// FCEUX's text movie format: "key value" header lines, then one line of
+// input per frame, "|commands|port 0|port 1|port 2|". The gamepad fields
+// are a character per button, '.' or ' ' when it's up. With a Four Score
+// there are four of them before the (empty) port 2 field.
+// https://fceux.com/web/FM2.html
+
+const FM2_VERSION: &str = "3";
+// the FCEUX release whose movies these are written like, 2.2.2
+const FM2_EMU_VERSION: &str = "22020";
+
+const COMMAND_SOFT_RESET: u8 = 0x01;
+const COMMAND_HARD_RESET: u8 = 0x02;
+
+// FCEUX's input device numbers for port0 and port1
+const SI_NONE: &str = "0";
+const SI_GAMEPAD: &str = "1";
+
+// one character per button, from BUTTON_RIGHT down to BUTTON_A
+const GAMEPAD_FIELD: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum	Reset {
+    Soft,	// the reset button
+    Hard,	// off and on again
+}
+
+impl	Reset {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+	match name.to_ascii_lowercase().as_str() {
+	    "soft" => Ok(Reset::Soft),
+	    "hard" => Ok(Reset::Hard),
+	    _ => Err(format!("unknown reset {}, expected soft or hard", name)),
+	}
+    }
+}
+
+/// How a movie names the ROM it was recorded on: a hash of its PRG and
+/// CHR ROM, MD5 for FCEUX and SHA-1 for BizHawk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum	RomHash {
+    Md5([u8; 16]),
+    Sha1([u8; 20]),
+}
+
+impl	RomHash {
+    fn bytes(&self) -> &[u8] {
+	match self {
+	    RomHash::Md5(digest) => digest,
+	    RomHash::Sha1(digest) => digest,
+	}
+    }
+}
+
+/// A frame's worth of input: a reset before the frame runs, and the
+/// buttons held on each joypad while it does.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct	MovieFrame {
+    pub reset:	Option<Reset>,
+    pub buttons:	[u8; 4],
+}
+
+#[derive(Debug, PartialEq)]
+pub struct	Movie {
+    pub rom_filename:	String,
+    pub rom_hash:	Option<RomHash>,	// scripts don't have one
+    pub pal:	bool,
+    pub four_score:	bool,
+    pub comments:	Vec<String>,
+    pub frames:	Vec<MovieFrame>,
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+	let group = chunk.iter().enumerate().fold(0u32, |group, (i, b)| group | (*b as u32) << (16 - 8 * i));
+	for i in 0..4 {
+	    if i <= chunk.len() {
+		out.push(BASE64[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+	    }
+	    else {
+		out.push('=');
+	    }
+	}
+    }
+    out
+}
+
+fn base64_decode(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let (mut group, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+	let value = BASE64.iter().position(|b| *b == c).ok_or(format!("'{}' is not base64", s))?;
+	group = group << 6 | value as u32;
+	bits += 6;
+	if bits >= 8 {
+	    bits -= 8;
+	    bytes.push((group >> bits) as u8);
+	}
+    }
+    Ok(bytes)
+}
+
+fn parse_gamepad(field: &str) -> Result<u8, String> {
+    if field.len() != GAMEPAD_FIELD.len() {
+	return Err(format!("'{}' is not a gamepad field", field));
+    }
+    Ok(field.bytes().enumerate()
+       .filter(|(_, c)| *c != b'.' && *c != b' ')
+       .fold(0, |buttons, (i, _)| buttons | 0x80 >> i))
+}
+
+fn gamepad_field(buttons: u8) -> String {
+    GAMEPAD_FIELD.iter().enumerate()
+	.map(|(i, c)| if buttons & 0x80 >> i != 0 { *c as char } else { '.' })
+	.collect()
+}
+
+impl	Movie {
+    pub fn	new(rom_filename: &str, rom_hash: Option<RomHash>) -> Self {
+	Movie {
+	    rom_filename: rom_filename.to_string(),
+	    rom_hash,
+	    pal: false,
+	    four_score: false,
+	    comments: Vec::new(),
+	    frames: Vec::new(),
+	}
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+	let mut movie = Movie::new("", None);
+	let mut checksum = None;
+	let mut version = None;
+	for (number, line) in text.lines().enumerate() {
+	    let error = |e: String| format!("line {}: {}", number + 1, e);
+	    let line = line.trim_end_matches('\r');
+	    if line.starts_with('|') {
+		movie.frames.push(movie.parse_frame(line).map_err(error)?);
+		continue;
+	    }
+	    let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+	    match key {
+		"version" => version = Some(value.to_string()),
+		"palFlag" => movie.pal = value == "1",
+		"fourscore" => movie.four_score = value == "1",
+		"romFilename" => movie.rom_filename = value.to_string(),
+		"comment" => movie.comments.push(value.to_string()),
+		"romChecksum" => {
+		    let digest = value.strip_prefix("base64:").map(base64_decode).transpose().map_err(error)?;
+		    checksum = Some(digest.and_then(|digest| digest.try_into().ok())
+				    .ok_or(error(format!("unknown romChecksum {}", value)))?);
+		}
+		"port0" | "port1" if value != SI_NONE && value != SI_GAMEPAD => {
+		    return Err(error(format!("only gamepads are supported, {} is device {}", key, value)));
+		}
+		"port2" | "FDS" if value != "0" => {
+		    return Err(error(format!("{} {} is not supported", key, value)));
+		}
+		_ => {}
+	    }
+	}
+	if version.as_deref() != Some(FM2_VERSION) {
+	    return Err(format!("not an FM2 version {} movie", FM2_VERSION));
+	}
+	movie.rom_hash = Some(RomHash::Md5(checksum.ok_or("the movie has no romChecksum")?));
+	Ok(movie)
+    }
+
+    // |1|R..U...A|........||
+    fn parse_frame(&self, line: &str) -> Result<MovieFrame, String> {
+	let fields: Vec<&str> = line.split('|').collect();
+	let gamepads = if self.four_score { 4 } else { 2 };
+	if fields.len() < 3 + gamepads {
+	    return Err(format!("'{}' has too few fields", line));
+	}
+	let commands: u8 = fields[1].trim().parse().map_err(|_| format!("'{}' is not a command", fields[1]))?;
+	let mut frame = MovieFrame::default();
+	if commands & COMMAND_HARD_RESET != 0 {
+	    frame.reset = Some(Reset::Hard);
+	}
+	else if commands & COMMAND_SOFT_RESET != 0 {
+	    frame.reset = Some(Reset::Soft);
+	}
+	for (player, field) in fields[2..2 + gamepads].iter().enumerate() {
+	    // an unplugged port has an empty field
+	    if !field.is_empty() {
+		frame.buttons[player] = parse_gamepad(field)?;
+	    }
+	}
+	Ok(frame)
+    }
+
+    /// A plain-text input script, one frame per line:
+    ///
+    /// ```text
+    /// # comments run to the end of the line
+    /// start             player 1 holds Start
+    /// a+right p2:b      players 1 and 2 hold buttons
+    /// .                 nothing is held
+    /// reset             reset before the frame runs; power power cycles
+    /// right x30         the same frame 30 times
+    /// ```
+    pub fn parse_script(text: &str) -> Result<Self, String> {
+	let mut movie = Movie::new("", None);
+	for (number, line) in text.lines().enumerate() {
+	    let error = |e: String| format!("line {}: {}", number + 1, e);
+	    let line = line.split('#').next().unwrap_or_default();
+	    if line.trim().is_empty() {
+		continue;
+	    }
+	    let mut frame = MovieFrame::default();
+	    let mut repeat: usize = 1;
+	    for token in line.split_whitespace() {
+		match token {
+		    "." => {}
+		    "reset" => frame.reset = Some(Reset::Soft),
+		    "power" => frame.reset = Some(Reset::Hard),
+		    _ if token.starts_with('x') && token[1..].parse::<usize>().is_ok() => {
+			repeat = token[1..].parse().unwrap_or(1);
+		    }
+		    _ => {
+			let (player, buttons) = controller::parse_player_buttons(token).map_err(error)?;
+			frame.buttons[player] |= buttons;
+			movie.four_score |= player >= 2;
+		    }
+		}
+	    }
+	    // only the first of the repeated frames resets
+	    movie.frames.push(frame.clone());
+	    frame.reset = None;
+	    movie.frames.extend(std::iter::repeat_n(frame, repeat.saturating_sub(1)));
+	}
+	Ok(movie)
+    }
+
+    /// An FCEUX .fm2 or BizHawk .bk2 movie, or else an input script.
+    pub fn load(path: &str) -> Result<Self, String> {
+	let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+	let movie = if path.to_ascii_lowercase().ends_with(".bk2") {
+	    bk2::parse(&data)
+	}
+	else {
+	    let text = String::from_utf8_lossy(&data);
+	    if path.to_ascii_lowercase().ends_with(".fm2") { Movie::parse(&text) } else { Movie::parse_script(&text) }
+	};
+	movie.map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+	fs::write(path, self.to_string()).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Fails unless the movie was recorded on this cartridge's ROM, for
+    /// movies that say which one that was.
+    pub fn check_rom(&self, cartridge: &Cartridge) -> Result<(), String> {
+	let (expected, actual) = match self.rom_hash {
+	    Some(RomHash::Md5(digest)) => (digest.to_vec(), cartridge.md5().to_vec()),
+	    Some(RomHash::Sha1(digest)) => (digest.to_vec(), cartridge.sha1().to_vec()),
+	    None => return Ok(()),
+	};
+	if actual != expected {
+	    return Err(format!("the ROM's hash is {}, the movie was recorded on {} ({})",
+			       hash::to_hex(&actual), self.rom_filename, hash::to_hex(&expected)));
+	}
+	Ok(())
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	writeln!(f, "version {}", FM2_VERSION)?;
+	writeln!(f, "emuVersion {}", FM2_EMU_VERSION)?;
+	writeln!(f, "rerecordCount 0")?;
+	writeln!(f, "palFlag {}", self.pal as u8)?;
+	writeln!(f, "romFilename {}", self.rom_filename)?;
+	if let Some(RomHash::Md5(digest)) = self.rom_hash {
+	    writeln!(f, "romChecksum base64:{}", base64_encode(&digest))?;
+	}
+	// FCEUX wants one; the ROM hash makes it the same for every
+	// recording of a ROM
+	let guid = hash::to_hex(&self.rom_hash.map_or([0; 16].to_vec(), |hash| hash.bytes()[..16].to_vec()));
+	writeln!(f, "guid {}-{}-{}-{}-{}", &guid[..8], &guid[8..12], &guid[12..16], &guid[16..20], &guid[20..])?;
+	writeln!(f, "fourscore {}", self.four_score as u8)?;
+	writeln!(f, "microphone 0")?;
+	writeln!(f, "port0 {}", SI_GAMEPAD)?;
+	writeln!(f, "port1 {}", SI_GAMEPAD)?;
+	writeln!(f, "port2 0")?;
+	writeln!(f, "FDS 0")?;
+	writeln!(f, "NewPPU 0")?;
+	for comment in &self.comments {
+	    writeln!(f, "comment {}", comment)?;
+	}
+	let gamepads = if self.four_score { 4 } else { 2 };
+	for frame in &self.frames {
+	    let commands = match frame.reset {
+		Some(Reset::Soft) => COMMAND_SOFT_RESET,
+		Some(Reset::Hard) => COMMAND_HARD_RESET,
+		None => 0,
+	    };
+	    write!(f, "|{}|", commands)?;
+	    for buttons in &frame.buttons[..gamepads] {
+		write!(f, "{}|", gamepad_field(*buttons))?;
+	    }
+	    writeln!(f, "|")?;
+	}
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{BUTTON_A, BUTTON_B, BUTTON_SELECT, BUTTON_START, BUTTON_RIGHT};
+    use crate::region::Region;
+
+    #[test]
+    fn test_base64() {
+	assert_eq!(base64_encode(b"Man"), "TWFu");
+	assert_eq!(base64_encode(b"Ma"), "TWE=");
+	assert_eq!(base64_encode(b"M"), "TQ==");
+	for len in 0..20 {
+	    let bytes: Vec<u8> = (0..len).map(|i: u8| i.wrapping_mul(37)).collect();
+	    assert_eq!(base64_decode(&base64_encode(&bytes)), Ok(bytes));
+	}
+	assert!(base64_decode("TW*u").is_err());
+    }
+
+    const FM2: &str = "version 3\r
+emuVersion 20604\r
+rerecordCount 12\r
+palFlag 0\r
+romFilename smb\r
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\r
+guid 26A7D80E-DA8B-5D25-BA5C-67BB0EE3B0F7\r
+fourscore 0\r
+port0 1\r
+port1 1\r
+port2 0\r
+comment author someone\r
+|2|........|........||\r
+|0|....T...|........||\r
+|0|R......A|.......A||\r
+|1|........|........||\r
+";
+
+    #[test]
+    fn test_parse() {
+	let movie = Movie::parse(FM2).unwrap();
+	assert_eq!(movie.rom_filename, "smb");
+	let Some(RomHash::Md5(digest)) = movie.rom_hash else { panic!("no MD5") };
+	assert_eq!(hash::to_hex(&digest), "8e3630186e35d477231bf8fd50e54cdd");
+	assert_eq!(movie.comments, vec!["author someone"]);
+	assert_eq!(movie.frames.len(), 4);
+	assert_eq!(movie.frames[0].reset, Some(Reset::Hard));
+	assert_eq!(movie.frames[1].buttons, [BUTTON_START, 0, 0, 0]);
+	assert_eq!(movie.frames[2].buttons, [BUTTON_RIGHT | BUTTON_A, BUTTON_A, 0, 0]);
+	assert_eq!(movie.frames[3].reset, Some(Reset::Soft));
+	let cartridge = Cartridge::from_nsf(&[0; 0x100], 0x8000, None, Region::Ntsc).unwrap();
+	assert!(movie.check_rom(&cartridge).is_err());
+	assert!(Movie { rom_hash: Some(RomHash::Md5(cartridge.md5())), ..movie }.check_rom(&cartridge).is_ok());
+
+	assert!(Movie::parse(&FM2.replace("port1 1", "port1 2")).is_err());
+	assert!(Movie::parse(&FM2.replace("version 3", "version 2")).is_err());
+	assert!(Movie::parse(&FM2.replace("|1|", "|x|")).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+	let mut movie = Movie::new("game.nes", Some(RomHash::Md5(hash::md5(b"game"))));
+	movie.four_score = true;
+	movie.comments.push("a test".to_string());
+	movie.frames.push(MovieFrame { reset: Some(Reset::Soft), buttons: [BUTTON_B, 0, BUTTON_START, BUTTON_A] });
+	movie.frames.push(MovieFrame::default());
+	let text = movie.to_string();
+	assert!(text.contains("|1|......B.|........|....T...|.......A||\n"));
+	assert_eq!(Movie::parse(&text), Ok(movie));
+    }
+
+    #[test]
+    fn test_parse_script() {
+	let movie = Movie::parse_script("# boot
+.  x2
+start      # to the menu
+reset a+right p2:b x3
+
+p4:select
+power
+").unwrap();
+	assert_eq!(movie.rom_hash, None);
+	assert!(movie.four_score);
+	assert_eq!(movie.frames.len(), 8);
+	assert_eq!(movie.frames[1], MovieFrame::default());
+	assert_eq!(movie.frames[2].buttons[0], BUTTON_START);
+	assert_eq!(movie.frames[3], MovieFrame { reset: Some(Reset::Soft), buttons: [BUTTON_A | BUTTON_RIGHT, BUTTON_B, 0, 0] });
+	assert_eq!(movie.frames[5], MovieFrame { reset: None, buttons: [BUTTON_A | BUTTON_RIGHT, BUTTON_B, 0, 0] });
+	assert_eq!(movie.frames[6].buttons[3], BUTTON_SELECT);
+	assert_eq!(movie.frames[7].reset, Some(Reset::Hard));
+	assert!(Movie::parse_script("jump").is_err());
+	assert!(Movie::parse_script("p5:a").is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use std::fmt;
+
+use crate::Cpu6502;
+use crate::STATUS_FLAG_I;
+use crate::apu::Channel;
+use crate::cartridge::Cartridge;
+use crate::region::Region;
+
+// NES Sound Format: a game's music driver and data ripped out of the ROM,
+// with INIT and PLAY entry points for a player to call.
+// https://www.nesdev.org/wiki/NSF
+// https://www.nesdev.org/wiki/NSFe
+
+const NSF_MAGIC: &[u8; 5] = b"NESM\x1A";
+const NSFE_MAGIC: &[u8; 4] = b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+// $7A
+const REGION_PAL: u8 = 0x01;
+const REGION_DUAL: u8 = 0x01 << 1;
+
+// $7B: the expansion chips the music is written for
+const EXPANSION_CHIPS: [(u8, &str, Option<Channel>); 6] = [
+    (0x01, "VRC6", Some(Channel::Vrc6)),
+    (0x01 << 1, "VRC7", None),
+    (0x01 << 2, "FDS", None),
+    (0x01 << 3, "MMC5", Some(Channel::Mmc5)),
+    (0x01 << 4, "Namco 163", None),
+    (0x01 << 5, "Sunsoft 5B", None),
+];
+
+// in microseconds, for rips that leave their rate at 0
+const DEFAULT_PLAY_SPEED_NTSC: u16 = 16639;
+const DEFAULT_PLAY_SPEED_PAL: u16 = 19997;
+
+// INIT and PLAY are called with this return address pushed. Nothing is
+// mapped there: the player stops as soon as the PC lands on it.
+const RETURN_ADDR: u16 = 0x4100;
+// a routine still running after a second's worth of cycles is stuck
+const CALL_LIMIT_CYCLES: u64 = 1789773;
+
+pub struct	Nsf {
+    pub nsfe:	bool,
+    pub songs:	u8,
+    pub start_song:	u8,	// counting from 1
+    pub load_addr:	u16,
+    pub init_addr:	u16,
+    pub play_addr:	u16,
+    pub title:	String,
+    pub artist:	String,
+    pub copyright:	String,
+    pub play_speed_ntsc:	u16,
+    pub play_speed_pal:	u16,
+    pub banks:	Option<[u8; 8]>,	// initial banks, when the rip bank switches
+    pub region:	Region,
+    pub expansion:	u8,
+    pub data:	Vec<u8>,
+}
+
+fn	string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+fn	word(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn	region(flags: u8) -> Region {
+    // dual region rips play as NTSC
+    if flags & (REGION_PAL | REGION_DUAL) == REGION_PAL { Region::Pal } else { Region::Ntsc }
+}
+
+impl	Nsf {
+    pub fn is_nsf(data: &[u8]) -> bool {
+	data.starts_with(NSF_MAGIC) || data.starts_with(NSFE_MAGIC)
+    }
+
+    pub fn	parse(data: &[u8]) -> Result<Self, String> {
+	if data.starts_with(NSFE_MAGIC) {
+	    return Self::from_nsfe(data);
+	}
+	if data.len() <= NSF_HEADER_SIZE || !data.starts_with(NSF_MAGIC) {
+	    return Err("not an NSF file".to_string());
+	}
+
+	// NSF2 may have metadata after the program, and then says how
+	// long the program is
+	let mut data_end = data.len();
+	let data_length = data[0x7D] as usize | (data[0x7E] as usize) << 8 | (data[0x7F] as usize) << 16;
+	if data[5] >= 2 && data_length != 0 {
+	    data_end = data.len().min(NSF_HEADER_SIZE + data_length);
+	}
+	let banks: [u8; 8] = data[0x70..0x78].try_into().unwrap();
+
+	Ok(Nsf {
+	    nsfe: false,
+	    songs: data[6],
+	    start_song: data[7],
+	    load_addr: word(data, 0x08),
+	    init_addr: word(data, 0x0A),
+	    play_addr: word(data, 0x0C),
+	    title: string(&data[0x0E..0x2E]),
+	    artist: string(&data[0x2E..0x4E]),
+	    copyright: string(&data[0x4E..0x6E]),
+	    play_speed_ntsc: word(data, 0x6E),
+	    play_speed_pal: word(data, 0x78),
+	    banks: if banks.iter().any(|bank| *bank != 0) { Some(banks) } else { None },
+	    region: region(data[0x7A]),
+	    expansion: data[0x7B],
+	    data: data[NSF_HEADER_SIZE..data_end].to_vec(),
+	})
+    }
+
+    // A list of chunks: a 32-bit length, a 4-character ID, then the data.
+    // Chunks whose ID starts with a capital letter have to be understood.
+    fn	from_nsfe(data: &[u8]) -> Result<Self, String> {
+	let mut nsf = Nsf {
+	    nsfe: true,
+	    songs: 1,
+	    start_song: 1,
+	    load_addr: 0,
+	    init_addr: 0,
+	    play_addr: 0,
+	    title: String::new(),
+	    artist: String::new(),
+	    copyright: String::new(),
+	    play_speed_ntsc: 0,
+	    play_speed_pal: 0,
+	    banks: None,
+	    region: Region::Ntsc,
+	    expansion: 0,
+	    data: Vec::new(),
+	};
+	let mut has_info = false;
+	let mut offset = NSFE_MAGIC.len();
+	loop {
+	    if offset + 8 > data.len() {
+		return Err("NSFe ends without an NEND chunk".to_string());
+	    }
+	    let length = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+	    let id = &data[offset + 4..offset + 8];
+	    offset += 8;
+	    if length > data.len() - offset {
+		return Err(format!("NSFe chunk {} is truncated", String::from_utf8_lossy(id)));
+	    }
+	    let chunk = &data[offset..offset + length];
+	    offset += length;
+
+	    match id {
+		b"INFO" => {
+		    if length < 8 {
+			return Err("NSFe INFO chunk is too short".to_string());
+		    }
+		    nsf.load_addr = word(chunk, 0);
+		    nsf.init_addr = word(chunk, 2);
+		    nsf.play_addr = word(chunk, 4);
+		    nsf.region = region(chunk[6]);
+		    nsf.expansion = chunk[7];
+		    nsf.songs = chunk.get(8).copied().unwrap_or(1);
+		    // the chunk counts songs from 0
+		    let start = chunk.get(9).copied().unwrap_or(0);
+		    nsf.start_song = start.checked_add(1).filter(|song| *song <= nsf.songs)
+			.ok_or(format!("NSFe INFO chunk starts on song {} of {}", start as u16 + 1, nsf.songs))?;
+		    has_info = true;
+		}
+		b"DATA" => nsf.data = chunk.to_vec(),
+		b"BANK" => {
+		    let mut banks = [0; 8];
+		    let count = length.min(banks.len());
+		    banks[..count].copy_from_slice(&chunk[..count]);
+		    nsf.banks = Some(banks);
+		}
+		b"RATE" => {
+		    if length >= 2 {
+			nsf.play_speed_ntsc = word(chunk, 0);
+		    }
+		    if length >= 4 {
+			nsf.play_speed_pal = word(chunk, 2);
+		    }
+		}
+		b"auth" => {
+		    let mut strings = chunk.split(|b| *b == 0).map(string);
+		    nsf.title = strings.next().unwrap_or_default();
+		    nsf.artist = strings.next().unwrap_or_default();
+		    nsf.copyright = strings.next().unwrap_or_default();
+		}
+		b"NEND" => break,
+		_ if id[0].is_ascii_uppercase() => {
+		    return Err(format!("NSFe chunk {} is not supported", String::from_utf8_lossy(id)));
+		}
+		_ => {}
+	    }
+	}
+	if !has_info || nsf.data.is_empty() {
+	    return Err("NSFe needs an INFO and a DATA chunk".to_string());
+	}
+	Ok(nsf)
+    }
+
+    /// Every expansion chip the header asks for, with the APU channel
+    /// playing it, if there is one.
+    pub fn expansion_chips(&self) -> Vec<(&'static str, Option<Channel>)> {
+	EXPANSION_CHIPS.iter()
+	    .filter(|(flag, _, _)| self.expansion & flag != 0)
+	    .map(|(_, name, channel)| (*name, *channel))
+	    .collect()
+    }
+
+    /// CPU cycles between two calls to PLAY.
+    pub fn play_period(&self, region: Region) -> f64 {
+	let speed = match region {
+	    Region::Ntsc if self.play_speed_ntsc != 0 => self.play_speed_ntsc,
+	    Region::Ntsc => DEFAULT_PLAY_SPEED_NTSC,
+	    _ if self.play_speed_pal != 0 => self.play_speed_pal,
+	    _ => DEFAULT_PLAY_SPEED_PAL,
+	};
+	speed as f64 * region.cpu_clock_rate() / 1e6
+    }
+}
+
+impl fmt::Display for Nsf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{}, \"{}\" by {}, {} songs, {}", if self.nsfe { "NSFe" } else { "NSF" }, self.title, self.artist, self.songs, self.region)?;
+	for (chip, _) in self.expansion_chips() {
+	    write!(f, ", {}", chip)?;
+	}
+	Ok(())
+    }
+}
+
+/// Drives a `Cpu6502` through an NSF's INIT and PLAY routines, with the
+/// program mapped in like a cartridge.
+pub struct	NsfPlayer {
+    pub cpu:	Cpu6502,
+    nsf:	Nsf,
+    region:	Region,
+    play_period:	f64,
+    next_play:	f64,	// CPU cycle PLAY is due at
+}
+
+impl	NsfPlayer {
+    pub fn	new(nsf: Nsf) -> Result<Self, String> {
+	let cartridge = Cartridge::from_nsf(&nsf.data, nsf.load_addr, nsf.banks, nsf.region)?;
+	let mut cpu = Cpu6502::new();
+	cpu.trace = false;
+	cpu.insert_cartridge(cartridge);
+	for (_, channel) in nsf.expansion_chips() {
+	    if let Some(channel) = channel {
+		cpu.bus.apu.enable_expansion(channel);
+	    }
+	}
+	let region = nsf.region;
+	let mut player = NsfPlayer { cpu, nsf, region, play_period: 0.0, next_play: 0.0 };
+	player.set_region(region);
+	Ok(player)
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+	self.cpu.set_region(region);
+	self.region = region;
+	self.play_period = self.nsf.play_period(region);
+    }
+
+    // Runs the routine at `addr` until it returns.
+    fn call(&mut self, addr: u16) -> Result<(), String> {
+	let ret = RETURN_ADDR - 1;
+	self.cpu.stack_push((ret >> 8) as u8);
+	self.cpu.stack_push(ret as u8);
+	self.cpu.regs.pc = addr;
+	let start = self.cpu.cycles;
+	while self.cpu.regs.pc != RETURN_ADDR {
+	    let status = self.cpu.step().map_err(|e| format!("{} in the routine at ${:04X}", e, addr))?;
+	    if status == 0 {
+		return Err(format!("BRK at ${:04X} in the routine at ${:04X}", self.cpu.regs.pc.wrapping_sub(1), addr));
+	    }
+	    if self.cpu.cycles - start > CALL_LIMIT_CYCLES {
+		return Err(format!("the routine at ${:04X} doesn't return", addr));
+	    }
+	}
+	Ok(())
+    }
+
+    /// Clears the machine the way the NSF spec asks for and runs INIT for
+    /// `song`, counting from 1.
+    pub fn init(&mut self, song: u8) -> Result<(), String> {
+	if song == 0 || song > self.nsf.songs {
+	    return Err(format!("no song {}, the NSF has {}", song, self.nsf.songs));
+	}
+	for addr in (0x0000..0x0800).chain(0x6000..0x8000) {
+	    self.cpu.bus.write(addr, 0);
+	}
+	for addr in 0x4000..=0x4013 {
+	    self.cpu.bus.write(addr, 0);
+	}
+	self.cpu.bus.write(0x4015, 0x0F);
+	self.cpu.bus.write(0x4017, 0x40);
+	if let Some(banks) = self.nsf.banks {
+	    for (i, bank) in banks.iter().enumerate() {
+		self.cpu.bus.write(0x5FF8 + i as u16, *bank);
+	    }
+	}
+
+	// there's no IRQ handler to take interrupts
+	self.cpu.regs.p = STATUS_FLAG_I;
+	self.cpu.regs.sp = 0xFD;
+	self.cpu.regs.a = song - 1;
+	self.cpu.regs.x = (self.region == Region::Pal) as u8;
+	self.cpu.regs.y = 0;
+	self.call(self.nsf.init_addr)?;
+	self.next_play = self.cpu.cycles as f64;
+	Ok(())
+    }
+
+    /// Idles until PLAY is due and calls it, then ends the APU's frame, so
+    /// every call makes one play period of audio.
+    pub fn play(&mut self) -> Result<(), String> {
+	while (self.cpu.cycles as f64) < self.next_play {
+	    match self.cpu.bus.apu.dmc_fetch_request() {
+		Some(addr) => self.cpu.dmc_fetch(addr),
+		None => self.cpu.clock(1),
+	    }
+	}
+	self.next_play += self.play_period;
+	self.call(self.nsf.play_addr)?;
+	self.cpu.bus.apu.end_frame();
+	Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // INIT stores the song number and starts a 440 Hz note on pulse 1,
+    // PLAY counts its calls
+    const PROGRAM: [u8; 27] = [
+	0x85, 0x11,		// sta $11
+	0xa9, 0x01,		// lda #$01
+	0x8d, 0x15, 0x40,	// sta $4015
+	0xa9, 0xbf,		// lda #$bf
+	0x8d, 0x00, 0x40,	// sta $4000
+	0xa9, 0xfd,		// lda #$fd
+	0x8d, 0x02, 0x40,	// sta $4002
+	0xa9, 0x08,		// lda #$08
+	0x8d, 0x03, 0x40,	// sta $4003
+	0x60,			// rts
+	0xe6, 0x10,		// play: inc $10
+	0x60,			// rts
+	0x00,
+    ];
+
+    fn nsf_file(expansion: u8) -> Vec<u8> {
+	let mut file = NSF_MAGIC.to_vec();
+	file.resize(NSF_HEADER_SIZE, 0);
+	file[5] = 1;
+	file[6] = 3;
+	file[7] = 1;
+	file[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x17, 0x80]);
+	file[0x0E..0x13].copy_from_slice(b"Title");
+	file[0x2E..0x34].copy_from_slice(b"Artist");
+	file[0x7B] = expansion;
+	file.extend(PROGRAM);
+	file
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+	let nsf = Nsf::parse(&nsf_file(0x09)).unwrap();
+	assert_eq!((nsf.load_addr, nsf.init_addr, nsf.play_addr), (0x8000, 0x8000, 0x8017));
+	assert_eq!(nsf.banks, None);
+	assert_eq!(nsf.data.len(), PROGRAM.len());
+	assert_eq!(nsf.expansion_chips(), vec![("VRC6", Some(Channel::Vrc6)), ("MMC5", Some(Channel::Mmc5))]);
+	assert_eq!(nsf.to_string(), "NSF, \"Title\" by Artist, 3 songs, NTSC, VRC6, MMC5");
+	assert!((nsf.play_period(Region::Ntsc) - 29780.0).abs() < 1.0);
+	assert!(Nsf::parse(b"NESM\x1A").is_err());
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+	let chunk = |id: &[u8], data: &[u8]| {
+	    let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+	    chunk.extend(id);
+	    chunk.extend(data);
+	    chunk
+	};
+	let mut file = NSFE_MAGIC.to_vec();
+	file.extend(chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x17, 0x80, 0x01, 0x04, 0x05, 0x02]));
+	file.extend(chunk(b"DATA", &PROGRAM));
+	file.extend(chunk(b"BANK", &[0x00, 0x01]));
+	file.extend(chunk(b"auth", b"Title\0Artist\0(c)\0Ripper\0"));
+	file.extend(chunk(b"tlbl", b"Intro\0"));
+	file.extend(chunk(b"NEND", &[]));
+
+	let nsf = Nsf::parse(&file).unwrap();
+	assert_eq!(nsf.to_string(), "NSFe, \"Title\" by Artist, 5 songs, PAL, FDS");
+	assert_eq!(nsf.start_song, 3);
+	assert_eq!(nsf.copyright, "(c)");
+	assert_eq!(nsf.banks, Some([0, 1, 0, 0, 0, 0, 0, 0]));
+	assert_eq!(nsf.expansion_chips(), vec![("FDS", None)]);
+
+	let mut unknown = file[..file.len() - 8].to_vec();
+	unknown.extend(chunk(b"VRC7", &[]));
+	assert!(Nsf::parse(&unknown).is_err());
+	assert!(Nsf::parse(&file[..file.len() - 8]).is_err());
+
+	// the starting song, counted from 0, is byte 9 of INFO
+	file[12 + 9] = 5;
+	assert_eq!(Nsf::parse(&file).err().as_deref(), Some("NSFe INFO chunk starts on song 6 of 5"));
+	file[12 + 9] = 0xFF;
+	assert_eq!(Nsf::parse(&file).err().as_deref(), Some("NSFe INFO chunk starts on song 256 of 5"));
+    }
+
+    #[test]
+    fn test_player() {
+	let mut player = NsfPlayer::new(Nsf::parse(&nsf_file(0)).unwrap()).unwrap();
+	assert!(player.init(4).is_err());
+	player.init(2).unwrap();
+	assert_eq!(player.cpu.bus.vram[0x11], 1);
+	let start = player.cpu.cycles;
+	let mut samples = Vec::new();
+	for _ in 0..60 {
+	    player.play().unwrap();
+	    samples.extend_from_slice(player.cpu.bus.apu.frame_samples());
+	}
+	assert_eq!(player.cpu.bus.vram[0x10], 60);
+	let cycles = player.cpu.cycles - start;
+	assert!((59 * 29780..60 * 29780).contains(&cycles), "{}", cycles);
+	assert!((43000..44200).contains(&samples.len()), "{}", samples.len());
+	assert!(samples.iter().any(|s| s.abs() > 0.1));
+
+	// a driver using an opcode the CPU doesn't run yet: dex
+	player.cpu.bus.poke(0x8017, 0xca);
+	assert_eq!(player.play().unwrap_err(), "unimplemented opcode $CA at $8017 in the routine at $8017");
+    }
+}
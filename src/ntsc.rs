@@ -0,0 +1,197 @@
+use std::f32::consts::PI;
+
+use crate::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT, DOTS_PER_SCANLINE};
+
+// Composite video, after Bisqwit's "NES NTSC" write-up. The PPU outputs
+// eight samples per dot of a square wave that switches between a low and a
+// high voltage, at a phase picked by the color's hue. Decoding runs a
+// 12-sample window (one color subcarrier cycle) across the signal, and
+// the window not lining up with pixel edges is what gives the fringing.
+// https://www.nesdev.org/wiki/NTSC_video
+
+pub const NTSC_WIDTH: usize = 602;
+
+const SAMPLES_PER_DOT: usize = 8;
+const SAMPLES_PER_CYCLE: usize = 12;	// one cycle of the color subcarrier
+const SAMPLES_PER_LINE: usize = SCREEN_WIDTH * SAMPLES_PER_DOT;
+
+// voltages relative to sync, for luma 0-3: low half then high half of the wave
+const LEVELS: [f32; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+const HUE: f32 = 3.9;	// subcarrier phase of the decoder, in samples
+const GAMMA: f32 = 2.0;	// of the monitor being imitated; sRGB is 2.2
+
+pub struct	NtscFilter {
+    width:	usize,
+    cos:	[f32; SAMPLES_PER_CYCLE],
+    sin:	[f32; SAMPLES_PER_CYCLE],
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+	Self::new(NTSC_WIDTH)
+    }
+}
+
+impl	NtscFilter {
+    pub fn	new(width: usize) -> Self {
+	let mut cos = [0.0; SAMPLES_PER_CYCLE];
+	let mut sin = [0.0; SAMPLES_PER_CYCLE];
+	for p in 0..SAMPLES_PER_CYCLE {
+	    let angle = PI * (p as f32 + HUE) / 6.0;
+	    cos[p] = angle.cos();
+	    sin[p] = angle.sin();
+	}
+	NtscFilter { width, cos, sin }
+    }
+
+    pub fn width(&self) -> usize {
+	self.width
+    }
+
+    // One sample of a pixel's wave at subcarrier phase `phase`, scaled so
+    // black is 0 and white 1.
+    fn sample(pixel: u16, phase: usize) -> f32 {
+	let color = (pixel & 0x0F) as usize;
+	let emphasis = pixel >> 6;	// red, green, blue
+	let mut level = ((pixel >> 4) & 0x03) as usize;
+	if color > 0x0D {
+	    level = 1;
+	}
+	let mut low = LEVELS[level];
+	let mut high = LEVELS[4 + level];
+	if color == 0x00 {
+	    low = high;
+	}
+	if color > 0x0C {
+	    high = low;
+	}
+
+	let in_phase = |hue: usize| (hue + phase) % SAMPLES_PER_CYCLE < SAMPLES_PER_CYCLE / 2;
+	let mut signal = if in_phase(color) { high } else { low };
+	// each emphasis bit darkens the wave during its third of the cycle
+	if (emphasis & 0x01 != 0 && in_phase(0))
+	    || (emphasis & 0x02 != 0 && in_phase(4))
+	    || (emphasis & 0x04 != 0 && in_phase(8)) {
+	    signal *= EMPHASIS_ATTENUATION;
+	}
+	(signal - BLACK) / (WHITE - BLACK)
+    }
+
+    /// The composite signal of one scanline of pixels, starting at
+    /// subcarrier phase `phase`.
+    pub fn encode(&self, pixels: &[u16], phase: usize) -> Vec<f32> {
+	let mut signal = Vec::with_capacity(pixels.len() * SAMPLES_PER_DOT);
+	for (x, pixel) in pixels.iter().enumerate() {
+	    for s in 0..SAMPLES_PER_DOT {
+		signal.push(Self::sample(*pixel, phase + x * SAMPLES_PER_DOT + s));
+	    }
+	}
+	signal
+    }
+
+    /// Decodes a scanline's signal into `width` RGB pixels.
+    pub fn decode(&self, signal: &[f32], phase: usize, out: &mut Vec<u8>) {
+	let gamma = |f: f32| if f <= 0.0 { 0.0 } else { f.powf(2.2 / GAMMA) };
+	let clamp = |f: f32| (255.95 * gamma(f)).clamp(0.0, 255.0) as u8;
+
+	for x in 0..self.width {
+	    let center = x * signal.len() / self.width;
+	    let begin = center.saturating_sub(SAMPLES_PER_CYCLE / 2);
+	    let end = (center + SAMPLES_PER_CYCLE / 2).min(signal.len());
+	    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+	    for (p, level) in signal.iter().enumerate().take(end).skip(begin) {
+		let level = level / SAMPLES_PER_CYCLE as f32;
+		let p = (phase + p) % SAMPLES_PER_CYCLE;
+		y += level;
+		i += level * self.cos[p];
+		q += level * self.sin[p];
+	    }
+	    out.push(clamp(y + 0.946882 * i + 0.623557 * q));
+	    out.push(clamp(y - 0.274788 * i - 0.635691 * q));
+	    out.push(clamp(y - 1.108545 * i + 1.709007 * q));
+	}
+    }
+
+    /// Runs a whole frame through the encoder and decoder, returning packed
+    /// 8-bit RGB that is `width()` pixels wide. The subcarrier phase moves
+    /// by a third of a cycle every scanline and, with the dot skipped on
+    /// odd frames, between frames too, which makes the dot crawl.
+    pub fn filter(&self, pixels: &[u16], frame: u64) -> Vec<u8> {
+	let mut rgb = Vec::with_capacity(self.width * SCREEN_HEIGHT * 3);
+	let line_phase = DOTS_PER_SCANLINE as usize * SAMPLES_PER_DOT;
+	let frame_phase = (frame % 2) as usize * SAMPLES_PER_CYCLE / 3;
+	for (y, line) in pixels.chunks(SCREEN_WIDTH).enumerate() {
+	    let phase = (frame_phase + y * line_phase) % SAMPLES_PER_CYCLE;
+	    let signal = self.encode(line, phase);
+	    debug_assert_eq!(signal.len(), SAMPLES_PER_LINE);
+	    self.decode(&signal, phase, &mut rgb);
+	}
+	rgb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat(pixel: u16) -> Vec<u16> {
+	vec![pixel; SCREEN_WIDTH * SCREEN_HEIGHT]
+    }
+
+    // a pixel away from the edges of the picture
+    fn middle(filter: &NtscFilter, rgb: &[u8]) -> [u8; 3] {
+	let i = (100 * filter.width() + filter.width() / 2) * 3;
+	[rgb[i], rgb[i + 1], rgb[i + 2]]
+    }
+
+    #[test]
+    fn test_output_size() {
+	let filter = NtscFilter::default();
+	assert_eq!(filter.filter(&flat(0x0F), 0).len(), NTSC_WIDTH * SCREEN_HEIGHT * 3);
+	assert_eq!(NtscFilter::new(256).filter(&flat(0x0F), 0).len(), 256 * SCREEN_HEIGHT * 3);
+    }
+
+    #[test]
+    fn test_greys_have_no_chroma() {
+	let filter = NtscFilter::default();
+	assert_eq!(middle(&filter, &filter.filter(&flat(0x0F), 0)), [0, 0, 0]);
+	assert_eq!(middle(&filter, &filter.filter(&flat(0x20), 0)), [255, 255, 255]);
+	let [r, g, b] = middle(&filter, &filter.filter(&flat(0x00), 0));
+	assert!(r == g && g == b && r > 0x40);
+    }
+
+    #[test]
+    fn test_hues() {
+	let filter = NtscFilter::default();
+	let [r, g, b] = middle(&filter, &filter.filter(&flat(0x16), 0));
+	assert!(r > g && r > b, "0x16 is red, got {:?}", [r, g, b]);
+	let [r, g, b] = middle(&filter, &filter.filter(&flat(0x2A), 0));
+	assert!(g > r && g > b, "0x2A is green, got {:?}", [r, g, b]);
+	let [r, g, b] = middle(&filter, &filter.filter(&flat(0x12), 0));
+	assert!(b > r && b > g, "0x12 is blue, got {:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn test_dot_crawl() {
+	let filter = NtscFilter::default();
+	// vertical stripes, one dot wide, make artifact colors
+	let stripes: Vec<u16> = (0..SCREEN_WIDTH * SCREEN_HEIGHT).map(|i| if i % 2 == 0 { 0x20 } else { 0x0F }).collect();
+	let even = filter.filter(&stripes, 0);
+	let odd = filter.filter(&stripes, 1);
+	assert_ne!(even, odd);
+	let [r, g, b] = middle(&filter, &even);
+	assert!(r != g || g != b);
+    }
+
+    #[test]
+    fn test_emphasis_darkens() {
+	let filter = NtscFilter::default();
+	let plain = middle(&filter, &filter.filter(&flat(0x20), 0));
+	let emphasized = middle(&filter, &filter.filter(&flat(0x1C0 | 0x20), 0));
+	assert!(emphasized.iter().zip(plain.iter()).all(|(e, p)| e < p));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::AddressingMode::{self, *};
+
+// Every official 6502 opcode with its addressing mode and cycle count,
+// before any page crossing or taken branch. The CPU runs the ones listed
+// in CPU_OPCODES; the disassembler and assembler use them all.
+// https://www.masswerk.at/6502/6502_instruction_set.html
+
+pub const OPCODES: [(u8, &str, AddressingMode, u8); 151] = [
+    (0x69, "ADC", AddressingImmediate, 2), (0x65, "ADC", AddressingZeroPage, 3), (0x75, "ADC", AddressingZeroPageX, 4),
+    (0x6D, "ADC", AddressingAbsolute, 4), (0x7D, "ADC", AddressingAbsoluteX, 4), (0x79, "ADC", AddressingAbsoluteY, 4),
+    (0x61, "ADC", AddressingIndirectX, 6), (0x71, "ADC", AddressingIndirectY, 5),
+    (0x29, "AND", AddressingImmediate, 2), (0x25, "AND", AddressingZeroPage, 3), (0x35, "AND", AddressingZeroPageX, 4),
+    (0x2D, "AND", AddressingAbsolute, 4), (0x3D, "AND", AddressingAbsoluteX, 4), (0x39, "AND", AddressingAbsoluteY, 4),
+    (0x21, "AND", AddressingIndirectX, 6), (0x31, "AND", AddressingIndirectY, 5),
+    (0x0A, "ASL", AddressingAccumulator, 2), (0x06, "ASL", AddressingZeroPage, 5), (0x16, "ASL", AddressingZeroPageX, 6),
+    (0x0E, "ASL", AddressingAbsolute, 6), (0x1E, "ASL", AddressingAbsoluteX, 7),
+    (0x90, "BCC", AddressingRelative, 2), (0xB0, "BCS", AddressingRelative, 2), (0xF0, "BEQ", AddressingRelative, 2),
+    (0x24, "BIT", AddressingZeroPage, 3), (0x2C, "BIT", AddressingAbsolute, 4),
+    (0x30, "BMI", AddressingRelative, 2), (0xD0, "BNE", AddressingRelative, 2), (0x10, "BPL", AddressingRelative, 2),
+    (0x00, "BRK", AddressingImplied, 7),
+    (0x50, "BVC", AddressingRelative, 2), (0x70, "BVS", AddressingRelative, 2),
+    (0x18, "CLC", AddressingImplied, 2), (0xD8, "CLD", AddressingImplied, 2), (0x58, "CLI", AddressingImplied, 2),
+    (0xB8, "CLV", AddressingImplied, 2),
+    (0xC9, "CMP", AddressingImmediate, 2), (0xC5, "CMP", AddressingZeroPage, 3), (0xD5, "CMP", AddressingZeroPageX, 4),
+    (0xCD, "CMP", AddressingAbsolute, 4), (0xDD, "CMP", AddressingAbsoluteX, 4), (0xD9, "CMP", AddressingAbsoluteY, 4),
+    (0xC1, "CMP", AddressingIndirectX, 6), (0xD1, "CMP", AddressingIndirectY, 5),
+    (0xE0, "CPX", AddressingImmediate, 2), (0xE4, "CPX", AddressingZeroPage, 3), (0xEC, "CPX", AddressingAbsolute, 4),
+    (0xC0, "CPY", AddressingImmediate, 2), (0xC4, "CPY", AddressingZeroPage, 3), (0xCC, "CPY", AddressingAbsolute, 4),
+    (0xC6, "DEC", AddressingZeroPage, 5), (0xD6, "DEC", AddressingZeroPageX, 6), (0xCE, "DEC", AddressingAbsolute, 6),
+    (0xDE, "DEC", AddressingAbsoluteX, 7),
+    (0xCA, "DEX", AddressingImplied, 2), (0x88, "DEY", AddressingImplied, 2),
+    (0x49, "EOR", AddressingImmediate, 2), (0x45, "EOR", AddressingZeroPage, 3), (0x55, "EOR", AddressingZeroPageX, 4),
+    (0x4D, "EOR", AddressingAbsolute, 4), (0x5D, "EOR", AddressingAbsoluteX, 4), (0x59, "EOR", AddressingAbsoluteY, 4),
+    (0x41, "EOR", AddressingIndirectX, 6), (0x51, "EOR", AddressingIndirectY, 5),
+    (0xE6, "INC", AddressingZeroPage, 5), (0xF6, "INC", AddressingZeroPageX, 6), (0xEE, "INC", AddressingAbsolute, 6),
+    (0xFE, "INC", AddressingAbsoluteX, 7),
+    (0xE8, "INX", AddressingImplied, 2), (0xC8, "INY", AddressingImplied, 2),
+    (0x4C, "JMP", AddressingAbsolute, 3), (0x6C, "JMP", AddressingIndirect, 5), (0x20, "JSR", AddressingAbsolute, 6),
+    (0xA9, "LDA", AddressingImmediate, 2), (0xA5, "LDA", AddressingZeroPage, 3), (0xB5, "LDA", AddressingZeroPageX, 4),
+    (0xAD, "LDA", AddressingAbsolute, 4), (0xBD, "LDA", AddressingAbsoluteX, 4), (0xB9, "LDA", AddressingAbsoluteY, 4),
+    (0xA1, "LDA", AddressingIndirectX, 6), (0xB1, "LDA", AddressingIndirectY, 5),
+    (0xA2, "LDX", AddressingImmediate, 2), (0xA6, "LDX", AddressingZeroPage, 3), (0xB6, "LDX", AddressingZeroPageY, 4),
+    (0xAE, "LDX", AddressingAbsolute, 4), (0xBE, "LDX", AddressingAbsoluteY, 4),
+    (0xA0, "LDY", AddressingImmediate, 2), (0xA4, "LDY", AddressingZeroPage, 3), (0xB4, "LDY", AddressingZeroPageX, 4),
+    (0xAC, "LDY", AddressingAbsolute, 4), (0xBC, "LDY", AddressingAbsoluteX, 4),
+    (0x4A, "LSR", AddressingAccumulator, 2), (0x46, "LSR", AddressingZeroPage, 5), (0x56, "LSR", AddressingZeroPageX, 6),
+    (0x4E, "LSR", AddressingAbsolute, 6), (0x5E, "LSR", AddressingAbsoluteX, 7),
+    (0xEA, "NOP", AddressingImplied, 2),
+    (0x09, "ORA", AddressingImmediate, 2), (0x05, "ORA", AddressingZeroPage, 3), (0x15, "ORA", AddressingZeroPageX, 4),
+    (0x0D, "ORA", AddressingAbsolute, 4), (0x1D, "ORA", AddressingAbsoluteX, 4), (0x19, "ORA", AddressingAbsoluteY, 4),
+    (0x01, "ORA", AddressingIndirectX, 6), (0x11, "ORA", AddressingIndirectY, 5),
+    (0x48, "PHA", AddressingImplied, 3), (0x08, "PHP", AddressingImplied, 3), (0x68, "PLA", AddressingImplied, 4),
+    (0x28, "PLP", AddressingImplied, 4),
+    (0x2A, "ROL", AddressingAccumulator, 2), (0x26, "ROL", AddressingZeroPage, 5), (0x36, "ROL", AddressingZeroPageX, 6),
+    (0x2E, "ROL", AddressingAbsolute, 6), (0x3E, "ROL", AddressingAbsoluteX, 7),
+    (0x6A, "ROR", AddressingAccumulator, 2), (0x66, "ROR", AddressingZeroPage, 5), (0x76, "ROR", AddressingZeroPageX, 6),
+    (0x6E, "ROR", AddressingAbsolute, 6), (0x7E, "ROR", AddressingAbsoluteX, 7),
+    (0x40, "RTI", AddressingImplied, 6), (0x60, "RTS", AddressingImplied, 6),
+    (0xE9, "SBC", AddressingImmediate, 2), (0xE5, "SBC", AddressingZeroPage, 3), (0xF5, "SBC", AddressingZeroPageX, 4),
+    (0xED, "SBC", AddressingAbsolute, 4), (0xFD, "SBC", AddressingAbsoluteX, 4), (0xF9, "SBC", AddressingAbsoluteY, 4),
+    (0xE1, "SBC", AddressingIndirectX, 6), (0xF1, "SBC", AddressingIndirectY, 5),
+    (0x38, "SEC", AddressingImplied, 2), (0xF8, "SED", AddressingImplied, 2), (0x78, "SEI", AddressingImplied, 2),
+    (0x85, "STA", AddressingZeroPage, 3), (0x95, "STA", AddressingZeroPageX, 4), (0x8D, "STA", AddressingAbsolute, 4),
+    (0x9D, "STA", AddressingAbsoluteX, 5), (0x99, "STA", AddressingAbsoluteY, 5), (0x81, "STA", AddressingIndirectX, 6),
+    (0x91, "STA", AddressingIndirectY, 6),
+    (0x86, "STX", AddressingZeroPage, 3), (0x96, "STX", AddressingZeroPageY, 4), (0x8E, "STX", AddressingAbsolute, 4),
+    (0x84, "STY", AddressingZeroPage, 3), (0x94, "STY", AddressingZeroPageX, 4), (0x8C, "STY", AddressingAbsolute, 4),
+    (0xAA, "TAX", AddressingImplied, 2), (0xA8, "TAY", AddressingImplied, 2), (0xBA, "TSX", AddressingImplied, 2),
+    (0x8A, "TXA", AddressingImplied, 2), (0x9A, "TXS", AddressingImplied, 2), (0x98, "TYA", AddressingImplied, 2),
+];
+
+/// The mnemonic and addressing mode of an official opcode.
+pub fn opcode(opcode: u8) -> Option<(&'static str, AddressingMode)> {
+    OPCODES.iter().find(|(op, _, _, _)| *op == opcode).map(|(_, mnem, mode, _)| (*mnem, *mode))
+}
+
+/// Bytes an instruction takes up, opcode included.
+pub fn length(mode: AddressingMode) -> usize {
+    match mode {
+	AddressingImplied | AddressingAccumulator => 1,
+	AddressingAbsolute | AddressingAbsoluteX | AddressingAbsoluteY | AddressingIndirect => 3,
+	_ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcodes_unique() {
+	let opcodes: std::collections::BTreeSet<u8> = OPCODES.iter().map(|(op, _, _, _)| *op).collect();
+	assert_eq!(opcodes.len(), OPCODES.len());
+	assert_eq!(opcode(0xB1), Some(("LDA", AddressingIndirectY)));
+	assert_eq!(opcode(0xFF), None);
+	assert_eq!(length(AddressingIndirect), 3);
+    }
+}
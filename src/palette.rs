@@ -0,0 +1,131 @@
+use std::fs;
+
+// 2C02 colors, indexed by the 6-bit values stored in palette RAM
+const NES_PALETTE: [[u8; 3]; 64] = [
+    [0x80, 0x80, 0x80], [0x00, 0x3D, 0xA6], [0x00, 0x12, 0xB0], [0x44, 0x00, 0x96],
+    [0xA1, 0x00, 0x5E], [0xC7, 0x00, 0x28], [0xBA, 0x06, 0x00], [0x8C, 0x17, 0x00],
+    [0x5C, 0x2F, 0x00], [0x10, 0x45, 0x00], [0x05, 0x4A, 0x00], [0x00, 0x47, 0x2E],
+    [0x00, 0x41, 0x66], [0x00, 0x00, 0x00], [0x05, 0x05, 0x05], [0x05, 0x05, 0x05],
+    [0xC7, 0xC7, 0xC7], [0x00, 0x77, 0xFF], [0x21, 0x55, 0xFF], [0x82, 0x37, 0xFA],
+    [0xEB, 0x2F, 0xB5], [0xFF, 0x29, 0x50], [0xFF, 0x22, 0x00], [0xD6, 0x32, 0x00],
+    [0xC4, 0x62, 0x00], [0x35, 0x80, 0x00], [0x05, 0x8F, 0x00], [0x00, 0x8A, 0x55],
+    [0x00, 0x99, 0xCC], [0x21, 0x21, 0x21], [0x09, 0x09, 0x09], [0x09, 0x09, 0x09],
+    [0xFF, 0xFF, 0xFF], [0x0F, 0xD7, 0xFF], [0x69, 0xA2, 0xFF], [0xD4, 0x80, 0xFF],
+    [0xFF, 0x45, 0xF3], [0xFF, 0x61, 0x8B], [0xFF, 0x88, 0x33], [0xFF, 0x9C, 0x12],
+    [0xFA, 0xBC, 0x20], [0x9F, 0xE3, 0x0E], [0x2B, 0xF0, 0x35], [0x0C, 0xF0, 0xA4],
+    [0x05, 0xFB, 0xFF], [0x5E, 0x5E, 0x5E], [0x0D, 0x0D, 0x0D], [0x0D, 0x0D, 0x0D],
+    [0xFF, 0xFF, 0xFF], [0xA6, 0xFC, 0xFF], [0xB3, 0xEC, 0xFF], [0xDA, 0xAB, 0xEB],
+    [0xFF, 0xA8, 0xF9], [0xFF, 0xAB, 0xB3], [0xFF, 0xD2, 0xB0], [0xFF, 0xEF, 0xA6],
+    [0xFF, 0xF7, 0x9C], [0xD7, 0xE8, 0x95], [0xA6, 0xED, 0xAF], [0xA2, 0xF2, 0xDA],
+    [0x99, 0xFF, 0xFC], [0xDD, 0xDD, 0xDD], [0x11, 0x11, 0x11], [0x11, 0x11, 0x11],
+];
+
+const COLORS: usize = 64;
+const EMPHASIS_COLORS: usize = 512;
+
+// How much an emphasis bit darkens the two channels it doesn't name, for
+// palettes that don't spell out the emphasized colors themselves.
+const EMPHASIS_ATTENUATION: f32 = 0.816328;
+
+/// Maps framebuffer pixels, `emphasis << 6 | color`, to RGB.
+pub struct	Palette {
+    colors:	Vec<[u8; 3]>,	// always EMPHASIS_COLORS entries
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+	Self::with_emphasis(&NES_PALETTE)
+    }
+}
+
+impl	Palette {
+    // Fills in the seven emphasized copies of a 64-color palette.
+    fn with_emphasis(base: &[[u8; 3]]) -> Self {
+	let mut colors = Vec::with_capacity(EMPHASIS_COLORS);
+	for emphasis in 0..EMPHASIS_COLORS / COLORS {
+	    for rgb in base {
+		let mut out = *rgb;
+		for (channel, value) in out.iter_mut().enumerate() {
+		    // bit 0 is red, 1 green, 2 blue
+		    let others = emphasis & !(1 << channel);
+		    for _ in 0..others.count_ones() {
+			*value = (*value as f32 * EMPHASIS_ATTENUATION).round() as u8;
+		    }
+		}
+		colors.push(out);
+	    }
+	}
+	Palette { colors }
+    }
+
+    /// Reads a .pal file: 64 RGB triplets, or 512 when it also covers every
+    /// combination of the emphasis bits.
+    pub fn from_pal(data: &[u8]) -> Result<Self, String> {
+	let colors: Vec<[u8; 3]> = data.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+	if data.len() == COLORS * 3 {
+	    Ok(Self::with_emphasis(&colors))
+	}
+	else if data.len() == EMPHASIS_COLORS * 3 {
+	    Ok(Palette { colors })
+	}
+	else {
+	    Err(format!("a .pal file holds {} or {} bytes, not {}", COLORS * 3, EMPHASIS_COLORS * 3, data.len()))
+	}
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+	let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+	Self::from_pal(&data).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+	self.colors[pixel as usize % EMPHASIS_COLORS]
+    }
+
+    /// Turns a frame of pixels into packed 8-bit RGB.
+    pub fn to_rgb(&self, pixels: &[u16]) -> Vec<u8> {
+	let mut rgb = Vec::with_capacity(pixels.len() * 3);
+	for pixel in pixels {
+	    rgb.extend_from_slice(&self.rgb(*pixel));
+	}
+	rgb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::PIXEL_EMPHASIS_SHIFT;
+
+    const RED_EMPHASIS: u16 = 0x20 << PIXEL_EMPHASIS_SHIFT;
+
+    #[test]
+    fn test_default_palette() {
+	let palette = Palette::default();
+	assert_eq!(palette.rgb(0x30), [0xFF, 0xFF, 0xFF]);
+	assert_eq!(palette.rgb(RED_EMPHASIS | 0x30), [0xFF, 0xD0, 0xD0]);
+	assert_eq!(palette.to_rgb(&[0x0F, 0x20]), vec![0x05, 0x05, 0x05, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_64_color_pal() {
+	let data: Vec<u8> = (0..COLORS * 3).map(|i| (i / 3) as u8 * 4).collect();
+	let palette = Palette::from_pal(&data).unwrap();
+	assert_eq!(palette.rgb(0x3F), [0xFC, 0xFC, 0xFC]);
+	assert_eq!(palette.rgb(0x1C0 | 0x3F), [0xA8, 0xA8, 0xA8]);
+    }
+
+    #[test]
+    fn test_512_color_pal() {
+	let data: Vec<u8> = (0..EMPHASIS_COLORS * 3).map(|i| (i / 3 / COLORS) as u8).collect();
+	let palette = Palette::from_pal(&data).unwrap();
+	assert_eq!(palette.rgb(0x05), [0, 0, 0]);
+	assert_eq!(palette.rgb(RED_EMPHASIS | 0x05), [1, 1, 1]);
+	assert_eq!(palette.rgb(0x1C0), [7, 7, 7]);
+    }
+
+    #[test]
+    fn test_bad_pal_size() {
+	assert!(Palette::from_pal(&[0; 100]).is_err());
+    }
+}
@@ -0,0 +1,842 @@
+use crate::cartridge::Mirroring;
+use crate::region::Region;
+use crate::savestate::{Snapshot, State};
+
+// 7  bit  0
+// ---- ----
+// VPHB SINN
+// |||| ||||
+// |||| ||++- Base nametable address
+// |||| |+--- VRAM address increment per CPU read/write of PPUDATA
+// |||| +---- Sprite pattern table address for 8x8 sprites
+// |||+------ Background pattern table address
+// ||+------- Sprite size
+// |+-------- PPU master/slave select
+// +--------- Generate an NMI at the start of vblank
+
+pub const PPUCTRL_NAMETABLE: u8 = 0x03;
+pub const PPUCTRL_INCREMENT_32: u8 = 0x01 << 2;
+pub const PPUCTRL_SPRITE_TABLE: u8 = 0x01 << 3;
+pub const PPUCTRL_BACKGROUND_TABLE: u8 = 0x01 << 4;
+pub const PPUCTRL_SPRITE_8X16: u8 = 0x01 << 5;
+pub const PPUCTRL_NMI_ENABLE: u8 = 0x01 << 7;
+
+// 7  bit  0
+// ---- ----
+// BGRs bMmG
+// |||| ||||
+// |||| |||+- Greyscale
+// |||| ||+-- Show background in leftmost 8 pixels of screen
+// |||| |+--- Show sprites in leftmost 8 pixels of screen
+// |||| +---- Show background
+// |||+------ Show sprites
+// ||+------- Emphasize red
+// |+-------- Emphasize green
+// +--------- Emphasize blue
+
+pub const PPUMASK_GREYSCALE: u8 = 0x01;
+pub const PPUMASK_BACKGROUND_LEFT: u8 = 0x01 << 1;
+pub const PPUMASK_SPRITES_LEFT: u8 = 0x01 << 2;
+pub const PPUMASK_SHOW_BACKGROUND: u8 = 0x01 << 3;
+pub const PPUMASK_SHOW_SPRITES: u8 = 0x01 << 4;
+pub const PPUMASK_EMPHASIS: u8 = 0xE0;
+
+// Framebuffer pixels carry the emphasis bits above the 6-bit color, which
+// makes them direct indices into a 512-entry palette.
+pub const PIXEL_EMPHASIS_SHIFT: u16 = 1;
+
+// 7  bit  0
+// ---- ----
+// VSO. ....
+// |||| ||||
+// |||+-++++- PPU open bus
+// ||+------- Sprite overflow
+// |+-------- Sprite 0 hit
+// +--------- Vertical blank has started
+
+pub const PPUSTATUS_VBLANK: u8 = 0x01 << 7;
+pub const PPUSTATUS_SPRITE_0_HIT: u8 = 0x01 << 6;
+pub const PPUSTATUS_SPRITE_OVERFLOW: u8 = 0x01 << 5;
+
+const PPUCTRL: u16 = 0;
+const PPUMASK: u16 = 1;
+const PPUSTATUS: u16 = 2;
+const OAMADDR: u16 = 3;
+const OAMDATA: u16 = 4;
+const PPUSCROLL: u16 = 5;
+const PPUADDR: u16 = 6;
+const PPUDATA: u16 = 7;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+pub const DOTS_PER_SCANLINE: u16 = 341;
+
+const CHR_RAM_SIZE: usize = 0x2000;
+
+pub struct	Ppu {
+    ctrl:	u8,
+    mask:	u8,
+    status:	u8,
+    io_latch:	u8,	// last value put on the PPU's data bus, read back as open bus
+    w:		bool,	// first/second write toggle shared by $2005 and $2006
+
+    // https://www.nesdev.org/wiki/PPU_scrolling
+    v:		u16,	// current VRAM address
+    t:		u16,	// temporary VRAM address, top left onscreen tile
+    x:		u8,	// fine x scroll
+    read_buffer:	u8,
+
+    oam_addr:	u8,
+    oam:	[u8; 256],
+    nametables:	[u8; 0x1000],
+    palette:	[u8; 32],
+    chr:	Vec<u8>,
+    chr_is_ram:	bool,
+    mirroring:	Mirroring,
+    region:	Region,
+
+    scanline:	u16,
+    dot:	u16,	// next dot to be clocked on the current scanline
+    frame:	u64,
+    frame_complete:	bool,
+    framebuffer:	Vec<u16>,	// emphasis | color, SCREEN_WIDTH * SCREEN_HEIGHT
+
+    nmi_occurred:	bool,	// latched rising edge of the NMI output
+    nmi_delayed:	bool,	// edge raised by a register write, visible from the next dot
+    vblank_suppressed:	bool,	// $2002 was read the dot before vblank
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+	Self::new()
+    }
+}
+
+impl	Ppu {
+    pub fn	new() -> Self {
+	Ppu {
+	    ctrl: 0,
+	    mask: 0,
+	    status: 0,
+	    io_latch: 0,
+	    w: false,
+	    v: 0,
+	    t: 0,
+	    x: 0,
+	    read_buffer: 0,
+	    oam_addr: 0,
+	    oam: [0; 256],
+	    nametables: [0; 0x1000],
+	    palette: [0; 32],
+	    chr: vec![0; CHR_RAM_SIZE],
+	    chr_is_ram: true,
+	    mirroring: Mirroring::Horizontal,
+	    region: Region::Ntsc,
+	    scanline: 0,
+	    dot: 0,
+	    frame: 0,
+	    frame_complete: false,
+	    framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+	    nmi_occurred: false,
+	    nmi_delayed: false,
+	    vblank_suppressed: false,
+	}
+    }
+
+    /// Hooks up the cartridge's pattern tables. An empty `chr` means the
+    /// board carries 8KB of CHR RAM instead.
+    pub fn load_chr(&mut self, chr: &[u8], mirroring: Mirroring) {
+	self.chr_is_ram = chr.is_empty();
+	self.chr = if self.chr_is_ram { vec![0; CHR_RAM_SIZE] } else { chr.to_vec() };
+	self.mirroring = mirroring;
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+	self.region = region;
+    }
+
+    pub fn framebuffer(&self) -> &[u16] {
+	&self.framebuffer
+    }
+
+    pub fn frame(&self) -> u64 {
+	self.frame
+    }
+
+    /// The scanline being drawn and the next dot on it.
+    pub fn position(&self) -> (u16, u16) {
+	(self.scanline, self.dot)
+    }
+
+    /// True once per frame, when the picture is complete at the start of
+    /// vblank.
+    pub fn take_frame(&mut self) -> bool {
+	let complete = self.frame_complete;
+	self.frame_complete = false;
+	complete
+    }
+
+    // greyscale keeps only the luma column of the color
+    fn color_mask(&self) -> u8 {
+	if self.mask & PPUMASK_GREYSCALE != 0 { 0x30 } else { 0x3F }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+	self.mask & (PPUMASK_SHOW_BACKGROUND | PPUMASK_SHOW_SPRITES) != 0
+    }
+
+    fn nmi_output(&self) -> bool {
+	self.ctrl & PPUCTRL_NMI_ENABLE != 0 && self.status & PPUSTATUS_VBLANK != 0
+    }
+
+    // true when the vblank flag was raised on the dot just clocked or the one
+    // before it, which is the window where $2002/$2000 accesses race the NMI
+    fn vblank_just_started(&self) -> bool {
+	self.scanline == self.region.vblank_scanline() && (self.dot == 2 || self.dot == 3)
+    }
+
+    /// Returns and clears the latched NMI edge. The CPU calls this once per
+    /// instruction, right before its last cycle.
+    pub fn take_nmi(&mut self) -> bool {
+	let occurred = self.nmi_occurred;
+	self.nmi_occurred = false;
+	occurred
+    }
+
+    fn nametable_index(&self, addr: u16) -> usize {
+	let table = ((addr >> 10) & 0x03) as usize;
+	let physical = match self.mirroring {
+	    Mirroring::Horizontal => table >> 1,
+	    Mirroring::Vertical => table & 0x01,
+	    Mirroring::FourScreen => table,
+	};
+	physical * 0x400 + (addr & 0x03FF) as usize
+    }
+
+    fn palette_index(addr: u16) -> usize {
+	let index = (addr & 0x1F) as usize;
+	// the backdrop entries of the sprite palettes mirror the background ones
+	if index & 0x13 == 0x10 { index & 0x0F } else { index }
+    }
+
+    // v keeps a 15th bit for fine Y, so addresses past $3FFF come in and
+    // mirror down
+    fn ppu_read(&self, addr: u16) -> u8 {
+	let addr = addr & 0x3FFF;
+	match addr {
+	    0x0000..=0x1FFF => self.chr.get(addr as usize).copied().unwrap_or(0),
+	    0x2000..=0x3EFF => self.nametables[self.nametable_index(addr)],
+	    _ => self.palette[Self::palette_index(addr)],
+	}
+    }
+
+    fn ppu_write(&mut self, addr: u16, value: u8) {
+	let addr = addr & 0x3FFF;
+	match addr {
+	    0x0000..=0x1FFF => {
+		if self.chr_is_ram {
+		    self.chr[addr as usize] = value;
+		}
+	    }
+	    0x2000..=0x3EFF => { let i = self.nametable_index(addr); self.nametables[i] = value; }
+	    _ => self.palette[Self::palette_index(addr)] = value & 0x3F,
+	}
+    }
+
+    fn increment_v(&mut self) {
+	let step = if self.ctrl & PPUCTRL_INCREMENT_32 != 0 { 32 } else { 1 };
+	self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+	match addr & 0x0007 {
+	    PPUSTATUS => {
+		if self.scanline == self.region.vblank_scanline() && self.dot == 1 {
+		    // one dot early: the flag reads clear and never gets set
+		    // this frame, so no NMI either
+		    self.vblank_suppressed = true;
+		}
+		else if self.vblank_just_started() {
+		    self.nmi_occurred = false;
+		}
+		let value = self.status | (self.io_latch & 0x1F);
+		self.status &= !PPUSTATUS_VBLANK;
+		self.w = false;
+		self.io_latch = value;
+		value
+	    }
+	    OAMDATA => {
+		self.io_latch = self.oam[self.oam_addr as usize];
+		self.io_latch
+	    }
+	    PPUDATA => {
+		let addr = self.v & 0x3FFF;
+		let value = if addr >= 0x3F00 {
+		    // palette reads aren't buffered, the buffer gets the
+		    // nametable byte "underneath" instead
+		    self.read_buffer = self.ppu_read(addr - 0x1000);
+		    (self.ppu_read(addr) & self.color_mask()) | (self.io_latch & 0xC0)
+		}
+		else {
+		    let buffered = self.read_buffer;
+		    self.read_buffer = self.ppu_read(addr);
+		    buffered
+		};
+		self.increment_v();
+		self.io_latch = value;
+		value
+	    }
+	    _ => self.io_latch,
+	}
+    }
+
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+	self.io_latch = value;
+	match addr & 0x0007 {
+	    PPUCTRL => {
+		let was_high = self.nmi_output();
+		self.ctrl = value;
+		self.t = (self.t & !0x0C00) | (((value & PPUCTRL_NAMETABLE) as u16) << 10);
+		if !was_high && self.nmi_output() {
+		    self.nmi_delayed = true;
+		}
+		else if value & PPUCTRL_NMI_ENABLE == 0 && self.vblank_just_started() {
+		    self.nmi_occurred = false;
+		}
+	    }
+	    PPUMASK => { self.mask = value; }
+	    OAMADDR => { self.oam_addr = value; }
+	    OAMDATA => { self.write_oam(value); }
+	    PPUSCROLL => {
+		if !self.w {
+		    self.t = (self.t & !0x001F) | (value >> 3) as u16;
+		    self.x = value & 0x07;
+		}
+		else {
+		    self.t = (self.t & !0x73E0)
+			| (((value & 0x07) as u16) << 12)
+			| (((value >> 3) as u16) << 5);
+		}
+		self.w = !self.w;
+	    }
+	    PPUADDR => {
+		if !self.w {
+		    self.t = (self.t & 0x00FF) | (((value & 0x3F) as u16) << 8);
+		}
+		else {
+		    self.t = (self.t & 0x7F00) | value as u16;
+		    self.v = self.t;
+		}
+		self.w = !self.w;
+	    }
+	    PPUDATA => {
+		self.ppu_write(self.v, value);
+		self.increment_v();
+	    }
+	    _ => {}
+	}
+    }
+
+    /// Writes through OAMDATA, which is also where OAM DMA bytes end up.
+    pub fn write_oam(&mut self, value: u8) {
+	self.oam[self.oam_addr as usize] = value;
+	self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    fn increment_x(v: &mut u16) {
+	if *v & 0x001F == 31 {
+	    *v &= !0x001F;
+	    *v ^= 0x0400;
+	}
+	else {
+	    *v += 1;
+	}
+    }
+
+    fn increment_y(&mut self) {
+	if self.v & 0x7000 != 0x7000 {
+	    self.v += 0x1000;
+	    return;
+	}
+	self.v &= !0x7000;
+	let mut coarse_y = (self.v & 0x03E0) >> 5;
+	if coarse_y == 29 {
+	    coarse_y = 0;
+	    self.v ^= 0x0800;
+	}
+	else if coarse_y == 31 {
+	    coarse_y = 0;
+	}
+	else {
+	    coarse_y += 1;
+	}
+	self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // Background pixels of the current scanline as palette RAM indices, 0
+    // where transparent.
+    fn render_background(&self, line: &mut [u8; SCREEN_WIDTH]) {
+	let mut v = self.v;
+	let fine_y = (v >> 12) & 0x07;
+	let table: u16 = if self.ctrl & PPUCTRL_BACKGROUND_TABLE != 0 { 0x1000 } else { 0 };
+
+	for tile in 0..33 {
+	    let name = self.ppu_read(0x2000 | (v & 0x0FFF)) as u16;
+	    let attribute = self.ppu_read(0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+	    let shift = ((v >> 4) & 0x04) | (v & 0x02);
+	    let palette = ((attribute >> shift) & 0x03) << 2;
+	    let lo = self.ppu_read(table + name * 16 + fine_y);
+	    let hi = self.ppu_read(table + name * 16 + fine_y + 8);
+
+	    for bit in 0..8 {
+		let x = tile * 8 + bit - self.x as i32;
+		if !(0..SCREEN_WIDTH as i32).contains(&x) {
+		    continue;
+		}
+		let color = ((lo >> (7 - bit)) & 0x01) | (((hi >> (7 - bit)) & 0x01) << 1);
+		if color != 0 {
+		    line[x as usize] = palette | color;
+		}
+	    }
+	    Self::increment_x(&mut v);
+	}
+
+	if self.mask & PPUMASK_BACKGROUND_LEFT == 0 {
+	    line[..8].fill(0);
+	}
+    }
+
+    // Sprite pixels of the current scanline, with whether each one sits
+    // behind the background and whether it belongs to sprite 0.
+    fn render_sprites(&mut self, line: &mut [u8; SCREEN_WIDTH], behind: &mut [bool; SCREEN_WIDTH], zero: &mut [bool; SCREEN_WIDTH]) {
+	let height: i32 = if self.ctrl & PPUCTRL_SPRITE_8X16 != 0 { 16 } else { 8 };
+	let mut found = 0;
+
+	for sprite in 0..64 {
+	    let entry = &self.oam[sprite * 4..sprite * 4 + 4];
+	    // sprite data is delayed by a scanline, so OAM holds y - 1
+	    let mut row = self.scanline as i32 - entry[0] as i32 - 1;
+	    if !(0..height).contains(&row) {
+		continue;
+	    }
+	    found += 1;
+	    if found > 8 {
+		self.status |= PPUSTATUS_SPRITE_OVERFLOW;
+		break;
+	    }
+
+	    let (tile, attributes, x) = (entry[1] as u16, entry[2], entry[3] as usize);
+	    if attributes & 0x80 != 0 {
+		row = height - 1 - row;
+	    }
+	    let addr = if height == 8 {
+		let table: u16 = if self.ctrl & PPUCTRL_SPRITE_TABLE != 0 { 0x1000 } else { 0 };
+		table + tile * 16 + row as u16
+	    }
+	    else {
+		let table: u16 = (tile & 0x01) * 0x1000;
+		let tile = (tile & 0xFE) + (row as u16 >> 3);
+		table + tile * 16 + (row as u16 & 0x07)
+	    };
+	    let lo = self.ppu_read(addr);
+	    let hi = self.ppu_read(addr + 8);
+
+	    for bit in 0..8 {
+		// lower OAM entries win, even when transparent on top of
+		// something later
+		if x + bit >= SCREEN_WIDTH || line[x + bit] != 0 {
+		    continue;
+		}
+		let shift = if attributes & 0x40 != 0 { bit } else { 7 - bit };
+		let color = ((lo >> shift) & 0x01) | (((hi >> shift) & 0x01) << 1);
+		if color != 0 {
+		    line[x + bit] = 0x10 | ((attributes & 0x03) << 2) | color;
+		    behind[x + bit] = attributes & 0x20 != 0;
+		    zero[x + bit] = sprite == 0;
+		}
+	    }
+	}
+
+	if self.mask & PPUMASK_SPRITES_LEFT == 0 {
+	    line[..8].fill(0);
+	}
+    }
+
+    fn render_scanline(&mut self) {
+	let y = self.scanline as usize;
+	let mut background = [0u8; SCREEN_WIDTH];
+	let mut sprites = [0u8; SCREEN_WIDTH];
+	let mut behind = [false; SCREEN_WIDTH];
+	let mut zero = [false; SCREEN_WIDTH];
+	let color_mask = self.color_mask();
+	let mut emphasis = self.mask & PPUMASK_EMPHASIS;
+	if self.region.swaps_emphasis() {
+	    emphasis = (emphasis & 0x80) | ((emphasis & 0x20) << 1) | ((emphasis & 0x40) >> 1);
+	}
+	let emphasis = (emphasis as u16) << PIXEL_EMPHASIS_SHIFT;
+
+	if self.mask & PPUMASK_SHOW_BACKGROUND != 0 {
+	    self.render_background(&mut background);
+	}
+	if self.mask & PPUMASK_SHOW_SPRITES != 0 {
+	    self.render_sprites(&mut sprites, &mut behind, &mut zero);
+	}
+
+	for x in 0..SCREEN_WIDTH {
+	    let (bg, sp) = (background[x], sprites[x]);
+	    if zero[x] && bg != 0 && x != 255 {
+		self.status |= PPUSTATUS_SPRITE_0_HIT;
+	    }
+	    let entry = if sp != 0 && (bg == 0 || !behind[x]) { sp } else { bg };
+	    let color = self.palette[Self::palette_index(entry as u16)] & color_mask;
+	    self.framebuffer[y * SCREEN_WIDTH + x] = emphasis | color as u16;
+	}
+    }
+
+    pub fn tick(&mut self) {
+	if self.nmi_delayed {
+	    self.nmi_delayed = false;
+	    self.nmi_occurred = true;
+	}
+
+	let prerender = self.scanline == self.region.prerender_scanline();
+	let visible = (self.scanline as usize) < SCREEN_HEIGHT;
+	let rendering = self.rendering_enabled() && (visible || prerender);
+
+	match self.dot {
+	    1 if self.scanline == self.region.vblank_scanline() => {
+		if !self.vblank_suppressed {
+		    self.status |= PPUSTATUS_VBLANK;
+		    if self.nmi_output() {
+			self.nmi_occurred = true;
+		    }
+		}
+		self.vblank_suppressed = false;
+		self.frame_complete = true;
+	    }
+	    1 if prerender => {
+		self.status &= !(PPUSTATUS_VBLANK | PPUSTATUS_SPRITE_0_HIT | PPUSTATUS_SPRITE_OVERFLOW);
+	    }
+	    // the whole line is drawn at once, with the scroll as it stands
+	    // when the PPU is done fetching it
+	    256 => {
+		if visible {
+		    self.render_scanline();
+		}
+		if rendering {
+		    self.increment_y();
+		}
+	    }
+	    257 if rendering => {
+		self.v = (self.v & !0x041F) | (self.t & 0x041F);
+	    }
+	    304 if rendering && prerender => {
+		self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+	    }
+	    _ => {}
+	}
+
+	self.dot += 1;
+	// odd frames drop the last dot of the pre-render line while rendering
+	let skip_dot = prerender && self.dot == DOTS_PER_SCANLINE - 1 && self.region.skips_odd_frame_dot()
+	    && self.frame % 2 == 1 && self.rendering_enabled();
+	if self.dot == DOTS_PER_SCANLINE || skip_dot {
+	    self.dot = 0;
+	    self.scanline += 1;
+	    if self.scanline == self.region.scanlines() {
+		self.scanline = 0;
+		self.frame += 1;
+	    }
+	}
+    }
+}
+
+// CHR ROM and the mirroring come with the cartridge, the region with
+// the bus.
+impl Snapshot for Ppu {
+    fn snapshot(&mut self, state: &mut State) {
+	state.u8(&mut self.ctrl);
+	state.u8(&mut self.mask);
+	state.u8(&mut self.status);
+	state.u8(&mut self.io_latch);
+	state.bool(&mut self.w);
+	state.u16(&mut self.v);
+	state.u16(&mut self.t);
+	state.u8(&mut self.x);
+	state.u8(&mut self.read_buffer);
+	state.u8(&mut self.oam_addr);
+	state.bytes(&mut self.oam);
+	state.bytes(&mut self.nametables);
+	state.bytes(&mut self.palette);
+	if self.chr_is_ram {
+	    state.bytes(&mut self.chr);
+	}
+	state.u16(&mut self.scanline);
+	state.u16(&mut self.dot);
+	state.u64(&mut self.frame);
+	state.bool(&mut self.frame_complete);
+	state.u16s(&mut self.framebuffer);
+	state.bool(&mut self.nmi_occurred);
+	state.bool(&mut self.nmi_delayed);
+	state.bool(&mut self.vblank_suppressed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCANLINE_VBLANK: u16 = 241;
+    const SCANLINE_PRERENDER: u16 = 261;
+
+    fn tick_to(ppu: &mut Ppu, scanline: u16, dot: u16) {
+	while ppu.scanline != scanline || ppu.dot != dot {
+	    ppu.tick();
+	}
+    }
+
+    fn write_vram(ppu: &mut Ppu, addr: u16, bytes: &[u8]) {
+	ppu.write_register(0x2006, (addr >> 8) as u8);
+	ppu.write_register(0x2006, addr as u8);
+	for b in bytes {
+	    ppu.write_register(0x2007, *b);
+	}
+    }
+
+    // tile 1 is solid color 3, tile 2 is solid color 1
+    fn test_chr() -> Vec<u8> {
+	let mut chr = vec![0; 0x2000];
+	chr[16..32].fill(0xFF);
+	chr[32..40].fill(0xFF);
+	chr
+    }
+
+    #[test]
+    fn test_vblank_sets_flag_and_nmi() {
+	let mut ppu = Ppu::new();
+	ppu.write_register(0x2000, PPUCTRL_NMI_ENABLE);
+	tick_to(&mut ppu, SCANLINE_VBLANK, 1);
+	assert!(!ppu.take_nmi());
+	ppu.tick();
+	assert!(ppu.status & PPUSTATUS_VBLANK != 0);
+	assert!(ppu.take_nmi());
+	assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn test_vblank_without_nmi_enabled() {
+	let mut ppu = Ppu::new();
+	tick_to(&mut ppu, SCANLINE_VBLANK, 2);
+	assert!(ppu.status & PPUSTATUS_VBLANK != 0);
+	assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn test_vblank_cleared_on_prerender_line() {
+	let mut ppu = Ppu::new();
+	tick_to(&mut ppu, SCANLINE_VBLANK, 2);
+	tick_to(&mut ppu, SCANLINE_PRERENDER, 2);
+	assert!(ppu.status & PPUSTATUS_VBLANK == 0);
+    }
+
+    #[test]
+    fn test_status_read_clears_vblank() {
+	let mut ppu = Ppu::new();
+	tick_to(&mut ppu, SCANLINE_VBLANK, 10);
+	assert!(ppu.read_register(0x2002) & PPUSTATUS_VBLANK != 0);
+	assert!(ppu.read_register(0x2002) & PPUSTATUS_VBLANK == 0);
+    }
+
+    #[test]
+    fn test_status_read_one_dot_before_vblank() {
+	let mut ppu = Ppu::new();
+	ppu.write_register(0x2000, PPUCTRL_NMI_ENABLE);
+	tick_to(&mut ppu, SCANLINE_VBLANK, 1);
+	assert!(ppu.read_register(0x2002) & PPUSTATUS_VBLANK == 0);
+	tick_to(&mut ppu, SCANLINE_VBLANK, 10);
+	assert!(ppu.status & PPUSTATUS_VBLANK == 0);
+	assert!(!ppu.take_nmi());
+    }
+
+    #[test]
+    fn test_status_read_races_nmi() {
+	for dot in [2, 3] {
+	    let mut ppu = Ppu::new();
+	    ppu.write_register(0x2000, PPUCTRL_NMI_ENABLE);
+	    tick_to(&mut ppu, SCANLINE_VBLANK, dot);
+	    assert!(ppu.read_register(0x2002) & PPUSTATUS_VBLANK != 0);
+	    assert!(!ppu.take_nmi());
+	}
+
+	let mut ppu = Ppu::new();
+	ppu.write_register(0x2000, PPUCTRL_NMI_ENABLE);
+	tick_to(&mut ppu, SCANLINE_VBLANK, 4);
+	assert!(ppu.read_register(0x2002) & PPUSTATUS_VBLANK != 0);
+	assert!(ppu.take_nmi());
+    }
+
+    #[test]
+    fn test_enabling_nmi_during_vblank() {
+	let mut ppu = Ppu::new();
+	tick_to(&mut ppu, SCANLINE_VBLANK + 1, 0);
+	ppu.write_register(0x2000, PPUCTRL_NMI_ENABLE);
+	assert!(!ppu.take_nmi());
+	ppu.tick();
+	assert!(ppu.take_nmi());
+
+	// re-enabling only raises another edge after it was turned off
+	ppu.write_register(0x2000, PPUCTRL_NMI_ENABLE);
+	ppu.tick();
+	assert!(!ppu.take_nmi());
+	ppu.write_register(0x2000, 0);
+	ppu.write_register(0x2000, PPUCTRL_NMI_ENABLE);
+	ppu.tick();
+	assert!(ppu.take_nmi());
+    }
+
+    #[test]
+    fn test_odd_frame_skips_a_dot_when_rendering() {
+	let mut ppu = Ppu::new();
+	ppu.write_register(0x2001, PPUMASK_SHOW_BACKGROUND);
+	while ppu.frame < 1 {
+	    ppu.tick();
+	}
+	let mut dots = 0;
+	while ppu.frame < 3 {
+	    ppu.tick();
+	    dots += 1;
+	}
+	// one even and one odd frame
+	assert_eq!(dots, 341 * 262 * 2 - 1);
+    }
+
+    #[test]
+    fn test_pal_frame() {
+	let mut ppu = Ppu::new();
+	ppu.set_region(Region::Pal);
+	ppu.write_register(0x2001, PPUMASK_SHOW_BACKGROUND);
+	while ppu.frame < 1 {
+	    ppu.tick();
+	}
+	let mut dots = 0;
+	while ppu.frame < 3 {
+	    ppu.tick();
+	    dots += 1;
+	}
+	assert_eq!(dots, 341 * 312 * 2);
+	tick_to(&mut ppu, 260, 1);
+	assert!(ppu.status & PPUSTATUS_VBLANK != 0);
+    }
+
+    #[test]
+    fn test_ppudata_read_is_buffered() {
+	let mut ppu = Ppu::new();
+	write_vram(&mut ppu, 0x2400, &[0x11, 0x22]);
+	write_vram(&mut ppu, 0x3F00, &[0x0F]);
+	ppu.write_register(0x2006, 0x24);
+	ppu.write_register(0x2006, 0x00);
+	assert_eq!(ppu.read_register(0x2007), 0x00);
+	assert_eq!(ppu.read_register(0x2007), 0x11);
+	assert_eq!(ppu.read_register(0x2007), 0x22);
+
+	ppu.write_register(0x2006, 0x3F);
+	ppu.write_register(0x2006, 0x10);
+	assert_eq!(ppu.read_register(0x2007) & 0x3F, 0x0F);
+    }
+
+    #[test]
+    fn test_ppudata_wraps_past_3fff() {
+	let mut ppu = Ppu::new();
+	ppu.load_chr(&[], Mirroring::Horizontal);
+	// $3FFF then $4000, which is CHR RAM's $0000 again
+	write_vram(&mut ppu, 0x3FFF, &[0x0F, 0x42]);
+	assert_eq!(ppu.ppu_read(0x0000), 0x42);
+	assert_eq!(ppu.ppu_read(0x4000), 0x42);
+	ppu.write_register(0x2006, 0x3F);
+	ppu.write_register(0x2006, 0xFF);
+	ppu.read_register(0x2007);
+	ppu.read_register(0x2007);
+	assert_eq!(ppu.read_register(0x2007), 0x42);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+	let mut ppu = Ppu::new();
+	write_vram(&mut ppu, 0x2005, &[0x42]);
+	assert_eq!(ppu.ppu_read(0x2405), 0x42);
+	assert_eq!(ppu.ppu_read(0x2805), 0x00);
+	ppu.load_chr(&[], Mirroring::Vertical);
+	write_vram(&mut ppu, 0x2005, &[0x42]);
+	assert_eq!(ppu.ppu_read(0x2805), 0x42);
+    }
+
+    #[test]
+    fn test_render_background() {
+	let mut ppu = Ppu::new();
+	ppu.load_chr(&test_chr(), Mirroring::Horizontal);
+	write_vram(&mut ppu, 0x2000, &[0x01, 0x00, 0x02]);
+	write_vram(&mut ppu, 0x3F00, &[0x0F, 0x16, 0x00, 0x21]);
+	write_vram(&mut ppu, 0x0000, &[]);
+	ppu.write_register(0x2001, PPUMASK_SHOW_BACKGROUND | PPUMASK_BACKGROUND_LEFT);
+	while !ppu.take_frame() {
+	    ppu.tick();
+	}
+	let fb = ppu.framebuffer();
+	assert_eq!(fb[0], 0x21);
+	assert_eq!(fb[7 * SCREEN_WIDTH + 7], 0x21);
+	assert_eq!(fb[8], 0x0F);
+	assert_eq!(fb[16], 0x16);
+	assert_eq!(fb[8 * SCREEN_WIDTH], 0x0F);
+    }
+
+    #[test]
+    fn test_render_sprite_and_sprite_0_hit() {
+	let mut ppu = Ppu::new();
+	ppu.load_chr(&test_chr(), Mirroring::Horizontal);
+	write_vram(&mut ppu, 0x2000, &[0x00, 0x00, 0x01]);
+	write_vram(&mut ppu, 0x3F00, &[0x0F, 0x00, 0x00, 0x21]);
+	write_vram(&mut ppu, 0x3F13, &[0x16]);
+	write_vram(&mut ppu, 0x0000, &[]);
+	ppu.write_register(0x2003, 0);
+	for b in [0x03, 0x01, 0x00, 0x14] {
+	    ppu.write_register(0x2004, b);
+	}
+	ppu.write_register(0x2001, PPUMASK_SHOW_BACKGROUND | PPUMASK_SHOW_SPRITES);
+	tick_to(&mut ppu, 4, 0);
+	assert!(ppu.status & PPUSTATUS_SPRITE_0_HIT == 0);
+	tick_to(&mut ppu, 5, 0);
+	assert!(ppu.status & PPUSTATUS_SPRITE_0_HIT != 0);
+	while !ppu.take_frame() {
+	    ppu.tick();
+	}
+	let fb = ppu.framebuffer();
+	assert_eq!(fb[4 * SCREEN_WIDTH + 0x14], 0x16);
+	assert_eq!(fb[4 * SCREEN_WIDTH + 0x13], 0x21);
+	assert_eq!(fb[4 * SCREEN_WIDTH + 0x1C], 0x0F);
+	assert_eq!(fb[3 * SCREEN_WIDTH + 0x14], 0x21);
+    }
+
+    #[test]
+    fn test_greyscale_and_emphasis() {
+	let mut ppu = Ppu::new();
+	write_vram(&mut ppu, 0x3F00, &[0x16]);
+	write_vram(&mut ppu, 0x0000, &[]);
+	ppu.write_register(0x2001, PPUMASK_SHOW_BACKGROUND | PPUMASK_GREYSCALE | 0xA0);
+	while !ppu.take_frame() {
+	    ppu.tick();
+	}
+	assert_eq!(ppu.framebuffer()[0], 0x140 | 0x10);
+
+	// the 2C07 swaps red and green
+	ppu.set_region(Region::Pal);
+	while !ppu.take_frame() {
+	    ppu.tick();
+	}
+	assert_eq!(ppu.framebuffer()[0], 0x180 | 0x10);
+
+	ppu.write_register(0x2006, 0x3F);
+	ppu.write_register(0x2006, 0x00);
+	assert_eq!(ppu.read_register(0x2007) & 0x3F, 0x10);
+    }
+}
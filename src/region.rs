@@ -0,0 +1,198 @@
+use std::fmt;
+
+use crate::savestate::{Snapshot, State};
+
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+//
+//                      NTSC        PAL         Dendy
+// master clock         21.477 MHz  26.602 MHz  26.602 MHz
+// CPU divider          12          16          15
+// PPU divider          4           5           5
+// scanlines            262         312         312
+// vblank starts on     241         241         291
+// vblank lines         20          70          20
+// odd frame dot skip   yes         no          no
+// emphasis bits        BGR         BRG         BRG
+// APU tables           NTSC        PAL         NTSC
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+// CPU cycles at which the APU frame sequencer fires, for the 4-step and
+// the 5-step mode
+const FRAME_STEPS_NTSC: [[u32; 6]; 2] = [
+    [7457, 14913, 22371, 29828, 29829, 29830],
+    [7457, 14913, 22371, 29829, 37281, 37282],
+];
+const FRAME_STEPS_PAL: [[u32; 6]; 2] = [
+    [8313, 16627, 24939, 33252, 33253, 33254],
+    [8313, 16627, 24939, 33253, 41565, 41566],
+];
+
+// in CPU cycles
+const DMC_RATES_NTSC: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const DMC_RATES_PAL: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+const NOISE_PERIODS_NTSC: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const NOISE_PERIODS_PAL: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+impl	Region {
+    /// The CPU/PPU timing field of an NES 2.0 header, byte 12. Multi-region
+    /// games run as NTSC.
+    pub fn from_nes2(byte: u8) -> Self {
+	match byte & 0x03 {
+	    1 => Region::Pal,
+	    3 => Region::Dendy,
+	    _ => Region::Ntsc,
+	}
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, String> {
+	match name.to_ascii_lowercase().as_str() {
+	    "ntsc" => Ok(Region::Ntsc),
+	    "pal" => Ok(Region::Pal),
+	    "dendy" => Ok(Region::Dendy),
+	    _ => Err(format!("unknown region {}, expected ntsc, pal or dendy", name)),
+	}
+    }
+
+    /// Master clock frequency, in Hz.
+    pub fn master_clock(&self) -> f64 {
+	match self {
+	    Region::Ntsc => 236.25e6 / 11.0,
+	    Region::Pal | Region::Dendy => 26601712.5,
+	}
+    }
+
+    pub fn cpu_clock_rate(&self) -> f64 {
+	self.master_clock() / self.cpu_divider() as f64
+    }
+
+    /// Master clock ticks per CPU cycle.
+    pub fn cpu_divider(&self) -> u32 {
+	match self {
+	    Region::Ntsc => 12,
+	    Region::Pal => 16,
+	    Region::Dendy => 15,
+	}
+    }
+
+    /// Master clock ticks per PPU dot.
+    pub fn ppu_divider(&self) -> u32 {
+	match self {
+	    Region::Ntsc => 4,
+	    Region::Pal | Region::Dendy => 5,
+	}
+    }
+
+    pub fn scanlines(&self) -> u16 {
+	match self {
+	    Region::Ntsc => 262,
+	    Region::Pal | Region::Dendy => 312,
+	}
+    }
+
+    pub fn vblank_scanline(&self) -> u16 {
+	match self {
+	    Region::Ntsc | Region::Pal => 241,
+	    Region::Dendy => 291,
+	}
+    }
+
+    pub fn prerender_scanline(&self) -> u16 {
+	self.scanlines() - 1
+    }
+
+    pub fn skips_odd_frame_dot(&self) -> bool {
+	*self == Region::Ntsc
+    }
+
+    /// The 2C07 and the Dendy clones have PPUMASK's red and green emphasis
+    /// bits the other way round.
+    pub fn swaps_emphasis(&self) -> bool {
+	*self != Region::Ntsc
+    }
+}
+
+// The APU's timing tables. Dendy consoles clock their APU like an NTSC one.
+impl	Region {
+    fn pal_apu(&self) -> bool {
+	*self == Region::Pal
+    }
+
+    pub fn apu_frame_steps(&self, five_step: bool) -> &'static [u32; 6] {
+	let steps = if self.pal_apu() { &FRAME_STEPS_PAL } else { &FRAME_STEPS_NTSC };
+	&steps[five_step as usize]
+    }
+
+    pub fn dmc_rate(&self, index: u8) -> u16 {
+	let rates = if self.pal_apu() { &DMC_RATES_PAL } else { &DMC_RATES_NTSC };
+	rates[(index & 0x0F) as usize]
+    }
+
+    pub fn noise_period(&self, index: u8) -> u16 {
+	let periods = if self.pal_apu() { &NOISE_PERIODS_PAL } else { &NOISE_PERIODS_NTSC };
+	periods[(index & 0x0F) as usize]
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	f.write_str(match self {
+	    Region::Ntsc => "NTSC",
+	    Region::Pal => "PAL",
+	    Region::Dendy => "Dendy",
+	})
+    }
+}
+
+impl Snapshot for Region {
+    fn snapshot(&mut self, state: &mut State) {
+	let mut index = match self {
+	    Region::Ntsc => 0,
+	    Region::Pal => 1,
+	    Region::Dendy => 2,
+	};
+	state.u8(&mut index);
+	*self = match index {
+	    1 => Region::Pal,
+	    2 => Region::Dendy,
+	    _ => Region::Ntsc,
+	};
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_header_and_name() {
+	assert_eq!(Region::from_nes2(0), Region::Ntsc);
+	assert_eq!(Region::from_nes2(1), Region::Pal);
+	assert_eq!(Region::from_nes2(2), Region::Ntsc);
+	assert_eq!(Region::from_nes2(3), Region::Dendy);
+	assert_eq!(Region::from_name("PAL"), Ok(Region::Pal));
+	assert!(Region::from_name("secam").is_err());
+    }
+
+    #[test]
+    fn test_dots_per_cpu_cycle() {
+	assert_eq!(Region::Ntsc.cpu_divider() as f32 / Region::Ntsc.ppu_divider() as f32, 3.0);
+	assert_eq!(Region::Pal.cpu_divider() as f32 / Region::Pal.ppu_divider() as f32, 3.2);
+	assert_eq!(Region::Dendy.cpu_divider() as f32 / Region::Dendy.ppu_divider() as f32, 3.0);
+	assert_eq!(Region::Ntsc.cpu_clock_rate().round(), 1789773.0);
+    }
+
+    #[test]
+    fn test_apu_tables() {
+	assert_eq!(Region::Ntsc.dmc_rate(0), 428);
+	assert_eq!(Region::Pal.dmc_rate(0x0F), 50);
+	assert_eq!(Region::Dendy.noise_period(0x0F), 4068);
+	assert_eq!(Region::Pal.apu_frame_steps(true)[4], 41565);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+// The rewind buffer: save states taken every few frames, of which only
+// the newest is kept whole. Each older one is kept as its XOR against
+// the one after it, which is mostly zeros between nearby frames, so the
+// zeros are run-length encoded:
+//
+//     0x00 n    n + 1 zero bytes
+//     other     itself
+//
+// behind the state's length as 4 little-endian bytes. The oldest states
+// are dropped when the buffer grows past its memory budget.
+
+/// Save states of the last few frames, to go back to.
+pub struct	Rewind {
+    interval:	u64,	// in frames
+    budget:	usize,	// in bytes
+    newest:	Option<(u64, Vec<u8>)>,
+    deltas:	VecDeque<(u64, Vec<u8>)>,	// newest first
+    size:	usize,	// of newest and deltas
+}
+
+// `older` XOR `newer`, run-length encoded
+fn encode(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut out = (older.len() as u32).to_le_bytes().to_vec();
+    let mut zeros = 0;
+    for (i, byte) in older.iter().enumerate() {
+	let delta = byte ^ newer.get(i).unwrap_or(&0);
+	if delta == 0 {
+	    zeros += 1;
+	    if zeros == 256 {
+		out.extend_from_slice(&[0, 255]);
+		zeros = 0;
+	    }
+	    continue;
+	}
+	if zeros > 0 {
+	    out.extend_from_slice(&[0, zeros as u8 - 1]);
+	    zeros = 0;
+	}
+	out.push(delta);
+    }
+    if zeros > 0 {
+	out.extend_from_slice(&[0, zeros as u8 - 1]);
+    }
+    out
+}
+
+// the older state back out of `encode`'s delta and the newer one
+fn decode(delta: &[u8], newer: &[u8]) -> Vec<u8> {
+    let length = u32::from_le_bytes([delta[0], delta[1], delta[2], delta[3]]) as usize;
+    let mut out = Vec::with_capacity(length);
+    let mut bytes = delta[4..].iter();
+    while let Some(&byte) = bytes.next() {
+	if byte == 0 {
+	    let zeros = *bytes.next().unwrap_or(&0) as usize + 1;
+	    out.extend((out.len()..out.len() + zeros).map(|i| *newer.get(i).unwrap_or(&0)));
+	}
+	else {
+	    out.push(byte ^ newer.get(out.len()).unwrap_or(&0));
+	}
+    }
+    out
+}
+
+impl	Rewind {
+    /// Takes a state every `interval` frames and keeps as many as fit in
+    /// `budget` bytes, but always the newest.
+    pub fn	new(interval: u64, budget: usize) -> Self {
+	Rewind { interval: interval.max(1), budget, newest: None, deltas: VecDeque::new(), size: 0 }
+    }
+
+    /// The frame of the oldest state held.
+    pub fn oldest(&self) -> Option<u64> {
+	self.deltas.back().or(self.newest.as_ref()).map(|(frame, _)| *frame)
+    }
+
+    /// Adds `state`, the machine after `frame`, if it's on the interval.
+    pub fn capture(&mut self, frame: u64, state: Vec<u8>) {
+	if !frame.is_multiple_of(self.interval) {
+	    return;
+	}
+	self.size += state.len();
+	if let Some((newest_frame, newest)) = self.newest.take() {
+	    let delta = encode(&newest, &state);
+	    self.size += delta.len();
+	    self.size -= newest.len();
+	    self.deltas.push_front((newest_frame, delta));
+	}
+	self.newest = Some((frame, state));
+	while self.size > self.budget {
+	    match self.deltas.pop_back() {
+		Some((_, delta)) => self.size -= delta.len(),
+		None => break,
+	    }
+	}
+    }
+
+    /// Goes back to the newest state at or before `frame`, dropping those
+    /// after it, and returns it with its frame. None if the states held
+    /// don't go back that far.
+    pub fn rewind_to(&mut self, frame: u64) -> Option<(u64, &[u8])> {
+	if self.oldest()? > frame {
+	    return None;
+	}
+	while let Some((newest_frame, newest)) = self.newest.take() {
+	    if newest_frame <= frame {
+		self.newest = Some((newest_frame, newest));
+		break;
+	    }
+	    let (older_frame, delta) = self.deltas.pop_front()?;
+	    let older = decode(&delta, &newest);
+	    self.size -= newest.len() + delta.len();
+	    self.size += older.len();
+	    self.newest = Some((older_frame, older));
+	}
+	self.newest.as_ref().map(|(frame, state)| (*frame, state.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a state that changes a little from frame to frame
+    fn state(frame: u64) -> Vec<u8> {
+	let mut state = vec![0x55; 1000];
+	state[10] = frame as u8;
+	state[500..508].copy_from_slice(&frame.to_le_bytes());
+	state
+    }
+
+    #[test]
+    fn test_delta() {
+	let older = state(1);
+	let newer = state(2);
+	let delta = encode(&older, &newer);
+	assert!(delta.len() < 20, "{:?}", delta);
+	assert_eq!(decode(&delta, &newer), older);
+	// states of different lengths
+	assert_eq!(decode(&encode(&older[..700], &newer), &newer), older[..700]);
+	assert_eq!(decode(&encode(&older, &newer[..300]), &newer[..300]), older);
+	assert_eq!(decode(&encode(&[], &newer), &newer), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_step_back() {
+	let mut rewind = Rewind::new(2, 1 << 20);
+	for frame in 0..=10 {
+	    rewind.capture(frame, state(frame));
+	}
+	assert_eq!(rewind.oldest(), Some(0));
+	assert_eq!(rewind.rewind_to(7), Some((6, state(6).as_slice())));
+	assert_eq!(rewind.rewind_to(6), Some((6, state(6).as_slice())));
+	// new states after going back replace the ones that were dropped
+	rewind.capture(8, state(80));
+	assert_eq!(rewind.rewind_to(9), Some((8, state(80).as_slice())));
+	for frame in (0..=6).rev().step_by(2) {
+	    assert_eq!(rewind.rewind_to(frame).map(|(at, state)| (at, state.to_vec())), Some((frame, state(frame))));
+	}
+	assert_eq!(rewind.size, state(0).len());
+    }
+
+    #[test]
+    fn test_budget() {
+	let mut rewind = Rewind::new(1, 1100);
+	for frame in 0..100 {
+	    rewind.capture(frame, state(frame));
+	    assert!(rewind.size <= 1100);
+	}
+	let oldest = rewind.oldest().unwrap();
+	assert!(oldest > 0 && oldest < 99);
+	assert_eq!(rewind.rewind_to(oldest - 1), None);
+	assert_eq!(rewind.rewind_to(oldest), Some((oldest, state(oldest).as_slice())));
+
+	// the newest is kept even when it's over budget on its own
+	let mut rewind = Rewind::new(1, 10);
+	rewind.capture(0, state(0));
+	rewind.capture(1, state(1));
+	assert_eq!(rewind.oldest(), Some(1));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::hash;
+
+// Save states: the whole machine as a versioned, little-endian blob.
+//
+//     offset  size
+//     0       8     "RONSTATE"
+//     8       4     format version
+//     12      16    MD5 of the cartridge's PRG and CHR ROM, zeros without one
+//     28      4     length of the body
+//     32      4     CRC-32 of the body
+//     36            the body: each part's fields, in the order its
+//                   Snapshot visits them
+//
+// Whenever the body's layout changes, VERSION goes up. A state of
+// another version is refused with an error saying so, never misread.
+// Things that are set up rather than emulated, like what's plugged into
+// the controller ports and the audio output filters, aren't saved.
+
+const MAGIC: &[u8; 8] = b"RONSTATE";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 36;
+
+/// A part of the machine that can be saved and loaded.
+pub trait	Snapshot {
+    /// Hands each field to `state`, which either writes it out or
+    /// overwrites it. Going through the same calls both ways keeps the
+    /// order the same.
+    fn snapshot(&mut self, state: &mut State);
+}
+
+/// One pass over the machine, saving or loading.
+pub struct	State<'a> {
+    input:	Option<&'a [u8]>,	// the body, when loading
+    pos:	usize,
+    output:	Vec<u8>,
+    short:	bool,	// the body ended before the fields did
+    refused:	Option<String>,	// a field read that the machine can't take
+}
+
+impl<'a>	State<'a> {
+    fn new(input: Option<&'a [u8]>) -> Self {
+	State { input, pos: 0, output: Vec::new(), short: false, refused: None }
+    }
+
+    /// Turns the state down over a field that was read but doesn't fit
+    /// the machine, like a bank past the end of the ROM. The part keeps
+    /// its own value.
+    pub fn refuse(&mut self, why: String) {
+	self.refused.get_or_insert(why);
+    }
+
+    fn field<const N: usize>(&mut self, bytes: [u8; N]) -> [u8; N] {
+	let Some(input) = self.input else {
+	    self.output.extend_from_slice(&bytes);
+	    return bytes;
+	};
+	match input.get(self.pos..self.pos + N) {
+	    Some(field) => {
+		self.pos += N;
+		field.try_into().unwrap_or(bytes)
+	    }
+	    None => {
+		self.short = true;
+		bytes
+	    }
+	}
+    }
+
+    pub fn u8(&mut self, value: &mut u8) {
+	*value = u8::from_le_bytes(self.field(value.to_le_bytes()));
+    }
+
+    pub fn u16(&mut self, value: &mut u16) {
+	*value = u16::from_le_bytes(self.field(value.to_le_bytes()));
+    }
+
+    pub fn u32(&mut self, value: &mut u32) {
+	*value = u32::from_le_bytes(self.field(value.to_le_bytes()));
+    }
+
+    pub fn u64(&mut self, value: &mut u64) {
+	*value = u64::from_le_bytes(self.field(value.to_le_bytes()));
+    }
+
+    // as a u64, whatever the size of usize
+    pub fn usize(&mut self, value: &mut usize) {
+	let mut wide = *value as u64;
+	self.u64(&mut wide);
+	*value = wide as usize;
+    }
+
+    pub fn bool(&mut self, value: &mut bool) {
+	let mut byte = *value as u8;
+	self.u8(&mut byte);
+	*value = byte != 0;
+    }
+
+    pub fn option_u8(&mut self, value: &mut Option<u8>) {
+	let mut some = value.is_some();
+	let mut byte = value.unwrap_or(0);
+	self.bool(&mut some);
+	self.u8(&mut byte);
+	*value = some.then_some(byte);
+    }
+
+    /// A fixed-size block, like RAM.
+    pub fn bytes(&mut self, value: &mut [u8]) {
+	match self.input {
+	    Some(input) => match input.get(self.pos..self.pos + value.len()) {
+		Some(bytes) => {
+		    value.copy_from_slice(bytes);
+		    self.pos += value.len();
+		}
+		None => self.short = true,
+	    },
+	    None => self.output.extend_from_slice(value),
+	}
+    }
+
+    pub fn u16s(&mut self, value: &mut [u16]) {
+	for word in value {
+	    self.u16(word);
+	}
+    }
+
+    /// A part that's only there sometimes, like an expansion sound chip.
+    pub fn option<T: Snapshot + Default>(&mut self, value: &mut Option<T>) {
+	let mut some = value.is_some();
+	self.bool(&mut some);
+	match (some, value.as_mut()) {
+	    (true, Some(part)) => part.snapshot(self),
+	    (true, None) => {
+		let mut part = T::default();
+		part.snapshot(self);
+		*value = Some(part);
+	    }
+	    (false, _) => *value = None,
+	}
+    }
+}
+
+/// The machine's state with its header, for a cartridge whose ROM has
+/// the MD5 `rom`.
+pub fn save(machine: &mut impl Snapshot, rom: [u8; 16]) -> Vec<u8> {
+    let mut state = State::new(None);
+    machine.snapshot(&mut state);
+    let body = state.output;
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + body.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&rom);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&hash::crc32(&body).to_le_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+/// Loads a state made by `save`. The header is checked before anything
+/// is touched, and a body that doesn't fit is backed out again, so a
+/// state that's refused leaves the machine as it was.
+pub fn load(machine: &mut impl Snapshot, rom: [u8; 16], data: &[u8]) -> Result<(), String> {
+    if data.len() < HEADER_SIZE || &data[..8] != MAGIC {
+	return Err("not a save state".to_string());
+    }
+    let word = |offset: usize| u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]);
+    let version = word(8);
+    if version != VERSION {
+	return Err(format!("save state version {} is not supported, this build reads version {}", version, VERSION));
+    }
+    if data[12..28] != rom {
+	return Err("the save state is of another ROM".to_string());
+    }
+    let body = &data[HEADER_SIZE..];
+    if body.len() != word(28) as usize || hash::crc32(body) != word(32) {
+	return Err("the save state is truncated or corrupt".to_string());
+    }
+
+    // the fields are overwritten as they're read, so keep the machine's
+    // own state to put back if the body turns out not to fit
+    let mut backup = State::new(None);
+    machine.snapshot(&mut backup);
+    let mut state = State::new(Some(body));
+    machine.snapshot(&mut state);
+    let error = if state.short || state.pos != body.len() {
+	format!("the save state's body is {} bytes, version {} has {}", body.len(), VERSION, state.pos)
+    }
+    else if let Some(why) = state.refused {
+	format!("the save state doesn't fit this machine: {}", why)
+    }
+    else {
+	return Ok(());
+    };
+    machine.snapshot(&mut State::new(Some(&backup.output)));
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default, Debug, PartialEq)]
+    struct	Part {
+	a:	u8,
+	b:	u16,
+	c:	u64,
+	flag:	bool,
+	latch:	Option<u8>,
+	ram:	[u8; 4],
+	words:	[u16; 2],
+	chip:	Option<Chip>,
+    }
+
+    #[derive(Default, Debug, PartialEq)]
+    struct	Chip {
+	slot:	usize,
+    }
+
+    impl Snapshot for Chip {
+	fn snapshot(&mut self, state: &mut State) {
+	    let mut slot = self.slot;
+	    state.usize(&mut slot);
+	    if slot <= 0xFFFF {
+		self.slot = slot;
+	    }
+	    else {
+		state.refuse(format!("slot {:#X}", slot));
+	    }
+	}
+    }
+
+    impl Snapshot for Part {
+	fn snapshot(&mut self, state: &mut State) {
+	    state.u8(&mut self.a);
+	    state.u16(&mut self.b);
+	    state.u64(&mut self.c);
+	    state.bool(&mut self.flag);
+	    state.option_u8(&mut self.latch);
+	    state.bytes(&mut self.ram);
+	    state.u16s(&mut self.words);
+	    state.option(&mut self.chip);
+	}
+    }
+
+    fn part() -> Part {
+	Part {
+	    a: 1,
+	    b: 0x0302,
+	    c: u64::MAX,
+	    flag: true,
+	    latch: Some(9),
+	    ram: [4, 5, 6, 7],
+	    words: [0x1234, 0xFFFF],
+	    chip: Some(Chip { slot: 0x2000 }),
+	}
+    }
+
+    #[test]
+    fn test_round_trip() {
+	let rom = hash::md5(b"rom");
+	let data = save(&mut part(), rom);
+	assert_eq!(&data[..8], MAGIC);
+	assert_eq!(data[8..12], [1, 0, 0, 0]);
+	// little-endian
+	assert_eq!(data[HEADER_SIZE..HEADER_SIZE + 3], [1, 0x02, 0x03]);
+
+	let mut loaded = Part::default();
+	load(&mut loaded, rom, &data).unwrap();
+	assert_eq!(loaded, part());
+    }
+
+    #[test]
+    fn test_refused_states() {
+	let rom = hash::md5(b"rom");
+	let data = save(&mut part(), rom);
+	let mut loaded = Part::default();
+
+	assert_eq!(load(&mut loaded, rom, b"RONSTATE"), Err("not a save state".to_string()));
+	let mut future = data.clone();
+	future[8] = 2;
+	assert_eq!(load(&mut loaded, rom, &future),
+		   Err("save state version 2 is not supported, this build reads version 1".to_string()));
+	assert!(load(&mut loaded, [0; 16], &data).is_err());
+	let mut corrupt = data.clone();
+	corrupt[HEADER_SIZE] ^= 1;
+	assert!(load(&mut loaded, rom, &corrupt).is_err());
+	assert!(load(&mut loaded, rom, &data[..data.len() - 1]).is_err());
+	assert_eq!(loaded, Part::default());
+    }
+
+    #[test]
+    fn test_refused_body_is_backed_out() {
+	let rom = hash::md5(b"rom");
+	// a body one byte short of its fields, with a header that fits it
+	let mut data = save(&mut part(), rom);
+	data.pop();
+	let body_len = (data.len() - HEADER_SIZE) as u32;
+	data[28..32].copy_from_slice(&body_len.to_le_bytes());
+	let crc = hash::crc32(&data[HEADER_SIZE..]);
+	data[32..36].copy_from_slice(&crc.to_le_bytes());
+
+	let mut loaded = Part { a: 7, ram: [1, 2, 3, 4], ..Part::default() };
+	let error = load(&mut loaded, rom, &data).unwrap_err();
+	assert!(error.starts_with("the save state's body is"), "{}", error);
+	assert_eq!(loaded, Part { a: 7, ram: [1, 2, 3, 4], ..Part::default() });
+
+	let mut bad = part();
+	bad.chip = Some(Chip { slot: 0x10000 });
+	let error = load(&mut loaded, rom, &save(&mut bad, rom)).unwrap_err();
+	assert_eq!(error, "the save state doesn't fit this machine: slot 0x10000");
+	assert_eq!(loaded, Part { a: 7, ram: [1, 2, 3, 4], ..Part::default() });
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+// Mono 16-bit PCM. The RIFF and data chunk sizes are only known once the
+// last sample is in, so they're patched in by `finish`.
+// http://soundfile.sapp.org/doc/WaveFormat/
+
+const WAV_HEADER_SIZE: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+const CHANNELS: u16 = 1;
+
+pub struct	WavWriter<W: Write + Seek> {
+    out:	W,
+    samples:	u32,
+}
+
+impl<W: Write + Seek>	WavWriter<W> {
+    pub fn	new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+	let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+	out.write_all(b"RIFF")?;
+	out.write_all(&(WAV_HEADER_SIZE - 8).to_le_bytes())?;
+	out.write_all(b"WAVE")?;
+	out.write_all(b"fmt ")?;
+	out.write_all(&16u32.to_le_bytes())?;
+	out.write_all(&1u16.to_le_bytes())?;	// PCM
+	out.write_all(&CHANNELS.to_le_bytes())?;
+	out.write_all(&sample_rate.to_le_bytes())?;
+	out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+	out.write_all(&block_align.to_le_bytes())?;
+	out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+	out.write_all(b"data")?;
+	out.write_all(&0u32.to_le_bytes())?;
+	Ok(WavWriter { out, samples: 0 })
+    }
+
+    /// Samples run from -1.0 to 1.0; anything past that is clipped.
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+	for sample in samples {
+	    let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+	    self.out.write_all(&value.to_le_bytes())?;
+	}
+	self.samples += samples.len() as u32;
+	Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+	let data_size = self.samples * (BITS_PER_SAMPLE / 8) as u32;
+	self.out.seek(SeekFrom::Start(4))?;
+	self.out.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+	self.out.seek(SeekFrom::Start(40))?;
+	self.out.write_all(&data_size.to_le_bytes())?;
+	self.out.seek(SeekFrom::End(0))?;
+	self.out.flush()?;
+	Ok(self.out)
+    }
+}
+
+pub fn create(path: &str, sample_rate: u32) -> io::Result<WavWriter<BufWriter<File>>> {
+    WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_and_samples() {
+	let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+	wav.write_samples(&[0.0, 1.0, -2.0]).unwrap();
+	let bytes = wav.finish().unwrap().into_inner();
+
+	assert_eq!(bytes.len(), 44 + 6);
+	assert_eq!(&bytes[0..4], b"RIFF");
+	assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 6);
+	assert_eq!(&bytes[8..16], b"WAVEfmt ");
+	assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 44100);
+	assert_eq!(u32::from_le_bytes(bytes[28..32].try_into().unwrap()), 88200);
+	assert_eq!(&bytes[36..40], b"data");
+	assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 6);
+	assert_eq!(&bytes[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
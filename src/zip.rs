@@ -0,0 +1,277 @@
+// Reading zip archives, for BizHawk's movies: the central directory, and
+// entries that are stored or deflated, which is all zip writers use for
+// text. Nothing is written, so there's no compressor.
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+// https://www.rfc-editor.org/rfc/rfc1951
+
+use crate::hash;
+
+const LOCAL_HEADER: u32 = 0x04034B50;
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER: u32 = 0x02014B50;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054B50;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]])).ok_or("truncated zip".to_string())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).ok_or("truncated zip".to_string())
+}
+
+struct	Entry {
+    name:	String,
+    method:	u16,
+    crc:	u32,
+    compressed_size:	usize,
+    size:	usize,
+    header_offset:	usize,	// of the local header, which the data follows
+}
+
+pub struct	Zip<'a> {
+    data:	&'a [u8],
+    entries:	Vec<Entry>,
+}
+
+impl<'a>	Zip<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+	// the end record is last, followed by a comment of up to 64KB
+	let end = (0..=data.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE)).rev()
+	    .take(0x10000 + END_OF_CENTRAL_DIRECTORY_SIZE)
+	    .find(|offset| u32_at(data, *offset) == Ok(END_OF_CENTRAL_DIRECTORY))
+	    .ok_or("not a zip file")?;
+	let count = u16_at(data, end + 10)? as usize;
+	let mut offset = u32_at(data, end + 16)? as usize;
+
+	let mut entries = Vec::with_capacity(count);
+	for _ in 0..count {
+	    if u32_at(data, offset)? != CENTRAL_HEADER {
+		return Err("broken zip central directory".to_string());
+	    }
+	    let name_len = u16_at(data, offset + 28)? as usize;
+	    let name = data.get(offset + CENTRAL_HEADER_SIZE..offset + CENTRAL_HEADER_SIZE + name_len).ok_or("truncated zip")?;
+	    entries.push(Entry {
+		name: String::from_utf8_lossy(name).to_string(),
+		method: u16_at(data, offset + 10)?,
+		crc: u32_at(data, offset + 16)?,
+		compressed_size: u32_at(data, offset + 20)? as usize,
+		size: u32_at(data, offset + 24)? as usize,
+		header_offset: u32_at(data, offset + 42)? as usize,
+	    });
+	    offset += CENTRAL_HEADER_SIZE + name_len + u16_at(data, offset + 30)? as usize + u16_at(data, offset + 32)? as usize;
+	}
+	Ok(Zip { data, entries })
+    }
+
+    /// The contents of the entry called `name`, checked against its CRC.
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, String> {
+	let entry = self.entries.iter().find(|entry| entry.name == name).ok_or(format!("no {} in the zip", name))?;
+	let offset = entry.header_offset;
+	if u32_at(self.data, offset)? != LOCAL_HEADER {
+	    return Err(format!("{}: broken zip local header", name));
+	}
+	let start = offset + LOCAL_HEADER_SIZE + u16_at(self.data, offset + 26)? as usize + u16_at(self.data, offset + 28)? as usize;
+	let compressed = self.data.get(start..start + entry.compressed_size).ok_or("truncated zip")?;
+	let contents = match entry.method {
+	    METHOD_STORED => compressed.to_vec(),
+	    METHOD_DEFLATE => inflate(compressed).map_err(|e| format!("{}: {}", name, e))?,
+	    method => return Err(format!("{}: zip compression method {} is not supported", name, method)),
+	};
+	if contents.len() != entry.size || hash::crc32(&contents) != entry.crc {
+	    return Err(format!("{}: CRC mismatch in zip", name));
+	}
+	Ok(contents)
+    }
+}
+
+// Deflate streams are read least significant bit first.
+struct	BitReader<'a> {
+    data:	&'a [u8],
+    pos:	usize,
+    buffer:	u32,
+    count:	u32,
+}
+
+impl	BitReader<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32, String> {
+	while self.count < n {
+	    let byte = *self.data.get(self.pos).ok_or("deflate stream ends early")?;
+	    self.pos += 1;
+	    self.buffer |= (byte as u32) << self.count;
+	    self.count += 8;
+	}
+	let value = self.buffer & ((1u64 << n) - 1) as u32;
+	self.buffer >>= n;
+	self.count -= n;
+	Ok(value)
+    }
+
+    // stored blocks start on a byte boundary
+    fn align(&mut self) {
+	self.buffer = 0;
+	self.count = 0;
+    }
+}
+
+const MAX_CODE_LENGTH: usize = 15;
+
+// A canonical Huffman code, as the number of codes of each length and
+// the symbols in code order.
+struct	Huffman {
+    counts:	[u16; MAX_CODE_LENGTH + 1],
+    symbols:	Vec<u16>,
+}
+
+impl	Huffman {
+    fn	new(lengths: &[u8]) -> Self {
+	let mut counts = [0; MAX_CODE_LENGTH + 1];
+	for length in lengths {
+	    counts[*length as usize] += 1;
+	}
+	counts[0] = 0;
+	let mut symbols = Vec::with_capacity(lengths.len());
+	for length in 1..=MAX_CODE_LENGTH {
+	    for (symbol, _) in lengths.iter().enumerate().filter(|(_, l)| **l as usize == length) {
+		symbols.push(symbol as u16);
+	    }
+	}
+	Huffman { counts, symbols }
+    }
+
+    // one bit at a time, comparing against the first code of each length
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+	let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+	for count in &self.counts[1..] {
+	    code |= reader.bits(1)? as i32;
+	    let count = *count as i32;
+	    if code - first < count {
+		return Ok(self.symbols[(index + code - first) as usize]);
+	    }
+	    index += count;
+	    first = (first + count) << 1;
+	    code <<= 1;
+	}
+	Err("bad Huffman code in deflate stream".to_string())
+    }
+}
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// the order code length code lengths come in, in a dynamic block header
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+const END_OF_BLOCK: u16 = 256;
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let literals = reader.bits(5)? as usize + 257;
+    let distances = reader.bits(5)? as usize + 1;
+    let code_lengths = reader.bits(4)? as usize + 4;
+    let mut lengths = [0u8; 19];
+    for index in &CODE_LENGTH_ORDER[..code_lengths] {
+	lengths[*index] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths);
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+	let (length, repeat) = match code_length_code.decode(reader)? {
+	    length @ 0..=15 => (length as u8, 1),
+	    16 => (*lengths.last().ok_or("deflate repeats a length before the first")?, 3 + reader.bits(2)?),
+	    17 => (0, 3 + reader.bits(3)?),
+	    _ => (0, 11 + reader.bits(7)?),
+	};
+	lengths.extend(std::iter::repeat_n(length, repeat as usize));
+    }
+    if lengths.len() > literals + distances {
+	return Err("deflate code lengths run over".to_string());
+    }
+    Ok((Huffman::new(&lengths[..literals]), Huffman::new(&lengths[literals..])))
+}
+
+/// Decompresses a raw deflate stream.
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader { data, pos: 0, buffer: 0, count: 0 };
+    let mut out = Vec::new();
+    loop {
+	let last = reader.bits(1)? != 0;
+	let (literal, distance) = match reader.bits(2)? {
+	    0 => {
+		reader.align();
+		let len = u16_at(data, reader.pos).map_err(|_| "deflate stream ends early")? as usize;
+		let block = data.get(reader.pos + 4..reader.pos + 4 + len).ok_or("deflate stream ends early")?;
+		out.extend_from_slice(block);
+		reader.pos += 4 + len;
+		if last {
+		    return Ok(out);
+		}
+		continue;
+	    }
+	    1 => fixed_codes(),
+	    2 => dynamic_codes(&mut reader)?,
+	    _ => return Err("bad deflate block type".to_string()),
+	};
+	loop {
+	    let symbol = literal.decode(&mut reader)?;
+	    if symbol < END_OF_BLOCK {
+		out.push(symbol as u8);
+		continue;
+	    }
+	    if symbol == END_OF_BLOCK {
+		break;
+	    }
+	    let index = (symbol - END_OF_BLOCK - 1) as usize;
+	    if index >= LENGTH_BASES.len() {
+		return Err("bad deflate length".to_string());
+	    }
+	    let length = LENGTH_BASES[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+	    let index = distance.decode(&mut reader)? as usize;
+	    if index >= DISTANCE_BASES.len() {
+		return Err("bad deflate distance".to_string());
+	    }
+	    let back = DISTANCE_BASES[index] as usize + reader.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+	    if back > out.len() {
+		return Err("deflate distance goes back too far".to_string());
+	    }
+	    // the copy can overlap what it's writing
+	    for _ in 0..length {
+		out.push(out[out.len() - back]);
+	    }
+	}
+	if last {
+	    return Ok(out);
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inflate() {
+	// stored, then fixed Huffman codes, both from zlib
+	assert_eq!(inflate(&[0x01, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c']), Ok(b"abc".to_vec()));
+	let fixed = [0x4B, 0x4C, 0x4A, 0x4E, 0x44, 0x42, 0x0A, 0x19, 0xA9, 0x39, 0x39, 0xF9, 0x10, 0x12, 0x00];
+	assert_eq!(inflate(&fixed), Ok(b"abcabcabcabcabc hello hello".to_vec()));
+	assert!(inflate(&fixed[..8]).is_err());
+	assert!(inflate(&[0x07]).is_err());
+    }
+}